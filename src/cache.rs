@@ -0,0 +1,297 @@
+use git2::{ObjectType, Oid};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::hash::Hash;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+mod disk;
+
+pub use disk::DiskCache;
+
+/// Number of missing names remembered at most
+const NEGATIVE_CACHE_SIZE: usize = 1 << 16;
+
+/// Least recently used cache of blob contents, bounded by their total size.
+/// Evicted contents may move to a second tier where they are kept compressed.
+pub struct ContentCache {
+    budget: usize,
+    used: usize,
+    // object id => (content, last use)
+    entries: HashMap<Oid, (Arc<[u8]>, u64)>,
+    // last use => object id, oldest first
+    order: BTreeMap<u64, Oid>,
+    tick: u64,
+    compressed: CompressedTier,
+}
+
+impl ContentCache {
+    /// A cache holding up to `budget` bytes, 0 disables caching, and up to
+    /// `compressed` bytes of evicted contents once compressed
+    pub fn new(budget: usize, compressed: usize) -> Self {
+        ContentCache {
+            budget,
+            used: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            compressed: CompressedTier::new(compressed),
+        }
+    }
+
+    pub fn get(&mut self, oid: Oid) -> Option<Arc<[u8]>> {
+        self.tick += 1;
+        if let Some((content, last_use)) = self.entries.get_mut(&oid) {
+            self.order.remove(last_use);
+            self.order.insert(self.tick, oid);
+            *last_use = self.tick;
+            return Some(content.clone());
+        }
+
+        // back to the uncompressed tier, it is in use again
+        let content = self.compressed.take(oid)?;
+        self.insert(oid, content.clone());
+        Some(content)
+    }
+
+    /// Cache `content`, evicting the least recently used entries to make room.
+    /// Contents larger than the whole budget are not cached.
+    pub fn insert(&mut self, oid: Oid, content: Arc<[u8]>) {
+        if content.len() > self.budget || self.entries.contains_key(&oid) {
+            return;
+        }
+
+        while self.used + content.len() > self.budget {
+            let oldest = match self.order.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.used -= evicted.len();
+                self.compressed.insert(oldest, &evicted);
+            }
+        }
+
+        self.tick += 1;
+        self.used += content.len();
+        self.order.insert(self.tick, oid);
+        self.entries.insert(oid, (content, self.tick));
+    }
+
+    /// Forget every content, compressed ones included
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+        self.compressed.clear();
+    }
+
+    /// Number of contents held, compressed ones included
+    pub fn entries(&self) -> usize {
+        self.entries.len() + self.compressed.entries.len()
+    }
+
+    /// Bytes held uncompressed, and compressed
+    pub fn used(&self) -> (usize, usize) {
+        (self.used, self.compressed.used)
+    }
+}
+
+/// Blob contents evicted from a `ContentCache`, lz4 compressed, least recently
+/// evicted first out
+struct CompressedTier {
+    budget: usize,
+    used: usize,
+    // object id => (compressed content, time of eviction)
+    entries: HashMap<Oid, (Vec<u8>, u64)>,
+    // time of eviction => object id, oldest first
+    order: BTreeMap<u64, Oid>,
+    tick: u64,
+}
+
+impl CompressedTier {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.used = 0;
+    }
+
+    fn new(budget: usize) -> Self {
+        CompressedTier {
+            budget,
+            used: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn insert(&mut self, oid: Oid, content: &[u8]) {
+        if self.budget == 0 || self.entries.contains_key(&oid) {
+            return;
+        }
+        let compressed = lz4_flex::compress_prepend_size(content);
+        // contents that do not compress are as cheap to read again
+        if compressed.len() > self.budget || compressed.len() >= content.len() {
+            return;
+        }
+
+        while self.used + compressed.len() > self.budget {
+            let oldest = match self.order.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.used -= evicted.len();
+            }
+        }
+
+        self.tick += 1;
+        self.used += compressed.len();
+        self.order.insert(self.tick, oid);
+        self.entries.insert(oid, (compressed, self.tick));
+    }
+
+    /// Take a content out of the tier, decompressed
+    fn take(&mut self, oid: Oid) -> Option<Arc<[u8]>> {
+        let (compressed, evicted) = self.entries.remove(&oid)?;
+        self.order.remove(&evicted);
+        self.used -= compressed.len();
+        lz4_flex::decompress_size_prepended(&compressed)
+            .ok()
+            .map(Arc::from)
+    }
+}
+
+/// Sizes and kinds of objects, which never change. Past `capacity` entries the
+/// cache starts over empty.
+pub struct HeaderCache {
+    capacity: usize,
+    headers: RwLock<HashMap<Oid, (usize, ObjectType)>>,
+}
+
+impl HeaderCache {
+    pub fn new(capacity: usize) -> Self {
+        HeaderCache {
+            capacity,
+            headers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, oid: Oid) -> Option<(usize, ObjectType)> {
+        self.headers.read().unwrap().get(&oid).copied()
+    }
+
+    pub fn insert(&self, oid: Oid, header: (usize, ObjectType)) {
+        let mut headers = self.headers.write().unwrap();
+        if headers.len() >= self.capacity {
+            headers.clear();
+        }
+        headers.insert(oid, header);
+    }
+
+    /// Forget the header of an object no longer in the repository
+    pub fn remove(&self, oid: Oid) {
+        self.headers.write().unwrap().remove(&oid);
+    }
+}
+
+/// Least recently used cache of up to `capacity` values, for those whose
+/// number rather than size bounds what they cost
+pub struct Lru<K, V> {
+    capacity: usize,
+    // key => (value, last use)
+    entries: HashMap<K, (V, u64)>,
+    // last use => key, oldest first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let (value, last_use) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        self.order.remove(last_use);
+        self.order.insert(self.tick, key.clone());
+        *last_use = self.tick;
+        Some(value.clone())
+    }
+
+    /// Cache `value`, evicting the least recently used value past `capacity`
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        while self.entries.len() >= self.capacity.max(1) {
+            let oldest = match self.order.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_use) = self.entries.remove(key)?;
+        self.order.remove(&last_use);
+        Some(value)
+    }
+}
+
+/// Names recently found missing from a directory, so that probing for them
+/// again and again does not search the tree every time
+pub struct NegativeCache {
+    ttl: Duration,
+    // (directory inode, name) => when it was found missing
+    entries: HashMap<(u64, OsString), Instant>,
+}
+
+impl NegativeCache {
+    /// Remember names for `ttl`, a zero `ttl` disables the cache
+    pub fn new(ttl: Duration) -> Self {
+        NegativeCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&mut self, parent: u64, name: &OsStr) -> bool {
+        let key = (parent, name.to_os_string());
+        match self.entries.get(&key) {
+            Some(missed) if missed.elapsed() < self.ttl => true,
+            Some(_) => {
+                self.entries.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn insert(&mut self, parent: u64, name: &OsStr) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        if self.entries.len() >= NEGATIVE_CACHE_SIZE {
+            let ttl = self.ttl;
+            self.entries.retain(|_, missed| missed.elapsed() < ttl);
+        }
+        if self.entries.len() < NEGATIVE_CACHE_SIZE {
+            self.entries
+                .insert((parent, name.to_os_string()), Instant::now());
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Blob contents kept on local disk across mounts, laid out like loose objects
+//! (`ab/cdef...`) but stored inflated. Files are written under a temporary
+//! name and renamed, so mounts sharing the directory never see partial ones.
+//! Those left behind by a mount that died while writing are removed by the
+//! next one.
+
+use crate::stats::{Cache, Stats};
+use anyhow::Result;
+use git2::{ObjectType, Oid};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// Least recently used blob contents on disk, bounded by their total size
+pub struct DiskCache {
+    dir: Option<PathBuf>,
+    capacity: u64,
+    lru: Mutex<Lru>,
+    // names temporary files apart within this process
+    writes: AtomicU64,
+    stats: Arc<Stats>,
+}
+
+#[derive(Default)]
+struct Lru {
+    used: u64,
+    // object id => (size, last use)
+    entries: HashMap<Oid, (u64, u64)>,
+    // last use => object id, oldest first
+    order: BTreeMap<u64, Oid>,
+    tick: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, oid: Oid, size: u64) {
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.insert(oid, (size, self.tick)) {
+            self.order.remove(&last_use);
+        } else {
+            self.used += size;
+        }
+        self.order.insert(self.tick, oid);
+    }
+
+    fn remove(&mut self, oid: Oid) {
+        if let Some((size, last_use)) = self.entries.remove(&oid) {
+            self.order.remove(&last_use);
+            self.used -= size;
+        }
+    }
+
+    /// Forget the oldest entries until `size` more bytes fit in `capacity`
+    fn evict(&mut self, capacity: u64, size: u64) -> Vec<Oid> {
+        let mut evicted = Vec::new();
+        while self.used + size > capacity {
+            let oldest = match self.order.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            if let Some((size, _)) = self.entries.remove(&oldest) {
+                self.used -= size;
+            }
+            evicted.push(oldest);
+        }
+        evicted
+    }
+}
+
+/// None for a file that another mount sharing the directory removed meanwhile
+fn unless_gone<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `name` is a temporary file whose writer is gone
+fn is_leftover(name: &OsStr) -> bool {
+    let pid = name
+        .to_str()
+        .and_then(|name| name.strip_prefix("tmp-"))
+        .and_then(|name| name.split('-').next())
+        .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+    match pid {
+        // signal 0 only checks that the process exists
+        Some(pid) => {
+            let signaled = unsafe { libc::kill(pid, 0) };
+            signaled != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        }
+        None => false,
+    }
+}
+
+impl DiskCache {
+    /// A cache of up to `capacity` bytes in `dir`, picking up the blobs an
+    /// earlier mount left there. Without a directory nothing is cached.
+    pub fn open(dir: Option<&Path>, capacity: u64, stats: Arc<Stats>) -> Result<Self> {
+        let mut lru = Lru::default();
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir)?;
+            let mut found = Vec::new();
+            for fanout in fs::read_dir(dir)? {
+                let fanout = fanout?;
+                if !fanout.file_type()?.is_dir() {
+                    continue;
+                }
+                let files = match unless_gone(fs::read_dir(fanout.path()))? {
+                    Some(files) => files,
+                    None => continue,
+                };
+                for file in files {
+                    let file = file?;
+                    if is_leftover(&file.file_name()) {
+                        debug!("removing the leftover {}", file.path().display());
+                        let _ = fs::remove_file(file.path());
+                        continue;
+                    }
+                    let hex = format!(
+                        "{}{}",
+                        fanout.file_name().to_string_lossy(),
+                        file.file_name().to_string_lossy()
+                    );
+                    // leftovers of interrupted writes are not objects
+                    let oid = match Oid::from_str(&hex) {
+                        Ok(oid) if hex.len() == 40 => oid,
+                        _ => continue,
+                    };
+                    let meta = file
+                        .metadata()
+                        .and_then(|meta| Ok((meta.modified()?, meta.len())));
+                    if let Some((modified, size)) = unless_gone(meta)? {
+                        found.push((modified, oid, size));
+                    }
+                }
+            }
+            // the most recently used last
+            found.sort();
+            for (_, oid, size) in found {
+                lru.touch(oid, size);
+            }
+            debug!("found {} bytes of blobs in {}", lru.used, dir.display());
+        }
+
+        let cache = DiskCache {
+            dir: dir.map(Path::to_path_buf),
+            capacity,
+            lru: Mutex::new(lru),
+            writes: AtomicU64::new(0),
+            stats,
+        };
+        let evicted = cache.lru.lock().unwrap().evict(capacity, 0);
+        cache.remove_files(&evicted);
+        Ok(cache)
+    }
+
+    /// Bytes of blobs on disk
+    pub fn used(&self) -> u64 {
+        self.lru.lock().unwrap().used
+    }
+
+    fn path(&self, oid: Oid) -> Option<PathBuf> {
+        let hex = oid.to_string();
+        Some(self.dir.as_ref()?.join(&hex[..2]).join(&hex[2..]))
+    }
+
+    fn remove_files(&self, oids: &[Oid]) {
+        for &oid in oids {
+            if let Some(path) = self.path(oid) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Content of blob `oid`, if it is on disk and intact
+    pub fn get(&self, oid: Oid) -> Option<Arc<[u8]>> {
+        let path = self.path(oid)?;
+        let content = self.read(oid, &path);
+        self.stats.cache(Cache::Disk, content.is_some());
+        content
+    }
+
+    fn read(&self, oid: Oid, path: &Path) -> Option<Arc<[u8]>> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("unable to read {}: {}", path.display(), e);
+                }
+                // evicted by another mount sharing the directory
+                self.lru.lock().unwrap().remove(oid);
+                return None;
+            }
+        };
+        if Oid::hash_object(ObjectType::Blob, &content).ok() != Some(oid) {
+            warn!("discarding damaged {}", path.display());
+            let _ = fs::remove_file(path);
+            self.lru.lock().unwrap().remove(oid);
+            return None;
+        }
+
+        // the order of use outlives the mount through the modification time
+        if let Ok(file) = File::options().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        self.lru.lock().unwrap().touch(oid, content.len() as u64);
+        Some(content.into())
+    }
+
+    /// Store `content` as blob `oid`, evicting the least recently used blobs
+    /// to make room. Contents larger than the whole capacity are not stored.
+    pub fn insert(&self, oid: Oid, content: &[u8]) {
+        let path = match self.path(oid) {
+            Some(path) => path,
+            None => return,
+        };
+        let size = content.len() as u64;
+        let evicted = {
+            let mut lru = self.lru.lock().unwrap();
+            if size > self.capacity || lru.entries.contains_key(&oid) {
+                return;
+            }
+            let evicted = lru.evict(self.capacity, size);
+            lru.touch(oid, size);
+            evicted
+        };
+        self.remove_files(&evicted);
+
+        if let Err(e) = self.write(&path, content) {
+            warn!("unable to cache {}: {}", oid, e);
+            self.lru.lock().unwrap().remove(oid);
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+        let temp = dir.join(format!(
+            "tmp-{}-{}",
+            process::id(),
+            self.writes.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(content))
+            .and_then(|_| fs::rename(&temp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Content of blob `oid` from disk, or read with `read` and stored
+    pub fn get_or_insert_with<E, F>(&self, oid: Oid, read: F) -> Result<Arc<[u8]>, E>
+    where
+        F: FnOnce() -> Result<Arc<[u8]>, E>,
+    {
+        if let Some(content) = self.get(oid) {
+            return Ok(content);
+        }
+        let content = read()?;
+        self.insert(oid, &content);
+        Ok(content)
+    }
+}
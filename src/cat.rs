@@ -0,0 +1,52 @@
+//! `giblefs cat`, prints a file of a revision exactly as reading it from a
+//! mount given the same options returns it, without going through FUSE.
+
+use crate::fs::{self, GilberFS};
+use crate::git;
+use crate::show::ShowOptions;
+use anyhow::{Context, Result};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct CatOptions {
+    /// Path to the git repository
+    repo: PathBuf,
+
+    /// Look for the repository in the parent directories of its path too
+    #[structopt(long)]
+    search: bool,
+
+    /// Revision and path of the file, e.g. `master:README.md`
+    #[structopt(value_name = "REV:PATH")]
+    target: String,
+
+    #[structopt(flatten)]
+    show: ShowOptions,
+}
+
+pub fn run(options: CatOptions) -> Result<()> {
+    let mut fs_options = fs::Options {
+        threads: 1,
+        ..Default::default()
+    };
+    options.show.apply(&mut fs_options)?;
+    let mut fs = GilberFS::local(
+        git::locate_env(&options.repo, options.search)?,
+        options.show.inject.clone(),
+        fs_options,
+    )?;
+    let attr = fs.resolve(&options.target)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = fs.cat(attr.ino, &mut out).and_then(|_| Ok(out.flush()?));
+    match result {
+        // piped into `head` and the like
+        Err(e) if matches!(e.downcast_ref::<io::Error>(), Some(e) if e.kind() == io::ErrorKind::BrokenPipe) => {
+            Ok(())
+        }
+        result => result.with_context(|| options.target.clone()),
+    }
+}
@@ -0,0 +1,109 @@
+//! `giblefs check`, walks the tree of a revision and makes sure every object
+//! the mount would read is present and inflates, so that the gaps of shallow
+//! and partial clones show up before something reads them through the mount.
+
+use crate::git::{self, Backend, GitError, GitRepo, EMPTY_BLOB, EMPTY_TREE};
+use anyhow::{anyhow, Result};
+use git2::{ObjectType, Oid};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Bytes inflated per read when streaming
+const CHUNK_SIZE: u32 = 1 << 20;
+
+#[derive(StructOpt)]
+pub struct CheckOptions {
+    /// Path to the git repository
+    repo: PathBuf,
+
+    /// Look for the repository in the parent directories of its path too
+    #[structopt(long)]
+    search: bool,
+
+    /// Revision to check, e.g. `master`
+    rev: String,
+}
+
+/// Inflate a blob whole, streaming it when it can be
+fn check_blob(repo: &GitRepo, hash: Oid) -> Result<(), String> {
+    let mut stream = match repo.blob_stream(hash) {
+        Some(stream) => stream,
+        None => return repo.read_blob(hash).map(|_| ()).map_err(describe),
+    };
+    let mut offset = 0;
+    while offset < stream.size() {
+        let chunk = stream
+            .read_at(offset, CHUNK_SIZE)
+            .map_err(|e| format!("corrupt: {}", e))?;
+        if chunk.is_empty() {
+            return Err(format!(
+                "corrupt: truncated at {} of {} bytes",
+                offset,
+                stream.size()
+            ));
+        }
+        offset += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+fn describe(error: GitError) -> String {
+    match error {
+        GitError::Missing => "missing".to_string(),
+        error => error.to_string(),
+    }
+}
+
+pub fn run(options: CheckOptions) -> Result<()> {
+    let repo = GitRepo::new(
+        git::locate_env(&options.repo, options.search)?,
+        Backend::Libgit2,
+    )?;
+    let root = repo.resolve_entry(&options.rev)?;
+
+    // the same object is only checked once, under the first path found
+    let mut seen = HashSet::new();
+    let mut pending = vec![(String::new(), root.id, root.kind)];
+    let mut bad = 0;
+    while let Some((path, id, kind)) = pending.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let result = match kind {
+            // served even when the repository does not have them
+            _ if id == *EMPTY_BLOB || id == *EMPTY_TREE => Ok(()),
+            Some(ObjectType::Blob) => check_blob(&repo, id),
+            Some(ObjectType::Tree) => repo
+                .tree_entries(id)
+                .map(|entries| {
+                    for entry in entries.iter().rev() {
+                        let name = String::from_utf8_lossy(&entry.name);
+                        pending.push((format!("{}/{}", path, name), entry.id, entry.kind));
+                    }
+                })
+                .map_err(describe),
+            // submodules are not shown by the mount
+            _ => Ok(()),
+        };
+        if let Err(problem) = result {
+            println!(
+                "{} {} {}",
+                id,
+                if path.is_empty() { "/" } else { &path },
+                problem
+            );
+            bad += 1;
+        }
+    }
+
+    match bad {
+        0 => Ok(()),
+        bad => Err(anyhow!(
+            "{} of {} objects of {} cannot be served",
+            bad,
+            seen.len(),
+            options.rev
+        )),
+    }
+}
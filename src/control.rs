@@ -0,0 +1,262 @@
+//! Line based control interface on a Unix domain socket, one per mount in
+//! `/run/giblefs` by default, which `giblefs ctl` talks to.
+//!
+//! Each line received is a command, answered with `ok`, some output, or
+//! `error: <reason>`:
+//!
+//! * `stats`: dump session statistics, per operation and per cache
+//! * `reset`: reset session statistics
+//! * `sample latency <rate>`: record latency for a `rate` fraction of calls
+//! * `sample heatmap <rate>`: add the latency of a `rate` fraction of calls to
+//!   the heatmap, none by default
+//! * `heatmap`: dump the latency histogram of every second of the last five
+//!   minutes
+//! * `trace <op> on|off`: log every call to `op` along with its latency
+//! * `log-level <filters>`: log what GIBLEFS_LOG would, e.g. `debug` or
+//!   `warn,giblefs::fs=trace`
+//! * `invalidate`: drop cached blob contents and names found missing
+//! * `refresh-refs`: re-read refs, as SIGHUP does
+//! * `shutdown`: unmount and exit
+
+use crate::fs::Caches;
+use crate::logging;
+use crate::stats::{Op, Stats};
+use crate::stop::Stop;
+use crate::watch;
+use anyhow::{anyhow, Result};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::geteuid;
+use std::env;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use structopt::StructOpt;
+use tracing::{error, info, warn};
+
+/// Directory of the sockets of mounts made by root
+const RUN_DIR: &str = "/run/giblefs";
+
+/// What commands act upon
+#[derive(Clone)]
+pub struct Target {
+    pub stats: Arc<Stats>,
+    pub caches: Caches,
+    // asked to unmount and exit
+    pub stop: Arc<Stop>,
+}
+
+/// The listening socket, removed once dropped
+pub struct Socket {
+    path: PathBuf,
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Socket of the mount at `mount` unless told otherwise: in /run/giblefs for
+/// root, in $XDG_RUNTIME_DIR/giblefs for other users
+pub fn default_path(mount: &Path) -> Option<PathBuf> {
+    let dir = if geteuid().is_root() {
+        PathBuf::from(RUN_DIR)
+    } else {
+        PathBuf::from(env::var_os("XDG_RUNTIME_DIR")?).join("giblefs")
+    };
+    Some(dir.join(format!("{}.sock", mount_id(mount)?)))
+}
+
+/// The absolute path of `mount` escaped like systemd unit names, `mnt-repo`
+/// for `/mnt/repo`. The mount itself is not accessed, it may not respond.
+fn mount_id(mount: &Path) -> Option<String> {
+    let parent = match mount.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.canonicalize().ok()?,
+        _ => env::current_dir().ok()?,
+    };
+    let path = parent.join(mount.file_name()?);
+    let path = path.to_str()?.trim_matches('/');
+
+    let mut id = String::new();
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => id.push('-'),
+            b'.' if i == 0 => id.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' => id.push(byte as char),
+            _ => id.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    Some(id)
+}
+
+pub fn spawn(path: &Path, target: Target) -> Result<Socket> {
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    // a previous instance might have left its socket behind, anything else
+    // there is not ours to remove
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{} exists and is not a socket, not replacing it",
+                path.display()
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    // commands are only for the owner of the mount, whoever may reach the socket
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    info!("listening for control commands on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) if !from_owner(&stream) => {
+                    warn!("refusing a control connection from another user");
+                }
+                Ok(stream) => {
+                    let target = target.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &target) {
+                            error!("control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("unable to accept control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(Socket {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Whether the peer of `stream` runs as the user the mount runs as, or root
+fn from_owner(stream: &UnixStream) -> bool {
+    match getsockopt(stream.as_raw_fd(), PeerCredentials) {
+        Ok(credentials) => credentials.uid() == geteuid().as_raw() || credentials.uid() == 0,
+        Err(e) => {
+            warn!(
+                "unable to read the credentials of a control connection: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+fn serve(stream: UnixStream, target: &Target) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        match execute(&line, target) {
+            Ok(output) => write!(writer, "{}", output)?,
+            Err(e) => writeln!(writer, "error: {}", e)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn execute(line: &str, target: &Target) -> Result<String> {
+    let stats = &target.stats;
+    let args: Vec<&str> = line.split_whitespace().collect();
+
+    match args.as_slice() {
+        ["stats"] => Ok(stats.to_string()),
+        ["reset"] => {
+            stats.reset();
+            Ok("ok\n".into())
+        }
+        ["sample", "latency", rate] => {
+            stats.set_latency_sampling(rate.parse()?);
+            Ok("ok\n".into())
+        }
+        ["sample", "heatmap", rate] => {
+            stats.set_heatmap_sampling(rate.parse()?);
+            Ok("ok\n".into())
+        }
+        ["heatmap"] => Ok(stats.heatmap()),
+        ["trace", op, toggle] => {
+            let enabled = match *toggle {
+                "on" => true,
+                "off" => false,
+                _ => return Err(anyhow!("expected on or off, got {}", toggle)),
+            };
+            stats.set_trace(op.parse::<Op>()?, enabled);
+            Ok("ok\n".into())
+        }
+        ["log-level", filters] => {
+            logging::set_filters(filters)?;
+            info!("logging {}", filters);
+            Ok("ok\n".into())
+        }
+        ["invalidate"] => {
+            target.caches.invalidate();
+            info!("caches invalidated");
+            Ok("ok\n".into())
+        }
+        ["refresh-refs"] => {
+            watch::refresh();
+            Ok("ok\n".into())
+        }
+        ["shutdown"] => {
+            warn!("shutting down on request");
+            target.stop.stop();
+            Ok("ok\n".into())
+        }
+        _ => Err(anyhow!("unknown command: {}", line)),
+    }
+}
+
+#[derive(StructOpt)]
+pub struct CtlOptions {
+    /// Mount point, or the control socket itself
+    #[structopt(parse(from_os_str))]
+    target: PathBuf,
+
+    /// Command to send, e.g. `stats`, `heatmap`, `log-level debug`,
+    /// `invalidate`, `refresh-refs` or `shutdown`
+    #[structopt(required = true)]
+    command: Vec<String>,
+}
+
+/// `giblefs ctl`, send a command to a mount and print the answer
+pub fn ctl(options: CtlOptions) -> Result<()> {
+    let is_socket = fs::symlink_metadata(&options.target)
+        .map(|meta| meta.file_type().is_socket())
+        .unwrap_or(false);
+    let path = if is_socket {
+        options.target
+    } else {
+        default_path(&options.target).ok_or_else(|| {
+            anyhow!(
+                "no control socket for {}, pass the socket itself",
+                options.target.display()
+            )
+        })?
+    };
+
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| anyhow!("unable to connect to {}: {}", path.display(), e))?;
+    writeln!(stream, "{}", options.command.join(" "))?;
+    // the mount answers until the connection is closed
+    stream.shutdown(Shutdown::Write)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+
+    match answer.strip_prefix("error: ") {
+        Some(reason) => Err(anyhow!("{}", reason.trim_end())),
+        None => Ok(io::stdout().write_all(answer.as_bytes())?),
+    }
+}
@@ -0,0 +1,200 @@
+//! Credentials of the remotes git fetches from, by `--fetch-interval`, for
+//! partial clones and for LFS objects, given per remote with `--credential`.
+//! Remotes without any use the ssh agent or the credential helpers of git, as
+//! git would.
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// User name sent along with tokens when the URL has none, forges ignore it
+const TOKEN_USER: &str = "x-access-token";
+
+lazy_static! {
+    // replaced by `init`
+    static ref CREDENTIALS: RwLock<Vec<Credential>> = RwLock::new(Vec::new());
+}
+
+/// How to authenticate to a remote
+#[derive(Clone, Debug)]
+pub enum Method {
+    /// Keys of the running ssh agent
+    SshAgent,
+    /// A private key file, without passphrase
+    SshKey(PathBuf),
+    /// A token in this environment variable, as the password over https
+    TokenEnv(String),
+}
+
+/// Where a remote is, as far as credentials go
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Location {
+    // lowercase, `ssh` for scp-like `host:path` URLs
+    scheme: String,
+    // lowercase, with the port if any but without the user
+    host: String,
+    // components of the path
+    path: Vec<String>,
+}
+
+impl Location {
+    /// `scheme://[user@]host[:port]/path` or scp-like `[user@]host:path`,
+    /// None for anything else, such as a local path or a remote name
+    fn parse(url: &str) -> Option<Location> {
+        let (scheme, authority, path) = match url.split_once("://") {
+            Some((scheme, rest)) => {
+                let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+                (scheme.to_lowercase(), authority, path)
+            }
+            None => {
+                let (authority, path) = url.split_once(':')?;
+                if authority.contains('/') {
+                    return None;
+                }
+                ("ssh".to_string(), authority, path)
+            }
+        };
+        let host = authority.rsplit('@').next().unwrap_or_default();
+        if host.is_empty() {
+            return None;
+        }
+        Some(Location {
+            scheme,
+            host: host.to_lowercase(),
+            path: path
+                .split('/')
+                .filter(|component| !component.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Whether `other` is this location or under it: the same scheme and
+    /// host, and a path starting with whole components of this one
+    fn contains(&self, other: &Location) -> bool {
+        self.scheme == other.scheme && self.host == other.host && other.path.starts_with(&self.path)
+    }
+}
+
+/// The remotes a credential is for
+#[derive(Clone, Debug)]
+enum Remote {
+    Name(String),
+    Url(Location),
+}
+
+/// `REMOTE=METHOD`, REMOTE being a remote name such as `origin` or a URL
+/// covering those of the remotes it is for
+#[derive(Clone, Debug)]
+pub struct Credential {
+    remote: Remote,
+    method: Method,
+}
+
+impl FromStr for Credential {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (remote, method) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected REMOTE=METHOD, got {:?}", s))?;
+        let remote = match Location::parse(remote) {
+            Some(location) => Remote::Url(location),
+            None if remote.contains("://") => {
+                return Err(anyhow!("{:?} is a URL without a host", remote))
+            }
+            None if remote.is_empty() => {
+                return Err(anyhow!("expected REMOTE=METHOD, got {:?}", s))
+            }
+            None => Remote::Name(remote.to_string()),
+        };
+        let method = match method.split_once(':') {
+            _ if method == "ssh-agent" => Method::SshAgent,
+            Some(("ssh-key", path)) if !path.is_empty() => Method::SshKey(path.into()),
+            Some(("token-env", var))
+                if !var.is_empty()
+                    && !var.starts_with(|c: char| c.is_ascii_digit())
+                    && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                Method::TokenEnv(var.to_string())
+            }
+            _ => {
+                return Err(anyhow!(
+                    "unknown credential {:?}, expected ssh-agent, ssh-key:PATH or token-env:VAR",
+                    method
+                ))
+            }
+        };
+        Ok(Credential { remote, method })
+    }
+}
+
+/// Use `credentials` for every fetch from now on
+pub fn init(credentials: Vec<Credential>) {
+    *CREDENTIALS.write().unwrap() = credentials;
+}
+
+/// The method given for remote `name` at `url`, the first that matches
+fn find(name: &str, url: &str) -> Option<Method> {
+    let location = Location::parse(url);
+    CREDENTIALS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|credential| match &credential.remote {
+            Remote::Name(remote) => remote == name,
+            Remote::Url(remote) => location
+                .as_ref()
+                .is_some_and(|location| remote.contains(location)),
+        })
+        .map(|credential| credential.method.clone())
+}
+
+/// Whether `url` names the user to authenticate as, `https://user@host/...`
+fn has_username(url: &str) -> bool {
+    url.split_once("://")
+        .and_then(|(_, rest)| rest.split('/').next())
+        .is_some_and(|authority| authority.contains('@'))
+}
+
+/// Have `command`, a git command fetching from remote `name` at `url`, use
+/// its credentials
+pub fn apply(command: &mut Command, name: &str, url: &str) {
+    // nobody is there to answer
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    match find(name, url) {
+        Some(Method::SshKey(path)) => {
+            let path = path.to_string_lossy().replace('\'', "'\\''");
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!("ssh -i '{}' -o IdentitiesOnly=yes", path),
+            );
+        }
+        Some(Method::TokenEnv(var)) => {
+            // the token stays in the environment, the helper reads it there
+            let username = if has_username(url) {
+                String::new()
+            } else {
+                format!("echo username={} && ", TOKEN_USER)
+            };
+            let helper = format!(
+                "!f() {{ test \"$1\" = get && {}echo \"password=${}\"; }}; f",
+                username, var
+            );
+            let count = env::var("GIT_CONFIG_COUNT")
+                .ok()
+                .and_then(|count| count.parse::<usize>().ok())
+                .unwrap_or(0);
+            command
+                .env("GIT_CONFIG_COUNT", (count + 1).to_string())
+                .env(format!("GIT_CONFIG_KEY_{}", count), "credential.helper")
+                .env(format!("GIT_CONFIG_VALUE_{}", count), helper);
+        }
+        // git asks the agent itself
+        Some(Method::SshAgent) | None => {}
+    }
+}
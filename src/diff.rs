@@ -0,0 +1,252 @@
+//! `giblefs diff-trees`, compares two revisions using the same tree machinery
+//! the filesystem is built on, without going through FUSE.
+
+use crate::git::{self, Backend, Entry, GitRepo};
+use anyhow::{anyhow, Result};
+use git2::{ObjectType, Oid};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct DiffTreesOptions {
+    /// Path to the git repository
+    #[structopt(short = "C", long, default_value = ".")]
+    repo: PathBuf,
+
+    /// Look for the repository in the parent directories of its path too
+    #[structopt(long)]
+    search: bool,
+
+    rev_a: String,
+    rev_b: String,
+
+    /// Output format, `text` or `json`
+    #[structopt(long, default_value = "text")]
+    format: Format,
+}
+
+enum Format {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!("unknown format: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Serialize)]
+struct Change {
+    path: String,
+    status: Status,
+    old_oid: Option<String>,
+    new_oid: Option<String>,
+    // sizes are missing for submodules, whose commits are not in this repository
+    old_size: Option<usize>,
+    new_size: Option<usize>,
+}
+
+/// A pair of differing subtrees left to compare
+struct Work {
+    path: String,
+    old: Oid,
+    new: Oid,
+}
+
+struct Differ {
+    repo: GitRepo,
+    changes: Vec<Change>,
+}
+
+impl Differ {
+    fn new(repo: &PathBuf) -> Result<Self> {
+        Ok(Differ {
+            repo: GitRepo::new(repo, Backend::Libgit2)?,
+            changes: Vec::new(),
+        })
+    }
+
+    fn size(&self, entry: &Entry) -> Option<usize> {
+        match entry.kind {
+            Some(ObjectType::Commit) => None,
+            _ => self.repo.object_size(entry.id).ok(),
+        }
+    }
+
+    fn push(&mut self, path: &str, status: Status, old: Option<&Entry>, new: Option<&Entry>) {
+        let change = Change {
+            path: path.into(),
+            status,
+            old_oid: old.map(|entry| entry.id.to_string()),
+            new_oid: new.map(|entry| entry.id.to_string()),
+            old_size: old.and_then(|entry| self.size(entry)),
+            new_size: new.and_then(|entry| self.size(entry)),
+        };
+        self.changes.push(change);
+    }
+
+    /// Record every file below `entry` as added or removed
+    fn one_sided(&mut self, path: &str, entry: &Entry, status: Status) -> Result<()> {
+        if entry.kind == Some(ObjectType::Tree) {
+            for child in self.repo.tree_entries(entry.id)?.iter() {
+                self.one_sided(&join(path, child), child, status)?;
+            }
+        } else if let Status::Added = status {
+            self.push(path, status, None, Some(entry));
+        } else {
+            self.push(path, status, Some(entry), None);
+        }
+        Ok(())
+    }
+
+    /// Compare two trees, handing differing subtrees to `defer` instead of
+    /// descending into them
+    fn compare(
+        &mut self,
+        work: &Work,
+        defer: &mut dyn FnMut(&mut Self, Work) -> Result<()>,
+    ) -> Result<()> {
+        let mut pairs: BTreeMap<Vec<u8>, (Option<Entry>, Option<Entry>)> = BTreeMap::new();
+        for entry in self.repo.tree_entries(work.old)?.iter() {
+            pairs.entry(entry.name.clone()).or_default().0 = Some(entry.clone());
+        }
+        for entry in self.repo.tree_entries(work.new)?.iter() {
+            pairs.entry(entry.name.clone()).or_default().1 = Some(entry.clone());
+        }
+
+        for (old, new) in pairs.values() {
+            match (old, new) {
+                (Some(old), Some(new)) if old.id == new.id && old.filemode == new.filemode => {}
+                (Some(old), Some(new)) => {
+                    let path = join(&work.path, new);
+                    let old_tree = old.kind == Some(ObjectType::Tree);
+                    let new_tree = new.kind == Some(ObjectType::Tree);
+                    if old_tree && new_tree {
+                        defer(
+                            self,
+                            Work {
+                                path,
+                                old: old.id,
+                                new: new.id,
+                            },
+                        )?;
+                    } else if old_tree || new_tree {
+                        self.one_sided(&path, old, Status::Removed)?;
+                        self.one_sided(&path, new, Status::Added)?;
+                    } else {
+                        self.push(&path, Status::Changed, Some(old), Some(new));
+                    }
+                }
+                (Some(old), None) => {
+                    self.one_sided(&join(&work.path, old), old, Status::Removed)?
+                }
+                (None, Some(new)) => self.one_sided(&join(&work.path, new), new, Status::Added)?,
+                (None, None) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_recursive(&mut self, work: &Work) -> Result<()> {
+        self.compare(work, &mut |differ, work| differ.compare_recursive(&work))
+    }
+}
+
+fn join(parent: &str, entry: &Entry) -> String {
+    let name = String::from_utf8_lossy(&entry.name);
+    if parent.is_empty() {
+        name.into_owned()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+pub fn run(options: DiffTreesOptions) -> Result<()> {
+    let repo = git::locate_env(&options.repo, options.search)?;
+    let mut root = Differ::new(&repo)?;
+    let work = Work {
+        path: String::new(),
+        old: root.repo.resolve_tree(&options.rev_a)?,
+        new: root.repo.resolve_tree(&options.rev_b)?,
+    };
+
+    // the top level is compared here, differing subtrees are then spread over
+    // worker threads each with its own handle on the repository
+    let mut queue = Vec::new();
+    root.compare(&work, &mut |_, work| {
+        queue.push(work);
+        Ok(())
+    })?;
+
+    let queue = Mutex::new(queue);
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(queue.lock().unwrap().len());
+
+    let mut changes = root.changes;
+    let results: Vec<Result<Vec<Change>>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut differ = Differ::new(&repo)?;
+                    loop {
+                        let work = queue.lock().unwrap().pop();
+                        match work {
+                            Some(work) => differ.compare_recursive(&work)?,
+                            None => return Ok(differ.changes),
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    for result in results {
+        changes.extend(result?);
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    match options.format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&changes)?),
+        Format::Text => {
+            for change in &changes {
+                let status = match change.status {
+                    Status::Added => 'A',
+                    Status::Removed => 'D',
+                    Status::Changed => 'M',
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    status,
+                    change.old_oid.as_deref().unwrap_or("-"),
+                    change.new_oid.as_deref().unwrap_or("-"),
+                    change.path
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! Repositories found under a directory, served by the mount as they come and
+//! go: `--discover` rescans the directory periodically and on SIGHUP.
+
+use crate::fetch::Fetched;
+use crate::fs::multi::{Change, NamedRepo};
+use crate::fs::GilberFS;
+use crate::git;
+use crate::inode::MAX_NAMESPACES;
+use crate::watch;
+use anyhow::Result;
+use fuser::{Notifier, FUSE_ROOT_ID};
+use notify::RecommendedWatcher;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Directory levels below the one discovered repositories are looked for in,
+/// `owner/repo.git` layouts of forges and then some
+const DEPTH: usize = 4;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether `path` is a repository, bare or with a work tree
+fn is_repository(path: &Path) -> bool {
+    path.join(".git").exists()
+        || (path.join("HEAD").is_file()
+            && path.join("objects").is_dir()
+            && path.join("refs").is_dir())
+}
+
+/// Repositories under `dir`, named after their path from it without `.git`,
+/// `/` replaced with `-`. Repositories are not looked into. Of those getting
+/// the same name, `a-b` and `a/b` or `a` and `a.git`, the first path in
+/// order is kept.
+fn scan(dir: &Path) -> Vec<NamedRepo> {
+    let mut repos = Vec::new();
+    let mut pending = vec![(dir.to_path_buf(), 0)];
+    while let Some((path, depth)) = pending.pop() {
+        let entries = match fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("unable to scan {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                continue;
+            }
+            if is_repository(&path) {
+                let name = path
+                    .strip_prefix(dir)
+                    .unwrap_or(&path)
+                    .to_string_lossy()
+                    .replace('/', "-");
+                let name = name.strip_suffix(".git").unwrap_or(&name);
+                repos.push(NamedRepo {
+                    name: name.into(),
+                    path,
+                });
+            } else if depth + 1 < DEPTH {
+                pending.push((path, depth + 1));
+            }
+        }
+    }
+    repos.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.path.cmp(&b.path)));
+    repos
+}
+
+/// Leave out the repositories named like one kept before them, warning about
+/// each once
+fn dedup_names(repos: &mut Vec<NamedRepo>, warned: &mut HashSet<PathBuf>) {
+    let mut kept: Option<NamedRepo> = None;
+    repos.retain(|repo| match &kept {
+        Some(first) if first.name == repo.name => {
+            if warned.insert(repo.path.clone()) {
+                warn!(
+                    "not serving {}: {:?} already names {}",
+                    repo.path.display(),
+                    repo.name,
+                    first.path.display()
+                );
+            }
+            false
+        }
+        _ => {
+            kept = Some(repo.clone());
+            true
+        }
+    });
+}
+
+/// Repositories discovered under a directory alongside those given on the
+/// command line
+pub struct Discovery {
+    dir: PathBuf,
+    // given on the command line, never discovered
+    fixed: Vec<NamedRepo>,
+    // discovered and being served
+    serving: Vec<NamedRepo>,
+    // found but not opening, warned about once
+    broken: HashSet<PathBuf>,
+    // namespace the next repository goes in, unless one was freed
+    next_namespace: u64,
+    // namespaces of removed repositories the kernel holds no inode of anymore
+    free: Vec<u64>,
+    // with `--watch`, path of a repository served => watcher of its refs and packs
+    watch: bool,
+    watchers: HashMap<PathBuf, RecommendedWatcher>,
+    // with `--fetch-interval`, the repositories fetched
+    fetched: Option<Fetched>,
+}
+
+impl Discovery {
+    /// Discover repositories in `dir`, but those `fixed`, watching them with
+    /// `watch`
+    pub fn new(dir: PathBuf, fixed: &[NamedRepo], watch: bool) -> Self {
+        Discovery {
+            dir,
+            fixed: fixed.to_vec(),
+            serving: Vec::new(),
+            broken: HashSet::new(),
+            next_namespace: fixed.len() as u64 + 1,
+            free: Vec::new(),
+            watch,
+            watchers: HashMap::new(),
+            fetched: None,
+        }
+    }
+
+    /// Fetch the repositories served along with those of `fetched`, from now on
+    pub fn fetch(&mut self, fetched: Fetched) {
+        for repo in &self.serving {
+            add_fetched(&fetched, &repo.path);
+        }
+        self.fetched = Some(fetched);
+    }
+
+    /// Whether the repository at `path` was discovered and is served
+    pub fn serves(&self, path: &Path) -> bool {
+        self.serving.iter().any(|repo| repo.path == path)
+    }
+
+    /// Watch the refs and packs of a repository served and fetch it, if asked
+    /// to
+    fn track(&mut self, path: &Path) {
+        if let Some(fetched) = &self.fetched {
+            add_fetched(fetched, path);
+        }
+        if !self.watch {
+            return;
+        }
+        match watch::watch_repository(path) {
+            Ok(watcher) => {
+                self.watchers.insert(path.to_path_buf(), watcher);
+            }
+            Err(e) => error!("unable to watch {}: {}", path.display(), e),
+        }
+    }
+
+    /// Stop watching and fetching a repository no longer served
+    fn untrack(&mut self, path: &Path) {
+        if let Some(fetched) = &self.fetched {
+            fetched.remove(path);
+        }
+        self.watchers.remove(path);
+    }
+
+    /// Namespace for a repository to serve, none once the mount serves as many
+    /// as it can
+    fn namespace(&mut self) -> Option<u64> {
+        if let Some(namespace) = self.free.pop() {
+            return Some(namespace);
+        }
+        if self.next_namespace > MAX_NAMESPACES as u64 {
+            return None;
+        }
+        self.next_namespace += 1;
+        Some(self.next_namespace - 1)
+    }
+
+    /// Repositories found, at the path they open at, but those given on the
+    /// command line or named like one of them
+    fn scan(&mut self) -> Vec<NamedRepo> {
+        let fixed: Vec<_> = self
+            .fixed
+            .iter()
+            .map(|repo| {
+                let path = fs::canonicalize(&repo.path).unwrap_or_else(|_| repo.path.clone());
+                (&repo.name, path)
+            })
+            .collect();
+        let broken = &mut self.broken;
+        let mut found = scan(&self.dir);
+        dedup_names(&mut found, broken);
+        found.retain_mut(|repo| {
+            let located = git::locate(&repo.path, false);
+            let path = located
+                .as_ref()
+                .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+            if matches!(&path, Ok(path) if fixed.iter().any(|(_, fixed)| fixed == path)) {
+                return false;
+            }
+            let problem = match located {
+                Err(e) => e.to_string(),
+                Ok(_) if fixed.iter().any(|(name, _)| **name == repo.name) => {
+                    format!(
+                        "{}: {:?} names another repository",
+                        repo.path.display(),
+                        repo.name
+                    )
+                }
+                Ok(path) => {
+                    repo.path = path;
+                    return true;
+                }
+            };
+            if broken.insert(repo.path.clone()) {
+                warn!("not serving {}", problem);
+            }
+            false
+        });
+        found
+    }
+
+    /// Scan once, for the repositories to serve from the start, opened with
+    /// `new_fs` in the namespaces following those of the repositories given.
+    /// Those that do not open are left out, as rescans do.
+    pub fn initial<F>(&mut self, new_fs: &F) -> Vec<(NamedRepo, GilberFS)>
+    where
+        F: Fn(&Path, u64) -> Result<GilberFS>,
+    {
+        let mut opened = Vec::new();
+        for repo in self.scan() {
+            if let Some(fs) = self.open(&repo, new_fs) {
+                self.track(&repo.path);
+                self.serving.push(repo.clone());
+                opened.push((repo, fs));
+            }
+        }
+        info!(
+            "discovered {} repositories in {}",
+            opened.len(),
+            self.dir.display()
+        );
+        opened
+    }
+
+    /// Open a repository found in a free namespace, warning when it cannot
+    /// be served
+    fn open<F>(&mut self, repo: &NamedRepo, new_fs: &F) -> Option<GilberFS>
+    where
+        F: Fn(&Path, u64) -> Result<GilberFS>,
+    {
+        let namespace = match self.namespace() {
+            Some(namespace) => namespace,
+            None => {
+                warn!(
+                    "not serving {}: a mount serves {} repositories at most",
+                    repo.path.display(),
+                    MAX_NAMESPACES
+                );
+                return None;
+            }
+        };
+        match new_fs(&repo.path, namespace) {
+            Ok(fs) => Some(fs),
+            Err(e) => {
+                warn!("not serving {}: {}", repo.path.display(), e);
+                self.free.push(namespace);
+                None
+            }
+        }
+    }
+
+    /// Rescan every `interval` and on SIGHUP in the background, opening the
+    /// repositories found with `new_fs` and sending them to the mount
+    pub fn spawn<F>(
+        mut self,
+        interval: Duration,
+        new_fs: F,
+        (changes, freed): (Sender<Change>, Receiver<u64>),
+        notifier: Notifier,
+    ) where
+        F: Fn(&Path, u64) -> Result<GilberFS> + Send + 'static,
+    {
+        let mut generation = watch::refresh_generation();
+        let mut scanned = Instant::now();
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+            if watch::refresh_generation() == generation && scanned.elapsed() < interval {
+                continue;
+            }
+            generation = watch::refresh_generation();
+            scanned = Instant::now();
+            self.free.extend(freed.try_iter());
+            if self.rescan(&new_fs, &changes, &notifier).is_err() {
+                // the mount is gone
+                return;
+            }
+        });
+    }
+
+    fn rescan<F>(
+        &mut self,
+        new_fs: &F,
+        changes: &Sender<Change>,
+        notifier: &Notifier,
+    ) -> Result<(), ()>
+    where
+        F: Fn(&Path, u64) -> Result<GilberFS>,
+    {
+        let found = self.scan();
+        let same = |a: &NamedRepo, b: &NamedRepo| a.name == b.name && a.path == b.path;
+
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .serving
+            .drain(..)
+            .partition(|repo| found.iter().any(|found| same(repo, found)));
+        self.serving = kept;
+        for repo in removed {
+            info!("{} is gone", repo.path.display());
+            self.untrack(&repo.path);
+            changes
+                .send(Change::Remove(repo.name.clone()))
+                .map_err(drop)?;
+            invalidate(notifier, &repo.name);
+        }
+
+        for repo in found {
+            if self.serving.iter().any(|serving| same(serving, &repo)) {
+                continue;
+            }
+            let fs = match self.open(&repo, new_fs) {
+                Some(fs) => fs,
+                None => continue,
+            };
+            info!("discovered {}", repo.path.display());
+            if let Err(e) = watch::spawn(
+                repo.path.clone(),
+                fs.root(),
+                fs.branches(),
+                notifier.clone(),
+            ) {
+                error!("unable to watch {}: {}", repo.path.display(), e);
+            }
+            changes
+                .send(Change::Add(repo.name.clone(), Box::new(fs)))
+                .map_err(drop)?;
+            // in case the kernel remembers the name as missing
+            invalidate(notifier, &repo.name);
+            self.track(&repo.path);
+            self.serving.push(repo);
+        }
+        Ok(())
+    }
+}
+
+/// Fetch a discovered repository, which is served all the same when it cannot be
+fn add_fetched(fetched: &Fetched, path: &Path) {
+    if let Err(e) = fetched.add(path) {
+        warn!("not fetching {}", e);
+    }
+}
+
+fn invalidate(notifier: &Notifier, name: &OsString) {
+    match notifier.inval_entry(FUSE_ROOT_ID, name) {
+        // the kernel has no such entry
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+        Err(e) => error!("unable to invalidate {:?}: {}", name, e),
+        Ok(()) => {}
+    }
+}
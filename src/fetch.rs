@@ -0,0 +1,146 @@
+//! `--fetch-interval`, fetching the repositories served from their remotes in
+//! the background so that a long-lived mount keeps tracking upstream.
+
+use crate::{credentials, watch};
+use anyhow::{anyhow, Result};
+use git2::{Direction, Repository};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+
+/// Repositories to fetch, shared with the discovery of repositories which adds
+/// and removes those it serves
+#[derive(Clone, Default)]
+pub struct Fetched(Arc<Mutex<Vec<PathBuf>>>);
+
+impl Fetched {
+    /// Fetch the repository at `path` from now on, failing when its fetches
+    /// would not move the branches served
+    pub fn add(&self, path: &Path) -> Result<()> {
+        check(path)?;
+        self.0.lock().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Stop fetching the repository at `path`
+    pub fn remove(&self, path: &Path) {
+        self.0.lock().unwrap().retain(|repo| repo != path);
+    }
+}
+
+/// Fetch every repository of `repos` every `interval`, refreshing refs once
+/// any of them moved
+pub fn spawn(repos: Fetched, interval: Duration) -> Result<()> {
+    thread::Builder::new()
+        .name("fetch".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let mut moved = false;
+            let fetched = repos.0.lock().unwrap().clone();
+            for repo in &fetched {
+                let before = refs(repo);
+                let start = Instant::now();
+                if let Err(e) = fetch(repo) {
+                    error!("unable to fetch {}: {}", repo.display(), e);
+                    continue;
+                }
+                debug!("fetched {} in {:?}", repo.display(), start.elapsed());
+                if refs(repo) != before {
+                    info!("refs of {} moved upstream", repo.display());
+                    moved = true;
+                }
+            }
+            if moved {
+                watch::refresh();
+            }
+        })?;
+    Ok(())
+}
+
+/// Fetch from the remote git fetches from by default, as configured. git
+/// rather than libgit2 fetches, which keeps partial clones to their filter.
+fn fetch(path: &Path) -> Result<()> {
+    let repo = Repository::open(path)?;
+    let name = default_remote(&repo);
+    let url = repo
+        .find_remote(&name)?
+        .url()
+        .unwrap_or_default()
+        .to_string();
+    let mut command = Command::new("git");
+    credentials::apply(&mut command, &name, &url);
+    let output = command
+        .arg("-C")
+        .arg(path)
+        .args(["fetch", "--quiet", name.as_str()])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "git fetch exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Fail unless fetching the repository at `path` updates `refs/heads`, where
+/// branches are served from, as in mirrors. Fetches of other clones only
+/// update `refs/remotes`, so their branches would never move.
+fn check(path: &Path) -> Result<()> {
+    let repo = Repository::open(path)?;
+    let name = default_remote(&repo);
+    let remote = repo.find_remote(&name)?;
+    let updates_branches = remote.refspecs().any(|refspec| {
+        refspec.direction() == Direction::Fetch
+            && matches!(refspec.dst(), Some(dst) if dst.starts_with("refs/heads/") || dst == "refs/*")
+    });
+    if updates_branches {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{}: fetching {} does not update its branches, clone it with --mirror",
+            path.display(),
+            name
+        ))
+    }
+}
+
+/// The remote of the upstream of the current branch, or else `origin`
+fn default_remote(repo: &Repository) -> String {
+    let branch = repo
+        .head()
+        .ok()
+        .and_then(|head| head.shorthand().map(str::to_string));
+    branch
+        .and_then(|branch| {
+            repo.config()
+                .ok()?
+                .get_string(&format!("branch.{}.remote", branch))
+                .ok()
+        })
+        .unwrap_or_else(|| "origin".to_string())
+}
+
+/// Every ref of a repository along with its target, empty when it cannot be read
+fn refs(repo: &Path) -> HashMap<String, Option<git2::Oid>> {
+    let repo = match Repository::open(repo) {
+        Ok(repo) => repo,
+        Err(_) => return HashMap::new(),
+    };
+    let refs = match repo.references() {
+        Ok(refs) => refs,
+        Err(_) => return HashMap::new(),
+    };
+    refs.flatten()
+        .filter_map(|reference| Some((reference.name()?.to_string(), reference.target())))
+        .collect()
+}
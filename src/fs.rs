@@ -1,243 +1,1952 @@
-use crate::git::GitRepo;
-use crate::inode::InodeGen;
+use crate::cache::{ContentCache, DiskCache, HeaderCache, Lru, NegativeCache};
+use crate::git::{self, Backend, BlobStream, GitError, GitRepo, EMPTY_BLOB};
+use crate::inode::{IdleInodes, Ino, InodeGen, InodeMap};
+use crate::owners::{Mailmap, Owners};
+use crate::provider::{Providers, Xattrs};
+use crate::stats::{self, Cache, Op, Stats};
+use crate::watch::{self, Branches};
+use crate::workers::Workers;
 use anyhow::Result;
-use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+use fuser::{
+    consts, FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLseek, ReplyOpen,
+    ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
 };
-use git2::{BranchType, ObjectType, Oid};
-use lazy_static::lazy_static;
-use libc::ENOENT;
-use log::{debug, error};
+use git2::{ObjectType, Oid};
+use libc::{c_int, ENOENT};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
+use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use time::Timespec;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
 
 pub mod attr;
+pub mod commit_meta;
+pub mod handle;
+pub mod inject;
+pub mod ioctl;
+pub mod local;
+pub mod meta;
+pub mod multi;
+pub mod names;
+
+#[cfg(feature = "async")]
+pub mod aio;
 
 pub use attr::{FileAttrBuilder, ToFileAttr};
+pub use commit_meta::CommitMeta;
+pub use handle::{DirEntry, FileData, FileHandle, Handles};
+pub use inject::{InjectedFile, InjectedFiles};
+pub use meta::MetaFiles;
+
+pub const BLOCK_SIZE: u32 = 4096;
+const NAME_MAX: u32 = 255;
+/// Blobs from this size on are inflated as they are read, unless they are deltified
+const STREAM_SIZE: usize = 16 << 20;
+/// How far ahead of sequential reads streamed blobs are inflated, and in
+/// chunks of how much
+const READAHEAD: usize = 2 << 20;
+const READAHEAD_CHUNK: usize = 256 << 10;
+/// Number of blob headers a worker reads at once when preloading
+const PRELOAD_CHUNK: usize = 1024;
+/// Number of objects whose attributes from providers are kept
+const PROVIDER_XATTRS_CACHE_SIZE: usize = 4096;
+
+/// Map a repository error to the errno reported to the kernel
+fn errno(error: &GitError) -> c_int {
+    match error {
+        GitError::Missing => ENOENT,
+        GitError::Locked(_) => libc::EBUSY,
+        GitError::WrongKind | GitError::Corrupt(_) | GitError::Zlib(_) | GitError::Other(_) => {
+            error!("unable to read from the repository: {}", error);
+            libc::EIO
+        }
+    }
+}
+
+/// The part of a file of `len` bytes a read of `size` bytes at `offset`
+/// returns, which is short near the end and empty past it
+fn read_span(len: usize, offset: i64, size: u32) -> std::result::Result<Range<usize>, c_int> {
+    let offset = usize::try_from(offset).map_err(|_| libc::EINVAL)?;
+    let start = offset.min(len);
+    let end = start.saturating_add(size as usize).min(len);
+    Ok(start..end)
+}
+
+/// Read `size` bytes at `offset` of an open file
+fn read_handle(
+    handle: &FileHandle,
+    workers: &Workers,
+    ino: u64,
+    offset: i64,
+    size: u32,
+) -> std::result::Result<FileData, c_int> {
+    match handle {
+        FileHandle::Stream(stream) => {
+            let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+            // in request order, reading backwards restarts the stream
+            let mut locked = stream.lock().unwrap();
+            let data = match locked.read_at(offset, size) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("unable to stream inode {}: {}", ino, e);
+                    return Err(libc::EIO);
+                }
+            };
+            if locked.is_sequential() && locked.buffered() < READAHEAD / 2 {
+                let stream = stream.clone();
+                workers.run(move |_| read_ahead(&stream, ino));
+            }
+            Ok(FileData::Owned(data))
+        }
+        FileHandle::Content(content) => {
+            let range = read_span(content.len(), offset, size)?;
+            Ok(FileData::Shared(content.clone(), range))
+        }
+    }
+}
+
+/// Inflate a stream ahead of sequential reads, a chunk at a time so that
+/// reads are not held up for long
+fn read_ahead(stream: &Mutex<BlobStream>, ino: u64) {
+    loop {
+        let mut stream = stream.lock().unwrap();
+        if stream.buffered() >= READAHEAD {
+            break;
+        }
+        match stream.read_ahead(READAHEAD_CHUNK) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                // the read that reaches this point reports it
+                debug!("unable to read ahead of inode {}: {}", ino, e);
+                break;
+            }
+        }
+    }
+}
 
-lazy_static! {
-    static ref UNIX_EPOCH: Timespec = Timespec::new(0, 0);
-    static ref TTL: Timespec = Timespec::new(1, 0);
-    static ref ROOT_ATTR: FileAttr = FileAttr {
-        ino: 1,
-        size: 0,
-        blocks: 0,
-        atime: *UNIX_EPOCH, // 1970-01-01 00:00:00
-        mtime: *UNIX_EPOCH,
-        ctime: *UNIX_EPOCH,
-        crtime: *UNIX_EPOCH,
-        kind: FileType::Directory,
-        perm: 0o755,
-        nlink: 2,
-        uid: 501,
-        gid: 20,
-        rdev: 0,
-        flags: 0,
-    };
+/// Outcome of opening a file
+enum Opened {
+    /// The file is open under this handle
+    Handle(u64),
+    /// The blob has to be inflated first, then opened with `open_blob`
+    Inflate(Oid),
+}
+
+/// Open a blob that was just inflated, keeping its content for later opens
+fn open_blob(
+    files: &Mutex<Handles<FileHandle>>,
+    contents: &Mutex<ContentCache>,
+    oid: Oid,
+    content: Arc<[u8]>,
+) -> u64 {
+    contents.lock().unwrap().insert(oid, content.clone());
+    files.lock().unwrap().insert(FileHandle::Content(content))
+}
+
+/// (commit hash, object id) => extended attributes contributed by providers
+type ProviderXattrs = Arc<Mutex<Lru<(Oid, Oid), Xattrs>>>;
+
+/// Extended attributes of an object, along with the providers still to be
+/// asked about it unless what they answered is cached
+struct ObjectXattrs {
+    xattrs: Xattrs,
+    ask: Option<(Oid, Oid, Arc<Providers>, ProviderXattrs)>,
+}
+
+impl ObjectXattrs {
+    /// Whether providers have to be asked, which runs them
+    fn pending(&self) -> bool {
+        self.ask.is_some()
+    }
+
+    /// All of the attributes, once providers answered
+    fn complete(self) -> Xattrs {
+        let mut xattrs = self.xattrs;
+        if let Some((commit, oid, providers, cache)) = self.ask {
+            let provided = providers.xattrs(commit, oid);
+            cache
+                .lock()
+                .unwrap()
+                .insert((commit, oid), provided.clone());
+            xattrs.extend(provided);
+        }
+        xattrs
+    }
+}
+
+/// Value of extended attribute `name`
+fn xattr_value(xattrs: Option<Xattrs>, name: &OsStr) -> std::result::Result<Vec<u8>, c_int> {
+    xattrs
+        .and_then(|xattrs| xattrs.into_iter().find(|(key, _)| key == name))
+        .map(|(_, value)| value)
+        .ok_or(libc::ENODATA)
+}
+
+/// Names of the extended attributes, each followed by a NUL
+fn xattr_names(xattrs: Option<Xattrs>) -> Vec<u8> {
+    let mut names = Vec::new();
+    for (name, _) in xattrs.unwrap_or_default() {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    names
+}
+
+/// Tunables of the filesystem
+#[derive(Clone)]
+pub struct Options {
+    /// How long the kernel may cache names it looked up
+    pub entry_ttl: Duration,
+    /// How long the kernel may cache attributes
+    pub attr_ttl: Duration,
+    /// Match names in `lookup` regardless of case
+    pub case_insensitive: bool,
+    /// Mangle names Windows cannot represent
+    pub windows_names: bool,
+    /// Compute the attributes of the files of a directory as it is listed,
+    /// for clients stating every file listed one after the other
+    pub precompute_sizes: bool,
+    /// Present names in this normalization form, and match them in it
+    pub normalization: Option<names::Normalization>,
+    /// Support NFS re-exports: keep inode mappings alive after the kernel
+    /// forgets them so file handles can be reconnected later
+    pub export: bool,
+    /// Bypass the page cache, every read reaches the filesystem
+    pub direct_io: bool,
+    /// Keep cached pages across opens, blobs never change
+    pub keep_cache: bool,
+    /// Maximum size of the blob contents kept in memory
+    pub cache_bytes: usize,
+    /// Maximum size of the evicted blob contents kept compressed in memory
+    pub compressed_cache_bytes: usize,
+    /// Number of threads inflating blobs and reading streamed ones
+    pub threads: usize,
+    /// Number of inodes kept mapped at most, unless the kernel holds
+    /// references to more
+    pub max_inodes: usize,
+    /// How long names found missing are remembered as such
+    pub negative_ttl: Duration,
+    /// Library reading trees and blobs
+    pub backend: Backend,
+    /// How the repository is opened
+    pub open: git::OpenOptions,
+    /// Directory keeping inflated blobs across mounts
+    pub disk_cache: Option<PathBuf>,
+    /// Maximum size of the blobs kept in `disk_cache`
+    pub disk_cache_bytes: u64,
+    /// Permissions of every file and directory, whatever their git filemode
+    pub file_mode: u16,
+    pub dir_mode: u16,
+    /// Permission bits cleared from `file_mode` and `dir_mode`
+    pub umask: u16,
+    /// Local accounts owning the files of the commits of their authors
+    pub owners: Option<Arc<Owners>>,
+    /// Verify the signatures of commits, shown in the extended attributes of
+    /// their roots
+    pub verify_signatures: bool,
+    /// Directory of every commit shown as its root
+    pub subdir: Option<PathBuf>,
+    /// Size of the largest blob shown as it is, and what is done with larger ones
+    pub max_file_size: Option<(usize, git::Oversized)>,
+    /// Conversions a checkout applies to blobs, served converted
+    pub checkout: git::Checkout,
+    /// Sparse-checkout patterns, the paths they leave out are hidden
+    pub sparse_patterns: Option<Arc<git::SparseRules>>,
+    /// `--include` and `--exclude` globs, the paths they leave out are hidden
+    pub path_globs: Option<Arc<git::PathGlobs>>,
+    /// Add a `.git-meta` directory describing the commit to its root
+    pub git_meta: bool,
+    /// Inode namespace of the repository, when the mount serves several
+    pub namespace: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            entry_ttl: Duration::from_secs(1),
+            attr_ttl: Duration::from_secs(1),
+            case_insensitive: false,
+            windows_names: false,
+            precompute_sizes: false,
+            normalization: None,
+            export: false,
+            direct_io: false,
+            keep_cache: false,
+            cache_bytes: 64 << 20,
+            compressed_cache_bytes: 0,
+            threads: thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            negative_ttl: Duration::from_secs(5),
+            backend: Backend::Libgit2,
+            open: git::OpenOptions::default(),
+            disk_cache: None,
+            disk_cache_bytes: 1 << 30,
+            max_inodes: 1 << 20,
+            file_mode: 0o644,
+            dir_mode: 0o755,
+            umask: 0,
+            owners: None,
+            verify_signatures: false,
+            subdir: None,
+            max_file_size: None,
+            checkout: git::Checkout::default(),
+            sparse_patterns: None,
+            path_globs: None,
+            git_meta: false,
+            namespace: 0,
+        }
+    }
+}
+
+impl Options {
+    /// Settings for re-exporting the mount to Windows, e.g. through WSL2's 9p
+    /// server or Samba: long cache lifetimes, case-insensitive lookups,
+    /// Windows compatible names and sizes computed as directories are listed.
+    pub fn windows_compat() -> Self {
+        Options {
+            entry_ttl: Duration::from_secs(600),
+            attr_ttl: Duration::from_secs(600),
+            case_insensitive: true,
+            windows_names: true,
+            precompute_sizes: true,
+            ..Options::default()
+        }
+    }
 }
 
 pub struct GilberFS {
     repo: GitRepo,
+    // inode of the directory commits are looked up in, the root of the mount
+    // unless it serves several repositories
+    root: u64,
     builder: FileAttrBuilder,
+    injected: InjectedFiles,
+    // `/.giblefs`, where the mount reports on itself
+    meta: MetaFiles,
+    // `.git-meta` of commit roots, with `options.git_meta`
+    commit_meta: CommitMeta,
+    providers: Arc<Providers>,
+    // shared with the workers asking providers
+    provider_xattrs: ProviderXattrs,
+    // open file handles, pinning the content of the blob or streaming it,
+    // shared with the workers which open them
+    files: Arc<Mutex<Handles<FileHandle>>>,
+    // recently read blob contents
+    contents: Arc<Mutex<ContentCache>>,
+    // blobs inflated by this mount or earlier ones, shared with the workers
+    disk: Arc<DiskCache>,
+    // names recently found missing
+    missing: NegativeCache,
+    // bumped through `Caches` to have the missing names forgotten, and the
+    // value they were last forgotten at
+    invalidations: Arc<AtomicU64>,
+    invalidated: u64,
+    workers: Workers,
+    // open directory handles, pinning a snapshot of the entries
+    dirs: Handles<Vec<DirEntry>>,
+    // inode => references held by the kernel (lookup count) and directory snapshots
+    refs: HashMap<u64, u64>,
+    // inodes kept for NFS without any reference
+    idle: IdleInodes,
+    // directory inode => inode of the directory it was found in, for `..`
+    parents: HashMap<u64, u64>,
+    // inode => git filemode of the tree entry it was found as
+    modes: HashMap<u64, i32>,
+    // inode => its attributes, objects never change once written
+    attrs: RefCell<HashMap<u64, FileAttr>>,
+    // commit hash => uid and gid of its author, if `options.owners` maps them
+    commit_owners: RefCell<HashMap<Oid, Option<(u32, u32)>>>,
+    mailmap: Mailmap,
+    // generation of every inode number handed out by this instance, so file
+    // handles from an earlier mount go stale instead of resolving to another object
+    generation: u64,
+    // branches looked up by name, watched for moves
+    branches: Branches,
+    // value of `watch::objects_generation` objects were last refreshed at
+    objects_generation: u64,
+    stats: Arc<Stats>,
+    options: Options,
+}
+
+/// Caches of objects, shared by the mounts of a process since an object is
+/// the same in every repository, and with the control socket
+#[derive(Clone)]
+pub struct Caches {
+    contents: Arc<Mutex<ContentCache>>,
+    disk: Arc<DiskCache>,
+    headers: Arc<HeaderCache>,
+    invalidations: Arc<AtomicU64>,
+    // hits and misses are counted there
+    stats: Arc<Stats>,
+}
+
+impl Caches {
+    pub fn new(options: &Options, stats: Arc<Stats>) -> Result<Self> {
+        Ok(Caches {
+            contents: Arc::new(Mutex::new(ContentCache::new(
+                options.cache_bytes,
+                options.compressed_cache_bytes,
+            ))),
+            disk: Arc::new(DiskCache::open(
+                options.disk_cache.as_deref(),
+                options.disk_cache_bytes,
+                stats.clone(),
+            )?),
+            headers: Arc::new(HeaderCache::new(git::HEADER_CACHE_SIZE)),
+            invalidations: Arc::new(AtomicU64::new(0)),
+            stats,
+        })
+    }
+
+    /// Drop the blob contents held in memory and the names found missing
+    pub fn invalidate(&self) {
+        self.contents.lock().unwrap().clear();
+        // the filesystem forgets them on its next lookup
+        self.invalidations.fetch_add(1, Ordering::SeqCst);
+    }
 }
 
 impl GilberFS {
-    pub fn new(repo: PathBuf, uid: libc::uid_t, gid: libc::gid_t) -> Result<Self> {
-        let builder = FileAttrBuilder::new().uid(uid).gid(gid);
+    pub fn new(
+        repo: PathBuf,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        injected: Vec<InjectedFile>,
+        providers: Providers,
+        caches: Caches,
+        options: Options,
+    ) -> Result<Self> {
+        let builder = FileAttrBuilder::new().uid(uid).gid(gid).modes(
+            options.file_mode,
+            options.dir_mode,
+            options.umask,
+        );
+        let providers = Arc::new(providers);
+        let inode_gen = InodeGen::new(options.namespace);
+        let workers = Workers::new(
+            &repo,
+            options.threads,
+            options.backend,
+            &options.open,
+            caches.disk.clone(),
+        )?;
+        let mut repo = GitRepo::with_headers(repo, options.backend, &options.open, caches.headers)?
+            .in_namespace(options.namespace);
+        if let Some(subdir) = &options.subdir {
+            repo = repo.in_subdir(subdir);
+        }
+        if let Some((max, oversized)) = options.max_file_size {
+            repo = repo.with_max_blob_size(max, oversized);
+        }
+        repo = repo
+            .with_checkout(options.checkout)
+            .with_view(options.sparse_patterns.clone(), options.path_globs.clone());
+        let root = Ino::new(1).in_namespace(options.namespace).value();
+        // read once, like the mailmap of a clone is at checkout
+        let mailmap = match options.owners {
+            Some(_) => repo.mailmap(),
+            None => Mailmap::default(),
+        };
 
         Ok(GilberFS {
-            repo: GitRepo::new(repo, InodeGen::new())?,
+            workers,
+            repo,
             builder,
+            root,
+            meta: MetaFiles::new(&inode_gen, root),
+            injected: InjectedFiles::new(injected, providers.clone(), inode_gen.clone()),
+            commit_meta: CommitMeta::new(inode_gen),
+            providers,
+            provider_xattrs: Arc::new(Mutex::new(Lru::new(PROVIDER_XATTRS_CACHE_SIZE))),
+            files: Arc::new(Mutex::new(Handles::new())),
+            contents: caches.contents,
+            disk: caches.disk,
+            missing: NegativeCache::new(options.negative_ttl),
+            invalidations: caches.invalidations,
+            invalidated: 0,
+            dirs: Handles::new(),
+            refs: HashMap::new(),
+            idle: IdleInodes::new(),
+            parents: HashMap::new(),
+            modes: HashMap::new(),
+            attrs: RefCell::new(HashMap::new()),
+            commit_owners: RefCell::new(HashMap::new()),
+            mailmap,
+            generation: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            branches: Branches::default(),
+            objects_generation: watch::objects_generation(),
+            stats: caches.stats,
+            options,
         })
     }
 
-    fn lookup_commit(&mut self, hash: &str) -> Result<FileAttr> {
-        let commit = match Oid::from_str(hash) {
-            Ok(o) => self.repo.get_tree_by_commit(o)?,
-            Err(_e) => self.repo.get_tree_by_branch(hash)?,
+    /// Walk the tree of `rev` ahead of the first requests, assigning inodes and
+    /// parsing its trees. With `headers`, the workers also read the headers of
+    /// its blobs, which goes on once this returns.
+    pub fn preload(&self, rev: &str, headers: bool) -> Result<()> {
+        let start = Instant::now();
+        let commit = self.repo.resolve_commit(rev)?;
+        let root = self.repo.get_commit_root(commit)?;
+        self.repo.get_inode(commit, root);
+
+        let (mut trees, mut blobs) = (vec![root], Vec::new());
+        let mut count = 1;
+        while let Some(tree) = trees.pop() {
+            let entries = match self.repo.tree_entries_in(commit, tree) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("unable to preload tree {}: {}", tree, e);
+                    continue;
+                }
+            };
+            for entry in entries.iter() {
+                match entry.kind {
+                    Some(ObjectType::Tree) => trees.push(entry.id),
+                    // converted blobs are not stored, their size needs converting them
+                    Some(ObjectType::Blob) if self.repo.is_converted(entry.id) => {}
+                    Some(ObjectType::Blob) => blobs.push(entry.id),
+                    _ => continue,
+                }
+                self.repo.get_inode(commit, entry.id);
+                count += 1;
+            }
+        }
+        info!(
+            "preloaded {} objects of {} in {:?}",
+            count,
+            rev,
+            start.elapsed()
+        );
+
+        if headers {
+            let cached = self.repo.headers();
+            blobs.sort();
+            blobs.dedup();
+            blobs.retain(|oid| *oid != *EMPTY_BLOB && cached.get(*oid).is_none());
+            for chunk in blobs.chunks(PRELOAD_CHUNK) {
+                let (headers, chunk) = (cached.clone(), chunk.to_vec());
+                self.workers
+                    .run(move |store| git::prefetch_headers(store, &headers, &chunk));
+            }
+        }
+        Ok(())
+    }
+
+    /// Inode of the directory commits and branches are looked up in
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Branches looked up by name, see `watch::spawn`
+    pub fn branches(&self) -> Branches {
+        self.branches.clone()
+    }
+
+    /// Objects assigned an inode, for metrics
+    pub fn inodes(&self) -> Arc<InodeMap<(Oid, Oid)>> {
+        self.repo.inode_map()
+    }
+
+    /// Handle on the caches, to drop them while the mount is served
+    pub fn caches(&self) -> Caches {
+        Caches {
+            contents: self.contents.clone(),
+            disk: self.disk.clone(),
+            headers: self.repo.headers(),
+            invalidations: self.invalidations.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Whether `tree` is the root of the commit it is viewed from
+    fn is_commit_root(&self, commit: Oid, tree: Oid) -> bool {
+        match self.repo.get_commit_root(commit) {
+            Ok(root) => root == tree,
+            Err(_) => false,
+        }
+    }
+
+    /// Commit a directory inode is viewed from along with its entries
+    fn dir_entries(
+        &self,
+        ino: u64,
+    ) -> std::result::Result<(Oid, Oid, Arc<[git::Entry]>), GitError> {
+        let (commit, tree) = self
+            .repo
+            .get_ids_by_inode(ino.into())
+            .ok_or(GitError::Missing)?;
+        Ok((commit, tree, self.repo.tree_entries_in(commit, tree)?))
+    }
+
+    /// Whether an inode is mapped to the empty blob, which is served without
+    /// reading it since it may be missing from the object database
+    fn is_empty_blob(&self, ino: u64) -> bool {
+        matches!(self.repo.get_ids_by_inode(ino.into()), Some((_, oid)) if oid == *EMPTY_BLOB)
+    }
+
+    /// Attributes of a blob of `size` bytes, known without reading the blob
+    fn blob_attr(&self, ino: Ino, size: usize) -> FileAttr {
+        self.blob_builder(ino)
+            .ino(ino)
+            .file()
+            .size(size)
+            .blocks(size.div_ceil(512))
+            .build()
+    }
+
+    /// Content of a blob, from the cache if possible
+    fn blob_content(&self, ino: u64) -> std::result::Result<Arc<[u8]>, GitError> {
+        let (_, oid) = self
+            .repo
+            .get_ids_by_inode(ino.into())
+            .ok_or(GitError::Missing)?;
+        stats::record_oid(oid);
+        let cached = self.contents.lock().unwrap().get(oid);
+        self.stats.cache(Cache::Content, cached.is_some());
+        if let Some(content) = cached {
+            return Ok(content);
+        }
+
+        let content = self
+            .disk
+            .get_or_insert_with(oid, || self.repo.read_blob(oid))?;
+        self.contents.lock().unwrap().insert(oid, content.clone());
+        Ok(content)
+    }
+
+    /// Stream of a blob too large to be read at once, if it is loose
+    fn blob_stream(&self, ino: u64) -> Option<BlobStream> {
+        let (_, oid) = self.repo.get_ids_by_inode(ino.into())?;
+        match self.repo.object_size(oid) {
+            Ok(size) if size >= STREAM_SIZE => self.repo.blob_stream(oid),
+            _ => None,
+        }
+    }
+
+    /// Attribute builder for a blob, which has a link for every path it
+    /// appears at in its commit
+    fn blob_builder(&self, ino: Ino) -> FileAttrBuilder {
+        let links = match self.repo.get_ids_by_inode(ino) {
+            Some((commit, oid)) => self.repo.link_count(commit, oid),
+            None => 1,
+        };
+        let builder = match self.repo.get_ids_by_inode(ino) {
+            Some((commit, _)) => self.commit_builder(commit),
+            None => self.builder.clone(),
         };
-        Ok(commit.to_file_attr(self.builder.clone()))
+        builder.nlink(links)
     }
-}
 
-impl Filesystem for GilberFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent == 1 {
-            // looking up by commit hash
-            if let Some(hash) = name.to_str() {
-                if let Ok(attr) = self.lookup_commit(hash) {
-                    reply.entry(&TTL, &attr, 0);
-                    return;
+    /// Attribute builder for the objects of `commit`, owned by the local
+    /// account of its author when `--owners` maps one
+    fn commit_builder(&self, commit: Oid) -> FileAttrBuilder {
+        let owners = match &self.options.owners {
+            Some(owners) => owners,
+            None => return self.builder.clone(),
+        };
+        let owner = *self
+            .commit_owners
+            .borrow_mut()
+            .entry(commit)
+            .or_insert_with(|| match self.repo.get_commit_author(commit) {
+                Ok((name, email)) => owners.owner(&self.mailmap, &name, &email),
+                Err(e) => {
+                    error!("unable to read the author of {}: {}", commit, e);
+                    None
+                }
+            });
+        match owner {
+            Some((uid, gid)) => self.builder.clone().uid(uid).gid(gid),
+            None => self.builder.clone(),
+        }
+    }
+
+    /// Attributes of an object, built once per inode
+    fn object_attr(&self, ino: Ino, oid: Oid) -> std::result::Result<FileAttr, GitError> {
+        stats::record_oid(oid);
+        let cached = self.attrs.borrow().get(&ino.value()).copied();
+        self.stats.cache(Cache::Attr, cached.is_some());
+        if let Some(attr) = cached {
+            return Ok(attr);
+        }
+
+        // blobs are only inflated once they are read
+        let attr = match self.repo.object_header(oid)? {
+            (size, ObjectType::Blob) => self.blob_attr(ino, size),
+            _ => {
+                let tree = self.repo.get_tree_by_inode(ino)?;
+                tree.to_file_attr(self.commit_builder(tree.parent()))
+            }
+        };
+        self.attrs.borrow_mut().insert(ino.value(), attr);
+        Ok(attr)
+    }
+
+    fn get_attr(&self, ino: u64) -> std::result::Result<FileAttr, c_int> {
+        if ino == self.root || ino == FUSE_ROOT_ID {
+            Ok(self.meta.root_attr(self.builder.clone(), ino))
+        } else if self.meta.is_dir(ino) {
+            Ok(self.meta.dir_attr(self.builder.clone()))
+        } else if self.meta.is_stats(ino) {
+            Ok(self
+                .meta
+                .stats_attr(self.builder.clone(), &self.render_stats()))
+        } else if let Some(commit) = self.commit_meta.commit(ino) {
+            self.commit_meta
+                .attr(&self.repo, ino, self.commit_builder(commit))
+                .map_err(|e| {
+                    error!("unable to describe commit {}: {}", commit, e);
+                    libc::EIO
+                })
+        } else if let Some(attr) = self.injected.commit(ino.into()).and_then(|commit| {
+            self.injected
+                .file_attr(&self.repo, ino.into(), self.commit_builder(commit))
+        }) {
+            Ok(attr)
+        } else {
+            let (_, oid) = self.repo.get_ids_by_inode(ino.into()).ok_or(ENOENT)?;
+            self.object_attr(ino.into(), oid)
+                .map_err(|e| self.object_errno(ino, &e))
+        }
+    }
+
+    /// Errno of a failure to read the object of inode `ino`. An object given
+    /// an inode existed, one missing since was pruned or lost by `git gc`
+    /// while mounted: that is an I/O error rather than a missing name, and
+    /// what was cached about it is dropped for the next request to see it.
+    fn object_errno(&self, ino: u64, error: &GitError) -> c_int {
+        match (error, self.repo.get_ids_by_inode(ino.into())) {
+            (GitError::Missing, Some((_, oid))) => {
+                error!(
+                    "object {} of inode {} is gone from the repository",
+                    oid, ino
+                );
+                self.attrs.borrow_mut().remove(&ino);
+                self.repo.headers().remove(oid);
+                libc::EIO
+            }
+            (error, _) => errno(error),
+        }
+    }
+
+    /// Snapshot the entries of a directory
+    fn read_dir(&mut self, ino: u64) -> std::result::Result<Vec<DirEntry>, c_int> {
+        if ino == self.root {
+            return Err(ENOENT);
+        } else if self.meta.is_dir(ino) {
+            return Ok(self.meta.entries());
+        } else if let Some(entries) = self
+            .commit_meta
+            .entries(&self.repo, ino, self.parent_of(ino))
+        {
+            return entries.map_err(|e| {
+                error!("unable to describe the commit of inode {}: {}", ino, e);
+                libc::EIO
+            });
+        }
+
+        let (parent, tree, tree_entries) = self.dir_entries(ino).map_err(|e| match e {
+            GitError::WrongKind => libc::ENOTDIR,
+            e => self.object_errno(ino, &e),
+        })?;
+        let is_commit_root = self.is_commit_root(parent, tree);
+        let injected = if is_commit_root {
+            let injected = self.injected.entries(&self.repo, parent);
+            self.drop_retired_injected();
+            injected
+        } else {
+            Vec::new()
+        };
+        let git_meta = is_commit_root && self.options.git_meta;
+        let children: Vec<_> = tree_entries
+            .iter()
+            // injected files shadow entries of the same name
+            .filter(|entry| {
+                let name = OsStr::from_bytes(&entry.name);
+                !injected.iter().any(|(_, injected)| injected == name)
+                    && !(git_meta && name == commit_meta::DIR_NAME)
+            })
+            .map(|entry| {
+                let name = OsStr::from_bytes(&entry.name);
+                let name = match self.options.normalization {
+                    Some(form) => names::normalize(name, form),
+                    None => OsString::from(name),
+                };
+                let name = if self.options.windows_names {
+                    names::mangle_windows(&name)
+                } else {
+                    name
+                };
+                (entry.id, name, entry.kind, entry.filemode)
+            })
+            .collect();
+
+        let mut entries = vec![
+            DirEntry::new(ino, FileType::Directory, "."),
+            DirEntry::new(self.parent_of(ino), FileType::Directory, ".."),
+        ];
+
+        // kinds come from the tree, sizes are read ahead of the stats that follow
+        let mut blobs = Vec::new();
+        let mut files = Vec::new();
+        for (oid, name, kind, filemode) in children {
+            let kind = match kind {
+                Some(ObjectType::Blob) => FileType::RegularFile,
+                Some(ObjectType::Tree) => FileType::Directory,
+                // submodules point to commits of another repository
+                _ => {
+                    debug!("skipping {:?}, neither a blob nor a tree", name);
+                    continue;
                 }
+            };
+            let ino = self.repo.get_inode(parent, oid).value();
+            self.modes.insert(ino, filemode);
+            if kind == FileType::Directory {
+                self.parents.insert(ino, entries[0].ino);
+            } else if self.options.precompute_sizes {
+                files.push((ino, oid));
+            } else if oid != *EMPTY_BLOB && !self.repo.is_converted(oid) {
+                blobs.push(oid);
             }
+            entries.push(DirEntry::new(ino, kind, name));
+        }
 
-            reply.error(ENOENT);
+        let headers = self.repo.headers();
+        blobs.retain(|oid| headers.get(*oid).is_none());
+        if !blobs.is_empty() {
+            self.workers
+                .run(move |store| git::prefetch_headers(store, &headers, &blobs));
+        }
+        // converted ones included, errors are reported to the lookups
+        for (ino, oid) in files {
+            let _ = self.object_attr(ino.into(), oid);
+        }
+
+        for (ino, name) in injected {
+            entries.push(DirEntry::new(ino.value(), FileType::RegularFile, name));
+        }
+        if git_meta {
+            entries.push(DirEntry::new(
+                self.commit_meta.dir(parent).value(),
+                FileType::Directory,
+                commit_meta::DIR_NAME,
+            ));
+        }
+
+        Ok(entries)
+    }
+
+    /// Record an entry found in directory `parent`, which the kernel holds a
+    /// reference to until `forget`
+    fn record_entry(&mut self, parent: u64, attr: &FileAttr) {
+        if attr.kind == FileType::Directory && attr.ino != self.root {
+            self.parents.insert(attr.ino, parent);
+        }
+        self.acquire(attr.ino, 1);
+    }
+
+    /// Directory a directory inode was last found in, the root if unknown
+    fn parent_of(&self, ino: u64) -> u64 {
+        match self.parents.get(&ino) {
+            Some(parent) => *parent,
+            // the root of a repository is found in the root of the mount
+            None if ino == self.root => FUSE_ROOT_ID,
+            None => self.root,
+        }
+    }
+
+    fn acquire(&mut self, ino: u64, count: u64) {
+        *self.refs.entry(ino).or_insert(0) += count;
+        self.idle.remove(ino);
+    }
+
+    /// Drop references to an inode, forgetting its mapping once none are left.
+    /// NFS exports keep it for as long as `max_inodes` allows.
+    fn release_ref(&mut self, ino: u64, count: u64) {
+        if let Entry::Occupied(mut entry) = self.refs.entry(ino) {
+            let refs = entry.get_mut();
+            *refs = refs.saturating_sub(count);
+            if *refs == 0 {
+                entry.remove();
+                if self.options.export {
+                    self.idle.insert(ino);
+                } else {
+                    self.forget_inode(ino);
+                }
+            }
+        }
+        self.evict_idle();
+    }
+
+    /// Forget the inodes that went unused the longest until the map fits
+    /// `max_inodes`, then if it still does not, those the kernel was never
+    /// handed, such as the ones `preload` assigns. Objects seen again get
+    /// their inode back, it is derived from their ids.
+    fn evict_idle(&mut self) {
+        while self.repo.inode_count() > self.options.max_inodes {
+            match self.idle.pop_oldest() {
+                Some(ino) => self.forget_inode(ino),
+                None => break,
+            }
+        }
+        let count = self.repo.inode_count();
+        if count <= self.options.max_inodes || count <= self.refs.len() {
             return;
         }
+        let unreferenced: Vec<u64> = self
+            .repo
+            .inode_map()
+            .inodes()
+            .into_iter()
+            .map(|ino| ino.value())
+            .filter(|ino| !self.refs.contains_key(ino))
+            .collect();
+        debug!(
+            "forgetting {} inodes the kernel holds no reference to",
+            unreferenced.len()
+        );
+        for ino in unreferenced {
+            self.forget_inode(ino);
+        }
+    }
+
+    fn forget_inode(&mut self, ino: u64) {
+        self.parents.remove(&ino);
+        self.modes.remove(&ino);
+        self.attrs.borrow_mut().remove(&ino);
+        self.commit_meta.forget(ino);
+        self.repo.forget_inode(ino.into());
+        self.drop_retired_injected();
+    }
+
+    /// Drop the injected files rendered again since, once the kernel holds
+    /// no reference to them
+    fn drop_retired_injected(&mut self) {
+        let refs = &self.refs;
+        self.injected
+            .drop_retired(|ino| refs.contains_key(&ino.value()));
+    }
+
+    /// Extended attributes of an inode: its git metadata followed by whatever
+    /// providers contribute
+    fn xattrs(&mut self, ino: u64) -> Option<ObjectXattrs> {
+        let (commit, oid) = self.repo.get_ids_by_inode(ino.into())?;
+        // commit roots are looked up by commit rather than found in a tree
+        let mode = self.modes.get(&ino).copied().unwrap_or(0o040000);
+
+        let mut xattrs: Xattrs = vec![
+            ("user.git.oid".into(), oid.to_string().into_bytes()),
+            ("user.git.mode".into(), format!("{:06o}", mode).into_bytes()),
+        ];
+
+        if self.is_commit_root(commit, oid) {
+            match self.repo.get_commit_metadata(commit) {
+                Ok(metadata) => xattrs.extend(vec![
+                    ("user.git.commit".into(), commit.to_string().into_bytes()),
+                    ("user.git.author".into(), metadata.author.into_bytes()),
+                    ("user.git.committer".into(), metadata.committer.into_bytes()),
+                    ("user.git.message".into(), metadata.message),
+                ]),
+                Err(e) => error!("unable to read commit {}: {}", commit, e),
+            }
+            if let Some(note) = self.repo.get_commit_note(commit) {
+                xattrs.push(("user.git.notes".into(), note));
+            }
+            if self.options.verify_signatures {
+                let verification = self.repo.verify_commit(commit);
+                xattrs.push((
+                    "user.git.signature".into(),
+                    verification.status.as_bytes().to_vec(),
+                ));
+                if let Some(signer) = verification.signer {
+                    xattrs.push(("user.git.signer".into(), signer.into_bytes()));
+                }
+                if let Some(key) = verification.key {
+                    xattrs.push(("user.git.signing-key".into(), key.into_bytes()));
+                }
+            }
+        }
+
+        if self.providers.is_empty() {
+            return Some(ObjectXattrs { xattrs, ask: None });
+        }
+        let provided = self.provider_xattrs.lock().unwrap().get(&(commit, oid));
+        let ask = match provided {
+            Some(provided) => {
+                xattrs.extend(provided);
+                None
+            }
+            None => Some((
+                commit,
+                oid,
+                self.providers.clone(),
+                self.provider_xattrs.clone(),
+            )),
+        };
+        Some(ObjectXattrs { xattrs, ask })
+    }
+
+    /// Hand extended attributes to `answer`, on a worker when providers have
+    /// to be asked so that other requests go on meanwhile
+    fn answer_xattrs<F>(&self, xattrs: Option<ObjectXattrs>, answer: F)
+    where
+        F: FnOnce(Option<Xattrs>) + Send + 'static,
+    {
+        match xattrs {
+            Some(xattrs) if xattrs.pending() => {
+                self.workers.run(move |_| answer(Some(xattrs.complete())))
+            }
+            xattrs => answer(xattrs.map(ObjectXattrs::complete)),
+        }
+    }
+
+    /// Look up `name` in directory `parent`
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> std::result::Result<FileAttr, c_int> {
+        // the kernel reconnects file handles by looking up `.` and `..`
+        if name == "." || name == ".." {
+            let ino = if name == "." {
+                parent
+            } else {
+                self.parent_of(parent)
+            };
+            let attr = match self.get_attr(ino) {
+                Err(ENOENT) => Err(libc::ESTALE),
+                attr => attr,
+            }?;
+            let parent = self.parent_of(ino);
+            self.record_entry(parent, &attr);
+            return Ok(attr);
+        }
+
+        if let Some(ino) = self.meta.lookup(parent, name) {
+            let attr = self.get_attr(ino)?;
+            self.record_entry(parent, &attr);
+            return Ok(attr);
+        } else if self.meta.is_dir(parent) {
+            return Err(ENOENT);
+        }
+
+        if self.commit_meta.dir_commit(parent).is_some() {
+            let ino = self
+                .commit_meta
+                .lookup(&self.repo, parent, name)
+                .map_err(|e| {
+                    error!("unable to describe the commit of inode {}: {}", parent, e);
+                    libc::EIO
+                })?
+                .ok_or(ENOENT)?;
+            let attr = self.get_attr(ino.value())?;
+            self.record_entry(parent, &attr);
+            return Ok(attr);
+        }
+
+        if parent == self.root {
+            // looking up by commit hash
+            let hash = name.to_str().ok_or(ENOENT)?;
+            let attr = self.lookup_commit(hash).map_err(|e| errno(&e))?;
+            self.record_entry(parent, &attr);
+            return Ok(attr);
+        }
+
+        let invalidations = self.invalidations.load(Ordering::SeqCst);
+        if invalidations != self.invalidated {
+            self.invalidated = invalidations;
+            self.missing.clear();
+        }
+        // build systems probe for the same missing files over and over
+        let missing = self.missing.contains(parent, name);
+        self.stats.cache(Cache::Negative, missing);
+        if missing {
+            return Err(ENOENT);
+        }
 
         // you can only `lookup` on a tree
-        let tree = match self.repo.get_tree_by_inode(parent.into()) {
-            Ok(tree) => tree,
-            Err(_) => {
-                reply.error(ENOENT);
-                return;
+        let (commit, tree, entries) = self.dir_entries(parent).map_err(|e| match e {
+            GitError::WrongKind => libc::ENOTDIR,
+            e => self.object_errno(parent, &e),
+        })?;
+
+        if self.is_commit_root(commit, tree) {
+            let injected = self.injected.lookup(&self.repo, commit, name);
+            self.drop_retired_injected();
+            if let Some(ino) = injected {
+                let attr = self
+                    .injected
+                    .file_attr(&self.repo, ino, self.commit_builder(commit))
+                    .unwrap();
+                self.record_entry(parent, &attr);
+                return Ok(attr);
+            }
+            if self.options.git_meta && name == commit_meta::DIR_NAME {
+                let attr = self.get_attr(self.commit_meta.dir(commit).value())?;
+                self.record_entry(parent, &attr);
+                return Ok(attr);
             }
+        }
+
+        // names listings never show are not found, as the names they stand for
+        // are shown mangled
+        let unmangled = if self.options.windows_names {
+            names::unmangle_windows(name)
+        } else {
+            Some(OsString::from(name))
         };
+        let found = unmangled.as_ref().and_then(|unmangled| {
+            names::find_entry(
+                &entries,
+                unmangled,
+                self.options.case_insensitive,
+                self.options.normalization,
+            )
+        });
 
         // entry not found
-        let (oid, kind) = match tree.as_ref().get_path(&Path::new(&name)) {
-            Ok(entry) => (entry.id(), entry.kind()),
-            Err(_) => {
-                reply.error(ENOENT);
-                return;
+        let (oid, kind, filemode) = match found {
+            Some(entry) => (entry.id, entry.kind, entry.filemode),
+            None => {
+                self.missing.insert(parent, name);
+                return Err(ENOENT);
             }
         };
 
-        let parent = tree.parent();
+        let attr = match kind {
+            Some(ObjectType::Blob) | Some(ObjectType::Tree) => {
+                let ino = self.repo.get_inode(commit, oid);
+                self.object_attr(ino, oid)
+                    .map_err(|e| self.object_errno(ino.value(), &e))
+            }
+            // submodules point to commits of another repository
+            _ => Err(ENOENT),
+        }?;
+        self.modes.insert(attr.ino, filemode);
+        self.record_entry(parent, &attr);
+        Ok(attr)
+    }
+
+    /// Whether user `uid` of group `gid` may access an inode as `mask` asks
+    fn check_access(
+        &self,
+        uid: u32,
+        gid: u32,
+        ino: u64,
+        mask: i32,
+    ) -> std::result::Result<(), c_int> {
+        let attr = self.get_attr(ino)?;
+        if mask & libc::W_OK != 0 {
+            return Err(libc::EROFS);
+        }
+
+        let perm = attr.perm as i32;
+        let granted = if uid == 0 {
+            // root may read anything, but only execute if someone can
+            libc::R_OK | if perm & 0o111 != 0 { libc::X_OK } else { 0 }
+        } else if uid == attr.uid {
+            perm >> 6
+        } else if gid == attr.gid {
+            perm >> 3
+        } else {
+            perm
+        } & 0o7;
+
+        if mask & !granted == 0 {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    /// Blocks and inodes in use, which for a read-only filesystem are all of them
+    fn usage(&self) -> (u64, u64) {
+        let size = match self.repo.odb_size() {
+            Ok(size) => size,
+            Err(e) => {
+                error!("unable to compute object database size: {}", e);
+                0
+            }
+        };
+        let blocks = size.div_ceil(BLOCK_SIZE as u64);
+        let files = self.repo.inode_count() as u64 + 1;
+        (blocks, files)
+    }
+
+    /// Flags returned along with every file handle
+    fn open_flags(&self, ino: u64) -> u32 {
+        let mut open_flags = 0;
+        // the stats change between the size reported and the read, diffs are
+        // not rendered to report their size
+        if self.options.direct_io || self.meta.is_stats(ino) || self.commit_meta.is_diff(ino) {
+            open_flags |= consts::FOPEN_DIRECT_IO;
+        }
+        if self.options.keep_cache {
+            open_flags |= consts::FOPEN_KEEP_CACHE;
+        }
+        open_flags
+    }
+
+    /// Statistics of the mount as `/.giblefs/stats` shows them, in JSON
+    fn render_stats(&self) -> Vec<u8> {
+        let mut stats = self.stats.to_json();
+        {
+            let contents = self.contents.lock().unwrap();
+            let (used, compressed) = contents.used();
+            let content = &mut stats["caches"]["content"];
+            content["entries"] = contents.entries().into();
+            content["bytes"] = used.into();
+            content["compressed_bytes"] = compressed.into();
+        }
+        stats["caches"]["disk"]["bytes"] = self.disk.used().into();
+        stats["inodes"] = self.repo.inode_count().into();
+        stats["memory"] = serde_json::json!({
+            "resident_bytes": meta::resident_bytes(),
+            "libgit2_cached_bytes": git::cached_memory().map(|(current, _)| current),
+        });
+        stats["uptime_seconds"] = self.meta.uptime().as_secs().into();
+
+        let mut content = serde_json::to_vec_pretty(&stats).unwrap_or_default();
+        content.push(b'\n');
+        content
+    }
+
+    /// Target of a symbolic link
+    fn read_link(&self, ino: u64) -> std::result::Result<Arc<[u8]>, c_int> {
+        if self.commit_meta.is_link(ino) {
+            self.commit_meta_content(ino)
+        } else {
+            Err(libc::EINVAL)
+        }
+    }
+
+    /// Content of a file or link of `.git-meta`
+    fn commit_meta_content(&self, ino: u64) -> std::result::Result<Arc<[u8]>, c_int> {
+        self.commit_meta.content(&self.repo, ino).map_err(|e| {
+            error!("unable to describe the commit of inode {}: {}", ino, e);
+            libc::EIO
+        })
+    }
 
-        drop(tree);
+    /// Open a file, unless its blob has to be inflated first
+    fn open_file(&mut self, ino: u64, flags: i32) -> std::result::Result<Opened, c_int> {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return Err(libc::EROFS);
+        }
 
-        match kind {
-            Some(ObjectType::Blob) => {
-                if let Ok(blob) = self.repo.get_blob(parent, oid) {
-                    reply.entry(&TTL, &blob.to_file_attr(self.builder.clone()), 0);
-                    return;
+        let handle = if ino == self.root
+            || self.meta.is_dir(ino)
+            || self.commit_meta.dir_commit(ino).is_some()
+        {
+            return Err(libc::EISDIR);
+        } else if self.meta.is_stats(ino) {
+            FileHandle::Content(self.render_stats().into())
+        } else if self.commit_meta.commit(ino).is_some() {
+            FileHandle::Content(self.commit_meta_content(ino)?)
+        } else if let Some(content) = self.injected.content(ino.into()) {
+            FileHandle::Content(content.into())
+        } else if self.is_empty_blob(ino) {
+            FileHandle::Content(Arc::from(&[][..]))
+        } else if let Some(stream) = self.blob_stream(ino) {
+            FileHandle::Stream(Arc::new(Mutex::new(stream)))
+        } else {
+            let (_, oid) = self.repo.get_ids_by_inode(ino.into()).ok_or(ENOENT)?;
+            stats::record_oid(oid);
+            let cached = self.contents.lock().unwrap().get(oid);
+            self.stats.cache(Cache::Content, cached.is_some());
+            match cached {
+                Some(content) => FileHandle::Content(content),
+                // the workers read the object database, where converted
+                // blobs are not
+                None if self.repo.is_converted(oid) => {
+                    let content = self
+                        .disk
+                        .get_or_insert_with(oid, || self.repo.read_blob(oid))
+                        .map_err(|e| self.object_errno(ino, &e))?;
+                    self.contents.lock().unwrap().insert(oid, content.clone());
+                    FileHandle::Content(content)
                 }
+                None => return Ok(Opened::Inflate(oid)),
+            }
+        };
+
+        Ok(Opened::Handle(self.files.lock().unwrap().insert(handle)))
+    }
+
+    /// Read `size` bytes at `offset` of a file, through handle `fh` if it is open
+    fn read_file(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> std::result::Result<FileData, c_int> {
+        let handle = self.files.lock().unwrap().get(fh).cloned();
+        let content = match handle {
+            Some(handle) => return read_handle(&handle, &self.workers, ino, offset, size),
+            None if ino == self.root
+                || self.meta.is_dir(ino)
+                || self.commit_meta.dir_commit(ino).is_some() =>
+            {
+                return Err(libc::EISDIR)
             }
-            Some(ObjectType::Tree) => {
-                if let Ok(tree) = self.repo.get_tree(parent, oid) {
-                    reply.entry(&TTL, &tree.to_file_attr(self.builder.clone()), 0);
-                    return;
+            None => {
+                if self.meta.is_stats(ino) {
+                    self.render_stats().into()
+                } else if self.commit_meta.commit(ino).is_some() {
+                    self.commit_meta_content(ino)?
+                } else if let Some(content) = self.injected.content(ino.into()) {
+                    let range = read_span(content.len(), offset, size)?;
+                    return Ok(FileData::Owned(content[range].to_vec()));
+                } else if self.is_empty_blob(ino) {
+                    Arc::from(&[][..])
+                } else {
+                    self.blob_content(ino).map_err(|e| match e {
+                        GitError::WrongKind => libc::EISDIR,
+                        e => self.object_errno(ino, &e),
+                    })?
                 }
             }
-            _ => (),
-        }
+        };
 
-        reply.error(ENOENT);
+        let range = read_span(content.len(), offset, size)?;
+        Ok(FileData::Shared(content, range))
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        if ino == 1 {
-            reply.attr(&TTL, &ROOT_ATTR);
-        } else if let Ok(tree) = self.repo.get_tree_by_inode(ino.into()) {
-            reply.attr(&TTL, &tree.to_file_attr(self.builder.clone()));
-        } else if let Ok(blob) = self.repo.get_blob_by_inode(ino.into()) {
-            reply.attr(&TTL, &blob.to_file_attr(self.builder.clone()));
+    /// Position `lseek` moves to
+    fn seek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> std::result::Result<i64, c_int> {
+        let size = match self.files.lock().unwrap().get(fh) {
+            Some(handle) => handle.size() as i64,
+            None => self.get_attr(ino)?.size as i64,
+        };
+
+        // blobs have no holes: all of the file is data, followed by the hole at EOF
+        let position = match whence {
+            libc::SEEK_SET => offset,
+            libc::SEEK_END => size + offset,
+            libc::SEEK_DATA | libc::SEEK_HOLE if offset >= size => return Err(libc::ENXIO),
+            libc::SEEK_DATA => offset,
+            libc::SEEK_HOLE => size,
+            _ => return Err(libc::EINVAL),
+        };
+
+        if position < 0 {
+            Err(libc::EINVAL)
         } else {
-            reply.error(ENOENT);
+            Ok(position)
+        }
+    }
+
+    /// Copy `len` bytes of the file open as `fh_in` from `offset_in` to a file
+    /// open on this mount, the only destination the kernel forwards: a
+    /// destination on another filesystem fails with EXDEV before reaching us
+    fn copy_range(&self, fh_in: u64, offset_in: i64, len: u64) -> std::result::Result<u32, c_int> {
+        if offset_in < 0 {
+            return Err(libc::EINVAL);
+        }
+        let size = match self.files.lock().unwrap().get(fh_in) {
+            Some(handle) => handle.size().saturating_sub(offset_in as u64).min(len),
+            None => return Err(libc::EBADF),
+        };
+
+        // nothing past EOF is copied, as with any filesystem; anything else
+        // would be written into this mount, which is never writable
+        if size == 0 {
+            Ok(0)
+        } else {
+            Err(libc::EROFS)
+        }
+    }
+
+    /// Open a directory, taking a snapshot of its entries
+    fn open_dir(&mut self, ino: u64) -> std::result::Result<u64, c_int> {
+        let entries = self.read_dir(ino)?;
+        // keep children mapped for as long as the snapshot is around
+        for entry in entries.iter().skip(2) {
+            self.acquire(entry.ino, 1);
+        }
+        Ok(self.dirs.insert(entries))
+    }
+
+    fn release_dir(&mut self, fh: u64) {
+        if let Some(entries) = self.dirs.remove(fh) {
+            for entry in entries.iter().skip(2) {
+                self.release_ref(entry.ino, 1);
+            }
+        }
+    }
+
+    fn lookup_commit(&mut self, hash: &str) -> std::result::Result<FileAttr, GitError> {
+        // commits fetched since may be in packs the repository has not seen
+        let generation = watch::objects_generation();
+        if generation != self.objects_generation {
+            self.objects_generation = generation;
+            self.repo.refresh_objects();
+        }
+        let commit = match Oid::from_str(hash) {
+            Ok(o) => self.repo.get_tree_by_commit(o)?,
+            Err(_e) => {
+                let tree = self.repo.get_tree_by_branch(hash)?;
+                self.branches
+                    .lock()
+                    .unwrap()
+                    .insert(hash.to_string(), tree.parent());
+                tree
+            }
+        };
+        Ok(commit.to_file_attr(self.commit_builder(commit.parent())))
+    }
+}
+
+impl Filesystem for GilberFS {
+    fn init(
+        &mut self,
+        _req: &Request,
+        config: &mut KernelConfig,
+    ) -> std::result::Result<(), libc::c_int> {
+        // return attributes along with directory entries, saving a lookup per entry
+        let readdirplus = consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO;
+        if config.add_capabilities(readdirplus).is_err() {
+            debug!("kernel does not support readdirplus");
+        }
+        // symlinks never change, their targets can stay cached
+        if config
+            .add_capabilities(consts::FUSE_CACHE_SYMLINKS)
+            .is_err()
+        {
+            debug!("kernel does not cache symlinks");
+        }
+        if self.options.export
+            && config
+                .add_capabilities(consts::FUSE_EXPORT_SUPPORT)
+                .is_err()
+        {
+            error!("kernel does not support exporting FUSE filesystems");
         }
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let op = self.stats.op(Op::Lookup).ino(parent);
+        let _span = op.enter();
+
+        match op.check(self.lookup_entry(parent, name)) {
+            // fuser has the attributes that come along expire with the entry
+            Ok(attr) => reply.entry(&self.options.entry_ttl, &attr, self.generation),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        let op = self.stats.op(Op::Forget).ino(ino);
+        let _span = op.enter();
+        self.release_ref(ino, nlookup);
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let op = self.stats.op(Op::Getattr).ino(ino);
+        let _span = op.enter();
+        match op.check(self.get_attr(ino)) {
+            Ok(attr) => reply.attr(&self.options.attr_ttl, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let op = self.stats.op(Op::Readlink).ino(ino);
+        let _span = op.enter();
+        match op.check(self.read_link(ino)) {
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let op = self.stats.op(Op::Access).ino(ino);
+        let _span = op.enter();
+        match op.check(self.check_access(req.uid(), req.gid(), ino, mask)) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let op = self.stats.op(Op::Getxattr).ino(ino);
+        let span = op.enter();
+        let xattrs = self.xattrs(ino);
+        drop(span);
+        let name = name.to_os_string();
+        self.answer_xattrs(xattrs, move |xattrs| {
+            let _span = op.enter();
+            match op.check(xattr_value(xattrs, &name)) {
+                Ok(value) if size == 0 => reply.size(value.len() as u32),
+                Ok(value) if value.len() <= size as usize => reply.data(&value),
+                Ok(_) => reply.error(op.error(libc::ERANGE)),
+                Err(errno) => reply.error(errno),
+            }
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let op = self.stats.op(Op::Listxattr).ino(ino);
+        let span = op.enter();
+        let xattrs = self.xattrs(ino);
+        drop(span);
+        self.answer_xattrs(xattrs, move |xattrs| {
+            let _span = op.enter();
+            let names = xattr_names(xattrs);
+            if size == 0 {
+                reply.size(names.len() as u32);
+            } else if names.len() <= size as usize {
+                reply.data(&names);
+            } else {
+                reply.error(op.error(libc::ERANGE));
+            }
+        });
+    }
+
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let op = self.stats.op(Op::Statfs).ino(ino);
+        let _span = op.enter();
+        let (blocks, files) = self.usage();
+        // read-only: everything is used, nothing is free
+        reply.statfs(blocks, 0, 0, files, 0, BLOCK_SIZE, NAME_MAX, BLOCK_SIZE);
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let op = self.stats.op(Op::Open).ino(ino);
+        let span = op.enter();
+        let open_flags = self.open_flags(ino);
+        match op.check(self.open_file(ino, flags)) {
+            Ok(Opened::Handle(fh)) => reply.opened(fh, open_flags),
+            Ok(Opened::Inflate(oid)) => {
+                // inflate the blob on a worker, other requests go on meanwhile,
+                // the call goes on there too
+                drop(span);
+                let files = self.files.clone();
+                let contents = self.contents.clone();
+                self.workers.inflate(oid, move |content| {
+                    let _span = op.enter();
+                    match content {
+                        Ok(content) => reply.opened(
+                            open_blob(&files, &contents, oid, content.clone()),
+                            open_flags,
+                        ),
+                        Err(GitError::WrongKind) => reply.error(op.error(libc::EISDIR)),
+                        // it was found when the file was looked up
+                        Err(GitError::Missing) => {
+                            error!("blob {} is gone from the repository", oid);
+                            reply.error(op.error(libc::EIO))
+                        }
+                        Err(e) => reply.error(op.error(errno(e))),
+                    }
+                });
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let op = self.stats.op(Op::Release).ino(ino);
+        let _span = op.enter();
+        self.files.lock().unwrap().remove(fh);
+        reply.ok();
     }
 
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        if ino == 1 {
-            reply.error(libc::EISDIR);
-        } else if let Ok(blob) = self.repo.get_blob_by_inode(ino.into()) {
-            if let (Ok(offset), Ok(size)) = (usize::try_from(offset), usize::try_from(size)) {
-                let content = blob.as_ref().content();
-                reply.data(&content[offset..(std::cmp::min(offset + size, content.len()))])
-            } else {
-                // offset or size is too big for us to handle
-                reply.error(libc::EINVAL)
+        let op = self.stats.op(Op::Read).ino(ino);
+        // streams are inflated as they are read, on the workers so that reads
+        // of other files do not wait, and in request order as reading
+        // backwards restarts them
+        let handle = self.files.lock().unwrap().get(fh).cloned();
+        if let Some(handle @ FileHandle::Stream(_)) = handle {
+            let workers = self.workers.clone();
+            self.workers.run_in_order(fh, move |_| {
+                let _span = op.enter();
+                match op.check(read_handle(&handle, &workers, ino, offset, size)) {
+                    Ok(data) => {
+                        op.bytes(data.len());
+                        reply.data(&data)
+                    }
+                    Err(errno) => reply.error(errno),
+                }
+            });
+            return;
+        }
+        let _span = op.enter();
+        match op.check(self.read_file(ino, fh, offset, size)) {
+            Ok(data) => {
+                op.bytes(data.len());
+                reply.data(&data)
             }
-        } else if let Ok(_) = self.repo.get_tree_by_inode(ino.into()) {
-            reply.error(libc::EISDIR);
-        } else {
-            reply.error(ENOENT);
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn readdir(
+    fn lseek(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
-        mut reply: ReplyDirectory,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let op = self.stats.op(Op::Lseek).ino(ino);
+        let _span = op.enter();
+        match op.check(self.seek(ino, fh, offset, whence)) {
+            Ok(position) => reply.offset(position),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
     ) {
-        if ino == 1 {
-            reply.error(ENOENT);
+        let op = self.stats.op(Op::Ioctl).ino(ino);
+        let _span = op.enter();
+        if cmd != ioctl::GET_OID {
+            reply.error(op.error(libc::ENOTTY));
             return;
         }
+        if (out_size as usize) < ioctl::OID_INFO_SIZE {
+            reply.error(op.error(libc::EINVAL));
+            return;
+        }
+
+        // the root and injected files have no object behind them
+        match self.repo.get_ids_by_inode(ino.into()) {
+            Some((commit, oid)) => reply.ioctl(0, &ioctl::oid_info(commit, oid)),
+            None => reply.error(op.error(libc::ENODATA)),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        _ino_out: u64,
+        _fh_out: u64,
+        _offset_out: i64,
+        len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let op = self.stats.op(Op::CopyFileRange).ino(ino_in);
+        let _span = op.enter();
+        match op.check(self.copy_range(fh_in, offset_in, len)) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let op = self.stats.op(Op::Opendir).ino(ino);
+        let _span = op.enter();
+        match op.check(self.open_dir(ino)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
 
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let op = self.stats.op(Op::Releasedir).ino(ino);
+        let _span = op.enter();
+        self.release_dir(fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let op = self.stats.op(Op::Readdir).ino(ino);
+        let _span = op.enter();
         let offset = if let Ok(offset) = usize::try_from(offset) {
             offset
         } else {
             error!("invalid offset: {}", offset);
-            reply.error(libc::EINVAL);
+            reply.error(op.error(libc::EINVAL));
             return;
         };
 
-        let tree = match self.repo.get_tree_by_inode(ino.into()) {
-            Ok(tree) => tree,
-            Err(_) => {
-                reply.error(ENOENT);
+        let entries = match self.dirs.get(fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(op.error(libc::EBADF));
                 return;
             }
         };
-        let parent = tree.parent();
-        let ino = tree.inode();
-        let entries: Vec<_> = tree
-            .as_ref()
-            .iter()
-            .enumerate()
-            .map(|(idx, entry)| {
-                let oid = entry.id();
-                let name = OsStr::from_bytes(entry.name_bytes());
-                let name = OsString::from(name);
-                let mode = entry.filemode();
-                let kind = entry.kind();
-                (idx as i64 + 3, oid, name, kind, mode)
-            })
-            .collect();
-        drop(tree);
 
-        if !(offset >= 1) {
-            reply.add(ino.value(), 1, FileType::Directory, ".");
-            debug!("{} {} {}", ino.value(), 1, ".");
+        // offsets are 1-based positions in the snapshot
+        for (idx, entry) in entries.iter().enumerate().skip(offset) {
+            debug!("{} {} {:?}", entry.ino, idx + 1, entry.name);
+            if reply.add(entry.ino, idx as i64 + 1, entry.kind, &entry.name) {
+                break;
+            }
         }
 
-        if !(offset >= 2) {
-            reply.add(ino.parent(), 2, FileType::Directory, "..");
-            debug!("{} {} {}", ino.parent(), 2, "..");
-        }
+        reply.ok();
+    }
 
-        let offset = offset.saturating_sub(2);
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let op = self.stats.op(Op::Readdirplus).ino(ino);
+        let _span = op.enter();
+        let offset = if let Ok(offset) = usize::try_from(offset) {
+            offset
+        } else {
+            error!("invalid offset: {}", offset);
+            reply.error(op.error(libc::EINVAL));
+            return;
+        };
 
-        for (idx, oid, name, kind, _mode) in entries.into_iter().skip(offset) {
-            if let Ok((ino, _, obj)) = self.repo.get_object(parent, oid, kind) {
-                debug!("{} {} {:?}", ino.value(), idx, &name);
-                match obj.kind() {
-                    Some(ObjectType::Blob) => {
-                        // handle blobs
-                        reply.add(ino.value(), idx, FileType::RegularFile, name);
-                    }
-                    Some(ObjectType::Tree) => {
-                        // handle trees
-                        reply.add(ino.value(), idx, FileType::Directory, name);
-                    }
-                    Some(kind) => {
-                        error!("received impossible object type {} for {}", kind, oid);
-                    }
-                    None => {
-                        error!("unable to detect object type for {}", oid);
-                    }
+        let entries = match self.dirs.get(fh) {
+            Some(entries) => entries,
+            None => {
+                reply.error(op.error(libc::EBADF));
+                return;
+            }
+        };
+
+        let mut looked_up = Vec::new();
+        for (idx, entry) in entries.iter().enumerate().skip(offset) {
+            let attr = match self.get_attr(entry.ino) {
+                Ok(attr) => attr,
+                Err(_) => {
+                    error!("unable to get attributes of {:?}", entry.name);
+                    continue;
                 }
-            } else {
-                error!("unable to find {}", oid);
+            };
+            debug!("{} {} {:?}", entry.ino, idx + 1, entry.name);
+            if reply.add(
+                entry.ino,
+                idx as i64 + 1,
+                &entry.name,
+                &self.options.entry_ttl,
+                &attr,
+                self.generation,
+            ) {
+                break;
+            }
+            // every entry but `.` and `..` counts as a lookup
+            if idx >= 2 {
+                looked_up.push(entry.ino);
             }
         }
 
+        for ino in looked_up {
+            self.acquire(ino, 1);
+        }
         reply.ok();
     }
+
+    // everything below would modify the repository, which is read-only
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        _size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let op = self.stats.op(Op::Setattr).ino(ino);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let op = self.stats.op(Op::Mknod).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let op = self.stats.op(Op::Mkdir).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        let op = self.stats.op(Op::Unlink).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+        let op = self.stats.op(Op::Rmdir).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        _link_name: &OsStr,
+        _target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let op = self.stats.op(Op::Symlink).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        _name: &OsStr,
+        _newparent: u64,
+        _newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let op = self.stats.op(Op::Rename).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let op = self.stats.op(Op::Link).ino(ino);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let op = self.stats.op(Op::Write).ino(ino);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let op = self.stats.op(Op::Create).ino(parent);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let op = self.stats.op(Op::Setxattr).ino(ino);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+        let op = self.stats.op(Op::Removexattr).ino(ino);
+        let _span = op.enter();
+        reply.error(op.error(libc::EROFS));
+    }
 }
@@ -0,0 +1,495 @@
+//! Asynchronous backend on top of fuse3 and tokio, built with the `async`
+//! feature. Requests run on tokio's blocking pool, but every one that needs
+//! the filesystem itself, lookups, attributes, listings and opens of blobs
+//! already inflated, takes one lock over it: they are served one at a time,
+//! as its caches are not shared between threads. What goes on outside of the
+//! lock is blob inflation and reads of open files, so those no longer wait
+//! for each other nor hold up the rest.
+
+use super::{
+    errno, open_blob, read_handle, xattr_names, xattr_value, ContentCache, FileData, FileHandle,
+    GilberFS, Handles, ObjectXattrs, Opened, BLOCK_SIZE, NAME_MAX,
+};
+use crate::git::GitError;
+use crate::stats::{Op, Stats};
+use crate::workers::Workers;
+use bytes::Bytes;
+use fuse3::raw::prelude::*;
+use fuse3::raw::MountHandle;
+use fuse3::{Errno, MountOptions, Result};
+use futures_util::stream::{self, Stream, StreamExt};
+use libc::c_int;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::io;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+const MAX_WRITE: u32 = 128 * 1024;
+
+type Entries<'a, T> = Pin<Box<dyn Stream<Item = Result<T>> + Send + 'a>>;
+
+pub struct AsyncGilberFS {
+    fs: Arc<Mutex<GilberFS>>,
+    // shared with `fs`, to open and read files without holding its lock
+    files: Arc<Mutex<Handles<FileHandle>>>,
+    contents: Arc<Mutex<ContentCache>>,
+    workers: Workers,
+    stats: Arc<Stats>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    generation: u64,
+}
+
+impl AsyncGilberFS {
+    pub fn new(fs: GilberFS) -> Self {
+        AsyncGilberFS {
+            files: fs.files.clone(),
+            contents: fs.contents.clone(),
+            workers: fs.workers.clone(),
+            stats: fs.stats.clone(),
+            entry_ttl: fs.options.entry_ttl,
+            attr_ttl: fs.options.attr_ttl,
+            generation: fs.generation,
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Run `f` on the filesystem from the blocking pool, once the requests
+    /// before it are done with it
+    async fn with<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut GilberFS) -> std::result::Result<T, c_int> + Send + 'static,
+    {
+        let fs = self.fs.clone();
+        blocking(move || f(&mut fs.lock().unwrap())).await
+    }
+}
+
+/// Run `f` from the blocking pool
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::result::Result<T, c_int> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result.map_err(Errno::from),
+        Err(e) => {
+            error!("request failed: {}", e);
+            Err(libc::EIO.into())
+        }
+    }
+}
+
+fn file_type(kind: fuser::FileType) -> FileType {
+    match kind {
+        fuser::FileType::NamedPipe => FileType::NamedPipe,
+        fuser::FileType::CharDevice => FileType::CharDevice,
+        fuser::FileType::BlockDevice => FileType::BlockDevice,
+        fuser::FileType::Directory => FileType::Directory,
+        fuser::FileType::RegularFile => FileType::RegularFile,
+        fuser::FileType::Symlink => FileType::Symlink,
+        fuser::FileType::Socket => FileType::Socket,
+    }
+}
+
+fn file_attr(attr: fuser::FileAttr) -> FileAttr {
+    FileAttr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime: attr.atime.into(),
+        mtime: attr.mtime.into(),
+        ctime: attr.ctime.into(),
+        kind: file_type(attr.kind),
+        perm: attr.perm,
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        blksize: attr.blksize,
+    }
+}
+
+/// Reply to `getxattr` and `listxattr`: the size when asked for it, the data
+/// if it fits
+fn xattr_reply(data: Vec<u8>, size: u32) -> Result<ReplyXAttr> {
+    if size == 0 {
+        Ok(ReplyXAttr::Size(data.len() as u32))
+    } else if data.len() <= size as usize {
+        Ok(ReplyXAttr::Data(data.into()))
+    } else {
+        Err(libc::ERANGE.into())
+    }
+}
+
+impl Filesystem for AsyncGilberFS {
+    async fn init(&self, _req: Request) -> Result<ReplyInit> {
+        Ok(ReplyInit {
+            max_write: NonZeroU32::new(MAX_WRITE).unwrap(),
+        })
+    }
+
+    async fn destroy(&self, _req: Request) {}
+
+    async fn lookup(&self, _req: Request, parent: u64, name: &OsStr) -> Result<ReplyEntry> {
+        let op = self.stats.op(Op::Lookup).ino(parent);
+        let name = name.to_owned();
+        let attr = op.check(self.with(move |fs| fs.lookup_entry(parent, &name)).await)?;
+        Ok(ReplyEntry {
+            ttl: self.entry_ttl,
+            attr: file_attr(attr),
+            generation: self.generation,
+        })
+    }
+
+    async fn forget(&self, _req: Request, inode: u64, nlookup: u64) {
+        let _op = self.stats.op(Op::Forget).ino(inode);
+        let _ = self
+            .with(move |fs| {
+                fs.release_ref(inode, nlookup);
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn batch_forget(&self, _req: Request, inodes: &[(u64, u64)]) {
+        let _op = self.stats.op(Op::Forget);
+        let inodes = inodes.to_vec();
+        let _ = self
+            .with(move |fs| {
+                for (inode, nlookup) in inodes {
+                    fs.release_ref(inode, nlookup);
+                }
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn getattr(
+        &self,
+        _req: Request,
+        inode: u64,
+        _fh: Option<u64>,
+        _flags: u32,
+    ) -> Result<ReplyAttr> {
+        let op = self.stats.op(Op::Getattr).ino(inode);
+        let attr = op.check(self.with(move |fs| fs.get_attr(inode)).await)?;
+        Ok(ReplyAttr {
+            ttl: self.attr_ttl,
+            attr: file_attr(attr),
+        })
+    }
+
+    async fn readlink(&self, _req: Request, inode: u64) -> Result<ReplyData> {
+        let op = self.stats.op(Op::Readlink).ino(inode);
+        let target = op.check(self.with(move |fs| fs.read_link(inode)).await)?;
+        Ok(ReplyData {
+            data: Bytes::from_owner(target),
+        })
+    }
+
+    async fn access(&self, req: Request, inode: u64, mask: u32) -> Result<()> {
+        let op = self.stats.op(Op::Access).ino(inode);
+        op.check(
+            self.with(move |fs| fs.check_access(req.uid, req.gid, inode, mask as i32))
+                .await,
+        )
+    }
+
+    async fn getxattr(
+        &self,
+        _req: Request,
+        inode: u64,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<ReplyXAttr> {
+        let op = self.stats.op(Op::Getxattr).ino(inode);
+        let name = name.to_owned();
+        let xattrs = self.with(move |fs| Ok(fs.xattrs(inode))).await?;
+        // providers are asked outside of the lock
+        let xattrs = blocking(move || Ok(xattrs.map(ObjectXattrs::complete))).await?;
+        let value = op.check(xattr_value(xattrs, &name).map_err(Errno::from))?;
+        op.check(xattr_reply(value, size))
+    }
+
+    async fn listxattr(&self, _req: Request, inode: u64, size: u32) -> Result<ReplyXAttr> {
+        let op = self.stats.op(Op::Listxattr).ino(inode);
+        let xattrs = self.with(move |fs| Ok(fs.xattrs(inode))).await?;
+        let xattrs = blocking(move || Ok(xattrs.map(ObjectXattrs::complete))).await?;
+        op.check(xattr_reply(xattr_names(xattrs), size))
+    }
+
+    async fn statfs(&self, _req: Request, inode: u64) -> Result<ReplyStatFs> {
+        let _op = self.stats.op(Op::Statfs).ino(inode);
+        let (blocks, files) = self.with(|fs| Ok(fs.usage())).await?;
+        // read-only: everything is used, nothing is free
+        Ok(ReplyStatFs {
+            blocks,
+            bfree: 0,
+            bavail: 0,
+            files,
+            ffree: 0,
+            bsize: BLOCK_SIZE,
+            namelen: NAME_MAX,
+            frsize: BLOCK_SIZE,
+        })
+    }
+
+    async fn open(&self, _req: Request, inode: u64, flags: u32) -> Result<ReplyOpen> {
+        let op = self.stats.op(Op::Open).ino(inode);
+        let (opened, open_flags) = op.check(
+            self.with(move |fs| Ok((fs.open_file(inode, flags as i32)?, fs.open_flags(inode))))
+                .await,
+        )?;
+
+        let fh = match opened {
+            Opened::Handle(fh) => fh,
+            Opened::Inflate(oid) => {
+                // inflate the blob on a worker, other requests go on meanwhile
+                let (sender, receiver) = oneshot::channel();
+                self.workers.inflate(oid, move |content| {
+                    let _ = sender.send(content.as_ref().map(Arc::clone).map_err(|e| match e {
+                        GitError::WrongKind => libc::EISDIR,
+                        // it was found when the file was looked up
+                        GitError::Missing => libc::EIO,
+                        e => errno(e),
+                    }));
+                });
+                match receiver.await {
+                    Ok(Ok(content)) => open_blob(&self.files, &self.contents, oid, content),
+                    Ok(Err(errno)) => return Err(op.error(errno.into())),
+                    Err(_) => return Err(op.error(libc::EIO.into())),
+                }
+            }
+        };
+
+        Ok(ReplyOpen {
+            fh,
+            flags: open_flags,
+        })
+    }
+
+    async fn release(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> Result<()> {
+        let _op = self.stats.op(Op::Release).ino(inode);
+        self.files.lock().unwrap().remove(fh);
+        Ok(())
+    }
+
+    async fn read(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<ReplyData> {
+        let op = self.stats.op(Op::Read).ino(inode);
+        let offset = i64::try_from(offset).map_err(|_| op.error(Errno::from(libc::EINVAL)))?;
+        let handle = self.files.lock().unwrap().get(fh).cloned();
+        let data = match handle {
+            Some(handle) => {
+                let workers = self.workers.clone();
+                blocking(move || read_handle(&handle, &workers, inode, offset, size)).await
+            }
+            None => {
+                self.with(move |fs| fs.read_file(inode, fh, offset, size))
+                    .await
+            }
+        };
+        let data = op.check(data)?;
+        op.bytes(data.len());
+
+        let data = match data {
+            FileData::Shared(content, range) => Bytes::from_owner(content).slice(range),
+            FileData::Owned(data) => data.into(),
+        };
+        Ok(ReplyData { data })
+    }
+
+    async fn lseek(
+        &self,
+        _req: Request,
+        inode: u64,
+        fh: u64,
+        offset: u64,
+        whence: u32,
+    ) -> Result<ReplyLSeek> {
+        let op = self.stats.op(Op::Lseek).ino(inode);
+        let position = op.check(
+            self.with(move |fs| fs.seek(inode, fh, offset as i64, whence as i32))
+                .await,
+        )?;
+        Ok(ReplyLSeek {
+            offset: position as u64,
+        })
+    }
+
+    async fn opendir(&self, _req: Request, inode: u64, _flags: u32) -> Result<ReplyOpen> {
+        let op = self.stats.op(Op::Opendir).ino(inode);
+        let fh = op.check(self.with(move |fs| fs.open_dir(inode)).await)?;
+        Ok(ReplyOpen { fh, flags: 0 })
+    }
+
+    async fn releasedir(&self, _req: Request, inode: u64, fh: u64, _flags: u32) -> Result<()> {
+        let _op = self.stats.op(Op::Releasedir).ino(inode);
+        self.with(move |fs| {
+            fs.release_dir(fh);
+            Ok(())
+        })
+        .await
+    }
+
+    type DirEntryStream<'a>
+        = Entries<'a, DirectoryEntry>
+    where
+        Self: 'a;
+
+    async fn readdir<'a>(
+        &'a self,
+        _req: Request,
+        parent: u64,
+        fh: u64,
+        offset: i64,
+    ) -> Result<ReplyDirectory<Self::DirEntryStream<'a>>> {
+        let op = self.stats.op(Op::Readdir).ino(parent);
+        let offset = usize::try_from(offset).map_err(|_| op.error(Errno::from(libc::EINVAL)))?;
+        let entries = self.with(move |fs| {
+            let entries = fs.dirs.get(fh).ok_or(libc::EBADF)?;
+            // offsets are 1-based positions in the snapshot
+            Ok(entries
+                .iter()
+                .enumerate()
+                .skip(offset)
+                .map(|(idx, entry)| {
+                    Ok(DirectoryEntry {
+                        inode: entry.ino,
+                        kind: file_type(entry.kind),
+                        name: entry.name.clone(),
+                        offset: idx as i64 + 1,
+                    })
+                })
+                .collect::<Vec<_>>())
+        });
+        let entries = op.check(entries.await)?;
+
+        Ok(ReplyDirectory {
+            entries: stream::iter(entries).boxed(),
+        })
+    }
+
+    type DirEntryPlusStream<'a>
+        = Entries<'a, DirectoryEntryPlus>
+    where
+        Self: 'a;
+
+    async fn readdirplus<'a>(
+        &'a self,
+        _req: Request,
+        parent: u64,
+        fh: u64,
+        offset: u64,
+        _lock_owner: u64,
+    ) -> Result<ReplyDirectoryPlus<Self::DirEntryPlusStream<'a>>> {
+        let op = self.stats.op(Op::Readdirplus).ino(parent);
+        let offset = usize::try_from(offset).map_err(|_| op.error(Errno::from(libc::EINVAL)))?;
+        if self.with(move |fs| Ok(fs.dirs.get(fh).is_none())).await? {
+            return Err(op.error(libc::EBADF.into()));
+        }
+
+        // fuse3 pulls entries until one does not fit the reply, so an entry
+        // counts as looked up once the next one is pulled or the stream ends
+        let (entry_ttl, attr_ttl) = (self.entry_ttl, self.attr_ttl);
+        let generation = self.generation;
+        let entries = stream::unfold((offset, None), move |(offset, pending)| async move {
+            let next = self
+                .with(move |fs| {
+                    if let Some(ino) = pending {
+                        fs.acquire(ino, 1);
+                    }
+                    let entries = match fs.dirs.get(fh) {
+                        Some(entries) => entries,
+                        None => return Ok(None),
+                    };
+                    for (idx, entry) in entries.iter().enumerate().skip(offset) {
+                        match fs.get_attr(entry.ino) {
+                            Ok(attr) => return Ok(Some((idx, entry.name.clone(), attr))),
+                            Err(_) => error!("unable to get attributes of {:?}", entry.name),
+                        }
+                    }
+                    Ok(None)
+                })
+                .await
+                .ok()
+                .flatten();
+
+            let (idx, name, attr) = next?;
+            let entry = DirectoryEntryPlus {
+                inode: attr.ino,
+                generation,
+                kind: file_type(attr.kind),
+                name,
+                offset: idx as i64 + 1,
+                attr: file_attr(attr),
+                entry_ttl,
+                attr_ttl,
+            };
+            // every entry but `.` and `..` counts as a lookup
+            let pending = (idx >= 2).then_some(entry.inode);
+            Some((Ok(entry), (idx + 1, pending)))
+        });
+
+        Ok(ReplyDirectoryPlus {
+            entries: entries.boxed(),
+        })
+    }
+}
+
+/// Mount `fs` at `path` with the options given as strings to `-o`
+pub async fn mount(fs: AsyncGilberFS, path: &Path, options: &[String]) -> io::Result<MountHandle> {
+    let mut mount_options = MountOptions::default();
+    let mut custom = Vec::new();
+    for option in options {
+        match option.as_str() {
+            "ro" => mount_options.read_only(true),
+            "rw" => mount_options.read_only(false),
+            "allow_other" => mount_options.allow_other(true),
+            "allow_root" => mount_options.allow_root(true),
+            "default_permissions" => mount_options.default_permissions(true),
+            _ if option.starts_with("fsname=") => mount_options.fs_name(&option[7..]),
+            // options the kernel parses itself
+            _ if option.starts_with("max_read=") || option.starts_with("blksize=") => {
+                custom.push(option.as_str());
+                &mut mount_options
+            }
+            _ => {
+                warn!("ignoring {}: not supported with --async", option);
+                &mut mount_options
+            }
+        };
+    }
+    if !custom.is_empty() {
+        mount_options.custom_options(custom.join(","));
+    }
+
+    let session = Session::new(mount_options);
+    if nix::unistd::geteuid().is_root() {
+        session.mount(fs, path).await
+    } else {
+        session.mount_with_unprivileged(fs, path).await
+    }
+}
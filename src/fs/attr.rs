@@ -1,11 +1,7 @@
+use crate::fs::BLOCK_SIZE;
 use crate::inode::Ino;
-use fuse::{FileAttr, FileType};
-use lazy_static::lazy_static;
-use time::Timespec;
-
-lazy_static! {
-    static ref UNIX_EPOCH: Timespec = Timespec::new(0, 0);
-}
+use fuser::{FileAttr, FileType};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub trait ToFileAttr {
     fn to_file_attr(&self, builder: FileAttrBuilder) -> FileAttr;
@@ -16,10 +12,10 @@ pub struct FileAttrBuilder {
     pub ino: u64,
     pub size: u64,
     pub blocks: u64,
-    pub atime: Timespec,
-    pub mtime: Timespec,
-    pub ctime: Timespec,
-    pub crtime: Timespec,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    pub crtime: SystemTime,
     pub kind: FileType,
     pub perm: u16,
     pub nlink: u32,
@@ -27,6 +23,9 @@ pub struct FileAttrBuilder {
     pub gid: u32,
     pub rdev: u32,
     pub flags: u32,
+    // permissions `file()` and `directory()` give
+    pub file_perm: u16,
+    pub dir_perm: u16,
 }
 
 impl FileAttrBuilder {
@@ -49,7 +48,7 @@ impl FileAttrBuilder {
         self
     }
 
-    pub fn time(mut self, time: Timespec) -> Self {
+    pub fn time(mut self, time: SystemTime) -> Self {
         self.atime = time;
         self.mtime = time;
         self.ctime = time;
@@ -59,13 +58,27 @@ impl FileAttrBuilder {
 
     pub fn file(mut self) -> Self {
         self.kind = FileType::RegularFile;
-        self.perm = 0o644;
+        self.perm = self.file_perm;
         self
     }
 
     pub fn directory(mut self) -> Self {
         self.kind = FileType::Directory;
-        self.perm = 0o755;
+        self.perm = self.dir_perm;
+        self
+    }
+
+    pub fn symlink(mut self) -> Self {
+        self.kind = FileType::Symlink;
+        // the permissions of a link are never checked
+        self.perm = 0o777;
+        self
+    }
+
+    /// Permissions of files and directories, less the bits set in `umask`
+    pub fn modes(mut self, file: u16, dir: u16, umask: u16) -> Self {
+        self.file_perm = file & !umask;
+        self.dir_perm = dir & !umask;
         self
     }
 
@@ -99,21 +112,31 @@ impl FileAttrBuilder {
             uid: self.uid,
             gid: self.gid,
             rdev: self.rdev,
+            blksize: BLOCK_SIZE,
             flags: self.flags,
         }
     }
 }
 
+/// Point in time `secs` seconds from the epoch, as git records it
+pub fn unix_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
 impl Default for FileAttrBuilder {
     fn default() -> Self {
         Self {
             ino: 0,
             size: 0,
             blocks: 0,
-            atime: *UNIX_EPOCH,
-            mtime: *UNIX_EPOCH,
-            ctime: *UNIX_EPOCH,
-            crtime: *UNIX_EPOCH,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
             kind: FileType::RegularFile,
             perm: 0o644,
             nlink: 1,
@@ -121,6 +144,8 @@ impl Default for FileAttrBuilder {
             gid: 0,
             rdev: 0,
             flags: 0,
+            file_perm: 0o644,
+            dir_perm: 0o755,
         }
     }
 }
@@ -0,0 +1,234 @@
+//! `--git-meta`: a `.git-meta` directory in the root of every commit, holding
+//! what `git log` tells about the commit for tools that have no git at hand.
+//! Its files are rendered on first use. `parent1`, `parent2`, ... link to the
+//! directories of the parents in the root of the repository, so that history
+//! can be walked with `cd`. `DIFF` has the size it was last rendered at, 0
+//! before, and like `/.giblefs/stats` is read up to its end whatever its size.
+
+use crate::cache::Lru;
+use crate::fs::attr::unix_time;
+use crate::fs::{DirEntry, FileAttrBuilder};
+use crate::git::GitRepo;
+use crate::inode::{Ino, InodeGen};
+use anyhow::{anyhow, Result};
+use fuser::{FileAttr, FileType};
+use git2::Oid;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::Arc;
+
+/// Directory found in the root of every commit
+pub const DIR_NAME: &str = ".git-meta";
+/// File whose size is not worth rendering it to tell
+const DIFF: &str = "DIFF";
+/// Number of contents of files and links kept rendered
+const CONTENTS_CACHE_SIZE: usize = 64;
+/// Files of the directory, in listing order
+const FILES: &[&str] = &["MESSAGE", "AUTHOR", "COMMITTER", "TIMESTAMP", "TREE", DIFF];
+
+/// What an inode of a `.git-meta` directory is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Dir,
+    File(&'static str),
+    // the link to the nth parent, from 1
+    Parent(usize),
+}
+
+/// Inodes of the `.git-meta` directories of commits and of their files
+pub struct CommitMeta {
+    inode_gen: InodeGen,
+    // (commit hash, node) => its inode
+    inodes: RefCell<HashMap<(Oid, Node), Ino>>,
+    // inode => commit hash and node
+    nodes: RefCell<HashMap<u64, (Oid, Node)>>,
+    // inode of a file => its content, the target of a link
+    contents: RefCell<Lru<u64, Arc<[u8]>>>,
+    // commit hash => its parents
+    parents: RefCell<HashMap<Oid, Arc<[Oid]>>>,
+}
+
+impl CommitMeta {
+    pub fn new(inode_gen: InodeGen) -> Self {
+        CommitMeta {
+            inode_gen,
+            inodes: RefCell::new(HashMap::new()),
+            nodes: RefCell::new(HashMap::new()),
+            contents: RefCell::new(Lru::new(CONTENTS_CACHE_SIZE)),
+            parents: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn inode(&self, commit: Oid, node: Node) -> Ino {
+        *self
+            .inodes
+            .borrow_mut()
+            .entry((commit, node))
+            .or_insert_with(|| {
+                let ino = self.inode_gen.next();
+                self.nodes.borrow_mut().insert(ino.value(), (commit, node));
+                ino
+            })
+    }
+
+    /// Inode of the directory of `commit`
+    pub fn dir(&self, commit: Oid) -> Ino {
+        self.inode(commit, Node::Dir)
+    }
+
+    /// Commit of the directory `ino`, if it is one of these directories
+    pub fn dir_commit(&self, ino: u64) -> Option<Oid> {
+        match self.nodes.borrow().get(&ino) {
+            Some((commit, Node::Dir)) => Some(*commit),
+            _ => None,
+        }
+    }
+
+    /// Commit of the directory or file `ino`, if it is one of ours
+    pub fn commit(&self, ino: u64) -> Option<Oid> {
+        self.nodes.borrow().get(&ino).map(|(commit, _)| *commit)
+    }
+
+    /// Whether `ino` is the link to a parent
+    pub fn is_link(&self, ino: u64) -> bool {
+        matches!(self.nodes.borrow().get(&ino), Some((_, Node::Parent(_))))
+    }
+
+    /// Whether `ino` is a `DIFF`, which is read to its end rather than up to
+    /// the size it is given
+    pub fn is_diff(&self, ino: u64) -> bool {
+        matches!(self.nodes.borrow().get(&ino), Some((_, Node::File(DIFF))))
+    }
+
+    /// Forget inode `ino` and its content, once the kernel has
+    pub fn forget(&self, ino: u64) {
+        if let Some(node) = self.nodes.borrow_mut().remove(&ino) {
+            self.inodes.borrow_mut().remove(&node);
+            self.contents.borrow_mut().remove(&ino);
+        }
+    }
+
+    /// Parents of `commit`
+    fn parents(&self, repo: &GitRepo, commit: Oid) -> Result<Arc<[Oid]>> {
+        if let Some(parents) = self.parents.borrow().get(&commit) {
+            return Ok(parents.clone());
+        }
+        let parents: Arc<[Oid]> = repo.get_commit_metadata(commit)?.parents.into();
+        self.parents.borrow_mut().insert(commit, parents.clone());
+        Ok(parents)
+    }
+
+    /// Inode of entry `name` of directory `parent`, which is one of ours
+    pub fn lookup(&self, repo: &GitRepo, parent: u64, name: &OsStr) -> Result<Option<Ino>> {
+        let commit = match self.dir_commit(parent) {
+            Some(commit) => commit,
+            None => return Ok(None),
+        };
+        if let Some(name) = FILES.iter().find(|file| OsStr::new(file) == name) {
+            return Ok(Some(self.inode(commit, Node::File(*name))));
+        }
+        let n = match name.to_str().and_then(|name| name.strip_prefix("parent")) {
+            // `parent01` is not `parent1`
+            Some(n) if !n.starts_with('0') => n.parse::<usize>().ok(),
+            _ => None,
+        };
+        Ok(match n {
+            Some(n) if (1..=self.parents(repo, commit)?.len()).contains(&n) => {
+                Some(self.inode(commit, Node::Parent(n)))
+            }
+            _ => None,
+        })
+    }
+
+    /// Entries of directory `ino`, which was found in `parent`, None if it
+    /// is not one of ours
+    pub fn entries(&self, repo: &GitRepo, ino: u64, parent: u64) -> Option<Result<Vec<DirEntry>>> {
+        let commit = self.dir_commit(ino)?;
+        let parents = match self.parents(repo, commit) {
+            Ok(parents) => parents,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut entries = vec![
+            DirEntry::new(ino, FileType::Directory, "."),
+            DirEntry::new(parent, FileType::Directory, ".."),
+        ];
+        for name in FILES {
+            let file = self.inode(commit, Node::File(*name));
+            entries.push(DirEntry::new(file.value(), FileType::RegularFile, *name));
+        }
+        for n in 1..=parents.len() {
+            let link = self.inode(commit, Node::Parent(n));
+            entries.push(DirEntry::new(
+                link.value(),
+                FileType::Symlink,
+                format!("parent{}", n),
+            ));
+        }
+        Some(Ok(entries))
+    }
+
+    /// Attributes of one of our inodes, dated from its commit
+    pub fn attr(&self, repo: &GitRepo, ino: u64, builder: FileAttrBuilder) -> Result<FileAttr> {
+        let (commit, node) = *self
+            .nodes
+            .borrow()
+            .get(&ino)
+            .ok_or_else(|| anyhow!("no inode {}", ino))?;
+        let builder = builder
+            .ino(ino.into())
+            .time(unix_time(repo.get_commit_time(commit)?));
+        Ok(match node {
+            Node::Dir => builder.directory().nlink(2).build(),
+            Node::File(DIFF) => {
+                let rendered = self.contents.borrow_mut().get(&ino);
+                builder
+                    .file()
+                    .size(rendered.map_or(0, |content| content.len()))
+                    .build()
+            }
+            Node::File(_) => builder.file().size(self.content(repo, ino)?.len()).build(),
+            Node::Parent(_) => builder
+                .symlink()
+                .size(self.content(repo, ino)?.len())
+                .build(),
+        })
+    }
+
+    /// Content of file `ino`, or target of link `ino`
+    pub fn content(&self, repo: &GitRepo, ino: u64) -> Result<Arc<[u8]>> {
+        if let Some(content) = self.contents.borrow_mut().get(&ino) {
+            return Ok(content);
+        }
+        let (commit, node) = *self
+            .nodes
+            .borrow()
+            .get(&ino)
+            .ok_or_else(|| anyhow!("no inode {}", ino))?;
+        let content: Arc<[u8]> = match node {
+            Node::Dir => return Err(anyhow!("inode {} is a directory", ino)),
+            Node::File(name) => render(repo, commit, name)?.into(),
+            // from `<commit>/.git-meta` to `<parent>` next to `<commit>`
+            Node::Parent(n) => format!("../../{}", self.parents(repo, commit)?[n - 1])
+                .into_bytes()
+                .into(),
+        };
+        self.contents.borrow_mut().insert(ino, content.clone());
+        Ok(content)
+    }
+}
+
+/// Content of file `name` for `commit`, a line but for the message, as it was
+/// written, and the diff
+fn render(repo: &GitRepo, commit: Oid, name: &str) -> Result<Vec<u8>> {
+    let line = |text: String| format!("{}\n", text).into_bytes();
+    Ok(match name {
+        "MESSAGE" => repo.get_commit_metadata(commit)?.message,
+        "AUTHOR" => line(repo.get_commit_metadata(commit)?.author),
+        "COMMITTER" => line(repo.get_commit_metadata(commit)?.committer),
+        "TIMESTAMP" => line(repo.get_commit_time(commit)?.to_string()),
+        "TREE" => line(repo.get_commit_tree_id(commit)?.to_string()),
+        DIFF => repo.get_commit_diff(commit)?,
+        _ => unreachable!("no file {} in {}", name, DIR_NAME),
+    })
+}
@@ -0,0 +1,87 @@
+use crate::git::BlobStream;
+use fuser::FileType;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, Mutex};
+
+/// An entry of a directory snapshot
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+impl DirEntry {
+    pub fn new<S: Into<OsString>>(ino: u64, kind: FileType, name: S) -> Self {
+        DirEntry {
+            ino,
+            kind,
+            name: name.into(),
+        }
+    }
+}
+
+/// An open file, either read into memory or streamed
+#[derive(Clone)]
+pub enum FileHandle {
+    Content(Arc<[u8]>),
+    Stream(Arc<Mutex<BlobStream>>),
+}
+
+impl FileHandle {
+    pub fn size(&self) -> u64 {
+        match self {
+            FileHandle::Content(content) => content.len() as u64,
+            FileHandle::Stream(stream) => stream.lock().unwrap().size(),
+        }
+    }
+}
+
+/// Data read from a file, sharing the content of the file when it is in memory
+pub enum FileData {
+    Shared(Arc<[u8]>, Range<usize>),
+    Owned(Vec<u8>),
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileData::Shared(content, range) => &content[range.clone()],
+            FileData::Owned(data) => data,
+        }
+    }
+}
+
+/// Table of open handles, keyed by the `fh` returned to the kernel
+pub struct Handles<T> {
+    next_fh: u64,
+    handles: HashMap<u64, T>,
+}
+
+impl<T> Handles<T> {
+    pub fn new() -> Self {
+        Handles {
+            // 0 is left for "no handle"
+            next_fh: 1,
+            handles: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, handle: T) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, handle);
+        fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&T> {
+        self.handles.get(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<T> {
+        self.handles.remove(&fh)
+    }
+}
@@ -0,0 +1,216 @@
+use crate::fs::attr::unix_time;
+use crate::fs::FileAttrBuilder;
+use crate::git::GitRepo;
+use crate::inode::{Ino, InodeGen};
+use crate::provider::Providers;
+use crate::watch;
+use anyhow::{anyhow, Result};
+use fuser::FileAttr;
+use git2::Oid;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::error;
+
+/// A synthetic file injected into the root of every commit view.
+///
+/// The template may reference `{oid}`, `{short}`, `{describe}` and `{timestamp}`,
+/// which are substituted with the values of the commit being viewed.
+#[derive(Clone, Debug)]
+pub struct InjectedFile {
+    name: OsString,
+    template: String,
+}
+
+impl InjectedFile {
+    pub fn render(&self, repo: &GitRepo, commit: Oid) -> Result<Vec<u8>> {
+        let oid = commit.to_string();
+        let mut content = self
+            .template
+            .replace("\\n", "\n")
+            .replace("{oid}", &oid)
+            .replace("{short}", &oid[..7]);
+
+        // only pay for `describe` when the template asks for it
+        if content.contains("{describe}") {
+            content = content.replace("{describe}", &repo.describe_commit(commit)?);
+        }
+        if content.contains("{timestamp}") {
+            let timestamp = repo.get_commit_time(commit)?.to_string();
+            content = content.replace("{timestamp}", &timestamp);
+        }
+
+        if !content.ends_with('\n') {
+            content.push('\n');
+        }
+
+        Ok(content.into_bytes())
+    }
+}
+
+impl FromStr for InjectedFile {
+    type Err = anyhow::Error;
+
+    /// Parse `NAME=TEMPLATE`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap_or_default();
+        let template = parts
+            .next()
+            .ok_or_else(|| anyhow!("expected NAME=TEMPLATE, got {:?}", s))?;
+
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(anyhow!("invalid injected file name: {:?}", name));
+        }
+
+        Ok(InjectedFile {
+            name: name.into(),
+            template: template.into(),
+        })
+    }
+}
+
+/// Rendered injected files along with their inode numbers
+pub struct InjectedFiles {
+    files: Vec<InjectedFile>,
+    providers: Arc<Providers>,
+    inode_gen: InodeGen,
+    // commit hash => (name, inode) of every file injected into it
+    commits: HashMap<Oid, Vec<(OsString, Ino)>>,
+    content: HashMap<Ino, (Oid, Vec<u8>)>,
+    // commits rendered before refs were last refreshed, `{describe}` may differ
+    stale: HashSet<Oid>,
+    // inodes of files rendered again with another content since, kept for as
+    // long as the kernel holds them
+    retired: HashSet<Ino>,
+    generation: u64,
+}
+
+impl InjectedFiles {
+    pub fn new(files: Vec<InjectedFile>, providers: Arc<Providers>, inode_gen: InodeGen) -> Self {
+        InjectedFiles {
+            files,
+            providers,
+            inode_gen,
+            commits: HashMap::new(),
+            content: HashMap::new(),
+            stale: HashSet::new(),
+            retired: HashSet::new(),
+            generation: watch::refresh_generation(),
+        }
+    }
+
+    /// Look up an injected file of `commit` by name
+    pub fn lookup(&mut self, repo: &GitRepo, commit: Oid, name: &OsStr) -> Option<Ino> {
+        self.render(repo, commit)
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, ino)| *ino)
+    }
+
+    /// List all injected files of `commit`
+    pub fn entries(&mut self, repo: &GitRepo, commit: Oid) -> Vec<(Ino, OsString)> {
+        self.render(repo, commit)
+            .iter()
+            .map(|(name, ino)| (*ino, name.clone()))
+            .collect()
+    }
+
+    /// Commit an injected file was rendered for
+    pub fn commit(&self, ino: Ino) -> Option<Oid> {
+        self.content.get(&ino).map(|(commit, _)| *commit)
+    }
+
+    pub fn content(&self, ino: Ino) -> Option<&[u8]> {
+        self.content.get(&ino).map(|(_, content)| &content[..])
+    }
+
+    /// Drop the files rendered again since that the kernel no longer holds,
+    /// as `held` tells
+    pub fn drop_retired<F: Fn(Ino) -> bool>(&mut self, held: F) {
+        let dropped: Vec<Ino> = self
+            .retired
+            .iter()
+            .copied()
+            .filter(|ino| !held(*ino))
+            .collect();
+        for ino in dropped {
+            self.retired.remove(&ino);
+            self.content.remove(&ino);
+        }
+    }
+
+    pub fn file_attr(
+        &self,
+        repo: &GitRepo,
+        ino: Ino,
+        builder: FileAttrBuilder,
+    ) -> Option<FileAttr> {
+        let (commit, content) = self.content.get(&ino)?;
+        let time = repo.get_commit_time(*commit).unwrap_or(0);
+
+        Some(
+            builder
+                .ino(ino)
+                .file()
+                .size(content.len())
+                .time(unix_time(time))
+                .build(),
+        )
+    }
+
+    /// Render every injected file of `commit` on first access, and again on
+    /// the first access after refs are refreshed
+    fn render(&mut self, repo: &GitRepo, commit: Oid) -> &[(OsString, Ino)] {
+        let generation = watch::refresh_generation();
+        if generation != self.generation {
+            self.generation = generation;
+            self.stale = self.commits.keys().copied().collect();
+        }
+
+        if !self.commits.contains_key(&commit) || self.stale.remove(&commit) {
+            let mut rendered: Vec<(OsString, Vec<u8>)> = Vec::new();
+
+            for file in &self.files {
+                match file.render(repo, commit) {
+                    Ok(content) => rendered.push((file.name.clone(), content)),
+                    Err(e) => error!("unable to render {:?} for {}: {}", file.name, commit, e),
+                }
+            }
+
+            for (name, content) in self.providers.files(commit) {
+                // templates take precedence over providers
+                if !rendered.iter().any(|(existing, _)| *existing == name) {
+                    rendered.push((name, content));
+                }
+            }
+
+            // files rendered again keep their inode only if their content is
+            // the same: the kernel caches it along with the size, an inode
+            // keeps what it was first rendered with
+            let previous = self.commits.remove(&commit).unwrap_or_default();
+            let mut entries = Vec::with_capacity(rendered.len());
+            for (name, content) in rendered {
+                let unchanged = previous
+                    .iter()
+                    .find(|(existing, _)| *existing == name)
+                    .map(|(_, ino)| *ino)
+                    .filter(
+                        |ino| matches!(self.content.get(ino), Some((_, old)) if *old == content),
+                    );
+                let ino = unchanged.unwrap_or_else(|| self.inode_gen.next());
+                self.content.insert(ino, (commit, content));
+                entries.push((name, ino));
+            }
+            for (_, ino) in previous {
+                if !entries.iter().any(|(_, kept)| *kept == ino) {
+                    self.retired.insert(ino);
+                }
+            }
+            self.commits.insert(commit, entries);
+        }
+
+        &self.commits[&commit]
+    }
+}
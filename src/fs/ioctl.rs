@@ -0,0 +1,35 @@
+//! `ioctl` interface, a zero-parse path to the provenance of a file.
+//!
+//! `GIBLEFS_IOC_GET_OID` works on files and directories and fills:
+//!
+//! ```c
+//! struct giblefs_oid {
+//!     uint8_t oid[20];    /* object id of the file or directory */
+//!     uint8_t commit[20]; /* commit it is viewed from */
+//! };
+//!
+//! #define GIBLEFS_IOC_GET_OID _IOR('g', 1, struct giblefs_oid)
+//! ```
+
+use git2::Oid;
+
+const OID_LEN: usize = 20;
+
+/// Size of `struct giblefs_oid`
+pub const OID_INFO_SIZE: usize = 2 * OID_LEN;
+
+/// `_IOR('g', 1, struct giblefs_oid)`
+pub const GET_OID: u32 = ioc_read(b'g', 1, OID_INFO_SIZE);
+
+const fn ioc_read(kind: u8, nr: u8, size: usize) -> u32 {
+    const IOC_READ: u32 = 2;
+    (IOC_READ << 30) | ((size as u32) << 16) | ((kind as u32) << 8) | nr as u32
+}
+
+/// Encode `struct giblefs_oid`
+pub fn oid_info(commit: Oid, oid: Oid) -> [u8; OID_INFO_SIZE] {
+    let mut info = [0; OID_INFO_SIZE];
+    info[..OID_LEN].copy_from_slice(oid.as_bytes());
+    info[OID_LEN..].copy_from_slice(commit.as_bytes());
+    info
+}
@@ -0,0 +1,188 @@
+//! Reading a revision through the filesystem without mounting it, for
+//! `giblefs ls` and `giblefs cat`: the same lookups, listings, attributes and
+//! reads as the kernel would be served.
+
+use crate::fs::{Caches, GilberFS, InjectedFile, Opened, Options};
+use crate::git::GitError;
+use crate::provider::Providers;
+use crate::stats::Stats;
+use anyhow::{anyhow, Result};
+use fuser::FileAttr;
+use git2::Oid;
+use libc::c_int;
+use nix::unistd::{getgid, getuid};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Bytes read at once when printing a file
+const CHUNK_SIZE: u32 = 1 << 20;
+
+/// Entry of a directory as listed by the mount
+pub struct Listed {
+    pub name: OsString,
+    pub attr: FileAttr,
+    /// Git filemode and object of entries found in a tree, none for the
+    /// files the mount adds
+    pub object: Option<(i32, Oid)>,
+}
+
+/// Error of a request for `target`, as the mount would have returned it
+fn failed(target: &str, errno: c_int) -> anyhow::Error {
+    anyhow!("{}: {}", target, io::Error::from_raw_os_error(errno))
+}
+
+impl GilberFS {
+    /// Filesystem of `repo` owned by the calling user, with caches of its own
+    pub fn local(repo: PathBuf, injected: Vec<InjectedFile>, options: Options) -> Result<Self> {
+        let caches = Caches::new(&options, Arc::new(Stats::new()))?;
+        GilberFS::new(
+            repo,
+            getuid().as_raw(),
+            getgid().as_raw(),
+            injected,
+            Providers::new(Vec::new()),
+            caches,
+            options,
+        )
+    }
+
+    /// Look up `<rev>[:<path>]`, the directory named after the commit when
+    /// there is no path
+    pub fn resolve(&mut self, target: &str) -> Result<FileAttr> {
+        let (rev, path) = match target.split_once(':') {
+            Some((rev, path)) => (rev, path),
+            None => (target, ""),
+        };
+        // revisions the root does not know by name, `HEAD~2` and the like
+        let commit = self.repo.resolve_commit(rev)?.to_string();
+        let mut attr = self
+            .lookup_entry(self.root, OsStr::new(&commit))
+            .map_err(|errno| failed(rev, errno))?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            attr = self
+                .lookup_entry(attr.ino, OsStr::new(name))
+                .map_err(|errno| failed(target, errno))?;
+        }
+        Ok(attr)
+    }
+
+    /// Git filemode and object of `ino`, once it was looked up or listed
+    pub fn object(&self, ino: u64) -> Option<(i32, Oid)> {
+        let filemode = self.modes.get(&ino)?;
+        let (_, oid) = self.repo.get_ids_by_inode(ino.into())?;
+        Some((*filemode, oid))
+    }
+
+    /// Entries of directory `ino`, without `.` and `..`
+    pub fn list(&mut self, ino: u64) -> Result<Vec<Listed>> {
+        let entries = self.read_dir(ino).map_err(io::Error::from_raw_os_error)?;
+        entries
+            .into_iter()
+            .skip(2)
+            .map(|entry| {
+                let attr = self
+                    .get_attr(entry.ino)
+                    .map_err(|errno| failed(&entry.name.to_string_lossy(), errno))?;
+                Ok(Listed {
+                    object: self.object(entry.ino),
+                    name: entry.name,
+                    attr,
+                })
+            })
+            .collect()
+    }
+
+    /// Write the content of file `ino` as reading it from the mount returns it
+    pub fn cat(&mut self, ino: u64, out: &mut dyn Write) -> Result<()> {
+        let fh = match self
+            .open_file(ino, libc::O_RDONLY)
+            .map_err(io::Error::from_raw_os_error)?
+        {
+            Opened::Handle(fh) => fh,
+            // small blobs are inflated whole, as the workers do when mounted
+            Opened::Inflate(_) => {
+                let content = self.blob_content(ino).map_err(|e| match e {
+                    GitError::WrongKind => io::Error::from_raw_os_error(libc::EISDIR),
+                    e => io::Error::from_raw_os_error(self.object_errno(ino, &e)),
+                })?;
+                return Ok(out.write_all(&content)?);
+            }
+        };
+
+        let mut offset = 0;
+        let result = loop {
+            let chunk = match self.read_file(ino, fh, offset, CHUNK_SIZE) {
+                Ok(chunk) if chunk.is_empty() => break Ok(()),
+                Ok(chunk) => chunk,
+                Err(errno) => break Err(io::Error::from_raw_os_error(errno).into()),
+            };
+            if let Err(e) = out.write_all(&chunk) {
+                break Err(e.into());
+            }
+            offset += chunk.len() as i64;
+        };
+        self.files.lock().unwrap().remove(fh);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::Checkout;
+    use git2::{ObjectType, Repository, Signature, Time};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Bare repository at a fresh temporary path, whose HEAD commits `files`
+    fn repository(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let path = env::temp_dir().join(format!("giblefs-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let repo = Repository::init_bare(&path).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        for (name, content) in files {
+            let blob = repo.blob(content).unwrap();
+            tree.insert(name, blob, 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+        let author = Signature::new("A U Thor", "author@example.com", &Time::new(0, 0)).unwrap();
+        repo.commit(Some("HEAD"), &author, &author, "initial", &tree, &[])
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn open_converted_blob_with_cold_cache() {
+        let content = b"$Id$\n";
+        let path = repository(
+            "converted",
+            &[(".gitattributes", b"a.txt ident\n"), ("a.txt", content)],
+        );
+        let options = Options {
+            threads: 1,
+            checkout: Checkout {
+                ident: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut fs = GilberFS::local(path.clone(), Vec::new(), options).unwrap();
+
+        let attr = fs.resolve("HEAD:a.txt").unwrap();
+        // converted ids are not in the object database the workers read
+        let fh = match fs.open_file(attr.ino, libc::O_RDONLY) {
+            Ok(Opened::Handle(fh)) => fh,
+            Ok(Opened::Inflate(oid)) => panic!("{} was left to the workers", oid),
+            Err(errno) => panic!("unable to open: {}", errno),
+        };
+        let data = fs.read_file(attr.ino, fh, 0, 4096).unwrap();
+        let blob = Oid::hash_object(ObjectType::Blob, content).unwrap();
+        assert_eq!(&data[..], format!("$Id: {} $\n", blob).as_bytes());
+        assert_eq!(attr.size as usize, data.len());
+
+        let _ = fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::fs::{DirEntry, FileAttrBuilder};
+use crate::inode::{Ino, InodeGen};
+use fuser::{FileAttr, FileType};
+use std::ffi::OsStr;
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Directory of the files about the mount itself, found in the root next to
+/// commits though never listed
+pub const DIR_NAME: &str = ".giblefs";
+const STATS_NAME: &str = "stats";
+
+/// Inodes of `/.giblefs` and the files in it
+pub struct MetaFiles {
+    // the directory it is found in
+    root: u64,
+    dir: Ino,
+    stats: Ino,
+    mounted: SystemTime,
+    started: Instant,
+}
+
+impl MetaFiles {
+    pub fn new(inode_gen: &InodeGen, root: u64) -> Self {
+        MetaFiles {
+            root,
+            dir: inode_gen.next(),
+            stats: inode_gen.next(),
+            mounted: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Inode of `name` in directory `parent`, if it is one of ours
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        if parent == self.root && name == DIR_NAME {
+            Some(self.dir.value())
+        } else if parent == self.dir.value() && name == STATS_NAME {
+            Some(self.stats.value())
+        } else {
+            None
+        }
+    }
+
+    pub fn is_dir(&self, ino: u64) -> bool {
+        ino == self.dir.value()
+    }
+
+    pub fn is_stats(&self, ino: u64) -> bool {
+        ino == self.stats.value()
+    }
+
+    pub fn entries(&self) -> Vec<DirEntry> {
+        vec![
+            DirEntry::new(self.dir.value(), FileType::Directory, "."),
+            DirEntry::new(self.root, FileType::Directory, ".."),
+            DirEntry::new(self.stats.value(), FileType::RegularFile, STATS_NAME),
+        ]
+    }
+
+    /// Attributes of the root of the mount or of a repository, `ino`, as old
+    /// as the mount like ours
+    pub fn root_attr(&self, builder: FileAttrBuilder, ino: u64) -> FileAttr {
+        builder
+            .ino(ino.into())
+            .directory()
+            .nlink(2)
+            .time(self.mounted)
+            .build()
+    }
+
+    pub fn dir_attr(&self, builder: FileAttrBuilder) -> FileAttr {
+        builder
+            .ino(self.dir)
+            .directory()
+            .nlink(2)
+            .time(self.mounted)
+            .build()
+    }
+
+    /// Attributes of the stats file, rendered as `content`
+    pub fn stats_attr(&self, builder: FileAttrBuilder, content: &[u8]) -> FileAttr {
+        builder
+            .ino(self.stats)
+            .file()
+            .size(content.len())
+            .time(SystemTime::now())
+            .build()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Resident set size of this process in bytes, see proc_pid_statm(5)
+pub fn resident_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size as u64)
+}
@@ -0,0 +1,674 @@
+//! Several repositories served by one mount, each under a directory of the
+//! root named after it: `/<repo>/<commit or branch>/...`.
+//!
+//! Every repository has a `GilberFS` of its own, whose inodes are in the
+//! namespace of the repository so that they never collide. Requests go to the
+//! one owning their inode, the root of the mount and its listing are served
+//! here.
+
+use crate::fs::{DirEntry, GilberFS, Handles, BLOCK_SIZE, NAME_MAX};
+use crate::inode::{Ino, MAX_NAMESPACES};
+use crate::stats::{Op, Stats};
+use anyhow::{anyhow, Context};
+use fuser::{
+    FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLseek, ReplyOpen, ReplyStatfs,
+    ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID,
+};
+use libc::{c_int, ENOENT};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{debug, error, info};
+
+/// Repository served under `/<name>`
+#[derive(Clone, Debug)]
+pub struct NamedRepo {
+    pub name: OsString,
+    pub path: PathBuf,
+}
+
+impl NamedRepo {
+    /// Named after its directory, without `.git`
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let resolved = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        let dir = match resolved.file_name() {
+            Some(name) if name == ".git" => resolved.parent().and_then(Path::file_name),
+            name => name,
+        };
+        let name = dir
+            .map(|dir| dir.to_string_lossy())
+            .map(|dir| dir.strip_suffix(".git").unwrap_or(&dir).to_string())
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| anyhow!("{}: give the repository a name", path.display()))?;
+        Ok(NamedRepo {
+            name: name.into(),
+            path,
+        })
+    }
+}
+
+impl FromStr for NamedRepo {
+    type Err = anyhow::Error;
+
+    /// Parse `[NAME=]PATH`
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(NamedRepo {
+                name: name.into(),
+                path: path.into(),
+            }),
+            Some(_) => Err(anyhow!("expected [NAME=]PATH, got {:?}", s)),
+            None => NamedRepo::new(s.into()),
+        }
+    }
+}
+
+/// Read repositories from a file of `[NAME=]PATH` lines, relative paths from
+/// its directory
+pub fn read_list(path: &Path) -> anyhow::Result<Vec<NamedRepo>> {
+    let text = fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut repos = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut repo: NamedRepo = line
+            .parse()
+            .with_context(|| format!("{}:{}", path.display(), idx + 1))?;
+        repo.path = dir.join(repo.path);
+        repos.push(repo);
+    }
+    Ok(repos)
+}
+
+/// Check that every repository has a name of its own, one a directory can have
+pub fn check_names(repos: &[NamedRepo]) -> anyhow::Result<()> {
+    if repos.len() > MAX_NAMESPACES {
+        return Err(anyhow!(
+            "a mount serves {} repositories at most",
+            MAX_NAMESPACES
+        ));
+    }
+    let mut names = HashSet::new();
+    for repo in repos {
+        let name = &repo.name;
+        if name == "." || name == ".." || name.to_string_lossy().contains('/') {
+            return Err(anyhow!("{:?} cannot name a directory", name));
+        }
+        if !names.insert(name) {
+            return Err(anyhow!(
+                "two repositories are named {:?}, name them with NAME=PATH",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Repository to start or stop serving while the mount is served
+pub enum Change {
+    Add(OsString, Box<GilberFS>),
+    Remove(OsString),
+}
+
+pub struct MultiFS {
+    // name in the root => filesystem of the repository, each in a namespace
+    // of its own, the first one never removed
+    repos: Vec<(OsString, GilberFS)>,
+    // removed, answering for the inodes the kernel still holds until it
+    // forgets them all
+    retired: Vec<GilberFS>,
+    // applied as the root is looked at
+    changes: Option<Receiver<Change>>,
+    // namespaces of the retired filesystems once dropped, for reuse
+    freed: Option<Sender<u64>>,
+    // open handles on the root, pinning a snapshot of its entries
+    dirs: Handles<Arc<Vec<DirEntry>>>,
+    stats: Arc<Stats>,
+}
+
+impl MultiFS {
+    /// Serve `repos`, which must be in distinct namespaces
+    pub fn new(repos: Vec<(OsString, GilberFS)>) -> Self {
+        let stats = repos[0].1.stats.clone();
+        MultiFS {
+            repos,
+            retired: Vec::new(),
+            changes: None,
+            freed: None,
+            dirs: Handles::new(),
+            stats,
+        }
+    }
+
+    /// Have repositories added and removed through the sender returned, and
+    /// the namespaces of those removed sent back once no inode of theirs is
+    /// held by the kernel
+    pub fn changes(&mut self) -> (Sender<Change>, Receiver<u64>) {
+        let (sender, receiver) = mpsc::channel();
+        self.changes = Some(receiver);
+        let (freed, namespaces) = mpsc::channel();
+        self.freed = Some(freed);
+        (sender, namespaces)
+    }
+
+    fn apply_changes(&mut self) {
+        let changes = match &self.changes {
+            Some(changes) => changes,
+            None => return,
+        };
+        for change in changes.try_iter() {
+            match change {
+                Change::Add(name, fs) => {
+                    info!("serving {:?}", name);
+                    self.repos.push((name, *fs));
+                }
+                Change::Remove(name) => {
+                    info!("no longer serving {:?}", name);
+                    // the first repository keeps answering for the root
+                    if let Some(idx) = self
+                        .repos
+                        .iter()
+                        .skip(1)
+                        .position(|(repo, _)| *repo == name)
+                    {
+                        let (_, fs) = self.repos.remove(idx + 1);
+                        let namespace = Ino::new(fs.root()).namespace();
+                        self.retired.push(fs);
+                        self.drop_retired(namespace);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drop the retired filesystem of `namespace` if the kernel holds none of
+    /// its inodes anymore, freeing the namespace
+    fn drop_retired(&mut self, namespace: u64) {
+        let idx = match self
+            .retired
+            .iter()
+            .position(|fs| Ino::new(fs.root()).namespace() == namespace)
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+        if !self.retired[idx].refs.is_empty() {
+            return;
+        }
+        self.retired.remove(idx);
+        debug!("namespace {} is free", namespace);
+        if let Some(freed) = &self.freed {
+            let _ = freed.send(namespace);
+        }
+    }
+
+    /// Filesystem of the repository an inode belongs to, served or retired.
+    /// The root, and the inodes of repositories forgotten since, go to the
+    /// first one, which knows the attributes of the root and none of the others.
+    fn fs(&mut self, ino: u64) -> &mut GilberFS {
+        let namespace = Ino::new(ino).namespace();
+        let same = |fs: &GilberFS| Ino::new(fs.root()).namespace() == namespace;
+        if let Some(idx) = self.repos.iter().position(|(_, fs)| same(fs)) {
+            return &mut self.repos[idx].1;
+        }
+        match self.retired.iter().position(same) {
+            Some(idx) => &mut self.retired[idx],
+            None => &mut self.repos[0].1,
+        }
+    }
+
+    /// Directories of the repositories, after `.` and `..`
+    fn entries(&self) -> Vec<DirEntry> {
+        let mut entries = vec![
+            DirEntry::new(FUSE_ROOT_ID, FileType::Directory, "."),
+            DirEntry::new(FUSE_ROOT_ID, FileType::Directory, ".."),
+        ];
+        for (name, fs) in &self.repos {
+            entries.push(DirEntry::new(fs.root(), FileType::Directory, name));
+        }
+        entries
+    }
+
+    fn lookup_repo(&mut self, name: &OsStr) -> Result<&mut GilberFS, c_int> {
+        self.repos
+            .iter_mut()
+            .find(|(repo, _)| repo == name)
+            .map(|(_, fs)| fs)
+            .ok_or(ENOENT)
+    }
+}
+
+impl Filesystem for MultiFS {
+    fn init(&mut self, req: &Request, config: &mut KernelConfig) -> Result<(), c_int> {
+        for (_, fs) in &mut self.repos {
+            fs.init(req, config)?;
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent != FUSE_ROOT_ID {
+            return self.fs(parent).lookup(req, parent, name, reply);
+        }
+        self.apply_changes();
+
+        let op = self.stats.op(Op::Lookup).ino(parent);
+        let _span = op.enter();
+        let fs = match name.to_str() {
+            // the kernel reconnects file handles by looking up `.` and `..`
+            Some(".") | Some("..") => Ok(self.fs(FUSE_ROOT_ID)),
+            _ => self.lookup_repo(name),
+        };
+        let attr = fs.and_then(|fs| {
+            let ino = match name.to_str() {
+                Some(".") | Some("..") => FUSE_ROOT_ID,
+                _ => fs.root(),
+            };
+            let attr = fs.get_attr(ino)?;
+            fs.record_entry(FUSE_ROOT_ID, &attr);
+            Ok((attr, fs.options.entry_ttl, fs.generation))
+        });
+        match op.check(attr) {
+            Ok((attr, ttl, generation)) => reply.entry(&ttl, &attr, generation),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn forget(&mut self, req: &Request, ino: u64, nlookup: u64) {
+        self.fs(ino).forget(req, ino, nlookup);
+        self.drop_retired(Ino::new(ino).namespace());
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        self.fs(ino).getattr(req, ino, fh, reply);
+    }
+
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        self.fs(ino).readlink(req, ino, reply);
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.fs(ino).access(req, ino, mask, reply);
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.fs(ino).getxattr(req, ino, name, size, reply);
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.fs(ino).listxattr(req, ino, size, reply);
+    }
+
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let op = self.stats.op(Op::Statfs).ino(ino);
+        let _span = op.enter();
+        let (blocks, files) = self
+            .repos
+            .iter()
+            .map(|(_, fs)| fs.usage())
+            .fold((0, 0), |(blocks, files), usage| {
+                (blocks + usage.0, files + usage.1)
+            });
+        // read-only: everything is used, nothing is free
+        reply.statfs(blocks, 0, 0, files, 0, BLOCK_SIZE, NAME_MAX, BLOCK_SIZE);
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if ino == FUSE_ROOT_ID {
+            let op = self.stats.op(Op::Open).ino(ino);
+            let _span = op.enter();
+            return reply.error(op.error(libc::EISDIR));
+        }
+        self.fs(ino).open(req, ino, flags, reply);
+    }
+
+    fn release(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: i32,
+        lock_owner: Option<u64>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.fs(ino)
+            .release(req, ino, fh, flags, lock_owner, flush, reply);
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.fs(ino)
+            .read(req, ino, fh, offset, size, flags, lock_owner, reply);
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.fs(ino).lseek(req, ino, fh, offset, whence, reply);
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        self.fs(ino)
+            .ioctl(req, ino, fh, flags, cmd, in_data, out_size, reply);
+    }
+
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.fs(ino_in).copy_file_range(
+            req, ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags, reply,
+        );
+    }
+
+    fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if ino != FUSE_ROOT_ID {
+            return self.fs(ino).opendir(req, ino, flags, reply);
+        }
+        let op = self.stats.op(Op::Opendir).ino(ino);
+        let _span = op.enter();
+        self.apply_changes();
+        let entries = Arc::new(self.entries());
+        reply.opened(self.dirs.insert(entries), 0);
+    }
+
+    fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
+        if ino != FUSE_ROOT_ID {
+            self.fs(ino).releasedir(req, ino, fh, flags, reply);
+            return self.drop_retired(Ino::new(ino).namespace());
+        }
+        let op = self.stats.op(Op::Releasedir).ino(ino);
+        let _span = op.enter();
+        self.dirs.remove(fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != FUSE_ROOT_ID {
+            return self.fs(ino).readdir(req, ino, fh, offset, reply);
+        }
+
+        let op = self.stats.op(Op::Readdir).ino(ino);
+        let _span = op.enter();
+        let offset = match usize::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => {
+                error!("invalid offset: {}", offset);
+                return reply.error(op.error(libc::EINVAL));
+            }
+        };
+        let entries = match self.dirs.get(fh) {
+            Some(entries) => entries,
+            None => return reply.error(op.error(libc::EBADF)),
+        };
+        // offsets are 1-based positions in the snapshot
+        for (idx, entry) in entries.iter().enumerate().skip(offset) {
+            debug!("{} {} {:?}", entry.ino, idx + 1, entry.name);
+            if reply.add(entry.ino, idx as i64 + 1, entry.kind, &entry.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        if ino != FUSE_ROOT_ID {
+            return self.fs(ino).readdirplus(req, ino, fh, offset, reply);
+        }
+
+        let op = self.stats.op(Op::Readdirplus).ino(ino);
+        let _span = op.enter();
+        let offset = match usize::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => {
+                error!("invalid offset: {}", offset);
+                return reply.error(op.error(libc::EINVAL));
+            }
+        };
+        let entries = match self.dirs.get(fh) {
+            Some(entries) => entries.clone(),
+            None => return reply.error(op.error(libc::EBADF)),
+        };
+        for (idx, entry) in entries.iter().enumerate().skip(offset) {
+            let fs = self.fs(entry.ino);
+            let attr = match fs.get_attr(entry.ino) {
+                Ok(attr) => attr,
+                Err(_) => {
+                    error!("unable to get attributes of {:?}", entry.name);
+                    continue;
+                }
+            };
+            debug!("{} {} {:?}", entry.ino, idx + 1, entry.name);
+            let (ttl, generation) = (fs.options.entry_ttl, fs.generation);
+            if reply.add(
+                entry.ino,
+                idx as i64 + 1,
+                &entry.name,
+                &ttl,
+                &attr,
+                generation,
+            ) {
+                break;
+            }
+            // every entry but `.` and `..` counts as a lookup
+            if idx >= 2 {
+                fs.acquire(entry.ino, 1);
+            }
+        }
+        reply.ok();
+    }
+
+    // everything below is refused as the repositories are read-only, by the
+    // filesystem of the repository for its counts
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.fs(ino).setattr(
+            req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
+            flags, reply,
+        );
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.fs(parent)
+            .mknod(req, parent, name, mode, umask, rdev, reply);
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.fs(parent).mkdir(req, parent, name, mode, umask, reply);
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs(parent).unlink(req, parent, name, reply);
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs(parent).rmdir(req, parent, name, reply);
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        self.fs(parent)
+            .symlink(req, parent, link_name, target, reply);
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.fs(parent)
+            .rename(req, parent, name, newparent, newname, flags, reply);
+    }
+
+    fn link(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.fs(ino).link(req, ino, newparent, newname, reply);
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        flags: i32,
+        lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.fs(ino).write(
+            req,
+            ino,
+            fh,
+            offset,
+            data,
+            write_flags,
+            flags,
+            lock_owner,
+            reply,
+        );
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.fs(parent)
+            .create(req, parent, name, mode, umask, flags, reply);
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.fs(ino)
+            .setxattr(req, ino, name, value, flags, position, reply);
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs(ino).removexattr(req, ino, name, reply);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::git::Entry;
+use anyhow::anyhow;
+use git2::ObjectType;
+use std::cmp::Ordering;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use unicode_normalization::UnicodeNormalization;
+
+/// Characters Windows does not allow in file names are mapped into this
+/// private use block, the same way Cygwin and WSL do
+const PRIVATE_USE_BASE: u32 = 0xf000;
+/// Precedes the characters of names that are in the block already, or are this
+/// very character, so that they are not taken for mapped ones
+const PRIVATE_USE_ESCAPE: char = '\u{f0ff}';
+const WINDOWS_INVALID: &[char] = &['"', '*', ':', '<', '>', '?', '\\', '|'];
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn to_private_use(c: char) -> char {
+    std::char::from_u32(PRIVATE_USE_BASE + c as u32).unwrap_or(c)
+}
+
+/// Whether `c` is one a mapped character could be mistaken for
+fn is_private_use(c: char) -> bool {
+    matches!(c as u32, 0xf000..=0xf07f) || c == PRIVATE_USE_ESCAPE
+}
+
+/// Mangle a name so it can be represented on Windows. Names that are not valid
+/// UTF-8 are returned as is. Distinct names stay distinct, which
+/// `unmangle_windows` relies on.
+pub fn mangle_windows(name: &OsStr) -> OsString {
+    let name = match name.to_str() {
+        Some(name) => name,
+        None => return name.into(),
+    };
+
+    let mut chars: Vec<char> = name
+        .chars()
+        .map(|c| {
+            if WINDOWS_INVALID.contains(&c) || (c as u32) < 0x20 {
+                to_private_use(c)
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // trailing dots and spaces are silently dropped by Windows
+    if let Some(last) = chars.last_mut() {
+        if *last == '.' || *last == ' ' {
+            *last = to_private_use(*last);
+        }
+    }
+
+    // device names are reserved regardless of their extension
+    let stem: String = chars.iter().take_while(|c| **c != '.').collect();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(&stem))
+    {
+        let last = stem.chars().count() - 1;
+        chars[last] = to_private_use(chars[last]);
+    }
+
+    // characters of the name itself, unlike those mapped above
+    let mut mangled = String::new();
+    for (c, original) in chars.into_iter().zip(name.chars()) {
+        if is_private_use(original) {
+            mangled.push(PRIVATE_USE_ESCAPE);
+        }
+        mangled.push(c);
+    }
+    mangled.into()
+}
+
+/// Reverse `mangle_windows`, none when it does not give `name` for any name
+pub fn unmangle_windows(name: &OsStr) -> Option<OsString> {
+    let mangled = match name.to_str() {
+        Some(mangled) => mangled,
+        None => return Some(name.into()),
+    };
+
+    let mut unmangled = String::new();
+    let mut chars = mangled.chars();
+    while let Some(c) = chars.next() {
+        if c == PRIVATE_USE_ESCAPE {
+            unmangled.push(chars.next()?);
+        } else if is_private_use(c) {
+            unmangled.push(std::char::from_u32(c as u32 - PRIVATE_USE_BASE)?);
+        } else {
+            unmangled.push(c);
+        }
+    }
+    // names Windows can represent are not mapped, those it cannot are
+    let unmangled = OsString::from(unmangled);
+    (mangle_windows(&unmangled) == name).then(|| unmangled)
+}
+
+/// Unicode normalization form
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Normalization> {
+        match s.to_lowercase().as_str() {
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(anyhow!("unknown normalization form: {}", s)),
+        }
+    }
+}
+
+/// Normalize a name to `form`. Names that are not valid UTF-8 are returned as is.
+pub fn normalize(name: &OsStr, form: Normalization) -> OsString {
+    match name.to_str() {
+        Some(name) => match form {
+            Normalization::Nfc => name.nfc().collect::<String>().into(),
+            Normalization::Nfd => name.nfd().collect::<String>().into(),
+        },
+        None => name.into(),
+    }
+}
+
+/// Order of `entry` relative to `name` in a tree, where trees sort as if their
+/// name ended with a slash
+fn tree_order(entry: &Entry, name: &[u8], is_tree: bool) -> Ordering {
+    let slash = |tree| if tree { &b"/"[..] } else { &b""[..] };
+    let entry_tree = entry.kind == Some(ObjectType::Tree);
+    entry
+        .name
+        .iter()
+        .chain(slash(entry_tree))
+        .cmp(name.iter().chain(slash(is_tree)))
+}
+
+/// Find an entry of `entries`, in tree order, by name, optionally ignoring case
+/// and comparing names in a normalization form. An exact match wins, otherwise
+/// the first entry in tree order does.
+pub fn find_entry<'a>(
+    entries: &'a [Entry],
+    name: &OsStr,
+    case_insensitive: bool,
+    normalization: Option<Normalization>,
+) -> Option<&'a Entry> {
+    let exact = [false, true].iter().find_map(|&is_tree| {
+        entries
+            .binary_search_by(|entry| tree_order(entry, name.as_bytes(), is_tree))
+            .ok()
+    });
+    if let Some(idx) = exact {
+        return Some(&entries[idx]);
+    }
+
+    if !case_insensitive && normalization.is_none() {
+        return None;
+    }
+
+    let key = |name: &OsStr| {
+        let name = match normalization {
+            Some(form) => normalize(name, form),
+            None => name.into(),
+        };
+        if case_insensitive {
+            name.to_string_lossy().to_lowercase().into()
+        } else {
+            name
+        }
+    };
+
+    let name = key(name);
+    entries
+        .iter()
+        .find(|entry| key(OsStr::from_bytes(&entry.name)) == name)
+}
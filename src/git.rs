@@ -1,59 +1,308 @@
-use crate::git::types::{GitBlob, GitCommit, GitTree};
-use crate::inode::{Ino, InodeGen};
+use crate::cache::{HeaderCache, Lru};
+use crate::inode::{Ino, InodeMap};
+use crate::owners::Mailmap;
 use anyhow::{anyhow, Result};
-use bimap::BiMap;
-use git2::{Object, ObjectType, Oid, Repository, RepositoryOpenFlags};
-use log::debug;
-use std::convert::TryFrom;
+use git2::{
+    DescribeOptions, DiffFormat, ErrorClass, ErrorCode, Object, ObjectType, Oid, Repository,
+    RepositoryOpenFlags, Signature,
+};
+use lazy_static::lazy_static;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
 
+mod alternates;
+mod attributes;
+mod error;
+mod filters;
+mod graph;
+mod layout;
+mod lfs;
+mod pack;
+mod pattern;
+mod promisor;
+mod replace;
+mod signature;
+mod sparse;
+mod store;
+mod stream;
+mod submodules;
 mod types;
+mod view;
+
+use alternates::{add_alternates, check_alternates, object_dirs};
+use filters::Filters;
+use graph::CommitGraph;
+use lfs::MAX_POINTER_SIZE;
+use pack::Packs;
+use replace::Replacements;
+use signature::Verification;
+use submodules::{add_submodules, check_submodule_cache, submodule_objects};
+use view::View;
+
+pub use error::GitError;
+pub use filters::Checkout;
+pub use promisor::promisor_remote;
+pub use sparse::{read_sparse_patterns, Rules as SparseRules};
+pub use store::{open_store, Backend, ObjectStore};
+pub use stream::BlobStream;
+pub use types::GitTree;
+pub use view::Globs as PathGlobs;
+
+/// Number of object headers kept in memory
+pub const HEADER_CACHE_SIZE: usize = 1 << 18;
+/// Trees whose entries are kept with larger blobs hidden or emptied
+const LIMITED_CACHE_SIZE: usize = 1 << 14;
+/// Trees whose entries are kept parsed
+const TREE_CACHE_SIZE: usize = 1 << 16;
+/// Commits whose blobs at several paths are kept counted
+const LINK_COUNTS_CACHE_SIZE: usize = 16;
+/// Entries of a commit counted at most to tell link counts, larger commits
+/// have every blob linked once rather than hold up the mount
+const LINK_COUNTED_ENTRIES: usize = 1 << 18;
+/// How long the size of the object directories is reported before they are
+/// walked again
+const ODB_SIZE_TTL: Duration = Duration::from_secs(60);
+/// Commits of the shallow boundary named in errors, the rest are counted
+const SHALLOW_SHOWN: usize = 3;
+// see git_libgit2_opts(3), libgit2-sys leaves it out
+const GIT_OPT_GET_CACHED_MEMORY: libc::c_int = 9;
+
+extern "C" {
+    fn git_libgit2_opts(option: libc::c_int, ...) -> libc::c_int;
+}
+
+lazy_static! {
+    /// The empty tree, libgit2 resolves it even when it is not stored
+    pub static ref EMPTY_TREE: Oid = Oid::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap();
+    /// The empty blob, generated repositories often reference it without storing it
+    pub static ref EMPTY_BLOB: Oid = Oid::from_str("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391").unwrap();
+}
+
+/// Bytes of objects libgit2 caches, and the most it caches. libgit2 is
+/// initialized once a repository is opened, None before.
+pub fn cached_memory() -> Option<(i64, i64)> {
+    let (mut current, mut allowed): (libc::ssize_t, libc::ssize_t) = (0, 0);
+    match unsafe { git_libgit2_opts(GIT_OPT_GET_CACHED_MEMORY, &mut current, &mut allowed) } {
+        0 => Some((current as i64, allowed as i64)),
+        _ => None,
+    }
+}
+
+/// An entry of a tree, detached from the tree it was read from
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: Vec<u8>,
+    pub id: Oid,
+    pub kind: Option<ObjectType>,
+    pub filemode: i32,
+}
+
+/// What is done with blobs larger than `--max-file-size`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Oversized {
+    /// Left out of their trees
+    Hide,
+    /// Shown as empty files, the empty blob
+    Empty,
+}
+
+impl FromStr for Oversized {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Oversized> {
+        match s.to_lowercase().as_str() {
+            "hide" => Ok(Oversized::Hide),
+            "empty" => Ok(Oversized::Empty),
+            _ => Err(anyhow!("unknown handling of oversized files: {}", s)),
+        }
+    }
+}
+
+/// Provenance of a commit, signatures are formatted as `Name <email> <time> <offset>`
+pub struct CommitMetadata {
+    pub author: String,
+    pub committer: String,
+    pub message: Vec<u8>,
+    pub parents: Vec<Oid>,
+}
 
 pub struct GitRepo {
-    path: PathBuf,
     repo: Repository,
+    // trees and blobs are read through it, commits and refs through `repo`
+    store: Box<dyn ObjectStore>,
 
-    inode_gen: InodeGen,
-    // inode <=> (commit hash, object id)
-    inode_map: BiMap<Ino, (Oid, Oid)>,
+    // inode => (commit hash, object id), shared with the metrics listener
+    inode_map: Arc<InodeMap<(Oid, Oid)>>,
+    // commit hash => blobs shown at more than one path => number of paths
+    link_counts: RefCell<Lru<Oid, Arc<HashMap<Oid, u32>>>>,
+    // commit hash => its signature, verified once
+    signatures: RefCell<HashMap<Oid, Verification>>,
+    // commit hash => tree shown as its root
+    roots: RefCell<HashMap<Oid, Oid>>,
+    // with `--subdir`, names of the directories leading to that tree
+    subdir: Vec<Vec<u8>>,
+    // with `--max-file-size`, the size of the largest blob shown as it is
+    max_blob_size: Option<(usize, Oversized)>,
+    // tree, with the commit it is in when converted or filtered for it => its
+    // entries with larger blobs hidden or emptied
+    limited: RefCell<Lru<(Option<Oid>, Oid), Arc<[Entry]>>>,
+    // tree id => its entries, trees never change once written
+    trees: RefCell<Lru<Oid, Arc<[Entry]>>>,
+    // the object directory of the repository, then its alternates
+    objects: Vec<PathBuf>,
+    // total size of `objects` and when it was computed
+    odb_size: Cell<Option<(u64, Instant)>>,
+    packs: RefCell<Packs>,
+    // read again when objects are refreshed, newer commits are read from the
+    // object database
+    graph: RefCell<CommitGraph>,
+    // shared with the workers, which fill it ahead of lookups
+    headers: Arc<HeaderCache>,
+    // applied to commits here, to trees and blobs by `store`
+    replacements: Replacements,
+    // with `--filters` or `--ident`, converts blobs as a checkout does
+    filters: Option<Filters>,
+    // with `--sparse-patterns`, `--include` or `--exclude`, hides the paths
+    // they leave out
+    view: Option<View>,
+    // how `repo` and `store` were opened, whether they recurse into
+    // submodules and resolve LFS pointers
+    options: OpenOptions,
 }
 
 impl GitRepo {
-    pub fn new<P: Into<PathBuf>>(path: P, inode_gen: InodeGen) -> Result<Self> {
+    pub fn new<P: Into<PathBuf>>(path: P, backend: Backend) -> Result<Self> {
+        let headers = Arc::new(HeaderCache::new(HEADER_CACHE_SIZE));
+        GitRepo::with_headers(path, backend, &OpenOptions::default(), headers)
+    }
+
+    /// Open a repository as `options` ask, caching object headers in
+    /// `headers`, which may be shared with other repositories
+    pub fn with_headers<P: Into<PathBuf>>(
+        path: P,
+        backend: Backend,
+        options: &OpenOptions,
+        headers: Arc<HeaderCache>,
+    ) -> Result<Self> {
         let path = path.into();
-        let repo = Repository::open_ext::<_, OsString, _>(
-            &path,
-            RepositoryOpenFlags::NO_SEARCH,
-            Vec::new(),
-        )?;
+        let repo = open_repository(&path, options)?;
+        let store = open_store(&path, backend, options)?;
+        let mut objects = object_dirs(&repo.path().join("objects"), &options.alternates);
+        objects.extend(submodule_objects(&repo, options));
+        let packs = Packs::new(&objects);
+        let graph = CommitGraph::open(&repo.path().join("objects"));
+        let replacements = Replacements::read(&repo, options.no_replace_objects);
         Ok(GitRepo {
-            path,
             repo,
-            inode_gen,
-            inode_map: BiMap::new(),
+            store,
+            inode_map: Arc::new(InodeMap::new(0)),
+            link_counts: RefCell::new(Lru::new(LINK_COUNTS_CACHE_SIZE)),
+            signatures: RefCell::new(HashMap::new()),
+            roots: RefCell::new(HashMap::new()),
+            subdir: Vec::new(),
+            max_blob_size: None,
+            limited: RefCell::new(Lru::new(LIMITED_CACHE_SIZE)),
+            trees: RefCell::new(Lru::new(TREE_CACHE_SIZE)),
+            objects,
+            odb_size: Cell::new(None),
+            packs: RefCell::new(packs),
+            graph: RefCell::new(graph),
+            headers,
+            replacements,
+            filters: None,
+            view: View::new(None, None),
+            options: options.clone(),
         })
     }
 
+    /// Have the inodes of objects assigned in `namespace`, for a mount
+    /// serving several repositories
+    pub fn in_namespace(mut self, namespace: u64) -> Self {
+        self.inode_map = Arc::new(InodeMap::new(namespace));
+        self
+    }
+
+    /// Show the directory at `path` as the root of commits rather than their
+    /// root tree
+    pub fn in_subdir(mut self, path: &Path) -> Self {
+        self.subdir = path
+            .as_os_str()
+            .as_bytes()
+            .split(|&c| c == b'/')
+            .filter(|name| !name.is_empty() && *name != b".")
+            .map(<[u8]>::to_vec)
+            .collect();
+        self
+    }
+
+    /// Hide blobs larger than `max` bytes, or show them empty
+    pub fn with_max_blob_size(mut self, max: usize, oversized: Oversized) -> Self {
+        self.max_blob_size = Some((max, oversized));
+        self
+    }
+
+    /// Hide the paths `sparse` patterns or `globs` leave out
+    pub fn with_view(
+        mut self,
+        sparse: Option<Arc<SparseRules>>,
+        globs: Option<Arc<PathGlobs>>,
+    ) -> Self {
+        self.view = View::new(sparse, globs);
+        self
+    }
+
+    /// Convert blobs as `checkout` asks, if it asks for any conversion
+    pub fn with_checkout(mut self, checkout: Checkout) -> Self {
+        self.filters = checkout
+            .is_enabled()
+            .then(|| Filters::new(&self.repo, checkout));
+        self
+    }
+
     /// Get an object along with an inode number, assign one if it is not assigned already
     pub fn get_object(
-        &mut self,
+        &self,
         commit: Oid,
         hash: Oid,
         kind: Option<ObjectType>,
-    ) -> Result<(Ino, Oid, Object)> {
+    ) -> Result<(Ino, Oid, Object<'_>), GitError> {
         debug!("looking up object: {}", hash);
         let object = self.repo.find_object(hash, kind)?;
+        let ino = self.assign_inode(commit, hash);
+        Ok((ino, commit, object))
+    }
 
-        if let Some(ino) = self.inode_map.get_by_right(&(commit, hash)) {
-            debug!("found object {} in inode cache with inode {:?}", hash, ino);
-            Ok((*ino, commit, object))
-        } else {
-            let ino = self.inode_gen.next();
-            self.inode_map.insert(ino, (commit, hash));
+    /// Get the inode of an object without reading it, assign one if it is not assigned already
+    pub fn get_inode(&self, commit: Oid, hash: Oid) -> Ino {
+        self.assign_inode(commit, hash)
+    }
+
+    fn assign_inode(&self, commit: Oid, hash: Oid) -> Ino {
+        let (ino, assigned) = self
+            .inode_map
+            .assign((commit, hash), derive_inode(commit, hash));
+        if assigned {
             debug!("assigning {} with inode {:?}", hash, ino);
-            Ok((ino, commit, object))
+        } else {
+            debug!("found object {} in inode cache with inode {:?}", hash, ino);
         }
+        ino
+    }
+
+    /// Get the (commit hash, object id) an inode is assigned to
+    pub fn get_ids_by_inode(&self, ino: Ino) -> Option<(Oid, Oid)> {
+        self.inode_map.get(ino)
     }
 
     /// Get an object by directly looking up in inode cache
@@ -61,47 +310,780 @@ impl GitRepo {
         &self,
         ino: Ino,
         kind: Option<ObjectType>,
-    ) -> Result<(Ino, Oid, Object)> {
+    ) -> Result<(Ino, Oid, Object<'_>), GitError> {
         debug!("looking up object for inode: {:?}", ino);
-        if let Some((commit, hash)) = self.inode_map.get_by_left(&ino) {
+        if let Some((commit, hash)) = self.inode_map.get(ino) {
             debug!("found object {} for inode {:?}", hash, ino);
-            let object = self.repo.find_object(*hash, kind)?;
+            let object = self.repo.find_object(hash, kind)?;
 
-            Ok((ino, *commit, object))
+            Ok((ino, commit, object))
         } else {
-            Err(anyhow!("inode not found"))
+            Err(GitError::Missing)
         }
     }
 
-    pub fn get_tree_by_commit(&mut self, hash: Oid) -> Result<GitTree> {
-        let commit = self.repo.find_commit(hash)?;
-        let root_tree = commit.tree_id();
-        drop(commit);
-        self.get_tree(hash, root_tree)
+    pub fn get_tree_by_commit(&self, hash: Oid) -> Result<GitTree<'_>, GitError> {
+        let root = self.get_commit_root(hash)?;
+        self.get_tree(hash, root)
     }
 
-    pub fn get_tree_by_branch(&mut self, name: &str) -> Result<GitTree> {
+    pub fn get_tree_by_branch(&self, name: &str) -> Result<GitTree<'_>, GitError> {
         let branch = self.repo.find_branch(name, git2::BranchType::Local)?;
-        let commit = branch.get().peel_to_commit().unwrap();
+        let hash = branch.get().peel_to_commit()?.id();
         drop(branch);
-        let root_tree = commit.tree_id();
-        drop(commit);
-        self.get_tree(root_tree, root_tree)
+        self.get_tree_by_commit(hash)
+    }
+
+    /// Get the root tree id of a commit
+    pub fn get_commit_tree_id(&self, hash: Oid) -> Result<Oid> {
+        let hash = self.replacements.get(hash);
+        match self.graph.borrow().find(hash) {
+            Some(commit) => Ok(commit.tree),
+            None => Ok(self.repo.find_commit(hash)?.tree_id()),
+        }
+    }
+
+    /// Pick up the packs added and drop those removed since objects were last
+    /// read, as fetches, `git repack` and `git gc` leave them
+    pub fn refresh_objects(&self) {
+        debug!("refreshing the objects of {}", self.repo.path().display());
+        if let Err(e) = self.repo.odb().and_then(|odb| odb.refresh()) {
+            warn!("unable to refresh the object database: {}", e);
+        }
+        self.packs.borrow_mut().drop_removed();
+        *self.graph.borrow_mut() = CommitGraph::open(&self.repo.path().join("objects"));
+    }
+
+    /// Get the id of the tree shown as the root of a commit: its root tree,
+    /// or the directory at `--subdir` in it. Missing when the commit has no
+    /// such directory.
+    pub fn get_commit_root(&self, hash: Oid) -> Result<Oid, GitError> {
+        if let Some(root) = self.roots.borrow().get(&hash) {
+            return Ok(*root);
+        }
+        let replaced = self.replacements.get(hash);
+        let mut root = match self.graph.borrow().find(replaced) {
+            Some(commit) => commit.tree,
+            None => self.repo.find_commit(replaced)?.tree_id(),
+        };
+        // through the checkout, which learns the paths of the trees on the way
+        for name in &self.subdir {
+            root = self
+                .tree_entries_in(hash, root)?
+                .iter()
+                .find(|entry| entry.name == *name && entry.kind == Some(ObjectType::Tree))
+                .map(|entry| entry.id)
+                .ok_or(GitError::Missing)?;
+        }
+        self.roots.borrow_mut().insert(hash, root);
+        Ok(root)
+    }
+
+    /// Get the committer time of a commit, in seconds since epoch
+    pub fn get_commit_time(&self, hash: Oid) -> Result<i64> {
+        let hash = self.replacements.get(hash);
+        match self.graph.borrow().find(hash) {
+            Some(commit) => Ok(commit.time),
+            None => Ok(self.repo.find_commit(hash)?.time().seconds()),
+        }
+    }
+
+    pub fn get_commit_metadata(&self, hash: Oid) -> Result<CommitMetadata> {
+        let commit = self.repo.find_commit(self.replacements.get(hash))?;
+        let metadata = CommitMetadata {
+            author: format_signature(&commit.author()),
+            committer: format_signature(&commit.committer()),
+            message: commit.message_bytes().to_vec(),
+            parents: commit.parent_ids().collect(),
+        };
+        Ok(metadata)
+    }
+
+    /// Note attached to a commit in the notes ref of the repository,
+    /// `refs/notes/commits` unless `core.notesRef` names another one
+    pub fn get_commit_note(&self, hash: Oid) -> Option<Vec<u8>> {
+        match self.repo.find_note(None, hash) {
+            Ok(note) => Some(note.message_bytes().to_vec()),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => {
+                error!("unable to read the note of {}: {}", hash, e);
+                None
+            }
+        }
+    }
+
+    /// Verify the signature of a commit, once
+    pub fn verify_commit(&self, hash: Oid) -> Verification {
+        if let Some(verification) = self.signatures.borrow().get(&hash) {
+            return verification.clone();
+        }
+        let signed = match self.repo.find_commit(hash) {
+            Ok(commit) => commit
+                .raw_header_bytes()
+                .split(|&c| c == b'\n')
+                .any(|line| line.starts_with(b"gpgsig ") || line.starts_with(b"gpgsig-sha256 ")),
+            // git reports it
+            Err(_) => true,
+        };
+        let verification = if signed {
+            signature::verify(self.repo.path(), hash).unwrap_or_else(|e| {
+                error!("unable to verify the signature of {}: {}", hash, e);
+                Verification::failed()
+            })
+        } else {
+            Verification::unsigned()
+        };
+        self.signatures
+            .borrow_mut()
+            .insert(hash, verification.clone());
+        verification
+    }
+
+    /// Unified diff of a commit against its first parent, or against an
+    /// empty tree for a root commit, of what the mount shows of them: the
+    /// directory at `--subdir`, without the paths the view leaves out
+    pub fn get_commit_diff(&self, hash: Oid) -> Result<Vec<u8>> {
+        let shown = |commit: Oid| -> Result<Option<git2::Tree>> {
+            match self.get_commit_root(commit) {
+                Ok(root) => Ok(Some(self.repo.find_tree(root)?)),
+                // a parent from before the directory was added
+                Err(GitError::Missing) if !self.subdir.is_empty() => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        };
+        let commit = self.repo.find_commit(self.replacements.get(hash))?;
+        let parent = match commit.parent_ids().next() {
+            Some(parent) => shown(parent)?,
+            None => None,
+        };
+        let tree = shown(hash)?;
+        let diff = self
+            .repo
+            .diff_tree_to_tree(parent.as_ref(), tree.as_ref(), None)?;
+
+        // the view matches paths from the root of the commit
+        let mut prefix = self.subdir.join(&b'/');
+        if !prefix.is_empty() {
+            prefix.push(b'/');
+        }
+        let shows = |path: Option<&Path>| {
+            let path = path.map_or(&[][..], |path| path.as_os_str().as_bytes());
+            let path = [&prefix[..], path].concat();
+            self.view.as_ref().map_or(true, |view| view.includes(&path))
+        };
+        let mut patch = Vec::new();
+        diff.print(DiffFormat::Patch, |delta, _, line| {
+            if !shows(delta.old_file().path()) && !shows(delta.new_file().path()) {
+                return true;
+            }
+            // the origin of context, added and removed lines is their prefix
+            if let origin @ ('+' | '-' | ' ') = line.origin() {
+                patch.push(origin as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+        Ok(patch)
+    }
+
+    /// Name and email of the author of a commit
+    pub fn get_commit_author(&self, hash: Oid) -> Result<(String, String)> {
+        let commit = self.repo.find_commit(self.replacements.get(hash))?;
+        let author = commit.author();
+        Ok((
+            String::from_utf8_lossy(author.name_bytes()).into_owned(),
+            String::from_utf8_lossy(author.email_bytes()).into_owned(),
+        ))
+    }
+
+    /// The mailmap git applies: `.mailmap` of the work tree, or of HEAD in a
+    /// bare repository, then the one `mailmap.file` points to
+    pub fn mailmap(&self) -> Mailmap {
+        let mut text = String::new();
+        let committed = match self.repo.workdir() {
+            Some(workdir) => fs::read(workdir.join(".mailmap")).ok(),
+            None => self
+                .repo
+                .revparse_single("HEAD:.mailmap")
+                .ok()
+                .and_then(|object| object.peel_to_blob().ok())
+                .map(|blob| blob.content().to_vec()),
+        };
+        let configured = self
+            .repo
+            .config()
+            .and_then(|config| config.get_path("mailmap.file"))
+            .ok()
+            .and_then(|path| fs::read(path).ok());
+        for content in committed.iter().chain(configured.iter()) {
+            text.push_str(&String::from_utf8_lossy(content));
+            text.push('\n');
+        }
+        Mailmap::parse(&text)
+    }
+
+    /// Describe a commit the same way `git describe --tags --always` does
+    pub fn describe_commit(&self, hash: Oid) -> Result<String> {
+        let commit = self.repo.find_commit(hash)?;
+        let mut options = DescribeOptions::new();
+        options.describe_tags().show_commit_oid_as_fallback(true);
+        let describe = match commit.as_object().describe(&options) {
+            // libgit2 walks past the shallow boundary, settle for a tag of the
+            // commit itself or its id
+            Err(e) if e.class() == ErrorClass::Odb && !self.shallow_boundary().is_empty() => {
+                match commit.as_object().describe(options.max_candidates_tags(0)) {
+                    Ok(describe) => describe,
+                    Err(_) => {
+                        return Ok(commit
+                            .as_object()
+                            .short_id()?
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string())
+                    }
+                }
+            }
+            describe => describe?,
+        };
+        Ok(describe.format(None)?)
+    }
+
+    /// Forget the inode of an object, a later lookup assigns it a new one
+    pub fn forget_inode(&self, ino: Ino) {
+        if let Some((_, hash)) = self.inode_map.remove(ino) {
+            debug!("forgetting inode {:?} of {}", ino, hash);
+        }
+    }
+
+    /// Number of paths a blob is shown at in a commit, identical blobs share
+    /// their inode so this is its link count
+    pub fn link_count(&self, commit: Oid, hash: Oid) -> u32 {
+        let cached = self.link_counts.borrow_mut().get(&commit);
+        let counts = match cached {
+            Some(counts) => counts,
+            None => {
+                let counts = Arc::new(self.count_blobs(commit).unwrap_or_else(|e| {
+                    error!("unable to count blobs of {}: {}", commit, e);
+                    HashMap::new()
+                }));
+                self.link_counts.borrow_mut().insert(commit, counts.clone());
+                counts
+            }
+        };
+        counts.get(&hash).copied().unwrap_or(1)
+    }
+
+    /// Blobs shown at more than one path in `commit` and at how many, counted
+    /// over the entries listings show: below `--subdir`, through the views and
+    /// `--max-file-size`, converted blobs by their own ids. None past
+    /// `LINK_COUNTED_ENTRIES` entries.
+    fn count_blobs(&self, commit: Oid) -> Result<HashMap<Oid, u32>, GitError> {
+        let mut counts = HashMap::new();
+        let mut trees = vec![self.get_commit_root(commit)?];
+        let mut counted = 0;
+        while let Some(tree) = trees.pop() {
+            let entries = self.tree_entries_in(commit, tree)?;
+            counted += entries.len();
+            if counted > LINK_COUNTED_ENTRIES {
+                debug!("{} has too many entries to count links", commit);
+                return Ok(HashMap::new());
+            }
+            for entry in entries.iter() {
+                match entry.kind {
+                    Some(ObjectType::Tree) => trees.push(entry.id),
+                    Some(ObjectType::Blob) => *counts.entry(entry.id).or_insert(0) += 1,
+                    _ => {}
+                }
+            }
+        }
+        counts.retain(|_, count| *count > 1);
+        Ok(counts)
+    }
+
+    /// Resolve a revision (`HEAD~2`, a branch, a tag...) to the id of its commit
+    pub fn resolve_commit(&self, rev: &str) -> Result<Oid> {
+        let commit = self
+            .repo
+            .revparse_single(rev)
+            .and_then(|object| object.peel_to_commit());
+        commit
+            .map(|commit| commit.id())
+            .map_err(|e| self.resolve_error(rev, e))
+    }
+
+    /// Resolve a revision (`HEAD~2`, a branch, a tag...) to the id of its root tree
+    pub fn resolve_tree(&self, rev: &str) -> Result<Oid> {
+        let object = self
+            .repo
+            .revparse_single(rev)
+            .map_err(|e| self.resolve_error(rev, e))?;
+        match object.peel_to_commit() {
+            // the tree of the commit replacing it, if any
+            Ok(commit) => self.get_commit_tree_id(commit.id()),
+            Err(_) => Ok(object.peel_to_tree()?.id()),
+        }
+    }
+
+    /// Commits of a shallow clone whose parents were not fetched, none when
+    /// the repository has its whole history
+    pub fn shallow_boundary(&self) -> Vec<Oid> {
+        // deepening fetches rewrite it, read it every time
+        match fs::read_to_string(self.repo.path().join("shallow")) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| Oid::from_str(line.trim()).ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Tell revisions going up the history past the shallow boundary, which
+    /// libgit2 reports as a missing object, from those that do not exist
+    fn resolve_error(&self, rev: &str, error: git2::Error) -> anyhow::Error {
+        let ancestry = rev
+            .split(':')
+            .next()
+            .unwrap_or(rev)
+            .contains(&['~', '^'][..]);
+        if !ancestry || error.code() != ErrorCode::NotFound || error.class() != ErrorClass::Odb {
+            return error.into();
+        }
+        let boundary = self.shallow_boundary();
+        if boundary.is_empty() {
+            return error.into();
+        }
+
+        let mut shown: Vec<_> = boundary
+            .iter()
+            .take(SHALLOW_SHOWN)
+            .map(|commit| commit.to_string()[..7].to_string())
+            .collect();
+        if boundary.len() > SHALLOW_SHOWN {
+            shown.push(format!("{} more", boundary.len() - SHALLOW_SHOWN));
+        }
+        anyhow!(
+            "{}: beyond the history of this shallow clone, which stops at {}",
+            rev,
+            shown.join(", ")
+        )
+    }
+
+    /// Find the entry at `<rev>[:<path>]`, the root tree of the commit when
+    /// there is no path, as it stands for the directory named after it
+    pub fn resolve_entry(&self, target: &str) -> Result<Entry> {
+        let (rev, path) = match target.split_once(':') {
+            Some((rev, path)) => (rev, path),
+            None => (target, ""),
+        };
+        let commit = self.resolve_commit(rev)?;
+
+        let mut entry = Entry {
+            name: rev.as_bytes().to_vec(),
+            id: self.get_commit_tree_id(commit)?,
+            kind: Some(ObjectType::Tree),
+            filemode: 0o040000,
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if entry.kind != Some(ObjectType::Tree) {
+                return Err(anyhow!("{}: not a directory", path));
+            }
+            entry = self
+                .tree_entries(entry.id)?
+                .iter()
+                .find(|child| child.name == name.as_bytes())
+                .cloned()
+                .ok_or_else(|| anyhow!("{}: no such file or directory", path))?;
+        }
+        Ok(entry)
+    }
+
+    /// List the entries of a tree, in tree order. Trees are parsed once as long
+    /// as they are among the last `TREE_CACHE_SIZE` listed.
+    pub fn tree_entries(&self, hash: Oid) -> Result<Arc<[Entry]>, GitError> {
+        if let Some(entries) = self.trees.borrow_mut().get(&hash) {
+            return Ok(entries);
+        }
+
+        let mut entries = self.store.tree(hash)?;
+        if self.options.recurse_submodules {
+            for entry in entries.iter_mut() {
+                self.splice_submodule(entry);
+            }
+        }
+        let entries: Arc<[Entry]> = entries.into();
+        self.trees.borrow_mut().insert(hash, entries.clone());
+        Ok(entries)
+    }
+
+    /// List the entries of a tree as a checkout of `commit` has them: with
+    /// `--filters` or `--ident`, blobs it converts have ids of their own, with
+    /// `--sparse-patterns`, `--include` or `--exclude`, paths they leave out
+    /// are missing, and with `--max-file-size`, larger blobs are missing or empty
+    pub fn tree_entries_in(&self, commit: Oid, hash: Oid) -> Result<Arc<[Entry]>, GitError> {
+        let entries = match &self.filters {
+            Some(filters) => filters.entries(self, commit, hash)?,
+            None => self.tree_entries(hash)?,
+        };
+        let entries = match &self.view {
+            Some(view) => view.entries(self, commit, hash, entries)?,
+            None => entries,
+        };
+        match self.max_blob_size {
+            Some((max, oversized)) => Ok(self.limit_blobs(commit, hash, entries, max, oversized)),
+            None => Ok(entries),
+        }
+    }
+
+    /// `entries` of `tree` in `commit` with the blobs larger than `max` bytes
+    /// hidden or replaced by the empty blob. Their sizes are read from the
+    /// headers of the blobs as stored, before any conversion, so that neither
+    /// the conversion nor an LFS download runs to tell them; blobs whose
+    /// header is unreadable are left for reads to fail.
+    fn limit_blobs(
+        &self,
+        commit: Oid,
+        tree: Oid,
+        entries: Arc<[Entry]>,
+        max: usize,
+        oversized: Oversized,
+    ) -> Arc<[Entry]> {
+        // the entries only depend on the commit once converted or filtered
+        let key = (
+            Some(commit).filter(|_| self.filters.is_some() || self.view.is_some()),
+            tree,
+        );
+        let cached = self.limited.borrow_mut().get(&key);
+        if let Some(entries) = cached {
+            return entries;
+        }
+
+        let mut limited = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let mut entry = entry.clone();
+            if entry.kind == Some(ObjectType::Blob) && entry.id != *EMPTY_BLOB {
+                let stored = match &self.filters {
+                    Some(filters) => filters.source(entry.id).unwrap_or(entry.id),
+                    None => entry.id,
+                };
+                match self.object_header(stored) {
+                    Ok((size, _)) if size > max => {
+                        debug!(
+                            "{} is {} bytes, over --max-file-size",
+                            String::from_utf8_lossy(&entry.name),
+                            size
+                        );
+                        match oversized {
+                            Oversized::Hide => continue,
+                            Oversized::Empty => entry.id = *EMPTY_BLOB,
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!("unable to read the size of {}: {}", entry.id, e),
+                }
+            }
+            limited.push(entry);
+        }
+        let limited: Arc<[Entry]> = limited.into();
+        self.limited.borrow_mut().insert(key, limited.clone());
+        limited
+    }
+
+    /// Whether `hash` is the id of a blob converted for the checkout rather than
+    /// of an object of the repository
+    pub fn is_converted(&self, hash: Oid) -> bool {
+        self.filters
+            .as_ref()
+            .is_some_and(|filters| filters.is_converted(hash))
+    }
+
+    /// Make a gitlink the root tree of its commit, when the repository of the
+    /// submodule is found
+    fn splice_submodule(&self, entry: &mut Entry) {
+        if entry.kind != Some(ObjectType::Commit) {
+            return;
+        }
+        match self.get_commit_tree_id(entry.id) {
+            Ok(tree) => {
+                entry.id = tree;
+                entry.kind = Some(ObjectType::Tree);
+                entry.filemode = 0o040000;
+            }
+            Err(e) => debug!(
+                "not showing submodule {}: {}",
+                String::from_utf8_lossy(&entry.name),
+                e
+            ),
+        }
+    }
+
+    /// Stream the content of a blob, if it is stored as a loose object or
+    /// whole in a pack. Deltified blobs have to be read through libgit2, and
+    /// so do LFS pointers, to be served as their objects.
+    pub fn blob_stream(&self, hash: Oid) -> Option<BlobStream> {
+        // converted blobs are not stored
+        if self.is_converted(hash) {
+            return None;
+        }
+        let stream = self.stored_blob_stream(hash)?;
+        if self.options.lfs && stream.size() < MAX_POINTER_SIZE as u64 {
+            return None;
+        }
+        Some(stream)
+    }
+
+    fn stored_blob_stream(&self, hash: Oid) -> Option<BlobStream> {
+        let hash = self.replacements.get(hash);
+        let hex = hash.to_string();
+        for objects in &self.objects {
+            match BlobStream::loose(objects.join(&hex[..2]).join(&hex[2..])) {
+                Ok(stream) => return stream,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    debug!("unable to stream {}: {}", hash, e);
+                    return None;
+                }
+            }
+        }
+
+        let (data, size) = self.packs.borrow_mut().find_blob(hash)?;
+        debug!("streaming {} from a pack", hash);
+        Some(BlobStream::packed(data, size))
+    }
+
+    /// Size of an object, read from its header without inflating it
+    pub fn object_size(&self, hash: Oid) -> Result<usize> {
+        Ok(self.object_header(hash)?.0)
+    }
+
+    /// Size and kind of an object, read from its header without inflating it
+    pub fn object_header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        if hash == *EMPTY_BLOB {
+            return Ok((0, ObjectType::Blob));
+        }
+        if hash == *EMPTY_TREE {
+            return Ok((0, ObjectType::Tree));
+        }
+        if let Some(header) = self.headers.get(hash) {
+            return Ok(header);
+        }
+        // the size of a converted blob is only known once it is converted
+        let header = match self.filters.as_ref().and_then(|f| f.read(self, hash)) {
+            Some(content) => (content?.len(), ObjectType::Blob),
+            None => self.store.header(hash)?,
+        };
+        self.headers.insert(hash, header);
+        Ok(header)
+    }
+
+    pub fn headers(&self) -> Arc<HeaderCache> {
+        self.headers.clone()
+    }
+
+    pub fn inode_map(&self) -> Arc<InodeMap<(Oid, Oid)>> {
+        self.inode_map.clone()
+    }
+
+    /// Number of objects that have been assigned an inode so far
+    pub fn inode_count(&self) -> usize {
+        self.inode_map.len()
+    }
+
+    /// Total on-disk size of the object database, loose objects and packs
+    /// alike, alternates included. Walked once per `ODB_SIZE_TTL` at most.
+    pub fn odb_size(&self) -> Result<u64> {
+        fn dir_size(path: &Path) -> Result<u64> {
+            let mut size = 0;
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    // removed by a `git gc` meanwhile
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if meta.is_dir() {
+                    size += dir_size(&entry.path())?;
+                } else {
+                    size += meta.len();
+                }
+            }
+            Ok(size)
+        }
+
+        if let Some((size, computed)) = self.odb_size.get() {
+            if computed.elapsed() < ODB_SIZE_TTL {
+                return Ok(size);
+            }
+        }
+        let mut size = 0;
+        // alternates may be gone, as git ignores them then
+        for objects in self.objects.iter().filter(|objects| objects.is_dir()) {
+            size += dir_size(objects)?;
+        }
+        self.odb_size.set(Some((size, Instant::now())));
+        Ok(size)
+    }
+
+    pub fn get_tree(&self, commit: Oid, hash: Oid) -> Result<GitTree<'_>, GitError> {
+        GitTree::try_from(self.get_object(commit, hash, None)?)
+    }
+
+    pub fn get_tree_by_inode(&self, ino: Ino) -> Result<GitTree<'_>, GitError> {
+        GitTree::try_from(self.get_object_by_inode(ino, None)?)
+    }
+
+    /// Inflate a blob whole, converted when `hash` is that of a converted blob
+    pub fn read_blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        match self.filters.as_ref().and_then(|f| f.read(self, hash)) {
+            Some(content) => content,
+            None => self.store.blob(hash),
+        }
     }
+}
+
+/// Directory to read the objects and refs of the repository at `path` from,
+/// found the way git finds it: with `search`, in the parents of `path` too,
+/// up to GIT_CEILING_DIRECTORIES. A linked worktree gives the directory of its
+/// main repository, where they are.
+pub fn locate(path: &Path, search: bool) -> Result<PathBuf> {
+    let flags = if search {
+        RepositoryOpenFlags::empty()
+    } else {
+        RepositoryOpenFlags::NO_SEARCH
+    };
+    let ceilings: Vec<PathBuf> = match env::var_os("GIT_CEILING_DIRECTORIES") {
+        Some(dirs) => env::split_paths(&dirs).collect(),
+        None => Vec::new(),
+    };
+    let repo = Repository::open_ext(path, flags, ceilings)
+        .map_err(|e| layout::diagnose(path, search, e))?;
+    // see gitrepository-layout(5)
+    match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(common) => Ok(repo.path().join(common.trim_end())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(repo.path().to_path_buf()),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    pub fn get_tree(&mut self, commit: Oid, hash: Oid) -> Result<GitTree> {
-        GitTree::try_from(self.get_object(commit, hash, Some(ObjectType::Tree))?)
+/// `locate`, GIT_COMMON_DIR or else GIT_DIR naming the repository instead of
+/// `path` when set, as they do for git
+pub fn locate_env(path: &Path, search: bool) -> Result<PathBuf> {
+    for var in &["GIT_COMMON_DIR", "GIT_DIR"] {
+        match env::var_os(var) {
+            Some(dir) if !dir.is_empty() => return locate(Path::new(&dir), false),
+            _ => {}
+        }
     }
+    locate(path, search)
+}
 
-    pub fn get_tree_by_inode(&self, ino: Ino) -> Result<GitTree> {
-        GitTree::try_from(self.get_object_by_inode(ino, Some(ObjectType::Tree))?)
+/// How repositories are opened, the same for every store opened on one
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    /// Object directories read from too, given with `--alternate`
+    pub alternates: Vec<PathBuf>,
+    /// Read objects as they are rather than what replace refs replace them with
+    pub no_replace_objects: bool,
+    /// Read the objects of submodules too, and show gitlinks as their trees
+    pub recurse_submodules: bool,
+    /// Directory of clones of submodules, looked in when recursing
+    pub submodule_cache: Option<PathBuf>,
+    /// Serve LFS pointers as the objects they point to
+    pub lfs: bool,
+}
+
+impl OpenOptions {
+    /// Check the directories given exist as what they are given as
+    pub fn check(&self) -> Result<()> {
+        check_alternates(&self.alternates)?;
+        check_submodule_cache(self.submodule_cache.as_deref())
     }
+}
 
-    pub fn get_blob(&mut self, commit: Oid, hash: Oid) -> Result<GitBlob> {
-        GitBlob::try_from(self.get_object(commit, hash, Some(ObjectType::Blob))?)
+/// Open the repository at `path` exactly, without searching parent directories
+pub fn open_repository(path: &Path, options: &OpenOptions) -> Result<Repository> {
+    check_object_format(path)?;
+    let repo =
+        Repository::open_ext::<_, OsString, _>(path, RepositoryOpenFlags::NO_SEARCH, Vec::new())?;
+    add_alternates(&repo, &options.alternates)?;
+    add_submodules(&repo, options)?;
+    Ok(repo)
+}
+
+/// Refuse repositories whose object ids are not SHA-1 before libgit2 reads
+/// them, which has 20-byte ids throughout
+fn check_object_format(path: &Path) -> Result<()> {
+    let config = [path.join("config"), path.join(".git").join("config")]
+        .iter()
+        .find(|config| config.is_file())
+        .and_then(|config| git2::Config::open(config).ok());
+    let format = config.and_then(|config| config.get_string("extensions.objectformat").ok());
+    match format {
+        Some(format) if !format.eq_ignore_ascii_case("sha1") => Err(anyhow!(
+            "{}: object format {} is not supported, only sha1 is",
+            path.display(),
+            format
+        )),
+        _ => Ok(()),
     }
+}
+
+/// Read the headers of objects into `headers`, on any store of the repository
+pub fn prefetch_headers(store: &dyn ObjectStore, headers: &HeaderCache, hashes: &[Oid]) {
+    store.prefetch(hashes);
+    for &hash in hashes {
+        // lookups report objects that cannot be read
+        if let Ok(header) = store.header(hash) {
+            headers.insert(hash, header);
+        }
+    }
+}
+
+/// Inode of an object found in a commit, derived from both ids so that it is
+/// the same from one mount to the next
+fn derive_inode(commit: Oid, hash: Oid) -> Ino {
+    let prefix = |oid: Oid| u64::from_be_bytes(oid.as_bytes()[..8].try_into().unwrap());
+    Ino::derived(prefix(commit) ^ prefix(hash))
+}
+
+fn format_signature(signature: &Signature) -> String {
+    let time = signature.when();
+    let offset = time.offset_minutes();
+    format!(
+        "{} <{}> {} {}{:02}{:02}",
+        String::from_utf8_lossy(signature.name_bytes()),
+        String::from_utf8_lossy(signature.email_bytes()),
+        time.seconds(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn refuse_sha256_repository() {
+        let path = env::temp_dir().join(format!("giblefs-sha256-{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let repo = Repository::init_bare(&path).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_i32("core.repositoryformatversion", 1).unwrap();
+        config.set_str("extensions.objectformat", "sha256").unwrap();
+        drop(repo);
+
+        let error = open_repository(&path, &OpenOptions::default()).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("object format sha256 is not supported"),
+            "{}",
+            error
+        );
 
-    pub fn get_blob_by_inode(&self, ino: Ino) -> Result<GitBlob> {
-        GitBlob::try_from(self.get_object_by_inode(ino, Some(ObjectType::Blob))?)
+        let _ = fs::remove_dir_all(path);
     }
 }
@@ -0,0 +1,70 @@
+//! Alternate object directories: those `objects/info/alternates` lists, which
+//! forges share objects between forks through, and those given with
+//! `--alternate`. libgit2 reads the former itself, the packs and loose objects
+//! read directly are looked for in both.
+
+use anyhow::{anyhow, Result};
+use git2::Repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Alternates of alternates followed, as many as git follows
+const MAX_DEPTH: usize = 5;
+
+/// Check that `dirs`, given with `--alternate`, are object directories
+pub fn check_alternates(dirs: &[PathBuf]) -> Result<()> {
+    for dir in dirs {
+        if !dir.join("pack").is_dir() {
+            return Err(anyhow!(
+                "{} is not an object directory, it has no pack directory",
+                dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Add the `alternates` given to the object database of `repo`
+pub fn add_alternates(repo: &Repository, alternates: &[PathBuf]) -> Result<()> {
+    if alternates.is_empty() {
+        return Ok(());
+    }
+    let odb = repo.odb()?;
+    for dir in alternates.iter() {
+        odb.add_disk_alternate(&dir.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Object directories of a repository whose own is `objects`: it, then those
+/// its alternates list, then those `given`
+pub fn object_dirs(objects: &Path, given: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = vec![objects.to_path_buf()];
+    let mut pending = vec![(objects.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        if depth == MAX_DEPTH {
+            continue;
+        }
+        let list = match fs::read_to_string(dir.join("info").join("alternates")) {
+            Ok(list) => list,
+            Err(_) => continue,
+        };
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // relative to the object directory listing it
+            let alternate = dir.join(line);
+            if !dirs.contains(&alternate) {
+                dirs.push(alternate.clone());
+                pending.push((alternate, depth + 1));
+            }
+        }
+    }
+    for dir in given {
+        if !dirs.contains(dir) {
+            dirs.push(dir.clone());
+        }
+    }
+    dirs
+}
@@ -0,0 +1,115 @@
+//! `.gitattributes`: the attributes of paths, read from the files of the
+//! commit they are in along with `info/attributes` of the repository and
+//! `core.attributesFile`, see gitattributes(5).
+
+use super::pattern::Pattern;
+use std::sync::Arc;
+
+/// State of an attribute given to a path, unspecified ones have none
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Set,
+    Unset,
+    Value(String),
+}
+
+/// A line of an attributes file: attributes given to the paths it matches,
+/// None for those made unspecified again with `!`
+struct Rule {
+    pattern: Pattern,
+    attributes: Vec<(String, Option<State>)>,
+}
+
+/// The rules of an attributes file
+#[derive(Default)]
+pub struct AttributeFile {
+    rules: Vec<Rule>,
+}
+
+impl AttributeFile {
+    pub fn parse(text: &str) -> Self {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                // macros are not defined, negated patterns are not allowed
+                Some(word) if word.starts_with("[attr]") || word.starts_with('!') => continue,
+                Some(word) => word,
+                None => continue,
+            };
+            let pattern = match Pattern::parse(pattern) {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            let mut attributes = Vec::new();
+            for word in words {
+                match word {
+                    // the one macro git defines
+                    "binary" => {
+                        for name in &["diff", "merge", "text"] {
+                            attributes.push((name.to_string(), Some(State::Unset)));
+                        }
+                    }
+                    _ => attributes.push(match word.split_once('=') {
+                        Some((name, value)) => (name.into(), Some(State::Value(value.into()))),
+                        None => match word.as_bytes()[0] {
+                            b'-' => (word[1..].into(), Some(State::Unset)),
+                            b'!' => (word[1..].into(), None),
+                            _ => (word.into(), Some(State::Set)),
+                        },
+                    }),
+                }
+            }
+            rules.push(Rule {
+                pattern,
+                attributes,
+            });
+        }
+        AttributeFile { rules }
+    }
+}
+
+/// The attributes files applying to the paths of a directory, from the one
+/// of lowest precedence, each with the directory it is in
+#[derive(Clone, Default)]
+pub struct Attributes {
+    files: Vec<(Vec<u8>, Arc<AttributeFile>)>,
+}
+
+impl Attributes {
+    /// These files and then `file`, which is in directory `dir`
+    pub fn with(&self, dir: &[u8], file: Arc<AttributeFile>) -> Attributes {
+        let mut files = self.files.clone();
+        files.push((dir.to_vec(), file));
+        Attributes { files }
+    }
+
+    /// State of attribute `name` for the file, or directory, at `path`, rules
+    /// of files of higher precedence and later rules winning
+    pub fn get(&self, path: &[u8], is_dir: bool, name: &str) -> Option<State> {
+        let mut state = None;
+        for (dir, file) in &self.files {
+            let relative = match dir.as_slice() {
+                [] => path,
+                dir => match path
+                    .strip_prefix(dir)
+                    .and_then(|rest| rest.strip_prefix(b"/"))
+                {
+                    Some(relative) => relative,
+                    None => continue,
+                },
+            };
+            for rule in &file.rules {
+                if !rule.pattern.matches(relative, is_dir) {
+                    continue;
+                }
+                for (attribute, value) in &rule.attributes {
+                    if attribute == name {
+                        state = value.clone();
+                    }
+                }
+            }
+        }
+        state
+    }
+}
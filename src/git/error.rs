@@ -0,0 +1,47 @@
+use git2::{ErrorClass, ErrorCode};
+use std::fmt;
+
+/// Failure to get an object out of the repository
+#[derive(Debug)]
+pub enum GitError {
+    /// The object, or the inode it was looked up by, does not exist
+    Missing,
+    /// The object is not of the expected kind
+    WrongKind,
+    /// The object exists but could not be parsed
+    Corrupt(git2::Error),
+    /// The object could not be inflated
+    Zlib(git2::Error),
+    /// The repository is locked by another process
+    Locked(git2::Error),
+    Other(git2::Error),
+}
+
+impl From<git2::Error> for GitError {
+    fn from(error: git2::Error) -> Self {
+        match (error.code(), error.class()) {
+            (ErrorCode::NotFound, _) => GitError::Missing,
+            (ErrorCode::Locked, _) => GitError::Locked(error),
+            (_, ErrorClass::Zlib) => GitError::Zlib(error),
+            (_, ErrorClass::Object) | (_, ErrorClass::Odb) | (_, ErrorClass::Tree) => {
+                GitError::Corrupt(error)
+            }
+            _ => GitError::Other(error),
+        }
+    }
+}
+
+impl fmt::Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GitError::Missing => write!(f, "object not found"),
+            GitError::WrongKind => write!(f, "object is not of the expected kind"),
+            GitError::Corrupt(e) => write!(f, "corrupt object: {}", e),
+            GitError::Zlib(e) => write!(f, "unable to inflate object: {}", e),
+            GitError::Locked(e) => write!(f, "repository is locked: {}", e),
+            GitError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for GitError {}
@@ -0,0 +1,532 @@
+//! Checkout filters: with `--filters`, blobs are served as `git checkout`
+//! writes them, their line endings converted as their `text` and `eol`
+//! attributes and `core.autocrlf` ask, then run through the smudge command of
+//! their `filter` driver. Attributes depend on the path of a blob, so a
+//! converted blob is given an id of its own, derived from the blob and its
+//! conversion, which inodes and the content caches know it by. Identical
+//! directories share their inode, the path they were first listed at is the
+//! one their attributes are read for. `--ident` expands `$Id$` in blobs with
+//! the `ident` attribute, with or without `--filters`. `--archive-view` serves
+//! what `git archive` writes: both of them, then `export-subst` placeholders
+//! expanded, and paths with `export-ignore` left out.
+
+use super::attributes::{AttributeFile, Attributes, State};
+use super::{Entry, GitError, GitRepo, EMPTY_BLOB};
+use crate::cache::Lru;
+use anyhow::{anyhow, Result};
+use git2::{ObjectType, Oid, Repository};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Bytes looked at to tell binary content, as many as git looks at
+const BINARY_PROBE: usize = 8000;
+/// Converted blobs kept, those whose size was just asked for are opened next
+const CONTENTS_CACHE_SIZE: usize = 64;
+
+/// Conversions applied to blobs, none by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checkout {
+    /// Serve blobs as a checkout writes them, `--filters`
+    pub filters: bool,
+    /// Expand `$Id$` as a checkout does, `--ident`
+    pub ident: bool,
+    /// Serve commits as `git archive` writes them, `--archive-view`
+    pub archive_view: bool,
+}
+
+impl Checkout {
+    /// Whether blobs are converted at all
+    pub fn is_enabled(&self) -> bool {
+        self.filters || self.ident || self.archive_view
+    }
+}
+
+/// Line endings a checkout writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Eol {
+    Crlf,
+    // unless the content is binary or already has carriage returns, `text=auto`
+    AutoCrlf,
+}
+
+/// What a checkout does to a blob
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conversion {
+    // `$Id$` expanded to the id of the blob
+    ident: bool,
+    eol: Option<Eol>,
+    // smudge command, `%f` replaced, and whether it has to succeed
+    smudge: Option<(String, bool)>,
+    // `$Format:...$` expanded for this commit
+    subst: Option<Oid>,
+}
+
+impl Conversion {
+    /// Id of a blob once converted, the same from one mount to the next
+    fn id(&self, blob: Oid) -> Oid {
+        let key = format!(
+            "giblefs conversion {} {} {:?} {:?} {:?}",
+            blob, self.ident, self.eol, self.smudge, self.subst
+        );
+        Oid::hash_object(ObjectType::Blob, key.as_bytes()).unwrap_or(blob)
+    }
+}
+
+/// Settings of the repository conversions depend on
+struct Settings {
+    // what is applied: `--filters`, `--ident`, `--archive-view`
+    filters: bool,
+    ident: bool,
+    archive: bool,
+    git_dir: PathBuf,
+    workdir: Option<PathBuf>,
+    // `core.autocrlf`: true, input or false
+    autocrlf: String,
+    // `core.eol` asks for CRLF
+    eol_crlf: bool,
+    // filter driver => smudge command, whether it is required
+    drivers: HashMap<String, (Option<String>, bool)>,
+    // `core.attributesFile`, of lowest precedence
+    global: Attributes,
+    // `info/attributes`, of highest precedence
+    info: Option<Arc<AttributeFile>>,
+}
+
+impl Settings {
+    fn read(repo: &Repository, checkout: Checkout) -> Settings {
+        let config = repo.config().ok();
+        let get = |name: &str| {
+            config
+                .as_ref()
+                .and_then(|config| config.get_string(name).ok())
+        };
+
+        let mut drivers = HashMap::new();
+        if let Some(config) = &config {
+            if let Ok(entries) = config.entries(Some("filter\\..*\\.(smudge|required)")) {
+                for entry in &entries {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(_) => continue,
+                    };
+                    let (name, value) = match (entry.name(), entry.value()) {
+                        (Some(name), Some(value)) => (name.to_string(), value.to_string()),
+                        _ => continue,
+                    };
+                    let (driver, key) = match name["filter.".len()..].rsplit_once('.') {
+                        Some((driver, key)) => (driver.to_string(), key.to_string()),
+                        None => continue,
+                    };
+                    let driver = drivers.entry(driver).or_insert((None, false));
+                    match key.as_str() {
+                        "smudge" => driver.0 = Some(value),
+                        _ => driver.1 = matches!(value.as_str(), "true" | "yes" | "on" | "1"),
+                    }
+                }
+            }
+        }
+
+        let global = config
+            .as_ref()
+            .and_then(|config| config.get_path("core.attributesfile").ok())
+            .or_else(|| match env::var_os("XDG_CONFIG_HOME") {
+                Some(dir) if !dir.is_empty() => Some(Path::new(&dir).join("git/attributes")),
+                _ => {
+                    env::var_os("HOME").map(|home| Path::new(&home).join(".config/git/attributes"))
+                }
+            });
+        let read = |path: &Path| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|text| Arc::new(AttributeFile::parse(&text)))
+        };
+        let global = match global.as_deref().and_then(read) {
+            Some(file) => Attributes::default().with(b"", file),
+            None => Attributes::default(),
+        };
+
+        Settings {
+            filters: checkout.filters || checkout.archive_view,
+            ident: checkout.ident || checkout.archive_view,
+            archive: checkout.archive_view,
+            git_dir: repo.path().to_path_buf(),
+            workdir: repo.workdir().map(Path::to_path_buf),
+            autocrlf: get("core.autocrlf").unwrap_or_default().to_lowercase(),
+            eol_crlf: get("core.eol").is_some_and(|eol| eol.eq_ignore_ascii_case("crlf")),
+            drivers,
+            global,
+            info: read(&repo.path().join("info").join("attributes")),
+        }
+    }
+
+    /// `attributes` then `info/attributes`
+    fn with_info(&self, attributes: &Attributes) -> Attributes {
+        match &self.info {
+            Some(info) => attributes.with(b"", info.clone()),
+            None => attributes.clone(),
+        }
+    }
+
+    /// What a checkout of `commit` does to the blob at `path`, None when it
+    /// leaves it
+    fn conversion(&self, attributes: &Attributes, path: &[u8], commit: Oid) -> Option<Conversion> {
+        let ident = self.ident && attributes.get(path, false, "ident") == Some(State::Set);
+        let subst = Some(commit).filter(|_| {
+            self.archive && attributes.get(path, false, "export-subst") == Some(State::Set)
+        });
+        if !self.filters {
+            let conversion = Conversion {
+                ident,
+                eol: None,
+                smudge: None,
+                subst,
+            };
+            return if ident { Some(conversion) } else { None };
+        }
+        let text = attributes.get(path, false, "text");
+        let eol = attributes.get(path, false, "eol");
+
+        let autocrlf = matches!(self.autocrlf.as_str(), "true" | "yes" | "on" | "1");
+        let crlf = match &eol {
+            Some(State::Value(eol)) if eol == "crlf" => true,
+            Some(State::Value(eol)) if eol == "lf" => false,
+            _ => autocrlf || (self.autocrlf != "input" && self.eol_crlf),
+        };
+        let eol = match (text, eol) {
+            (Some(State::Unset), _) => None,
+            (Some(State::Value(text)), _) if text == "auto" => Some(Eol::AutoCrlf),
+            (Some(_), _) => Some(Eol::Crlf),
+            // setting `eol` has the path taken as text
+            (None, Some(State::Value(_))) => Some(Eol::Crlf),
+            (None, _) if autocrlf => Some(Eol::AutoCrlf),
+            (None, _) => None,
+        }
+        .filter(|_| crlf);
+
+        let smudge = match attributes.get(path, false, "filter") {
+            Some(State::Value(driver)) => match self.drivers.get(&driver) {
+                Some((Some(command), required)) => {
+                    let path = String::from_utf8_lossy(path).replace('\'', "'\\''");
+                    Some((command.replace("%f", &format!("'{}'", path)), *required))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if !ident && eol.is_none() && smudge.is_none() && subst.is_none() {
+            return None;
+        }
+        Some(Conversion {
+            ident,
+            eol,
+            smudge,
+            subst,
+        })
+    }
+
+    /// `$Format:...$` placeholders of `content` expanded for `commit` by
+    /// `git log`, as `git archive` expands them
+    fn subst(&self, content: &[u8], commit: Oid) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(content.len());
+        let mut rest = content;
+        while let Some(start) = rest.windows(8).position(|w| w == b"$Format:") {
+            let after = &rest[start + 8..];
+            let end = match after.iter().position(|&c| c == b'$') {
+                Some(end) => end,
+                None => break,
+            };
+            out.extend_from_slice(&rest[..start]);
+            let output = Command::new("git")
+                .arg("--git-dir")
+                .arg(&self.git_dir)
+                .args(["log", "-1", "--no-walk", "--no-show-signature"])
+                .arg(format!(
+                    "--pretty=format:{}",
+                    String::from_utf8_lossy(&after[..end])
+                ))
+                .arg(commit.to_string())
+                .stdin(Stdio::null())
+                .output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "git log exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            out.extend_from_slice(&output.stdout);
+            rest = &after[end + 1..];
+        }
+        out.extend_from_slice(rest);
+        Ok(out)
+    }
+
+    /// Run the smudge command of a filter driver on `content`
+    fn smudge(&self, command: &str, content: &[u8]) -> Result<Vec<u8>> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(self.workdir.as_ref().unwrap_or(&self.git_dir))
+            .env("GIT_DIR", &self.git_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // written from another thread, the filter may write before it has read it all
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+        let content = content.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&content));
+        let output = child.wait_with_output()?;
+        // filters may exit without reading their input
+        let _ = writer.join();
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(anyhow!(
+                "{} exited with {}: {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+/// `$Id$`, and `$Id: ... $` left by another checkout, replaced with
+/// `$Id: <blob> $`
+fn expand_ident(content: &[u8], blob: Oid) -> Vec<u8> {
+    let expanded = format!("$Id: {} $", blob);
+    let mut out = Vec::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.windows(3).position(|w| w == b"$Id") {
+        out.extend_from_slice(&rest[..start]);
+        let after = &rest[start + 3..];
+        let end = match after.first() {
+            Some(b'$') => Some(1),
+            // up to the closing dollar, on the same line
+            Some(b':') => after
+                .iter()
+                .position(|&c| c == b'$' || c == b'\n')
+                .filter(|&end| after[end] == b'$')
+                .map(|end| end + 1),
+            _ => None,
+        };
+        match end {
+            Some(end) => {
+                out.extend_from_slice(expanded.as_bytes());
+                rest = &after[end..];
+            }
+            None => {
+                out.extend_from_slice(b"$Id");
+                rest = after;
+            }
+        }
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// LF line endings turned into CRLF, those already CRLF left as they are
+fn to_crlf(content: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(content.len() + content.len() / 32);
+    for (i, &c) in content.iter().enumerate() {
+        if c == b'\n' && (i == 0 || content[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(c);
+    }
+    converted
+}
+
+/// Blobs converted for the commits of a repository
+pub struct Filters {
+    settings: Settings,
+    // (commit, tree) => path of the tree in the commit and the attributes
+    // applying there, but those of its own `.gitattributes`
+    dirs: RefCell<HashMap<(Oid, Oid), (Vec<u8>, Attributes)>>,
+    // (commit, tree) => its entries, converted blobs with their own ids
+    trees: RefCell<HashMap<(Oid, Oid), Arc<[Entry]>>>,
+    // converted id => blob and its conversion
+    converted: RefCell<HashMap<Oid, (Oid, Conversion)>>,
+    // converted id => content, as last converted
+    contents: RefCell<Lru<Oid, Arc<[u8]>>>,
+    // `.gitattributes` blob => its rules
+    files: RefCell<HashMap<Oid, Arc<AttributeFile>>>,
+}
+
+impl Filters {
+    /// Conversions of `checkout` for the blobs of `repo`
+    pub fn new(repo: &Repository, checkout: Checkout) -> Self {
+        Filters {
+            settings: Settings::read(repo, checkout),
+            dirs: RefCell::new(HashMap::new()),
+            trees: RefCell::new(HashMap::new()),
+            converted: RefCell::new(HashMap::new()),
+            contents: RefCell::new(Lru::new(CONTENTS_CACHE_SIZE)),
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Entries of `tree` as a checkout of `commit` has them, converted blobs
+    /// with ids of their own
+    pub fn entries(
+        &self,
+        repo: &GitRepo,
+        commit: Oid,
+        tree: Oid,
+    ) -> Result<Arc<[Entry]>, GitError> {
+        if let Some(entries) = self.trees.borrow().get(&(commit, tree)) {
+            return Ok(entries.clone());
+        }
+
+        let stored = repo.tree_entries(tree)?;
+        let known = self.dirs.borrow().get(&(commit, tree)).cloned();
+        let (path, inherited) = match known {
+            Some(dir) => dir,
+            None if repo.get_commit_tree_id(commit).ok() == Some(tree) => {
+                (Vec::new(), self.settings.global.clone())
+            }
+            // not reached from the root of the commit
+            None => return Ok(stored),
+        };
+        let attributes = match stored
+            .iter()
+            .find(|entry| entry.name == b".gitattributes" && entry.kind == Some(ObjectType::Blob))
+        {
+            Some(entry) => inherited.with(&path, self.attribute_file(repo, entry.id)),
+            None => inherited,
+        };
+        let applying = self.settings.with_info(&attributes);
+
+        let mut entries = Vec::with_capacity(stored.len());
+        for entry in stored.iter() {
+            let mut entry = entry.clone();
+            let child = if path.is_empty() {
+                entry.name.clone()
+            } else {
+                [&path[..], &b"/"[..], &entry.name[..]].concat()
+            };
+            let is_dir = entry.kind == Some(ObjectType::Tree);
+            if self.settings.archive
+                && applying.get(&child, is_dir, "export-ignore") == Some(State::Set)
+            {
+                continue;
+            }
+            match entry.kind {
+                Some(ObjectType::Tree) => {
+                    self.dirs
+                        .borrow_mut()
+                        .entry((commit, entry.id))
+                        .or_insert((child, attributes.clone()));
+                }
+                // symbolic links are left alone
+                Some(ObjectType::Blob) if entry.filemode != 0o120000 && entry.id != *EMPTY_BLOB => {
+                    if let Some(conversion) = self.settings.conversion(&applying, &child, commit) {
+                        let id = conversion.id(entry.id);
+                        self.converted
+                            .borrow_mut()
+                            .insert(id, (entry.id, conversion));
+                        entry.id = id;
+                    }
+                }
+                _ => {}
+            }
+            entries.push(entry);
+        }
+        let entries: Arc<[Entry]> = entries.into();
+        self.trees
+            .borrow_mut()
+            .insert((commit, tree), entries.clone());
+        Ok(entries)
+    }
+
+    fn attribute_file(&self, repo: &GitRepo, blob: Oid) -> Arc<AttributeFile> {
+        if let Some(file) = self.files.borrow().get(&blob) {
+            return file.clone();
+        }
+        let file = match repo.store.blob(blob) {
+            Ok(content) => AttributeFile::parse(&String::from_utf8_lossy(&content)),
+            Err(e) => {
+                warn!("unable to read attributes {}: {}", blob, e);
+                AttributeFile::default()
+            }
+        };
+        let file = Arc::new(file);
+        self.files.borrow_mut().insert(blob, file.clone());
+        file
+    }
+
+    /// Whether `id` is that of a converted blob
+    pub fn is_converted(&self, id: Oid) -> bool {
+        self.converted.borrow().contains_key(&id)
+    }
+
+    /// The blob `id` is converted from, None when it is not a converted blob
+    pub fn source(&self, id: Oid) -> Option<Oid> {
+        self.converted.borrow().get(&id).map(|(blob, _)| *blob)
+    }
+
+    /// Content of the converted blob `id`, None when it is not one
+    pub fn read(&self, repo: &GitRepo, id: Oid) -> Option<Result<Arc<[u8]>, GitError>> {
+        let (blob, conversion) = self.converted.borrow().get(&id).cloned()?;
+        if let Some(content) = self.contents.borrow_mut().get(&id) {
+            return Some(Ok(content));
+        }
+        let content = self.convert(repo, blob, &conversion);
+        if let Ok(content) = &content {
+            self.contents.borrow_mut().insert(id, content.clone());
+        }
+        Some(content)
+    }
+
+    fn convert(
+        &self,
+        repo: &GitRepo,
+        blob: Oid,
+        conversion: &Conversion,
+    ) -> Result<Arc<[u8]>, GitError> {
+        let mut content = repo.store.blob(blob)?;
+        if conversion.ident {
+            content = expand_ident(&content, blob).into();
+        }
+        let probe = &content[..content.len().min(BINARY_PROBE)];
+        let convert_eol = match conversion.eol {
+            Some(Eol::Crlf) => true,
+            Some(Eol::AutoCrlf) => !probe.contains(&0) && !content.contains(&b'\r'),
+            None => false,
+        };
+        if convert_eol {
+            content = to_crlf(&content).into();
+        }
+
+        if let Some((command, required)) = &conversion.smudge {
+            debug!("smudging {} with {}", blob, command);
+            match self.settings.smudge(command, &content) {
+                Ok(smudged) => content = smudged.into(),
+                Err(e) if *required => {
+                    return Err(GitError::Other(git2::Error::from_str(&e.to_string())))
+                }
+                // git checks out the content as it is
+                Err(e) => warn!("unable to smudge {}: {}", blob, e),
+            }
+        }
+
+        if let Some(commit) = conversion.subst {
+            content = self
+                .settings
+                .subst(&content, commit)
+                .map_err(|e| GitError::Other(git2::Error::from_str(&e.to_string())))?
+                .into();
+        }
+        Ok(content)
+    }
+}
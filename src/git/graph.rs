@@ -0,0 +1,154 @@
+//! Reader for commit-graph files, which record the tree and commit time of
+//! commits so they can be had without inflating the commits themselves.
+//!
+//! See `Documentation/gitformat-commit-graph.txt` in git for the format.
+
+use super::pack::be32;
+use anyhow::{anyhow, Result};
+use git2::Oid;
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::path::Path;
+use tracing::debug;
+
+const MAGIC: &[u8] = b"CGPH";
+const HEADER: usize = 8;
+const CHUNK_ENTRY: usize = 12;
+const OID_FANOUT: &[u8] = b"OIDF";
+const OID_LOOKUP: &[u8] = b"OIDL";
+const COMMIT_DATA: &[u8] = b"CDAT";
+const OID_LEN: usize = 20;
+// tree id, two parent positions, then generation and commit time
+const DATA_LEN: usize = OID_LEN + 16;
+
+/// A commit as recorded in the commit-graph
+pub struct GraphCommit {
+    pub tree: Oid,
+    pub time: i64,
+}
+
+/// One commit-graph file, mapped in memory
+struct Layer {
+    map: Mmap,
+    fanout: usize,
+    oids: usize,
+    data: usize,
+    count: usize,
+}
+
+impl Layer {
+    fn open(path: &Path) -> Result<Self> {
+        // SAFETY: commit-graph files are replaced rather than modified in place
+        let map = unsafe { Mmap::map(&File::open(path)?)? };
+        // SHA-1 graphs of version 1 only
+        if map.len() < HEADER || &map[..4] != MAGIC || map[4] != 1 || map[5] != 1 {
+            return Err(anyhow!("unsupported commit-graph {}", path.display()));
+        }
+
+        let chunks = map[6] as usize;
+        if map.len() < HEADER + (chunks + 1) * CHUNK_ENTRY {
+            return Err(anyhow!("truncated commit-graph {}", path.display()));
+        }
+        let chunk = |id: &[u8]| {
+            (0..chunks).find_map(|n| {
+                let entry = HEADER + n * CHUNK_ENTRY;
+                if &map[entry..entry + 4] != id {
+                    return None;
+                }
+                let high = be32(&map, entry + 4) as u64;
+                Some(((high << 32) | be32(&map, entry + 8) as u64) as usize)
+            })
+        };
+        let (fanout, oids, data) = match (chunk(OID_FANOUT), chunk(OID_LOOKUP), chunk(COMMIT_DATA))
+        {
+            (Some(fanout), Some(oids), Some(data)) => (fanout, oids, data),
+            _ => return Err(anyhow!("incomplete commit-graph {}", path.display())),
+        };
+
+        if map.len() < fanout + 256 * 4 {
+            return Err(anyhow!("truncated commit-graph {}", path.display()));
+        }
+        let count = be32(&map, fanout + 255 * 4) as usize;
+        if map.len() < oids + count * OID_LEN || map.len() < data + count * DATA_LEN {
+            return Err(anyhow!("truncated commit-graph {}", path.display()));
+        }
+
+        Ok(Layer {
+            map,
+            fanout,
+            oids,
+            data,
+            count,
+        })
+    }
+
+    fn find(&self, oid: Oid) -> Option<GraphCommit> {
+        let oid = oid.as_bytes();
+        let fanout = |byte: usize| be32(&self.map, self.fanout + byte * 4) as usize;
+        let start = if oid[0] == 0 {
+            0
+        } else {
+            fanout(oid[0] as usize - 1)
+        };
+        let end = fanout(oid[0] as usize).min(self.count);
+
+        let id = |n: usize| &self.map[self.oids + n * OID_LEN..self.oids + (n + 1) * OID_LEN];
+        let (mut low, mut high) = (start, end);
+        let n = loop {
+            if low >= high {
+                return None;
+            }
+            let mid = (low + high) / 2;
+            match id(mid).cmp(oid) {
+                std::cmp::Ordering::Equal => break mid,
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        };
+
+        let data = self.data + n * DATA_LEN;
+        let tree = Oid::from_bytes(&self.map[data..data + OID_LEN]).ok()?;
+        // 34 bits of commit time below the 30 bits of generation
+        let high = be32(&self.map, data + OID_LEN + 8) as i64 & 0x3;
+        let time = (high << 32) | be32(&self.map, data + OID_LEN + 12) as i64;
+        Some(GraphCommit { tree, time })
+    }
+}
+
+/// The commit-graph of a repository, a single file or a chain of them. Empty
+/// when the repository has none, commits missing from it are read as usual.
+pub struct CommitGraph {
+    layers: Vec<Layer>,
+}
+
+impl CommitGraph {
+    pub fn open(objects: &Path) -> Self {
+        let info = objects.join("info");
+        let mut paths = vec![info.join("commit-graph")];
+        let dir = info.join("commit-graphs");
+        if let Ok(chain) = fs::read_to_string(dir.join("commit-graph-chain")) {
+            paths.extend(
+                chain
+                    .lines()
+                    .map(|hash| dir.join(format!("graph-{}.graph", hash))),
+            );
+        }
+
+        let layers = paths
+            .iter()
+            .filter(|path| path.exists())
+            .filter_map(|path| match Layer::open(path) {
+                Ok(layer) => Some(layer),
+                Err(e) => {
+                    debug!("skipping commit-graph: {}", e);
+                    None
+                }
+            })
+            .collect();
+        CommitGraph { layers }
+    }
+
+    pub fn find(&self, oid: Oid) -> Option<GraphCommit> {
+        self.layers.iter().find_map(|layer| layer.find(oid))
+    }
+}
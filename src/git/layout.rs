@@ -0,0 +1,137 @@
+//! Why a repository could not be opened: libgit2 reports layouts it does not
+//! support as if there were no repository at all, or with messages that do
+//! not name what it found.
+
+use anyhow::{anyhow, Error};
+use git2::{Config, Repository};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions of repository format version 1, as they read to users
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("objectformat", "SHA-256 object ids"),
+    ("refstorage", "reftable refs"),
+    ("partialclone", "a partial clone"),
+    ("worktreeconfig", "per-worktree configuration"),
+    ("preciousobjects", "precious objects"),
+];
+
+/// Explain why the repository at `path` did not open with `error`
+pub fn diagnose(path: &Path, search: bool, error: git2::Error) -> Error {
+    if !path.exists() {
+        return anyhow!("{}: no such file or directory", path.display());
+    }
+
+    let git_dir = match git_dir(path) {
+        Ok(Some(git_dir)) => git_dir,
+        Ok(None) => {
+            if search {
+                return anyhow!(
+                    "neither {} nor its parents are a git repository",
+                    path.display()
+                );
+            }
+            let hint = match Repository::discover(path) {
+                Ok(_) => ", it is inside one: pass --search to use it",
+                Err(_) => "",
+            };
+            return anyhow!("{} is not a git repository{}", path.display(), hint);
+        }
+        Err(e) => return e,
+    };
+
+    match unsupported(&git_dir) {
+        Some(layout) => anyhow!(
+            "{}: unsupported repository layout: {}",
+            path.display(),
+            layout
+        ),
+        None => anyhow!("{}: {}", path.display(), error.message()),
+    }
+}
+
+/// Repository directory at `path`: `.git` in it, where a `.git` file points
+/// to, or `path` itself when bare
+fn git_dir(path: &Path) -> Result<Option<PathBuf>, Error> {
+    let dot_git = path.join(".git");
+    if dot_git.is_dir() {
+        return Ok(Some(dot_git));
+    }
+    if dot_git.is_file() {
+        // `gitdir: <path>`, left by `--separate-git-dir`, worktrees and submodules
+        let text = fs::read_to_string(&dot_git)?;
+        let target = text
+            .strip_prefix("gitdir:")
+            .map(|target| path.join(target.trim()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{}: not a `gitdir: <path>` pointer to a repository",
+                    dot_git.display()
+                )
+            })?;
+        if !target.is_dir() {
+            return Err(anyhow!(
+                "{} points to {}, which does not exist",
+                dot_git.display(),
+                target.display()
+            ));
+        }
+        return Ok(Some(target));
+    }
+    if path.join("HEAD").is_file() && path.join("objects").is_dir() {
+        return Ok(Some(path.to_path_buf()));
+    }
+    Ok(None)
+}
+
+/// What in the configuration of a repository libgit2 cannot read
+fn unsupported(git_dir: &Path) -> Option<String> {
+    // worktrees share the configuration of their main repository
+    let common = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim_end()),
+        Err(_) => git_dir.to_path_buf(),
+    };
+    let config = Config::open(&common.join("config")).ok()?;
+    let version = config.get_i32("core.repositoryformatversion").unwrap_or(0);
+
+    let mut found = Vec::new();
+    if let Ok(entries) = config.entries(Some("extensions\\..*")) {
+        for entry in &entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let name = entry.name().unwrap_or_default().to_lowercase();
+            let name = name.trim_start_matches("extensions.");
+            let value = entry.value().unwrap_or_default();
+            match EXTENSIONS.iter().find(|(extension, _)| *extension == name) {
+                // the default, spelled out
+                Some(("objectformat", _)) if value == "sha1" => {}
+                Some(("refstorage", _)) if value == "files" => {}
+                Some((_, description)) => {
+                    found.push(format!("{} ({} = {})", description, name, value))
+                }
+                None => found.push(format!("extensions.{} = {}", name, value)),
+            }
+        }
+    }
+
+    let promisor = config
+        .entries(Some("remote\\..*\\.promisor"))
+        .is_ok_and(|entries| (&entries).next().is_some());
+    match (version, found.is_empty()) {
+        (0, true) => None,
+        // what `git clone --filter` leaves, version 0 means the same without extensions
+        (1, true) if promisor => Some(
+            "repository format version 1 of a partial clone, \
+             `git config core.repositoryformatversion 0` makes it readable"
+                .to_string(),
+        ),
+        (version, true) => Some(format!("repository format version {}", version)),
+        (version, false) => Some(format!(
+            "repository format version {} with {}",
+            version,
+            found.join(", ")
+        )),
+    }
+}
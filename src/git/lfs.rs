@@ -0,0 +1,201 @@
+//! Git LFS: blobs committed as pointers to objects kept outside the repository
+//! are served, with `--lfs`, as the objects they point to. Those are read from
+//! the LFS store of the repository, `lfs/objects` unless `lfs.storage` moves it,
+//! and downloaded by `git lfs smudge` when missing, which keeps them there.
+
+use super::{Entry, GitError, ObjectStore};
+use crate::credentials;
+use anyhow::{anyhow, Result};
+use git2::{Config, ObjectType, Oid};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Pointers are smaller than this, larger blobs are not read to find out
+pub const MAX_POINTER_SIZE: usize = 1024;
+/// Time before objects that failed to download are tried again
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// What a pointer blob points to, see the LFS specification
+struct Pointer {
+    oid: String,
+    size: usize,
+}
+
+impl Pointer {
+    fn parse(content: &[u8]) -> Option<Pointer> {
+        if content.len() >= MAX_POINTER_SIZE {
+            return None;
+        }
+        let text = std::str::from_utf8(content).ok()?;
+        let mut lines = text.lines();
+        if !lines
+            .next()?
+            .starts_with("version https://git-lfs.github.com/spec/")
+        {
+            return None;
+        }
+        let (mut oid, mut size) = (None, None);
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let oid =
+            oid.filter(|oid| oid.len() == 64 && oid.bytes().all(|b| b.is_ascii_hexdigit()))?;
+        Some(Pointer {
+            oid: oid.to_string(),
+            size: size?,
+        })
+    }
+}
+
+/// Blobs read through another store, pointers replaced by the LFS objects
+/// they point to
+pub struct LfsStore {
+    inner: Box<dyn ObjectStore>,
+    git_dir: PathBuf,
+    // LFS object => when downloading it failed and why, not tried again until later
+    failed: RefCell<HashMap<String, (Instant, String)>>,
+}
+
+impl LfsStore {
+    pub fn new(inner: Box<dyn ObjectStore>, git_dir: PathBuf) -> Self {
+        LfsStore {
+            inner,
+            git_dir,
+            failed: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// The pointer blob `hash` is, if it is one
+    fn pointer(&self, hash: Oid, size: usize) -> Result<Option<Pointer>, GitError> {
+        if size >= MAX_POINTER_SIZE {
+            return Ok(None);
+        }
+        Ok(Pointer::parse(&self.inner.blob(hash)?))
+    }
+
+    /// Where the object a pointer points to is kept
+    fn path(&self, pointer: &Pointer) -> PathBuf {
+        let storage = Config::open(&self.git_dir.join("config"))
+            .and_then(|config| config.get_path("lfs.storage"))
+            .map(|storage| self.git_dir.join(storage))
+            .unwrap_or_else(|_| self.git_dir.join("lfs"));
+        storage
+            .join("objects")
+            .join(&pointer.oid[..2])
+            .join(&pointer.oid[2..4])
+            .join(&pointer.oid)
+    }
+
+    /// Content of the object a pointer points to, downloaded when missing
+    fn object(&self, pointer: &Pointer, blob: &[u8]) -> Result<Arc<[u8]>, GitError> {
+        if let Ok(content) = fs::read(self.path(pointer)) {
+            // left there by an interrupted download otherwise
+            if content.len() == pointer.size {
+                return Ok(content.into());
+            }
+        }
+
+        let mut failed = self.failed.borrow_mut();
+        failed.retain(|_, (time, _)| time.elapsed() < RETRY_DELAY);
+        if let Some((_, e)) = failed.get(&pointer.oid) {
+            return Err(GitError::Other(git2::Error::from_str(e)));
+        }
+        debug!("downloading LFS object {}", pointer.oid);
+        let start = Instant::now();
+        match self.smudge(blob) {
+            Ok(content) if content.len() == pointer.size => {
+                info!(
+                    "downloaded LFS object {} of {} bytes in {:?}",
+                    pointer.oid,
+                    pointer.size,
+                    start.elapsed()
+                );
+                Ok(content.into())
+            }
+            result => {
+                let e = match result {
+                    Ok(content) => format!(
+                        "LFS object {} has {} bytes rather than {}",
+                        pointer.oid,
+                        content.len(),
+                        pointer.size
+                    ),
+                    Err(e) => format!("unable to download LFS object {}: {}", pointer.oid, e),
+                };
+                failed.insert(pointer.oid.clone(), (Instant::now(), e.clone()));
+                Err(GitError::Other(git2::Error::from_str(&e)))
+            }
+        }
+    }
+
+    /// Have `git lfs smudge` turn a pointer into its object, as a checkout does
+    fn smudge(&self, blob: &[u8]) -> Result<Vec<u8>> {
+        let url = Config::open(&self.git_dir.join("config"))
+            .and_then(|config| config.get_string("remote.origin.url"))
+            .unwrap_or_default();
+        let mut command = Command::new("git");
+        credentials::apply(&mut command, "origin", &url);
+        let mut child = command
+            .arg("--git-dir")
+            .arg(&self.git_dir)
+            .args(["lfs", "smudge"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+        stdin.write_all(blob)?;
+        drop(stdin);
+
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(anyhow!(
+                "git lfs smudge exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+impl ObjectStore for LfsStore {
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        match self.inner.header(hash)? {
+            (size, ObjectType::Blob) => match self.pointer(hash, size)? {
+                Some(pointer) => Ok((pointer.size, ObjectType::Blob)),
+                None => Ok((size, ObjectType::Blob)),
+            },
+            header => Ok(header),
+        }
+    }
+
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+        self.inner.tree(hash)
+    }
+
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        let blob = self.inner.blob(hash)?;
+        match Pointer::parse(&blob) {
+            Some(pointer) => self.object(&pointer, &blob),
+            None => Ok(blob),
+        }
+    }
+
+    fn prefetch(&self, hashes: &[Oid]) {
+        self.inner.prefetch(hashes)
+    }
+}
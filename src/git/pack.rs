@@ -0,0 +1,204 @@
+//! Direct access to packfiles, for blobs stored whole rather than as deltas.
+//!
+//! See `Documentation/gitformat-pack.txt` in git for the formats read here.
+
+use anyhow::{anyhow, Result};
+use git2::Oid;
+use memmap2::Mmap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::debug;
+
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+const IDX_HEADER: usize = 8;
+const FANOUT: usize = 256 * 4;
+const OID_LEN: usize = 20;
+const OBJ_BLOB: u8 = 3;
+
+/// A packfile along with its index, both mapped in memory
+struct Pack {
+    idx_path: PathBuf,
+    idx: Mmap,
+    data: Arc<Mmap>,
+    // number of objects in the pack
+    count: usize,
+}
+
+impl Pack {
+    fn open(idx_path: PathBuf) -> Result<Self> {
+        // SAFETY: packs are never modified once written, only deleted, which
+        // leaves existing mappings intact
+        let idx = unsafe { Mmap::map(&File::open(&idx_path)?)? };
+        let data = unsafe { Mmap::map(&File::open(idx_path.with_extension("pack"))?)? };
+
+        if idx.len() < IDX_HEADER + FANOUT || &idx[..4] != IDX_MAGIC || be32(&idx, 4) != 2 {
+            return Err(anyhow!("unsupported pack index {}", idx_path.display()));
+        }
+        let count = be32(&idx, IDX_HEADER + FANOUT - 4) as usize;
+        if idx.len() < IDX_HEADER + FANOUT + count * (OID_LEN + 8) {
+            return Err(anyhow!("truncated pack index {}", idx_path.display()));
+        }
+
+        Ok(Pack {
+            idx_path,
+            idx,
+            data: Arc::new(data),
+            count,
+        })
+    }
+
+    /// Offset of an object in the pack
+    fn find(&self, oid: Oid) -> Option<usize> {
+        let oid = oid.as_bytes();
+        let fanout = |byte: usize| be32(&self.idx, IDX_HEADER + byte * 4) as usize;
+        let start = if oid[0] == 0 {
+            0
+        } else {
+            fanout(oid[0] as usize - 1)
+        };
+        let end = fanout(oid[0] as usize).min(self.count);
+
+        let oids = IDX_HEADER + FANOUT;
+        let id = |n: usize| &self.idx[oids + n * OID_LEN..oids + (n + 1) * OID_LEN];
+        let (mut low, mut high) = (start, end);
+        let n = loop {
+            if low >= high {
+                return None;
+            }
+            let mid = (low + high) / 2;
+            match id(mid).cmp(oid) {
+                std::cmp::Ordering::Equal => break mid,
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+            }
+        };
+
+        // 32-bit offsets follow the ids and their CRCs, offsets that do not fit
+        // have their high bit set and point into a table of 64-bit ones
+        let offsets = oids + self.count * (OID_LEN + 4);
+        let offset = be32(&self.idx, offsets + n * 4);
+        if offset & 0x8000_0000 == 0 {
+            return Some(offset as usize);
+        }
+        let large = offsets + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        let bytes = self.idx.get(large..large + 8)?;
+        Some(u64::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    }
+}
+
+/// Packs of a repository, to read blobs that are not deltified straight from
+/// the mapped pack
+pub struct Packs {
+    // of the object directory and its alternates
+    dirs: Vec<PathBuf>,
+    packs: Vec<Pack>,
+}
+
+impl Packs {
+    pub fn new(objects: &[PathBuf]) -> Self {
+        Packs {
+            dirs: objects.iter().map(|dir| dir.join("pack")).collect(),
+            packs: Vec::new(),
+        }
+    }
+
+    /// Map packs that appeared in `dir` since the last scan
+    fn rescan(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "idx")
+                || self.packs.iter().any(|pack| pack.idx_path == path)
+            {
+                continue;
+            }
+            match Pack::open(path) {
+                Ok(pack) => self.packs.push(pack),
+                Err(e) => debug!("skipping pack: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap the packs deleted since they were mapped, the next miss maps the
+    /// packs that replaced them
+    pub fn drop_removed(&mut self) {
+        self.packs.retain(|pack| pack.idx_path.exists());
+    }
+
+    /// Compressed content of a blob and its size, if it is stored whole in a pack
+    pub fn find_blob(&mut self, oid: Oid) -> Option<(PackedData, u64)> {
+        if !self.packs.iter().any(|pack| pack.find(oid).is_some()) {
+            for dir in self.dirs.clone() {
+                if let Err(e) = self.rescan(&dir) {
+                    debug!("unable to scan {}: {}", dir.display(), e);
+                }
+            }
+        }
+
+        self.packs.iter().find_map(|pack| {
+            let offset = pack.find(oid)?;
+            let (kind, size, header) = entry_header(pack.data.get(offset..)?)?;
+            if kind != OBJ_BLOB {
+                return None;
+            }
+            let data = PackedData {
+                map: pack.data.clone(),
+                position: offset + header,
+            };
+            Some((data, size))
+        })
+    }
+}
+
+/// Kind, size and length of the header of the pack entry at the start of `data`
+fn entry_header(data: &[u8]) -> Option<(u8, u64, usize)> {
+    let mut bytes = data.iter();
+    let mut byte = *bytes.next()?;
+    let kind = (byte >> 4) & 0x7;
+    let mut size = (byte & 0xf) as u64;
+    let mut shift = 4;
+    let mut len = 1;
+    while byte & 0x80 != 0 {
+        byte = *bytes.next()?;
+        if shift > 57 {
+            return None;
+        }
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        len += 1;
+    }
+    Some((kind, size, len))
+}
+
+pub(super) fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// Compressed data of a pack entry, read straight from the mapped pack
+#[derive(Clone)]
+pub struct PackedData {
+    map: Arc<Mmap>,
+    position: usize,
+}
+
+impl Read for PackedData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.fill_buf()?.read(buf)?;
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+// the decoder reads out of the mapping itself, without copying it first
+impl BufRead for PackedData {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.map.get(self.position..).unwrap_or_default())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt;
+    }
+}
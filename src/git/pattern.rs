@@ -0,0 +1,148 @@
+//! Patterns of `.gitignore`, `.gitattributes` and the like, matched the way
+//! git matches them, see gitignore(5).
+
+/// A pattern of a line of such a file
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    glob: Vec<u8>,
+    // has a slash other than a trailing one, so matches the whole path
+    anchored: bool,
+    // ends with a slash, so only matches directories
+    dir_only: bool,
+    pub negated: bool,
+}
+
+impl Pattern {
+    /// Parse a line, None when it is blank or a comment
+    pub fn parse(line: &str) -> Option<Pattern> {
+        let full = line.trim_end_matches(&['\n', '\r'][..]);
+        // trailing spaces are dropped unless escaped
+        let mut line = full.trim_end_matches(' ');
+        if line.ends_with('\\') && line.len() < full.len() {
+            line = &full[..line.len() + 1];
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            // `\!` and `\#` are matched as the characters they escape
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let glob = line.strip_prefix('/').unwrap_or(line);
+        if glob.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: glob.as_bytes().to_vec(),
+            anchored,
+            dir_only,
+            negated,
+        })
+    }
+
+    /// Whether `path`, relative to the directory the pattern applies in,
+    /// matches
+    pub fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            wildmatch(&self.glob, path)
+        } else {
+            let name = match path.iter().rposition(|&c| c == b'/') {
+                Some(slash) => &path[slash + 1..],
+                None => path,
+            };
+            wildmatch(&self.glob, name)
+        }
+    }
+}
+
+/// Match `text` against a glob: `*` and `?` do not match slashes, `**`
+/// between slashes matches any number of directories, `[...]` one character
+/// of a class
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] if rest.is_empty() || rest[0] == b'/' => match rest {
+            // everything below
+            [] => true,
+            [_, rest @ ..] => {
+                wildmatch(rest, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .any(|(i, &c)| c == b'/' && wildmatch(rest, &text[i + 1..]))
+            }
+        },
+        [b'*', rest @ ..] => {
+            let rest = match rest {
+                [b'*', rest @ ..] => rest,
+                rest => rest,
+            };
+            for i in 0..=text.len() {
+                if wildmatch(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    return false;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => wildmatch(rest, text),
+            _ => false,
+        },
+        [b'[', class @ ..] => match (text, match_class(class, text.first().copied())) {
+            ([_, text @ ..], Some((true, rest))) => wildmatch(rest, text),
+            // an unterminated class is taken literally
+            ([b'[', text @ ..], None) => wildmatch(class, text),
+            _ => false,
+        },
+        [b'\\', c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => wildmatch(rest, text),
+            _ => false,
+        },
+        [c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => wildmatch(rest, text),
+            _ => false,
+        },
+    }
+}
+
+/// Whether `c` is in the class starting at `class`, right after its `[`, and
+/// the pattern after the class. None when the class is not terminated.
+fn match_class(class: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut rest) = match class {
+        [b'!', rest @ ..] | [b'^', rest @ ..] => (true, rest),
+        rest => (false, rest),
+    };
+    let mut found = false;
+    let mut first = true;
+    loop {
+        match rest {
+            [] => return None,
+            [b']', after @ ..] if !first => {
+                let found = found != negated && c.is_some_and(|c| c != b'/');
+                return Some((found, after));
+            }
+            [low, b'-', high, after @ ..] if *high != b']' => {
+                found |= c.is_some_and(|c| (*low..=*high).contains(&c));
+                rest = after;
+            }
+            [b'\\', x, after @ ..] | [x, after @ ..] => {
+                found |= c == Some(*x);
+                rest = after;
+            }
+        }
+        first = false;
+    }
+}
@@ -0,0 +1,306 @@
+//! Partial clones: objects left out by `git clone --filter` are fetched from
+//! the promisor remote the first time they are read, as git fetches them, and
+//! then read from the packs the fetch writes.
+
+use super::{open_repository, Entry, GitError, ObjectStore, OpenOptions};
+use crate::credentials;
+use anyhow::{anyhow, Result};
+use git2::{Config, ObjectType, Oid};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Time before objects that failed to fetch are tried again
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Time a fetch may take before it is killed
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+/// Time between checks of whether a fetch is done
+const FETCH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    // git dir => its fetcher, while a store uses it
+    static ref FETCHERS: Mutex<HashMap<PathBuf, Weak<Fetcher>>> = Mutex::new(HashMap::new());
+}
+
+/// The remote the repository at `path` fetches missing objects from, if it is
+/// a partial clone and fetching is not turned off with GIT_NO_LAZY_FETCH
+pub fn promisor_remote(path: &Path) -> Option<(PathBuf, String)> {
+    if env::var_os("GIT_NO_LAZY_FETCH").is_some_and(|value| value != "0") {
+        return None;
+    }
+    let git_dir = open_repository(path, &OpenOptions::default())
+        .ok()?
+        .path()
+        .to_path_buf();
+    let config = Config::open(&git_dir.join("config")).ok()?;
+    if let Ok(remote) = config.get_string("extensions.partialclone") {
+        return Some((git_dir, remote));
+    }
+
+    let mut remotes = Vec::new();
+    let entries = config.entries(Some("remote\\..*\\.promisor")).ok()?;
+    for entry in &entries {
+        let name = entry.ok()?.name()?.to_string();
+        remotes.push(name);
+    }
+    let remote = remotes
+        .into_iter()
+        .find(|name| config.get_bool(name).unwrap_or(false))?;
+    let remote = remote
+        .strip_prefix("remote.")?
+        .strip_suffix(".promisor")?
+        .to_string();
+    Some((git_dir, remote))
+}
+
+/// Objects of a partial clone, read through another store and fetched when
+/// that store does not have them
+pub struct PromisorStore {
+    inner: Box<dyn ObjectStore>,
+    fetcher: Arc<Fetcher>,
+}
+
+impl PromisorStore {
+    pub fn new(inner: Box<dyn ObjectStore>, git_dir: PathBuf, remote: String) -> Self {
+        PromisorStore {
+            inner,
+            fetcher: Fetcher::shared(git_dir, remote),
+        }
+    }
+
+    /// Read with `read`, fetching `hash` first when it is missing
+    fn read<T, F>(&self, hash: Oid, read: F) -> Result<T, GitError>
+    where
+        F: Fn(&dyn ObjectStore) -> Result<T, GitError>,
+    {
+        match read(self.inner.as_ref()) {
+            Err(GitError::Missing) => {
+                self.fetcher.fetch(&[hash])?;
+                read(self.inner.as_ref())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Fetches of the missing objects of a repository, shared by every store
+/// opened on it. They run on a thread of their own, one at a time: objects
+/// asked for while one runs are fetched together in the next, and those
+/// already on their way are not asked for again.
+struct Fetcher {
+    git_dir: PathBuf,
+    remote: String,
+    state: Mutex<FetchState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct FetchState {
+    // objects to fetch once the running fetch is done
+    pending: HashSet<Oid>,
+    // objects of the running fetch
+    fetching: HashSet<Oid>,
+    // object => when fetching it failed and why, not fetched again until later
+    failed: HashMap<Oid, (Instant, String)>,
+    // whether the fetch thread is there
+    running: bool,
+}
+
+impl Fetcher {
+    /// The fetcher of the repository at `git_dir`, created unless one is in use
+    fn shared(git_dir: PathBuf, remote: String) -> Arc<Fetcher> {
+        let mut fetchers = FETCHERS.lock().unwrap();
+        fetchers.retain(|_, fetcher| fetcher.strong_count() > 0);
+        if let Some(fetcher) = fetchers.get(&git_dir).and_then(Weak::upgrade) {
+            return fetcher;
+        }
+        let fetcher = Arc::new(Fetcher {
+            git_dir: git_dir.clone(),
+            remote,
+            state: Mutex::new(FetchState::default()),
+            changed: Condvar::new(),
+        });
+        fetchers.insert(git_dir, Arc::downgrade(&fetcher));
+        fetcher
+    }
+
+    /// Fetch objects from the promisor remote, waiting for them at most as
+    /// long as the fetches they are in may take
+    fn fetch(self: &Arc<Self>, hashes: &[Oid]) -> Result<(), GitError> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failed
+            .retain(|_, (time, _)| time.elapsed() < RETRY_DELAY);
+        if let Some((_, e)) = hashes.iter().find_map(|hash| state.failed.get(hash)) {
+            return Err(GitError::Other(git2::Error::from_str(e)));
+        }
+
+        for hash in hashes {
+            if !state.fetching.contains(hash) {
+                state.pending.insert(*hash);
+            }
+        }
+        if !state.running {
+            let fetcher = self.clone();
+            let spawned = thread::Builder::new()
+                .name("promisor-fetch".into())
+                .spawn(move || fetcher.run());
+            if let Err(e) = spawned {
+                return Err(GitError::Other(git2::Error::from_str(&e.to_string())));
+            }
+            state.running = true;
+        }
+
+        // the fetch running, then the one they are in
+        let (state, waited) = self
+            .changed
+            .wait_timeout_while(state, FETCH_TIMEOUT * 2, |state| {
+                hashes
+                    .iter()
+                    .any(|hash| state.pending.contains(hash) || state.fetching.contains(hash))
+            })
+            .unwrap();
+        if waited.timed_out() {
+            return Err(GitError::Other(git2::Error::from_str(&format!(
+                "timed out fetching {} objects from {}",
+                hashes.len(),
+                self.remote
+            ))));
+        }
+        match hashes.iter().find_map(|hash| state.failed.get(hash)) {
+            Some((_, e)) => Err(GitError::Other(git2::Error::from_str(e))),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetch what is pending until nothing is
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.pending.is_empty() {
+            let batch: Vec<Oid> = state.pending.drain().collect();
+            state.fetching.extend(&batch);
+            drop(state);
+
+            debug!("fetching {} objects from {}", batch.len(), self.remote);
+            let start = Instant::now();
+            let result = self.run_fetch(&batch);
+            state = self.state.lock().unwrap();
+            for hash in &batch {
+                state.fetching.remove(hash);
+            }
+            match result {
+                Ok(()) => info!(
+                    "fetched {} objects from {} in {:?}",
+                    batch.len(),
+                    self.remote,
+                    start.elapsed()
+                ),
+                Err(e) => {
+                    let e = format!("unable to fetch from {}: {}", self.remote, e);
+                    for hash in batch {
+                        state.failed.insert(hash, (Instant::now(), e.clone()));
+                    }
+                }
+            }
+            self.changed.notify_all();
+        }
+        state.running = false;
+    }
+
+    /// Run the fetch git runs for missing objects, see `promisor-remote.c`,
+    /// killing it past `FETCH_TIMEOUT`
+    fn run_fetch(&self, hashes: &[Oid]) -> Result<()> {
+        let url = Config::open(&self.git_dir.join("config"))
+            .and_then(|config| config.get_string(&format!("remote.{}.url", self.remote)))
+            .unwrap_or_default();
+        let mut command = Command::new("git");
+        credentials::apply(&mut command, &self.remote, &url);
+        let mut child = command
+            .arg("--git-dir")
+            .arg(&self.git_dir)
+            .args([
+                "-c",
+                "fetch.negotiationAlgorithm=noop",
+                "fetch",
+                "--quiet",
+                "--no-tags",
+                "--no-write-fetch-head",
+                "--recurse-submodules=no",
+                "--filter=blob:none",
+                "--stdin",
+            ])
+            .arg(&self.remote)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("no stdin"))?;
+        for hash in hashes {
+            writeln!(stdin, "{}", hash)?;
+        }
+        drop(stdin);
+
+        // read as it comes, git would block on a full pipe
+        let mut stderr = child.stderr.take().ok_or_else(|| anyhow!("no stderr"))?;
+        let errors = thread::spawn(move || {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if start.elapsed() > FETCH_TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!("git fetch timed out after {:?}", FETCH_TIMEOUT));
+            }
+            thread::sleep(FETCH_POLL_INTERVAL);
+        };
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "git fetch exited with {}: {}",
+                status,
+                errors.join().unwrap_or_default().trim()
+            ))
+        }
+    }
+}
+
+impl ObjectStore for PromisorStore {
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        self.read(hash, |store| store.header(hash))
+    }
+
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+        self.read(hash, |store| store.tree(hash))
+    }
+
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        self.read(hash, |store| store.blob(hash))
+    }
+
+    fn prefetch(&self, hashes: &[Oid]) {
+        let missing: Vec<_> = hashes
+            .iter()
+            .copied()
+            .filter(|hash| matches!(self.inner.header(*hash), Err(GitError::Missing)))
+            .collect();
+        if !missing.is_empty() {
+            // reads report it
+            let _ = self.fetcher.fetch(&missing);
+        }
+    }
+}
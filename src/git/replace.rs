@@ -0,0 +1,112 @@
+//! Replace refs: `git replace` records under `refs/replace/<oid>` an object to
+//! read in place of `<oid>`, which libgit2 does not do. Objects are read in
+//! place of the ones they replace, as git reads them, unless turned off with
+//! `--no-replace-objects`, GIT_NO_REPLACE_OBJECTS or `core.useReplaceRefs`.
+
+use super::{Entry, GitError, ObjectStore};
+use git2::{ObjectType, Oid, Repository};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Replacements of replacements followed, as many as git follows
+const MAX_DEPTH: usize = 5;
+
+/// Replaced object => object read in its place
+#[derive(Clone, Default)]
+pub struct Replacements(HashMap<Oid, Oid>);
+
+impl Replacements {
+    /// The replace refs of `repo`, none when `disabled` or turned off by the
+    /// environment or the configuration
+    pub fn read(repo: &Repository, disabled: bool) -> Self {
+        let enabled = !disabled
+            && env::var_os("GIT_NO_REPLACE_OBJECTS").is_none()
+            && repo
+                .config()
+                .and_then(|config| config.get_bool("core.usereplacerefs"))
+                .unwrap_or(true);
+        if !enabled {
+            return Replacements::default();
+        }
+
+        let base = env::var("GIT_REPLACE_REF_BASE").unwrap_or_else(|_| "refs/replace/".into());
+        let base = format!("{}/", base.trim_end_matches('/'));
+        let mut replacements = HashMap::new();
+        let refs = match repo.references_glob(&format!("{}*", base)) {
+            Ok(refs) => refs,
+            Err(e) => {
+                warn!("unable to read replace refs: {}", e);
+                return Replacements::default();
+            }
+        };
+        for reference in refs.flatten() {
+            let replaced = reference
+                .name()
+                .and_then(|name| name.strip_prefix(&base))
+                .and_then(|hex| Oid::from_str(hex).ok());
+            if let (Some(replaced), Some(by)) = (replaced, reference.target()) {
+                replacements.insert(replaced, by);
+            }
+        }
+        if !replacements.is_empty() {
+            debug!("{} objects are replaced", replacements.len());
+        }
+        Replacements(replacements)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The object to read in place of `oid`, `oid` itself when not replaced
+    pub fn get(&self, oid: Oid) -> Oid {
+        let mut current = oid;
+        for _ in 0..MAX_DEPTH {
+            match self.0.get(&current) {
+                Some(by) => current = *by,
+                None => return current,
+            }
+        }
+        warn!("replacements of {} go more than {} deep", oid, MAX_DEPTH);
+        current
+    }
+}
+
+/// Objects read through another store in place of those they replace
+pub struct ReplacingStore {
+    inner: Box<dyn ObjectStore>,
+    replacements: Replacements,
+}
+
+impl ReplacingStore {
+    pub fn new(inner: Box<dyn ObjectStore>, replacements: Replacements) -> Self {
+        ReplacingStore {
+            inner,
+            replacements,
+        }
+    }
+}
+
+impl ObjectStore for ReplacingStore {
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        self.inner.header(self.replacements.get(hash))
+    }
+
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+        self.inner.tree(self.replacements.get(hash))
+    }
+
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        self.inner.blob(self.replacements.get(hash))
+    }
+
+    fn prefetch(&self, hashes: &[Oid]) {
+        let hashes: Vec<_> = hashes
+            .iter()
+            .map(|hash| self.replacements.get(*hash))
+            .collect();
+        self.inner.prefetch(&hashes)
+    }
+}
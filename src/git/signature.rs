@@ -0,0 +1,93 @@
+//! Signatures of commits, verified by `git verify-commit` so that the
+//! `gpg.program`, `gpg.format` and keyrings git is configured with are used.
+
+use anyhow::Result;
+use git2::Oid;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Outcome of verifying the signature of a commit
+#[derive(Clone, Debug, Default)]
+pub struct Verification {
+    /// `valid`, `bad`, `expired`, `revoked`, `unknown-key`, `unsigned` or
+    /// `error`
+    pub status: &'static str,
+    pub signer: Option<String>,
+    /// Fingerprint of the key when known, its id otherwise
+    pub key: Option<String>,
+}
+
+impl Verification {
+    pub fn unsigned() -> Self {
+        Verification {
+            status: "unsigned",
+            ..Verification::default()
+        }
+    }
+
+    /// Not verified, git could not be run
+    pub fn failed() -> Self {
+        Verification {
+            status: "error",
+            ..Verification::default()
+        }
+    }
+}
+
+/// Verify the signature of `commit`, which has one
+pub fn verify(git_dir: &Path, commit: Oid) -> Result<Verification> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(git_dir)
+        .args(["verify-commit", "--raw"])
+        .arg(commit.to_string())
+        .stdin(Stdio::null())
+        .output()?;
+    let raw = String::from_utf8_lossy(&output.stderr);
+
+    let mut verification = Verification {
+        status: if output.status.success() {
+            "valid"
+        } else {
+            "error"
+        },
+        ..Verification::default()
+    };
+    for line in raw.lines() {
+        // GnuPG status lines, see doc/DETAILS of GnuPG
+        if let Some(status) = line.strip_prefix("[GNUPG:] ") {
+            let mut words = status.splitn(3, ' ');
+            let (keyword, key, rest) = (words.next(), words.next(), words.next());
+            let status = match keyword {
+                Some("GOODSIG") => "valid",
+                Some("BADSIG") => "bad",
+                Some("EXPSIG") | Some("EXPKEYSIG") => "expired",
+                Some("REVKEYSIG") => "revoked",
+                Some("NO_PUBKEY") => "unknown-key",
+                Some("VALIDSIG") => {
+                    verification.key = key.map(str::to_string);
+                    continue;
+                }
+                _ => continue,
+            };
+            verification.status = status;
+            // VALIDSIG gives the fingerprint, the others the id of the key
+            if verification.key.is_none() {
+                verification.key = key.map(str::to_string);
+            }
+            if let Some(user) = rest {
+                verification.signer = Some(user.to_string());
+            }
+        // ssh signatures, `Good "git" signature for <principal> with <kind> key <fingerprint>`
+        } else if let Some(rest) = line.strip_prefix("Good \"git\" signature for ") {
+            if let Some((signer, key)) = rest.split_once(" with ") {
+                verification.signer = Some(signer.to_string());
+                verification.key = key.rsplit(' ').next().map(str::to_string);
+            }
+        }
+    }
+    if !output.status.success() && verification.status == "valid" {
+        verification.status = "error";
+    }
+    Ok(verification)
+}
@@ -0,0 +1,119 @@
+//! `--sparse-patterns`: only the paths a sparse checkout with these patterns
+//! would write are shown. Files written by `git sparse-checkout set --cone`
+//! are read as cone patterns: whole directories, and the files directly in
+//! their parents. Any other file is read as gitignore-style patterns naming
+//! what is included, the last matching pattern of the deepest matching path
+//! deciding, and directories shown as long as they hold an included path.
+
+use super::pattern::Pattern;
+use super::view::ancestors;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// The rules of the sparse-checkout patterns in `file`
+pub fn read_sparse_patterns(file: &Path) -> Result<Rules> {
+    let text = fs::read_to_string(file)
+        .map_err(|e| anyhow!("unable to read {}: {}", file.display(), e))?;
+    Ok(Rules::parse(&text))
+}
+
+/// What a sparse-checkout file includes
+pub enum Rules {
+    Cone {
+        // directories included with all they hold
+        recursive: HashSet<Vec<u8>>,
+        // directories whose files are included, the root as an empty path
+        parents: HashSet<Vec<u8>>,
+    },
+    Patterns(Vec<Pattern>),
+}
+
+impl Rules {
+    fn parse(text: &str) -> Rules {
+        let lines: Vec<_> = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        Rules::cone(&lines).unwrap_or_else(|| {
+            Rules::Patterns(
+                lines
+                    .iter()
+                    .filter_map(|line| Pattern::parse(line))
+                    .collect(),
+            )
+        })
+    }
+
+    /// Cone patterns as git writes them, None when a line is not one:
+    /// `/*` and `!/*/` for the root, `/dir/` for a directory, followed by
+    /// `!/dir/*/` when only its files are included
+    fn cone(lines: &[&str]) -> Option<Rules> {
+        let (mut recursive, mut parents) = (HashSet::new(), HashSet::new());
+        for line in lines {
+            match *line {
+                "/*" => {
+                    parents.insert(Vec::new());
+                }
+                "!/*/" => {}
+                _ => match line.strip_prefix("!/").and_then(|l| l.strip_suffix("/*/")) {
+                    Some(dir) if !dir.contains('*') => {
+                        recursive.remove(dir.as_bytes());
+                        parents.insert(dir.as_bytes().to_vec());
+                    }
+                    Some(_) => return None,
+                    None => {
+                        let dir = line.strip_prefix('/')?.strip_suffix('/')?;
+                        if dir.is_empty() || dir.contains(&['*', '?', '[', '\\', '!'][..]) {
+                            return None;
+                        }
+                        recursive.insert(dir.as_bytes().to_vec());
+                    }
+                },
+            }
+        }
+        Some(Rules::Cone { recursive, parents })
+    }
+
+    /// Whether the file at `path` is included
+    pub fn includes(&self, path: &[u8]) -> bool {
+        match self {
+            Rules::Cone { recursive, parents } => {
+                let dir = &path[..path.iter().rposition(|&c| c == b'/').unwrap_or(0)];
+                parents.contains(dir) || ancestors(path).any(|dir| recursive.contains(dir))
+            }
+            Rules::Patterns(patterns) => {
+                let (mut path, mut is_dir) = (path, false);
+                loop {
+                    if let Some(pattern) = patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+                        return !pattern.negated;
+                    }
+                    match path.iter().rposition(|&c| c == b'/') {
+                        Some(slash) => path = &path[..slash],
+                        None => return false,
+                    }
+                    is_dir = true;
+                }
+            }
+        }
+    }
+
+    /// For cone patterns, whether the directory at `path` is shown. None for
+    /// other patterns, which have to look at what it holds.
+    pub fn shows_dir(&self, path: &[u8]) -> Option<bool> {
+        match self {
+            Rules::Cone { recursive, parents } => Some(
+                ancestors(path).any(|dir| recursive.contains(dir))
+                    || recursive
+                        .iter()
+                        .chain(parents.iter())
+                        .any(|dir| dir.starts_with(path) && dir.get(path.len()) == Some(&b'/'))
+                    || parents.contains(path)
+                    || recursive.contains(path),
+            ),
+            Rules::Patterns(_) => None,
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! Backends reading objects out of the object database. libgit2 is always
+//! available, gitoxide comes with the `gix` feature.
+
+use super::lfs::LfsStore;
+use super::promisor::{promisor_remote, PromisorStore};
+use super::replace::{Replacements, ReplacingStore};
+use super::{open_repository, Entry, GitError, OpenOptions};
+use anyhow::{anyhow, Result};
+use git2::{ObjectType, Oid, Repository};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// Reads objects on the hot path: headers for attributes, trees for lookups
+/// and listings, blobs for reads
+pub trait ObjectStore: Send {
+    /// Size and kind of an object
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError>;
+    /// Entries of a tree, in tree order
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError>;
+    /// Content of a blob
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError>;
+    /// Make objects about to be read available, when the store can
+    fn prefetch(&self, _hashes: &[Oid]) {}
+}
+
+impl ObjectStore for Repository {
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        Ok(self.odb()?.read_header(hash)?)
+    }
+
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+        let tree = self
+            .find_object(hash, None)?
+            .into_tree()
+            .map_err(|_| GitError::WrongKind)?;
+        Ok(tree
+            .iter()
+            .map(|entry| Entry {
+                name: entry.name_bytes().to_vec(),
+                id: entry.id(),
+                kind: entry.kind(),
+                filemode: entry.filemode(),
+            })
+            .collect())
+    }
+
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        let blob = self
+            .find_object(hash, None)?
+            .into_blob()
+            .map_err(|_| GitError::WrongKind)?;
+        Ok(blob.content().into())
+    }
+}
+
+/// Library trees and blobs are read with
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Libgit2,
+    #[cfg(feature = "gix")]
+    Gix,
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Backend> {
+        match s.to_lowercase().as_str() {
+            "libgit2" => Ok(Backend::Libgit2),
+            #[cfg(feature = "gix")]
+            "gix" => Ok(Backend::Gix),
+            #[cfg(not(feature = "gix"))]
+            "gix" => Err(anyhow!("built without the gix feature")),
+            _ => Err(anyhow!("unknown object backend: {}", s)),
+        }
+    }
+}
+
+/// Open the object database of the repository at `path` with `backend`
+fn open_backend(
+    path: &Path,
+    backend: Backend,
+    options: &OpenOptions,
+) -> Result<Box<dyn ObjectStore>> {
+    Ok(match backend {
+        Backend::Libgit2 => Box::new(open_repository(path, options)?),
+        #[cfg(feature = "gix")]
+        Backend::Gix => Box::new(gix_store::GixStore::open(path)?),
+    })
+}
+
+/// Modification time of a directory, which changes as files come and go
+fn modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Objects of a backend opened again when one is not found, or its pack is
+/// unreadable, after packs were added or removed: `git gc` and `git repack`
+/// move objects into new packs and delete the old ones under the mount, which
+/// backends do not always notice
+struct ReopeningStore {
+    store: RefCell<Box<dyn ObjectStore>>,
+    path: PathBuf,
+    backend: Backend,
+    options: OpenOptions,
+    packs: PathBuf,
+    // modification time of `packs` when `store` was opened
+    opened: Cell<Option<SystemTime>>,
+}
+
+impl ReopeningStore {
+    fn new(path: &Path, backend: Backend, options: &OpenOptions, git_dir: &Path) -> Result<Self> {
+        let packs = git_dir.join("objects").join("pack");
+        let opened = modified(&packs);
+        Ok(ReopeningStore {
+            store: RefCell::new(open_backend(path, backend, options)?),
+            path: path.to_path_buf(),
+            backend,
+            options: options.clone(),
+            packs,
+            opened: Cell::new(opened),
+        })
+    }
+
+    /// Read with the store, once more with a new one if packs changed since
+    /// it was opened
+    fn read<T>(
+        &self,
+        read: impl Fn(&dyn ObjectStore) -> Result<T, GitError>,
+    ) -> Result<T, GitError> {
+        let result = read(&**self.store.borrow());
+        match result {
+            Err(GitError::Missing) | Err(GitError::Corrupt(_)) if self.reopen() => {
+                read(&**self.store.borrow())
+            }
+            result => result,
+        }
+    }
+
+    /// Open the store again if packs changed, whether it was
+    fn reopen(&self) -> bool {
+        let changed = modified(&self.packs);
+        if changed == self.opened.get() {
+            return false;
+        }
+        match open_backend(&self.path, self.backend, &self.options) {
+            Ok(store) => {
+                debug!("packs of {} changed, reopened it", self.path.display());
+                *self.store.borrow_mut() = store;
+                self.opened.set(changed);
+                true
+            }
+            Err(e) => {
+                warn!("unable to reopen {}: {}", self.path.display(), e);
+                false
+            }
+        }
+    }
+}
+
+impl ObjectStore for ReopeningStore {
+    fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+        self.read(|store| store.header(hash))
+    }
+
+    fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+        self.read(|store| store.tree(hash))
+    }
+
+    fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+        self.read(|store| store.blob(hash))
+    }
+
+    fn prefetch(&self, hashes: &[Oid]) {
+        self.store.borrow().prefetch(hashes)
+    }
+}
+
+/// Open the object database of the repository at `path` with `backend`,
+/// fetching missing objects when it is a partial clone, serving LFS pointers as
+/// their objects with `--lfs`, and reading objects in place of those they
+/// replace. It is opened again once `git gc` or `git repack` moved objects.
+pub fn open_store(
+    path: &Path,
+    backend: Backend,
+    options: &OpenOptions,
+) -> Result<Box<dyn ObjectStore>> {
+    let repo = open_repository(path, options)?;
+    let replacements = Replacements::read(&repo, options.no_replace_objects);
+    let store: Box<dyn ObjectStore> =
+        Box::new(ReopeningStore::new(path, backend, options, repo.path())?);
+    let store: Box<dyn ObjectStore> = match promisor_remote(path) {
+        Some((git_dir, remote)) => Box::new(PromisorStore::new(store, git_dir, remote)),
+        None => store,
+    };
+    let store: Box<dyn ObjectStore> = if options.lfs {
+        Box::new(LfsStore::new(store, repo.path().to_path_buf()))
+    } else {
+        store
+    };
+    if replacements.is_empty() {
+        Ok(store)
+    } else {
+        Ok(Box::new(ReplacingStore::new(store, replacements)))
+    }
+}
+
+#[cfg(feature = "gix")]
+mod gix_store {
+    use super::{Entry, GitError, ObjectStore};
+    use anyhow::Result;
+    use git2::{ObjectType, Oid};
+    use gix::objs::tree::EntryKind;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Objects read through gitoxide, whose pack access is faster for bulk reads
+    pub struct GixStore {
+        repo: gix::Repository,
+    }
+
+    impl GixStore {
+        pub fn open(path: &Path) -> Result<Self> {
+            let mut repo = gix::open_opts(path, gix::open::Options::isolated())?;
+            // keep decoded deltas around, trees are read over and over
+            repo.object_cache_size_if_unset(16 << 20);
+            Ok(GixStore { repo })
+        }
+    }
+
+    fn object_id(hash: Oid) -> gix::ObjectId {
+        gix::ObjectId::from_bytes_or_panic(hash.as_bytes())
+    }
+
+    fn corrupt<E: std::fmt::Display>(error: E) -> GitError {
+        GitError::Corrupt(git2::Error::from_str(&error.to_string()))
+    }
+
+    fn kind(kind: gix::object::Kind) -> ObjectType {
+        match kind {
+            gix::object::Kind::Blob => ObjectType::Blob,
+            gix::object::Kind::Tree => ObjectType::Tree,
+            gix::object::Kind::Commit => ObjectType::Commit,
+            gix::object::Kind::Tag => ObjectType::Tag,
+        }
+    }
+
+    impl ObjectStore for GixStore {
+        fn header(&self, hash: Oid) -> Result<(usize, ObjectType), GitError> {
+            let header = self
+                .repo
+                .try_find_header(object_id(hash))
+                .map_err(corrupt)?
+                .ok_or(GitError::Missing)?;
+            Ok((header.size() as usize, kind(header.kind())))
+        }
+
+        fn tree(&self, hash: Oid) -> Result<Vec<Entry>, GitError> {
+            let object = self
+                .repo
+                .try_find_object(object_id(hash))
+                .map_err(corrupt)?
+                .ok_or(GitError::Missing)?;
+            let tree = object.try_into_tree().map_err(|_| GitError::WrongKind)?;
+            let tree = tree.decode().map_err(corrupt)?;
+            tree.entries
+                .iter()
+                .map(|entry| {
+                    let mode = entry.mode;
+                    let kind = match mode.kind() {
+                        EntryKind::Tree => ObjectType::Tree,
+                        EntryKind::Commit => ObjectType::Commit,
+                        EntryKind::Blob | EntryKind::BlobExecutable | EntryKind::Link => {
+                            ObjectType::Blob
+                        }
+                    };
+                    Ok(Entry {
+                        name: entry.filename.to_vec(),
+                        id: Oid::from_bytes(entry.oid.as_bytes())?,
+                        kind: Some(kind),
+                        filemode: mode.0 as i32,
+                    })
+                })
+                .collect()
+        }
+
+        fn blob(&self, hash: Oid) -> Result<Arc<[u8]>, GitError> {
+            let object = self
+                .repo
+                .try_find_object(object_id(hash))
+                .map_err(corrupt)?
+                .ok_or(GitError::Missing)?;
+            if object.kind != gix::object::Kind::Blob {
+                return Err(GitError::WrongKind);
+            }
+            Ok(object.data.as_slice().into())
+        }
+    }
+}
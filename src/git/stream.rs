@@ -0,0 +1,134 @@
+use super::pack::PackedData;
+use flate2::bufread::ZlibDecoder;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+
+/// Where the compressed content of a blob is read from
+#[derive(Clone)]
+enum Source {
+    Loose(PathBuf),
+    Packed(PackedData),
+}
+
+/// Content of a blob, inflated as it is read rather than all at once.
+/// Reads continue from where the previous one stopped, reading backwards
+/// starts over from the beginning of the object.
+pub struct BlobStream {
+    source: Source,
+    reader: ZlibDecoder<Box<dyn BufRead + Send>>,
+    // offset in the content the reader is at
+    position: u64,
+    size: u64,
+    // content inflated ahead of reads, up to `position`
+    ahead: VecDeque<u8>,
+    // whether the last read started where the one before ended
+    sequential: bool,
+}
+
+impl BlobStream {
+    /// Open the loose object at `path`, `None` if it is not a blob
+    pub fn loose(path: PathBuf) -> io::Result<Option<Self>> {
+        let file: Box<dyn BufRead + Send> = Box::new(BufReader::new(File::open(&path)?));
+        let mut reader = ZlibDecoder::new(file);
+
+        // "<kind> <size>\0"
+        let mut header = Vec::new();
+        let mut byte = [0];
+        loop {
+            reader.read_exact(&mut byte)?;
+            match byte[0] {
+                0 => break,
+                _ if header.len() > 32 => return Ok(None),
+                byte => header.push(byte),
+            }
+        }
+        let size = std::str::from_utf8(&header)
+            .ok()
+            .and_then(|header| header.strip_prefix("blob "))
+            .and_then(|size| size.parse().ok());
+
+        Ok(size.map(|size| BlobStream {
+            source: Source::Loose(path),
+            reader,
+            position: 0,
+            size,
+            ahead: VecDeque::new(),
+            sequential: true,
+        }))
+    }
+
+    /// Stream a blob stored whole in a pack
+    pub fn packed(data: PackedData, size: u64) -> Self {
+        BlobStream {
+            reader: ZlibDecoder::new(Box::new(data.clone())),
+            source: Source::Packed(data),
+            position: 0,
+            size,
+            ahead: VecDeque::new(),
+            sequential: true,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    /// Number of bytes inflated ahead of reads
+    pub fn buffered(&self) -> usize {
+        self.ahead.len()
+    }
+
+    /// Read up to `size` bytes at `offset`
+    pub fn read_at(&mut self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let start = self.position - self.ahead.len() as u64;
+        self.sequential = offset == start;
+        if offset < start {
+            *self = match self.source.clone() {
+                Source::Loose(path) => BlobStream::loose(path)?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "object is no longer a blob")
+                })?,
+                Source::Packed(data) => BlobStream::packed(data, self.size),
+            };
+            self.sequential = false;
+        }
+
+        // what was inflated ahead comes first
+        let start = self.position - self.ahead.len() as u64;
+        let skip = (offset - start).min(self.ahead.len() as u64) as usize;
+        self.ahead.drain(..skip);
+        let len = self.ahead.len().min(size as usize);
+        let mut data: Vec<u8> = self.ahead.drain(..len).collect();
+        if !self.ahead.is_empty() {
+            return Ok(data);
+        }
+
+        let skip = offset + data.len() as u64 - self.position;
+        self.position += io::copy(&mut self.reader.by_ref().take(skip), &mut io::sink())?;
+
+        let len = data.len();
+        self.reader
+            .by_ref()
+            .take((size as usize - len) as u64)
+            .read_to_end(&mut data)?;
+        self.position += (data.len() - len) as u64;
+        Ok(data)
+    }
+
+    /// Inflate up to `len` bytes ahead of reads, returns how many were
+    pub fn read_ahead(&mut self, len: usize) -> io::Result<usize> {
+        let mut data = Vec::with_capacity(len);
+        self.reader
+            .by_ref()
+            .take(len as u64)
+            .read_to_end(&mut data)?;
+        self.position += data.len() as u64;
+        self.ahead.extend(data.iter());
+        Ok(data.len())
+    }
+}
@@ -0,0 +1,110 @@
+//! `--recurse-submodules`: the repositories of submodules are found where git
+//! clones them, `modules/` of the repository or a checkout of their own, or in
+//! a cache of clones, and their objects read along with those of the
+//! repository. Gitlinks whose commit is then found show as the tree of that
+//! commit, as if the submodule were a directory of the repository.
+
+use super::OpenOptions;
+use anyhow::{anyhow, Result};
+use git2::Repository;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Submodules of submodules followed, and directories looked into for clones
+const MAX_DEPTH: usize = 5;
+
+/// Check that `cache`, given with `--submodule-cache`, is a directory
+pub fn check_submodule_cache(cache: Option<&Path>) -> Result<()> {
+    match cache {
+        Some(cache) if !cache.is_dir() => Err(anyhow!("{} is not a directory", cache.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Add the objects of the submodules of `repo` to its object database
+pub fn add_submodules(repo: &Repository, options: &OpenOptions) -> Result<()> {
+    let odb = repo.odb()?;
+    for objects in submodule_objects(repo, options) {
+        odb.add_disk_alternate(&objects.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+/// Object directories of the submodules of `repo` and of theirs, none unless
+/// `options` recurse into submodules
+pub fn submodule_objects(repo: &Repository, options: &OpenOptions) -> Vec<PathBuf> {
+    if !options.recurse_submodules {
+        return Vec::new();
+    }
+    let mut dirs = Vec::new();
+    collect(repo, 0, &mut dirs);
+    if let Some(cache) = &options.submodule_cache {
+        for path in repositories(cache, 0) {
+            add(&path, 0, &mut dirs);
+        }
+    }
+    let own = repo.path().join("objects");
+    dirs.retain(|dir| *dir != own);
+    dirs
+}
+
+/// Add the objects of the submodules of `repo` to `dirs`
+fn collect(repo: &Repository, depth: usize, dirs: &mut Vec<PathBuf>) {
+    if depth == MAX_DEPTH {
+        return;
+    }
+    // cloned by `git submodule update`, named after the submodule
+    for path in repositories(&repo.path().join("modules"), 0) {
+        add(&path, depth + 1, dirs);
+    }
+    // or cloned in the work tree, by older versions of git
+    if let Ok(submodules) = repo.submodules() {
+        for submodule in submodules {
+            if let Ok(sub) = submodule.open() {
+                add(sub.path(), depth + 1, dirs);
+            }
+        }
+    }
+}
+
+/// Add the objects of the repository at `path` and of its submodules
+fn add(path: &Path, depth: usize, dirs: &mut Vec<PathBuf>) {
+    let repo = match Repository::open(path) {
+        Ok(repo) => repo,
+        Err(e) => {
+            debug!("skipping submodule {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let objects = repo.path().join("objects");
+    if dirs.contains(&objects) {
+        return;
+    }
+    dirs.push(objects);
+    collect(&repo, depth, dirs);
+}
+
+/// Repositories under `dir`, not looked into
+fn repositories(dir: &Path, depth: usize) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            continue;
+        }
+        if path.join(".git").exists()
+            || (path.join("HEAD").is_file() && path.join("objects").is_dir())
+        {
+            found.push(path);
+        } else if depth + 1 < MAX_DEPTH {
+            // names of submodules may have slashes
+            found.extend(repositories(&path, depth + 1));
+        }
+    }
+    found
+}
@@ -1,10 +1,9 @@
 use crate::fs::{FileAttrBuilder, ToFileAttr};
+use crate::git::GitError;
 use crate::inode::{Ino, Inode};
-use anyhow::{anyhow, Result};
-use fuse::FileAttr;
-use git2::{Blob, Commit, Object, Oid, Tree};
+use fuser::FileAttr;
+use git2::{Object, ObjectType, Oid, Tree};
 use std::convert::TryFrom;
-use time::Timespec;
 
 macro_rules! impl_types {
     ($type: ident, $smtype: ident) => {
@@ -12,6 +11,7 @@ macro_rules! impl_types {
             // (inode, commit hash, object)
             pub struct [<Git $type>]<'a>(Ino, Oid, $type<'a>);
 
+            #[allow(dead_code)]
             impl<'a> [<Git $type>]<'a> {
                 pub fn inode(&self) -> Ino {
                     self.0
@@ -35,15 +35,15 @@ macro_rules! impl_types {
             }
 
             impl<'a> TryFrom<(Ino, Oid, Object<'a>)> for [<Git $type>]<'a> {
-                type Error = anyhow::Error;
+                type Error = GitError;
 
-                fn try_from((ino, commit, object): (Ino, Oid, Object<'a>)) -> Result<[<Git $type>]<'a>> {
+                fn try_from((ino, commit, object): (Ino, Oid, Object<'a>)) -> Result<[<Git $type>]<'a>, GitError> {
                     let result = object.[<into_ $smtype>]();
 
                     if let Ok($smtype) = result {
                         Ok([<Git $type>](ino, commit, $smtype))
                     } else {
-                        Err(anyhow!(format!("not expected {}", stringify!($smtype))))
+                        Err(GitError::WrongKind)
                     }
                 }
             }
@@ -51,40 +51,20 @@ macro_rules! impl_types {
     };
 }
 
-impl_types!(Commit, commit);
-
-impl<'a> ToFileAttr for GitCommit<'a> {
-    fn to_file_attr(&self, builder: FileAttrBuilder) -> FileAttr {
-        builder
-            .ino(self.ino())
-            .directory()
-            .time(Timespec::new(self.as_ref().time().seconds(), 0))
-            .nlink(2)
-            .build()
-    }
-}
-
 impl_types!(Tree, tree);
 
 impl<'a> ToFileAttr for GitTree<'a> {
     fn to_file_attr(&self, builder: FileAttrBuilder) -> FileAttr {
+        // `.`, the entry in the parent and the `..` of every subdirectory
+        let subdirs = self
+            .as_ref()
+            .iter()
+            .filter(|entry| entry.kind() == Some(ObjectType::Tree))
+            .count();
         builder
             .ino(self.ino())
             .directory()
-            .nlink(2 + self.as_ref().len() as u32)
-            .build()
-    }
-}
-
-impl_types!(Blob, blob);
-
-impl<'a> ToFileAttr for GitBlob<'a> {
-    fn to_file_attr(&self, builder: FileAttrBuilder) -> FileAttr {
-        builder
-            .ino(self.ino())
-            .file()
-            .size(self.as_ref().size())
-            .nlink(2)
+            .nlink(2 + subdirs as u32)
             .build()
     }
 }
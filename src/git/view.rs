@@ -0,0 +1,204 @@
+//! The paths of commits left shown: those `--sparse-patterns` includes, and
+//! `--include` and `--exclude` globs, matched against the whole path from the
+//! root of the commit. A path is shown when it or a directory it is in
+//! matches an `--include`, if any are given, and neither matches an
+//! `--exclude`. Whether a path is shown depends on it, so like `--filters` a
+//! directory appearing at several paths of a commit is filtered as the path
+//! it was first listed at.
+
+use super::pattern::wildmatch;
+use super::sparse::Rules;
+use super::{Entry, GitError, GitRepo};
+use git2::{ObjectType, Oid};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// `--include` and `--exclude`
+pub struct Globs {
+    include: Vec<Vec<u8>>,
+    exclude: Vec<Vec<u8>>,
+}
+
+impl Globs {
+    /// Only show the paths matching `include`, if any, and not `exclude`,
+    /// None when both are empty
+    pub fn new(include: &[String], exclude: &[String]) -> Option<Self> {
+        if include.is_empty() && exclude.is_empty() {
+            return None;
+        }
+        let globs = |globs: &[String]| {
+            globs
+                .iter()
+                .map(|glob| glob.trim_matches('/').as_bytes().to_vec())
+                .collect()
+        };
+        Some(Globs {
+            include: globs(include),
+            exclude: globs(exclude),
+        })
+    }
+
+    /// Whether `path` or a directory it is in matches one of `globs`
+    fn matching(globs: &[Vec<u8>], path: &[u8]) -> bool {
+        ancestors(path)
+            .chain(Some(path))
+            .any(|path| globs.iter().any(|glob| wildmatch(glob, path)))
+    }
+
+    fn includes(&self, path: &[u8]) -> bool {
+        (self.include.is_empty() || Globs::matching(&self.include, path))
+            && !Globs::matching(&self.exclude, path)
+    }
+
+    /// Whether the directory at `path` is shown, None when that depends on
+    /// what it holds
+    fn shows_dir(&self, path: &[u8]) -> Option<bool> {
+        if Globs::matching(&self.exclude, path) {
+            Some(false)
+        } else if self.include.is_empty() || Globs::matching(&self.include, path) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+/// The directories `path` is in, not counting the root
+pub fn ancestors(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    path.iter()
+        .enumerate()
+        .filter(|&(_, &c)| c == b'/')
+        .map(move |(i, _)| &path[..i])
+}
+
+/// Trees of the commits of a repository with the paths left out hidden
+pub struct View {
+    sparse: Option<Arc<Rules>>,
+    globs: Option<Arc<Globs>>,
+    // (commit, tree) => path of the tree in the commit
+    dirs: RefCell<HashMap<(Oid, Oid), Vec<u8>>>,
+    // (commit, tree) => its entries left shown
+    trees: RefCell<HashMap<(Oid, Oid), Arc<[Entry]>>>,
+    // (tree, path) => whether it holds a path that is shown
+    populated: RefCell<HashMap<(Oid, Vec<u8>), bool>>,
+}
+
+impl View {
+    /// None unless there are `sparse` patterns or `globs`
+    pub fn new(sparse: Option<Arc<Rules>>, globs: Option<Arc<Globs>>) -> Option<Self> {
+        if sparse.is_none() && globs.is_none() {
+            return None;
+        }
+        Some(View {
+            sparse,
+            globs,
+            dirs: RefCell::new(HashMap::new()),
+            trees: RefCell::new(HashMap::new()),
+            populated: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// `entries` of `tree` in `commit` without those left out
+    pub fn entries(
+        &self,
+        repo: &GitRepo,
+        commit: Oid,
+        tree: Oid,
+        entries: Arc<[Entry]>,
+    ) -> Result<Arc<[Entry]>, GitError> {
+        if let Some(entries) = self.trees.borrow().get(&(commit, tree)) {
+            return Ok(entries.clone());
+        }
+
+        let known = self.dirs.borrow().get(&(commit, tree)).cloned();
+        let path = match known {
+            Some(path) => path,
+            None if repo.get_commit_tree_id(commit).ok() == Some(tree) => Vec::new(),
+            // not reached from the root of the commit
+            None => return Ok(entries),
+        };
+
+        let mut shown = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+            let child = join(&path, &entry.name);
+            if entry.kind == Some(ObjectType::Tree) {
+                if !self.shows_dir(repo, entry.id, &child)? {
+                    continue;
+                }
+                self.dirs
+                    .borrow_mut()
+                    .entry((commit, entry.id))
+                    .or_insert(child);
+            } else if !self.includes(&child) {
+                continue;
+            }
+            shown.push(entry.clone());
+        }
+        let shown: Arc<[Entry]> = shown.into();
+        self.trees
+            .borrow_mut()
+            .insert((commit, tree), shown.clone());
+        Ok(shown)
+    }
+
+    /// Whether the file at `path` is shown
+    pub fn includes(&self, path: &[u8]) -> bool {
+        if let Some(rules) = &self.sparse {
+            if !rules.includes(path) {
+                return false;
+            }
+        }
+        match &self.globs {
+            Some(globs) => globs.includes(path),
+            None => true,
+        }
+    }
+
+    /// Whether directory `tree`, at `path`, is shown
+    fn shows_dir(&self, repo: &GitRepo, tree: Oid, path: &[u8]) -> Result<bool, GitError> {
+        let sparse = self
+            .sparse
+            .as_ref()
+            .map_or(Some(true), |r| r.shows_dir(path));
+        let globs = self
+            .globs
+            .as_ref()
+            .map_or(Some(true), |g| g.shows_dir(path));
+        match (sparse, globs) {
+            (Some(false), _) | (_, Some(false)) => Ok(false),
+            (Some(true), Some(true)) => Ok(true),
+            _ => self.populated(repo, tree, path),
+        }
+    }
+
+    /// Whether `tree`, at `path`, holds a path that is shown
+    fn populated(&self, repo: &GitRepo, tree: Oid, path: &[u8]) -> Result<bool, GitError> {
+        let key = (tree, path.to_vec());
+        if let Some(populated) = self.populated.borrow().get(&key) {
+            return Ok(*populated);
+        }
+        let mut populated = false;
+        for entry in repo.tree_entries(tree)?.iter() {
+            let child = join(path, &entry.name);
+            populated = match entry.kind {
+                Some(ObjectType::Tree) => self.shows_dir(repo, entry.id, &child)?,
+                _ => self.includes(&child),
+            };
+            if populated {
+                break;
+            }
+        }
+        self.populated.borrow_mut().insert(key, populated);
+        Ok(populated)
+    }
+}
+
+/// Path of entry `name` of the directory at `dir`
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    if dir.is_empty() {
+        name.to_vec()
+    } else {
+        [dir, b"/", name].concat()
+    }
+}
@@ -1,26 +1,30 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Ino {
     no: u64,
-    parent: u64,
 }
 
 impl Ino {
     pub fn new(no: u64) -> Ino {
-        Ino { no, parent: 1 }
-    }
-
-    pub fn set_parent(&mut self, parent: Ino) {
-        self.parent = parent.no;
+        Ino { no }
     }
 
     pub fn value(&self) -> u64 {
         self.no
     }
 
-    pub fn parent(&self) -> u64 {
-        self.parent
+    /// Namespace of the repository the inode belongs to, see `NAMESPACE_SHIFT`
+    pub fn namespace(&self) -> u64 {
+        (self.no & NAMESPACE_MASK) >> NAMESPACE_SHIFT
+    }
+
+    pub fn in_namespace(self, namespace: u64) -> Ino {
+        Ino::new(self.no & !NAMESPACE_MASK | namespace << NAMESPACE_SHIFT)
     }
 }
 
@@ -34,19 +38,172 @@ pub trait Inode {
     fn ino(&self) -> Ino;
 }
 
+/// Inodes of a mount serving several repositories carry the namespace of
+/// their repository in these bits, numbered from 1. A mount of a single
+/// repository only has namespace 0.
+const NAMESPACE_SHIFT: u32 = 55;
+const NAMESPACE_MASK: u64 = 0xff << NAMESPACE_SHIFT;
+/// Number of repositories a mount can serve
+pub const MAX_NAMESPACES: usize = 0xff;
+
+/// Inode number generator, clones share the same counter
+#[derive(Clone)]
 pub struct InodeGen {
-    next_ino: AtomicU64,
+    next_ino: Arc<AtomicU64>,
 }
 
 impl InodeGen {
-    pub fn new() -> Self {
+    pub fn new(namespace: u64) -> Self {
         InodeGen {
-            next_ino: AtomicU64::new(2),
+            next_ino: Arc::new(AtomicU64::new(Ino::new(2).in_namespace(namespace).value())),
         }
     }
 
     pub fn next(&self) -> Ino {
-        let ino = Ino::new(self.next_ino.fetch_add(1, Ordering::SeqCst));
-        ino
+        Ino::new(self.next_ino.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+const SHARDS: usize = 16;
+/// Inodes derived from object ids have the top bit set, those handed out by
+/// `InodeGen` never do
+const DERIVED: u64 = 1 << 63;
+
+impl Ino {
+    /// Inode derived from the bits of an object id
+    pub fn derived(bits: u64) -> Ino {
+        Ino::new(bits | DERIVED)
+    }
+}
+
+/// Map from inodes to the keys they are derived from, split in shards behind
+/// their own locks so that concurrent requests rarely wait on each other. A
+/// key whose inode is taken by another one gets the next free inode instead.
+pub struct InodeMap<K> {
+    shards: Vec<RwLock<HashMap<Ino, K>>>,
+    // keys that collided => the inode they got instead
+    displaced: RwLock<HashMap<K, Ino>>,
+    // every inode assigned is in it
+    namespace: u64,
+}
+
+impl<K: Copy + Eq + Hash> InodeMap<K> {
+    pub fn new(namespace: u64) -> Self {
+        InodeMap {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            displaced: RwLock::default(),
+            namespace,
+        }
+    }
+
+    fn shard(&self, ino: Ino) -> &RwLock<HashMap<Ino, K>> {
+        &self.shards[ino.value() as usize % SHARDS]
+    }
+
+    /// Assign `key` its inode `ino`, unless another key holds it already.
+    /// Returns the inode of `key` and whether it was just assigned.
+    pub fn assign(&self, key: K, ino: Ino) -> (Ino, bool) {
+        let ino = ino.in_namespace(self.namespace);
+        if self.get(ino) == Some(key) {
+            return (ino, false);
+        }
+        if let Some(ino) = self.displaced.read().unwrap().get(&key) {
+            if self.get(*ino) == Some(key) {
+                return (*ino, false);
+            }
+        }
+
+        match self.shard(ino).write().unwrap().entry(ino) {
+            Entry::Vacant(entry) => {
+                entry.insert(key);
+                return (ino, true);
+            }
+            Entry::Occupied(entry) if *entry.get() == key => return (ino, false),
+            Entry::Occupied(_) => {}
+        }
+
+        // collision, probe for the next inode that is free or already ours
+        let mut displaced = self.displaced.write().unwrap();
+        let mut candidate = ino;
+        loop {
+            candidate =
+                Ino::derived(candidate.value().wrapping_add(1)).in_namespace(self.namespace);
+            match self.shard(candidate).write().unwrap().entry(candidate) {
+                Entry::Vacant(entry) => {
+                    entry.insert(key);
+                    displaced.insert(key, candidate);
+                    return (candidate, true);
+                }
+                Entry::Occupied(entry) if *entry.get() == key => return (candidate, false),
+                Entry::Occupied(_) => {}
+            }
+        }
+    }
+
+    pub fn get(&self, ino: Ino) -> Option<K> {
+        self.shard(ino).read().unwrap().get(&ino).copied()
+    }
+
+    /// Unassign an inode, returning the key it was assigned to
+    pub fn remove(&self, ino: Ino) -> Option<K> {
+        let key = self.shard(ino).write().unwrap().remove(&ino)?;
+        let mut displaced = self.displaced.write().unwrap();
+        if displaced.get(&key) == Some(&ino) {
+            displaced.remove(&key);
+        }
+        Some(key)
+    }
+
+    /// Every inode assigned
+    pub fn inodes(&self) -> Vec<Ino> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.read().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().len())
+            .sum()
+    }
+}
+
+/// Inodes the kernel holds no reference to, least recently used first
+pub struct IdleInodes {
+    // inode => last use
+    inodes: HashMap<u64, u64>,
+    // last use => inode
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl IdleInodes {
+    pub fn new() -> Self {
+        IdleInodes {
+            inodes: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn insert(&mut self, ino: u64) {
+        self.remove(ino);
+        self.tick += 1;
+        self.inodes.insert(ino, self.tick);
+        self.order.insert(self.tick, ino);
+    }
+
+    pub fn remove(&mut self, ino: u64) {
+        if let Some(tick) = self.inodes.remove(&ino) {
+            self.order.remove(&tick);
+        }
+    }
+
+    pub fn pop_oldest(&mut self) -> Option<u64> {
+        let (_, ino) = self.order.pop_first()?;
+        self.inodes.remove(&ino);
+        Some(ino)
     }
 }
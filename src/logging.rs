@@ -0,0 +1,234 @@
+//! Where log messages go besides stderr: a rotated file, syslog or the
+//! journal. Filtering is left to env_logger either way, with filters that can
+//! be replaced while mounted.
+
+use anyhow::{anyhow, Result};
+use env_logger::filter::{self, Filter};
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Rotated log files kept besides the current one, as `<file>.1` and so on
+const ROTATED_FILES: usize = 3;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+// LOG_DAEMON, see syslog(3)
+const SYSLOG_FACILITY: u8 = 3;
+const IDENTIFIER: &str = "giblefs";
+
+lazy_static! {
+    // replaced by `set_filters`
+    static ref FILTER: RwLock<Filter> = RwLock::new(filter::Builder::new().build());
+}
+
+pub enum Target {
+    Stderr,
+    /// Appended to, and rotated once it grows past `max_bytes`
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+    },
+    Syslog,
+    Journald,
+}
+
+enum Sink {
+    // formats as env_logger does, whatever it is told to filter
+    Stderr(Box<env_logger::Logger>),
+    File(RotatingFile),
+    Syslog(UnixDatagram),
+    Journald(UnixDatagram),
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_bytes,
+            written: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// `<file>` becomes `<file>.1`, `<file>.1` becomes `<file>.2`, and so on
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..ROTATED_FILES).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = RotatingFile::open(&self.path, self.max_bytes)?;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// A field of the journal native protocol, values with newlines have to be
+/// sent as their length followed by their bytes
+fn journal_field(message: &mut Vec<u8>, name: &str, value: &str) {
+    message.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        message.push(b'\n');
+        message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        message.push(b'=');
+    }
+    message.extend_from_slice(value.as_bytes());
+    message.push(b'\n');
+}
+
+impl Sink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self {
+            Sink::Stderr(logger) => {
+                logger.log(record);
+                Ok(())
+            }
+            Sink::File(file) => file.write(&format!(
+                "[{} {:<5} {}] {}\n",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                record.target(),
+                record.args()
+            )),
+            Sink::Syslog(socket) => {
+                let message = format!(
+                    "<{}>{}[{}]: {}: {}",
+                    SYSLOG_FACILITY * 8 + severity(record.level()),
+                    IDENTIFIER,
+                    process::id(),
+                    record.target(),
+                    record.args()
+                );
+                socket.send(message.as_bytes()).map(|_| ())
+            }
+            Sink::Journald(socket) => {
+                let mut message = Vec::new();
+                journal_field(&mut message, "MESSAGE", &record.args().to_string());
+                journal_field(
+                    &mut message,
+                    "PRIORITY",
+                    &severity(record.level()).to_string(),
+                );
+                journal_field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+                journal_field(&mut message, "SYSLOG_PID", &process::id().to_string());
+                journal_field(&mut message, "TARGET", record.target());
+                if let (Some(file), Some(line)) = (record.file(), record.line()) {
+                    journal_field(&mut message, "CODE_FILE", file);
+                    journal_field(&mut message, "CODE_LINE", &line.to_string());
+                }
+                socket.send_to(&message, JOURNALD_SOCKET).map(|_| ())
+            }
+        }
+    }
+}
+
+struct Logger {
+    sink: Mutex<Sink>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if FILTER.read().unwrap().matches(record) {
+            // nowhere left to report failing to log
+            let _ = self.sink.lock().unwrap().write(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::File(file) = &mut *self.sink.lock().unwrap() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Install the logger, filtered as `filter` says and writing to `target`
+pub fn init(mut filter: filter::Builder, target: Target) -> Result<()> {
+    let sink = match target {
+        Target::Stderr => {
+            let mut logger = env_logger::Builder::new();
+            logger.filter_level(LevelFilter::Trace);
+            if let Ok(style) = env::var("RUST_LOG_STYLE") {
+                logger.parse_write_style(&style);
+            }
+            Sink::Stderr(Box::new(logger.build()))
+        }
+        Target::File { path, max_bytes } => Sink::File(RotatingFile::open(&path, max_bytes)?),
+        Target::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(SYSLOG_SOCKET)?;
+            Sink::Syslog(socket)
+        }
+        Target::Journald => Sink::Journald(UnixDatagram::unbound()?),
+    };
+
+    let filter = filter.build();
+    log::set_max_level(filter.filter());
+    *FILTER.write().unwrap() = filter;
+    log::set_boxed_logger(Box::new(Logger {
+        sink: Mutex::new(sink),
+    }))?;
+    Ok(())
+}
+
+/// Replace the filters of the logger, written as GIBLEFS_LOG is, e.g.
+/// `debug` or `warn,giblefs::fs=trace`
+pub fn set_filters(filters: &str) -> Result<()> {
+    // env_logger skips invalid directives with a warning on stderr, which
+    // nobody reads once mounted
+    let directives = filters.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').filter(|d| !d.is_empty()) {
+        if let Some((_, level)) = directive.split_once('=') {
+            if !level.is_empty() && level.trim().parse::<LevelFilter>().is_err() {
+                return Err(anyhow!("invalid level in {:?}", directive));
+            }
+        }
+    }
+
+    let filter = filter::Builder::new().parse(filters).build();
+    log::set_max_level(filter.filter());
+    *FILTER.write().unwrap() = filter;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! `giblefs ls`, lists a directory of a revision the way the mount shows it,
+//! without going through FUSE.
+
+use crate::fs::{self, local::Listed, GilberFS};
+use crate::git;
+use crate::show::ShowOptions;
+use anyhow::{Context, Result};
+use fuser::{FileAttr, FileType};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct LsOptions {
+    /// Path to the git repository
+    repo: PathBuf,
+
+    /// Look for the repository in the parent directories of its path too
+    #[structopt(long)]
+    search: bool,
+
+    /// Revision and, after a colon, path to list, e.g. `master:src`
+    #[structopt(value_name = "REV[:PATH]")]
+    target: String,
+
+    #[structopt(flatten)]
+    show: ShowOptions,
+}
+
+fn print(attr: &FileAttr, object: Option<(i32, git2::Oid)>, name: &OsStr) {
+    let kind = match attr.kind {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        _ => '-',
+    };
+    let perm: String = (0..9)
+        .map(|bit| match attr.perm & (0o400 >> bit) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][bit % 3],
+        })
+        .collect();
+    // files the mount adds are in no tree
+    let (filemode, id) = match object {
+        Some((filemode, id)) => (format!("{:06o}", filemode), id.to_string()),
+        None => ("-".into(), "-".into()),
+    };
+    println!(
+        "{}{} {:>6} {:>10} {:>40} {}",
+        kind,
+        perm,
+        filemode,
+        attr.size,
+        id,
+        name.to_string_lossy()
+    );
+}
+
+pub fn run(options: LsOptions) -> Result<()> {
+    let mut fs_options = fs::Options {
+        threads: 1,
+        ..Default::default()
+    };
+    options.show.apply(&mut fs_options)?;
+    let mut fs = GilberFS::local(
+        git::locate_env(&options.repo, options.search)?,
+        options.show.inject.clone(),
+        fs_options,
+    )?;
+
+    let attr = fs.resolve(&options.target)?;
+    if attr.kind != FileType::Directory {
+        let name = options.target.rsplit(&[':', '/'][..]).next().unwrap();
+        let object = fs.object(attr.ino);
+        print(&attr, object, OsStr::new(name));
+        return Ok(());
+    }
+
+    let listed = fs.list(attr.ino).with_context(|| options.target.clone())?;
+    for Listed { name, attr, object } in listed {
+        print(&attr, object, &name);
+    }
+    Ok(())
+}
@@ -1,52 +1,847 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use nix::unistd::{getgid, getuid};
-use std::ffi::OsStr;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::env;
+use std::ffi::OsString;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
+use tracing::warn;
 
+mod cache;
+mod cat;
+mod check;
+mod control;
+mod credentials;
+mod diff;
+mod discover;
+mod fetch;
 mod fs;
 mod git;
 mod inode;
+mod logging;
+mod ls;
+mod metrics;
+mod mount;
+mod owners;
+mod provider;
+mod show;
+mod stats;
+mod stop;
+mod systemd;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod watch;
+mod workers;
+
+/// Expose a git repository as a filesystem, with a directory per commit
+#[derive(StructOpt)]
+#[structopt(name = "giblefs")]
+// parsed once, the size does not matter
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Mount a repository, `giblefs <repo> <mount>` is short for this
+    Mount(Options),
+    /// Compare two revisions without mounting
+    DiffTrees(diff::DiffTreesOptions),
+    /// List a directory of a revision as the mount would, without mounting
+    Ls(ls::LsOptions),
+    /// Print a file of a revision as the mount would, without mounting
+    Cat(cat::CatOptions),
+    /// Check that every object of a revision is present and readable
+    Check(check::CheckOptions),
+    /// Send a command to the control socket of a mount
+    Ctl(control::CtlOptions),
+}
+
+/// Arguments that may come first, anything else is the repository to mount
+const COMMANDS: &[&str] = &[
+    "mount",
+    "diff-trees",
+    "ls",
+    "cat",
+    "check",
+    "ctl",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+/// How often to check whether the mounts are still served without a watchdog
+const UNMOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(StructOpt)]
 struct Options {
+    /// Path to the git repository
     repo: PathBuf,
-    mount: PathBuf,
+    /// Directory to mount it on
+    #[structopt(required_unless = "fuse-fd")]
+    mount: Option<PathBuf>,
+
+    /// Look for the repository in the parent directories of its path too, as
+    /// git does. GIT_DIR or GIT_COMMON_DIR name it instead when set.
+    #[structopt(long)]
+    search: bool,
 
+    /// Log debug messages, short for `--log-level debug`
     #[structopt(short, long)]
     debug: bool,
+
+    /// Log messages of this level and above: `error`, `warn`, `info`, `debug`
+    /// or `trace`. GIBLEFS_LOG or RUST_LOG allow finer filters.
+    #[structopt(long, env = "GIBLEFS_LOG_LEVEL", value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Log to this file instead of stderr, rotated as it grows
+    #[structopt(
+        long,
+        env = "GIBLEFS_LOG_FILE",
+        value_name = "PATH",
+        conflicts_with_all = &["syslog", "journald"]
+    )]
+    log_file: Option<PathBuf>,
+
+    /// Size the log file is rotated at, 10 MiB by default, three rotated
+    /// files are kept
+    #[structopt(
+        long,
+        env = "GIBLEFS_LOG_FILE_BYTES",
+        value_name = "BYTES",
+        requires = "log-file"
+    )]
+    log_file_bytes: Option<u64>,
+
+    /// Log to syslog instead of stderr
+    #[structopt(long, conflicts_with = "journald")]
+    syslog: bool,
+
+    /// Log to the systemd journal instead of stderr
+    #[structopt(long)]
+    journald: bool,
+
+    /// Export a span per FUSE operation to this OTLP/HTTP endpoint, e.g.
+    /// `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otlp")]
+    #[structopt(long, env = "GIBLEFS_OTLP_ENDPOINT", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Return once the mount is established and serve it in the background
+    #[structopt(long)]
+    background: bool,
+
+    /// Owner of every file, the user mounting by default
+    #[structopt(long, env = "GIBLEFS_UID")]
+    uid: Option<u32>,
+
+    /// Group of every file, the group of the user mounting by default
+    #[structopt(long, env = "GIBLEFS_GID")]
+    gid: Option<u32>,
+
+    /// Have files owned by the local account of the author of their commit,
+    /// as mapped by this file of `<email> <user>[:<group>]` lines. The
+    /// mailmap of the repository applies first.
+    #[structopt(long, env = "GIBLEFS_OWNERS", value_name = "PATH")]
+    owners: Option<PathBuf>,
+
+    /// Verify the signatures of commits with `git verify-commit`, and show
+    /// the outcome in the extended attributes of their roots
+    #[structopt(long)]
+    verify_signatures: bool,
+
+    /// External metadata provider executable, see `provider.rs` for the protocol
+    #[structopt(long, value_name = "PROGRAM", number_of_values = 1)]
+    provider: Vec<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP at /metrics on this address, e.g.
+    /// `127.0.0.1:9477`
+    #[structopt(long, env = "GIBLEFS_METRICS_ADDR", value_name = "ADDR")]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Listen for control commands on this Unix socket instead of the one in
+    /// /run/giblefs, or $XDG_RUNTIME_DIR/giblefs, named after the mount point
+    #[structopt(long, env = "GIBLEFS_CONTROL", value_name = "PATH")]
+    control: Option<PathBuf>,
+
+    /// Mount options, e.g. `-o noatime,max_read=131072,fsname=repo`
+    #[structopt(short = "o", value_name = "OPTION[,OPTION...]", number_of_values = 1)]
+    mount_options: Vec<String>,
+
+    /// Let other users access the mount
+    #[structopt(long, conflicts_with = "allow-root")]
+    allow_other: bool,
+
+    /// Let root access the mount, besides the user mounting it
+    #[structopt(long)]
+    allow_root: bool,
+
+    /// Have the kernel enforce permissions based on file modes
+    #[structopt(long)]
+    default_permissions: bool,
+
+    /// Unmount even if giblefs is killed, this requires fusermount
+    #[structopt(long)]
+    auto_unmount: bool,
+
+    /// Serve another mount from this process, of this repository or of the
+    /// one given, e.g. `/mnt/b` or `/srv/other=/mnt/b`. Mounts share their
+    /// caches of objects. Can be repeated.
+    #[structopt(
+        long,
+        value_name = "[REPO=]MOUNT",
+        number_of_values = 1,
+        conflicts_with = "fuse-fd"
+    )]
+    mount_also: Vec<mount::ExtraMount>,
+
+    /// Serve another repository from the same mount, e.g. `/srv/b.git` or
+    /// `b=/srv/b.git`. Every repository, the first one included, then shows
+    /// under a directory named after it: `/<name>/<commit>/...`. Can be
+    /// repeated.
+    #[structopt(long, value_name = "[NAME=]PATH", number_of_values = 1)]
+    repo_also: Vec<fs::multi::NamedRepo>,
+
+    /// Serve the repositories listed in this file from the same mount, one
+    /// `[NAME=]PATH` per line, as `--repo-also` does
+    #[structopt(long, env = "GIBLEFS_REPO_LIST", value_name = "PATH")]
+    repo_list: Option<PathBuf>,
+
+    /// Serve every repository found under this directory from the same
+    /// mount, as `--repo-also` does, rescanning it periodically and on SIGHUP
+    #[structopt(long, env = "GIBLEFS_DISCOVER", value_name = "DIR")]
+    discover: Option<PathBuf>,
+
+    /// Seconds between rescans of the `--discover` directory, 60 by default
+    #[structopt(long, value_name = "SECONDS", requires = "discover")]
+    discover_interval: Option<u64>,
+
+    /// Fetch the repositories served from their remote every this many
+    /// seconds, so that branches track upstream
+    #[structopt(long, env = "GIBLEFS_FETCH_INTERVAL", value_name = "SECONDS")]
+    fetch_interval: Option<u64>,
+
+    /// Re-read refs as soon as those of the repositories served change or
+    /// packs are added to them, rather than on SIGHUP only
+    #[structopt(long)]
+    watch: bool,
+
+    /// How to authenticate to a remote when fetching, `REMOTE=METHOD` with
+    /// REMOTE a remote name or a URL covering the URLs of remotes and METHOD one of
+    /// `ssh-agent`, `ssh-key:PATH` or `token-env:VAR`. Can be repeated.
+    #[structopt(long, value_name = "REMOTE=METHOD", number_of_values = 1)]
+    credential: Vec<credentials::Credential>,
+
+    /// Mount even if giblefs is already mounted there, over it
+    #[structopt(long)]
+    force: bool,
+
+    /// Serve an already mounted /dev/fuse file descriptor instead of mounting
+    #[structopt(long, value_name = "FD")]
+    fuse_fd: Option<RawFd>,
+
+    /// Tune the mount for re-exporting to Windows (WSL2, Samba): long TTLs,
+    /// case-insensitive lookups, sizes computed ahead and mangling of names
+    /// Windows cannot represent
+    #[structopt(long)]
+    windows_compat: bool,
+
+    /// Match names case-insensitively in lookups, an exact match wins and
+    /// otherwise the first entry in tree order does
+    #[structopt(long)]
+    icase: bool,
+
+    /// Present names in this Unicode normalization form, `nfc` or `nfd`, and
+    /// look them up in it
+    #[structopt(long, env = "GIBLEFS_NORMALIZE", value_name = "FORM")]
+    normalize: Option<fs::names::Normalization>,
+
+    /// Allow re-exporting the mount over NFS, inode numbers stay valid for the
+    /// lifetime of the mount
+    #[structopt(long)]
+    nfs_export: bool,
+
+    /// Inodes kept mapped at most, unless the kernel holds more. With
+    /// `--nfs-export`, those it forgot that went unused the longest go first.
+    #[structopt(long, env = "GIBLEFS_MAX_INODES", value_name = "COUNT")]
+    max_inodes: Option<usize>,
+
+    /// Bypass the kernel page cache for file contents
+    #[structopt(long, conflicts_with = "kernel-cache")]
+    direct_io: bool,
+
+    /// Keep file contents in the kernel page cache across opens
+    #[structopt(long)]
+    kernel_cache: bool,
+
+    /// Maximum size of the blob contents cached in memory, 0 disables the cache
+    #[structopt(long, env = "GIBLEFS_CACHE_BYTES", value_name = "BYTES")]
+    cache_bytes: Option<usize>,
+
+    /// Walk the tree of this revision before mounting so that the first
+    /// requests find it cached, can be repeated
+    #[structopt(long, value_name = "REV", number_of_values = 1)]
+    preload: Vec<String>,
+
+    /// Also read the headers of the preloaded blobs, which gives their sizes
+    #[structopt(long, requires = "preload")]
+    preload_headers: bool,
+
+    /// Keep blob contents evicted from the cache lz4 compressed in memory, up
+    /// to this size
+    #[structopt(long, env = "GIBLEFS_COMPRESSED_CACHE_BYTES", value_name = "BYTES")]
+    compressed_cache_bytes: Option<usize>,
+
+    /// Keep inflated blobs in this directory, for later mounts to reuse
+    #[structopt(
+        long,
+        env = "GIBLEFS_DISK_CACHE",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    disk_cache: Option<PathBuf>,
+
+    /// Maximum size of the blobs kept in the disk cache, 1 GiB by default
+    #[structopt(
+        long,
+        env = "GIBLEFS_DISK_CACHE_BYTES",
+        value_name = "BYTES",
+        requires = "disk-cache"
+    )]
+    disk_cache_bytes: Option<u64>,
+
+    /// Number of threads inflating blobs and reading streamed ones, one per
+    /// CPU by default
+    #[structopt(long, env = "GIBLEFS_THREADS")]
+    threads: Option<usize>,
+
+    /// Seconds the kernel may cache names and attributes, or `infinite`.
+    /// GIBLEFS_TTL sets it from the environment.
+    #[structopt(long, value_name = "SECONDS", parse(try_from_str = parse_ttl))]
+    ttl: Option<Duration>,
+
+    /// Seconds the kernel may cache looked up names, or `infinite`.
+    /// GIBLEFS_ENTRY_TTL sets it from the environment.
+    #[structopt(long, value_name = "SECONDS", parse(try_from_str = parse_ttl))]
+    entry_ttl: Option<Duration>,
+
+    /// Seconds the kernel may cache attributes, or `infinite`.
+    /// GIBLEFS_ATTR_TTL sets it from the environment.
+    #[structopt(long, value_name = "SECONDS", parse(try_from_str = parse_ttl))]
+    attr_ttl: Option<Duration>,
+
+    /// Seconds names found missing are remembered as such, 0 disables it
+    #[structopt(long, env = "GIBLEFS_NEGATIVE_TTL", value_name = "SECONDS")]
+    negative_ttl: Option<u64>,
+
+    #[structopt(flatten)]
+    show: show::ShowOptions,
+
+    /// Serve requests concurrently from a tokio runtime through fuse3
+    #[cfg(feature = "async")]
+    #[structopt(
+        long = "async",
+        conflicts_with_all = &["fuse-fd", "mount-also", "repo-also", "repo-list", "discover"]
+    )]
+    serve_async: bool,
+}
+
+/// Parse a TTL in seconds, fractions allowed. Objects never change, so
+/// `infinite` is a valid choice: entries for branches are still invalidated
+/// when they move.
+fn parse_ttl(ttl: &str) -> Result<Duration> {
+    if ttl == "infinite" {
+        // the kernel caps timeouts to what it can represent
+        return Ok(Duration::from_secs(u32::MAX as u64));
+    }
+    let secs: f64 = ttl.parse()?;
+    Ok(Duration::try_from_secs_f64(secs)?)
+}
+
+/// TTL set by variable `var` of the environment, if any
+fn env_ttl(var: &str) -> Result<Option<Duration>> {
+    match env::var(var) {
+        Ok(ttl) => parse_ttl(&ttl)
+            .map(Some)
+            .map_err(|e| anyhow!("{}: {}", var, e)),
+        Err(_) => Ok(None),
+    }
 }
 
 fn main() -> Result<()> {
     better_panic::install();
-    env_logger::init();
 
-    let uid = getuid();
-    let gid = getgid();
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let program = args.first().map(std::path::Path::new);
+    if program.and_then(|program| program.file_name()) == Some(mount::HELPER.as_ref()) {
+        match mount::helper_args(&args[1..])? {
+            Some(translated) => args.splice(1.., translated),
+            None => return Ok(()),
+        };
+    }
+    // mounting came before subcommands and keeps its short form
+    if matches!(args.get(1), Some(arg) if !COMMANDS.iter().any(|command| arg == command)) {
+        args.insert(1, "mount".into());
+    }
 
-    let stop = Arc::new(AtomicBool::new(false));
+    match Command::from_iter(args) {
+        Command::Mount(options) => run(options),
+        Command::DiffTrees(options) => diff::run(options),
+        Command::Ls(options) => ls::run(options),
+        Command::Cat(options) => cat::run(options),
+        Command::Check(options) => check::run(options),
+        Command::Ctl(options) => control::ctl(options),
+    }
+}
+
+fn run(mut options: Options) -> Result<()> {
+    // GIBLEFS_LOG takes the same filters as RUST_LOG, and comes first
+    let filter = match env::var_os("GIBLEFS_LOG") {
+        Some(_) => "GIBLEFS_LOG",
+        None => "RUST_LOG",
+    };
+    let mut logger = env_logger::filter::Builder::from_env(filter);
+    if let Some(level) = options.log_level {
+        logger.filter_level(level);
+    }
+    if options.debug {
+        logger.filter_level(log::LevelFilter::Debug);
+    }
+    let target = if let Some(path) = &options.log_file {
+        logging::Target::File {
+            path: path.clone(),
+            max_bytes: options.log_file_bytes.unwrap_or(10 << 20),
+        }
+    } else if options.syslog {
+        logging::Target::Syslog
+    } else if options.journald {
+        logging::Target::Journald
+    } else {
+        logging::Target::Stderr
+    };
+    logging::init(logger, target)?;
 
+    // found the way git finds them, every later open is of the exact path
+    options.repo = git::locate_env(&options.repo, options.search)?;
+    credentials::init(options.credential.clone());
+    for extra in &mut options.mount_also {
+        if let Some(repo) = &mut extra.repo {
+            *repo = git::locate(repo, options.search)?;
+        }
+    }
+
+    let extra_mounts = options.mount_also.iter().map(|extra| &extra.mount);
+    for mount in options.mount.iter().chain(extra_mounts) {
+        mount::recover_stale(mount)?;
+        if !mount.is_dir() {
+            return Err(anyhow!(
+                "mount point {} is not a directory",
+                mount.display()
+            ));
+        }
+        if !options.force {
+            mount::check_not_mounted(mount)?;
+        }
+    }
+    // before any thread is spawned, they would not survive the fork
+    let detached = if options.background {
+        Some(mount::detach()?)
+    } else {
+        None
+    };
+    #[cfg(feature = "otlp")]
+    let _telemetry = match &options.otlp_endpoint {
+        Some(endpoint) => Some(telemetry::init(endpoint)?),
+        None => None,
+    };
+
+    let uid = options.uid.unwrap_or_else(|| getuid().as_raw());
+    let gid = options.gid.unwrap_or_else(|| getgid().as_raw());
+
+    let stop = Arc::new(stop::Stop::new());
+
+    watch::refresh_on_hangup()?;
     ctrlc::set_handler({
         let stop = stop.clone();
         move || {
-            stop.store(true, Ordering::SeqCst);
+            stop.stop();
         }
     })?;
 
-    let options = Options::from_args();
+    let mut mount_options: Vec<String> =
+        vec!["ro".into(), format!("fsname={}", mount::DEFAULT_FSNAME)];
+    if options.allow_other {
+        mount_options.push("allow_other".into());
+    }
+    if options.allow_root {
+        mount_options.push("allow_root".into());
+    }
+    if options.default_permissions {
+        mount_options.push("default_permissions".into());
+    }
+    if options.auto_unmount {
+        mount_options.push("auto_unmount".into());
+    }
+    for list in &options.mount_options {
+        mount::add_options(&mut mount_options, list);
+    }
+    if options.fuse_fd.is_some() && (!options.mount_options.is_empty() || options.auto_unmount) {
+        warn!("ignoring -o: the mount behind --fuse-fd is already established");
+    }
+    if options.fuse_fd.is_none() {
+        mount::check_environment(&mut mount_options)?;
+    }
+    let stats = Arc::new(stats::Stats::new());
+
+    let mut fs_options = if options.windows_compat {
+        fs::Options::windows_compat()
+    } else {
+        fs::Options::default()
+    };
+    fs_options.case_insensitive |= options.icase;
+    fs_options.normalization = options.normalize;
+    fs_options.export = options.nfs_export;
+    fs_options.direct_io = options.direct_io;
+    fs_options.verify_signatures = options.verify_signatures;
+    fs_options.keep_cache = options.kernel_cache;
+    if let Some(cache_bytes) = options.cache_bytes {
+        fs_options.cache_bytes = cache_bytes;
+    }
+    if let Some(compressed_cache_bytes) = options.compressed_cache_bytes {
+        fs_options.compressed_cache_bytes = compressed_cache_bytes;
+    }
+    fs_options.disk_cache = options.disk_cache.clone();
+    if let Some(disk_cache_bytes) = options.disk_cache_bytes {
+        fs_options.disk_cache_bytes = disk_cache_bytes;
+    }
+    if let Some(max_inodes) = options.max_inodes {
+        fs_options.max_inodes = max_inodes;
+    }
+    if let Some(threads) = options.threads {
+        fs_options.threads = threads;
+    }
+    // `--ttl` wins over GIBLEFS_ENTRY_TTL, the command line over the environment
+    let ttl = options.ttl.or(env_ttl("GIBLEFS_TTL")?);
+    let entry_ttl = options
+        .entry_ttl
+        .or(options.ttl)
+        .or(env_ttl("GIBLEFS_ENTRY_TTL")?)
+        .or(ttl);
+    let attr_ttl = options
+        .attr_ttl
+        .or(options.ttl)
+        .or(env_ttl("GIBLEFS_ATTR_TTL")?)
+        .or(ttl);
+    if let Some(entry_ttl) = entry_ttl {
+        fs_options.entry_ttl = entry_ttl;
+    }
+    if let Some(attr_ttl) = attr_ttl {
+        fs_options.attr_ttl = attr_ttl;
+    }
+    if let Some(negative_ttl) = options.negative_ttl {
+        fs_options.negative_ttl = Duration::from_secs(negative_ttl);
+    }
+    options.show.apply(&mut fs_options)?;
+    if let Some(owners) = &options.owners {
+        fs_options.owners = Some(Arc::new(owners::Owners::load(owners)?));
+    }
 
-    let mount_options: Vec<&OsStr> = ["-o", "ro", "-o", "fsname=gilber"]
+    let caches = fs::Caches::new(&fs_options, stats.clone())?;
+    // discovered repositories are opened while the mount is served
+    let new_fs = {
+        let (programs, inject) = (options.provider.clone(), options.show.inject.clone());
+        let (caches, fs_options) = (caches.clone(), fs_options.clone());
+        move |repo: &Path, namespace: u64| {
+            let providers = programs
+                .iter()
+                .map(|program| {
+                    Box::new(provider::CommandProvider::new(program))
+                        as Box<dyn provider::MetadataProvider>
+                })
+                .collect();
+            fs::GilberFS::new(
+                repo.to_path_buf(),
+                uid,
+                gid,
+                inject.clone(),
+                provider::Providers::new(providers),
+                caches.clone(),
+                fs::Options {
+                    namespace,
+                    ..fs_options.clone()
+                },
+            )
+        }
+    };
+    // with other repositories, each shows under a directory of its own
+    let mut also = options.repo_also.clone();
+    if let Some(list) = &options.repo_list {
+        also.extend(fs::multi::read_list(list)?);
+    }
+    for repo in &mut also {
+        repo.path = git::locate(&repo.path, options.search)?;
+    }
+    let multi = !also.is_empty() || options.discover.is_some();
+    let mut discovery = None;
+    let mut served = if !multi {
+        let fs = new_fs(&options.repo, 0)?;
+        vec![(OsString::new(), options.repo.clone(), fs)]
+    } else {
+        let mut repos = vec![fs::multi::NamedRepo::new(options.repo.clone())?];
+        repos.extend(also);
+        fs::multi::check_names(&repos)?;
+        if let Some(dir) = &options.discover {
+            discovery = Some(discover::Discovery::new(dir.clone(), &repos, options.watch));
+        }
+        let mut served = repos
+            .into_iter()
+            .enumerate()
+            .map(|(idx, repo)| {
+                let fs = new_fs(&repo.path, idx as u64 + 1)?;
+                Ok((repo.name, repo.path, fs))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // those discovered are not worth failing the mount over
+        if let Some(discovery) = &mut discovery {
+            let found = discovery.initial(&new_fs);
+            served.extend(
+                found
+                    .into_iter()
+                    .map(|(repo, fs)| (repo.name, repo.path, fs)),
+            );
+        }
+        served
+    };
+    let extra_fs = options
+        .mount_also
         .iter()
-        .map(|x| x.as_ref())
+        .map(|extra| {
+            let repo = extra.repo.as_deref().unwrap_or(&options.repo);
+            Ok((repo.to_path_buf(), extra.mount.clone(), new_fs(repo, 0)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(addr) = options.metrics_addr {
+        let inodes = served.iter().map(|(_, _, fs)| fs.inodes());
+        let extra_inodes = extra_fs.iter().map(|(_, _, fs)| fs.inodes());
+        metrics::spawn(
+            addr,
+            metrics::Metrics {
+                stats: stats.clone(),
+                inodes: inodes.chain(extra_inodes).collect(),
+            },
+        )?;
+    }
+    let target = control::Target {
+        stats: stats.clone(),
+        caches: served[0].2.caches(),
+        stop: stop.clone(),
+    };
+    let _control = match &options.control {
+        Some(path) => Some(control::spawn(path, target)?),
+        // without one of its own, a mount does without its socket if need be
+        None => match options.mount.as_deref().and_then(control::default_path) {
+            Some(path) => control::spawn(&path, target)
+                .map_err(|e| warn!("no control socket at {}: {}", path.display(), e))
+                .ok(),
+            None => None,
+        },
+    };
+    for rev in &options.preload {
+        systemd::notify(&format!("STATUS=Preloading {}", rev));
+        for (_, _, fs) in &served {
+            fs.preload(rev, options.preload_headers)?;
+        }
+    }
+    let mut repos: Vec<_> = served.iter().map(|(_, repo, _)| repo.clone()).collect();
+    for (repo, _, _) in &extra_fs {
+        if !repos.contains(repo) {
+            repos.push(repo.clone());
+        }
+    }
+    // watching stops once they are dropped
+    let mut _watchers = Vec::new();
+    // those discovered are watched and fetched for as long as they are served
+    let fixed: Vec<_> = repos
+        .iter()
+        .filter(|repo| !discovery.as_ref().map_or(false, |d| d.serves(repo)))
+        .collect();
+    if options.watch {
+        for repo in &fixed {
+            _watchers.push(watch::watch_repository(repo)?);
+        }
+    }
+    if let Some(interval) = options.fetch_interval {
+        let fetched = fetch::Fetched::default();
+        for repo in &fixed {
+            fetched.add(repo)?;
+        }
+        if let Some(discovery) = &mut discovery {
+            discovery.fetch(fetched.clone());
+        }
+        fetch::spawn(fetched, Duration::from_secs(interval))?;
+    }
+
+    #[cfg(feature = "async")]
+    if options.serve_async {
+        let mount = options
+            .mount
+            .as_deref()
+            .expect("--async conflicts with --fuse-fd");
+        let (_, _, fs) = served.remove(0);
+        return serve_async(fs, &options.repo, mount, &mount_options, &stop, detached);
+    }
+
+    let mount_options: Vec<_> = mount_options
+        .iter()
+        .map(|option| mount::mount_option(option))
+        .collect();
+    let watched: Vec<_> = served
+        .iter()
+        .map(|(_, repo, fs)| (repo.clone(), fs.root(), fs.branches()))
         .collect();
+    let mount = if !multi {
+        let (_, _, fs) = served.remove(0);
+        spawn(
+            fs,
+            options.fuse_fd,
+            options.mount.as_deref(),
+            &mount_options,
+        )?
+    } else {
+        let repos = served.into_iter().map(|(name, _, fs)| (name, fs));
+        let mut fs = fs::multi::MultiFS::new(repos.collect());
+        let changes = fs.changes();
+        let mount = spawn(
+            fs,
+            options.fuse_fd,
+            options.mount.as_deref(),
+            &mount_options,
+        )?;
+        if let Some(discovery) = discovery {
+            let interval = Duration::from_secs(options.discover_interval.unwrap_or(60));
+            discovery.spawn(interval, new_fs, changes, mount.notifier());
+        }
+        mount
+    };
+    for (repo, root, branches) in watched {
+        watch::spawn(repo, root, branches, mount.notifier())?;
+    }
+    // with the path each is served on, when known
+    let served_on = match options.fuse_fd {
+        Some(_) => None,
+        None => options.mount.clone(),
+    };
+    let mut mounts = vec![(served_on, mount)];
+    // sockets of the other mounts, at their default path only
+    let mut _controls = Vec::new();
+    for (repo, mount, fs) in extra_fs {
+        let target = control::Target {
+            stats: stats.clone(),
+            caches: fs.caches(),
+            stop: stop.clone(),
+        };
+        if let Some(path) = control::default_path(&mount) {
+            match control::spawn(&path, target) {
+                Ok(socket) => _controls.push(socket),
+                Err(e) => warn!("no control socket at {}: {}", path.display(), e),
+            }
+        }
+        let (root, branches) = (fs.root(), fs.branches());
+        let session = fuser::spawn_mount2(fs, &mount, &mount_options)?;
+        watch::spawn(repo, root, branches, session.notifier())?;
+        mounts.push((Some(mount), session));
+    }
+    ready(&options.repo, options.mount.as_deref(), detached);
+
+    // until interrupted or every mount is unmounted, which is checked for as
+    // often as the watchdog wants pings
+    let watchdog = systemd::Watchdog::new();
+    let interval = watchdog
+        .as_ref()
+        .map_or(UNMOUNT_CHECK_INTERVAL, systemd::Watchdog::interval);
+    while !stop.wait(interval) {
+        mounts.retain(|(_, session)| !session.guard.is_finished());
+        if mounts.is_empty() {
+            break;
+        }
+        // alive only if the mounts still served answer
+        let alive = mounts
+            .iter()
+            .all(|(path, _)| path.as_deref().map_or(true, mount::answers));
+        match &watchdog {
+            Some(watchdog) if alive => watchdog.ping(),
+            Some(_) => warn!("a mount stopped answering, not pinging the watchdog"),
+            None => {}
+        }
+    }
+    systemd::notify("STOPPING=1");
+
+    Ok(())
+}
+
+/// Serve `fs` on the mount behind `fuse_fd`, or on `mount`
+fn spawn<FS: fuser::Filesystem + Send + 'static>(
+    fs: FS,
+    fuse_fd: Option<RawFd>,
+    mount: Option<&Path>,
+    mount_options: &[fuser::MountOption],
+) -> Result<fuser::BackgroundSession> {
+    match (fuse_fd, mount) {
+        (Some(fd), _) => mount::spawn_from_fd(fs, fd),
+        (None, Some(mount)) => Ok(fuser::spawn_mount2(fs, mount, mount_options)?),
+        (None, None) => unreachable!("mount is required unless --fuse-fd is given"),
+    }
+}
 
-    let fs = fs::GilberFS::new(options.repo, uid.as_raw(), gid.as_raw())?;
+/// The mount is established: let the process started and systemd know
+fn ready(repo: &Path, mount: Option<&Path>, detached: Option<mount::Detached>) {
+    if let Some(detached) = detached {
+        detached.ready();
+    }
+    let status = match mount {
+        Some(mount) => format!("Serving {} on {}", repo.display(), mount.display()),
+        None => format!("Serving {}", repo.display()),
+    };
+    systemd::notify(&format!("READY=1\nSTATUS={}", status));
+}
 
-    let _mount = unsafe { fuse::spawn_mount(fs, &options.mount, &mount_options)? };
+/// Mount with the fuse3 backend and serve until interrupted
+#[cfg(feature = "async")]
+fn serve_async(
+    fs: fs::GilberFS,
+    repo: &Path,
+    mount: &Path,
+    mount_options: &[String],
+    stop: &stop::Stop,
+    detached: Option<mount::Detached>,
+) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handle = runtime.block_on(fs::aio::mount(
+        fs::aio::AsyncGilberFS::new(fs),
+        mount,
+        mount_options,
+    ))?;
+    // the kernel cannot be notified of branches moving through fuse3
+    warn!("branches are not watched with --async, moves show once the TTL expires");
+    ready(repo, Some(mount), detached);
 
-    while !stop.load(Ordering::SeqCst) {}
+    // fuse3 tells nothing of an unmount, the watchdog is pinged as long as
+    // the mount answers
+    let watchdog = systemd::Watchdog::new();
+    let interval = watchdog
+        .as_ref()
+        .map_or(UNMOUNT_CHECK_INTERVAL, systemd::Watchdog::interval);
+    while !stop.wait(interval) {
+        match &watchdog {
+            Some(watchdog) if mount::answers(mount) => watchdog.ping(),
+            Some(_) => warn!("the mount stopped answering, not pinging the watchdog"),
+            None => {}
+        }
+    }
+    systemd::notify("STOPPING=1");
 
+    runtime.block_on(handle.unmount())?;
     Ok(())
 }
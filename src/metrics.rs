@@ -0,0 +1,97 @@
+//! Prometheus metrics over HTTP, `GET /metrics` on `--metrics-addr`.
+
+use crate::git;
+use crate::inode::InodeMap;
+use crate::stats::Stats;
+use anyhow::Result;
+use git2::Oid;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// What is reported besides the session statistics
+pub struct Metrics {
+    pub stats: Arc<Stats>,
+    // of every mount
+    pub inodes: Vec<Arc<InodeMap<(Oid, Oid)>>>,
+}
+
+impl Metrics {
+    fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        self.stats.write_prometheus(&mut out)?;
+
+        writeln!(out, "# HELP giblefs_inodes Objects assigned an inode")?;
+        writeln!(out, "# TYPE giblefs_inodes gauge")?;
+        let inodes: usize = self.inodes.iter().map(|inodes| inodes.len()).sum();
+        writeln!(out, "giblefs_inodes {}", inodes)?;
+
+        if let Some((current, allowed)) = git::cached_memory() {
+            writeln!(
+                out,
+                "# HELP giblefs_libgit2_cached_bytes Objects cached by libgit2"
+            )?;
+            writeln!(out, "# TYPE giblefs_libgit2_cached_bytes gauge")?;
+            writeln!(out, "giblefs_libgit2_cached_bytes {}", current)?;
+            writeln!(
+                out,
+                "# HELP giblefs_libgit2_cache_limit_bytes Bytes libgit2 caches at most"
+            )?;
+            writeln!(out, "# TYPE giblefs_libgit2_cache_limit_bytes gauge")?;
+            writeln!(out, "giblefs_libgit2_cache_limit_bytes {}", allowed)?;
+        }
+        Ok(out)
+    }
+}
+
+pub fn spawn(addr: SocketAddr, metrics: Metrics) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving metrics on http://{}/metrics", addr);
+
+    // scrapes are few, they are answered one at a time
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| serve(stream, &metrics));
+            if let Err(e) = result {
+                error!("unable to serve metrics: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn serve(stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    // a client that never finishes its request does not hold up the next
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // headers are of no interest
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()?),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".into()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".into()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
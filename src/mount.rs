@@ -0,0 +1,404 @@
+use crate::control;
+use anyhow::{anyhow, Result};
+use fuser::{BackgroundSession, Filesystem, MountOption, Session, SessionACL};
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::mount::{umount2, MntFlags};
+use nix::sys::statfs::statfs;
+use nix::unistd::{self, geteuid, ForkResult};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::str::FromStr;
+use tracing::warn;
+
+const FUSE_DEVICE: &str = "/dev/fuse";
+const FUSE_CONF: &str = "/etc/fuse.conf";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+/// Name of mounts unless `-o fsname=` says otherwise
+pub const DEFAULT_FSNAME: &str = "gilber";
+
+/// Check that a FUSE mount can be established before attempting one, so that
+/// failures come with an explanation instead of a generic mount error.
+///
+/// Options that cannot be honored in this environment are dropped with a warning.
+pub fn check_environment(options: &mut Vec<String>) -> Result<()> {
+    if let Err(e) = OpenOptions::new().read(true).write(true).open(FUSE_DEVICE) {
+        return Err(match e.kind() {
+            ErrorKind::NotFound => anyhow!(
+                "{} does not exist: load the fuse kernel module (`modprobe fuse`), or when \
+                 running in a container, pass the device through (e.g. `--device /dev/fuse`)",
+                FUSE_DEVICE
+            ),
+            ErrorKind::PermissionDenied => anyhow!(
+                "permission denied opening {}: add the current user to the group owning it, \
+                 or when running in a container, allow the device in its cgroup policy",
+                FUSE_DEVICE
+            ),
+            _ => anyhow!("unable to open {}: {}", FUSE_DEVICE, e),
+        });
+    }
+
+    // the kernel knows nothing of it, fusermount watches for the process dying
+    if fusermount().is_none() && options.iter().any(|option| option == "auto_unmount") {
+        warn!("ignoring auto_unmount: it requires fusermount in PATH");
+        options.retain(|option| option != "auto_unmount");
+    }
+
+    if geteuid().is_root() {
+        return Ok(());
+    }
+
+    if fusermount().is_none() {
+        return Err(anyhow!(
+            "fusermount was not found in PATH: unprivileged mounts require it, install the \
+             fuse package of your distribution or run as root"
+        ));
+    }
+
+    if !user_allow_other() {
+        options.retain(|option| {
+            if option == "allow_other" || option == "allow_root" {
+                warn!(
+                    "ignoring {}: it requires `user_allow_other` in {} when not running as root",
+                    option, FUSE_CONF
+                );
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Unmount what a crashed process left at `path`: a FUSE mount nobody serves
+/// anymore fails every access with ENOTCONN.
+pub fn recover_stale(path: &Path) -> Result<()> {
+    match fs::metadata(path) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {}
+        _ => return Ok(()),
+    }
+    warn!("{} is a stale mount, unmounting it", path.display());
+
+    if geteuid().is_root() {
+        umount2(path, MntFlags::MNT_DETACH)?;
+        return Ok(());
+    }
+    let fusermount = fusermount().ok_or_else(|| {
+        anyhow!(
+            "{} is a stale mount and fusermount was not found in PATH to unmount it",
+            path.display()
+        )
+    })?;
+    let status = Command::new(&fusermount).arg("-uz").arg(path).status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "unable to unmount stale mount {}: {} {}",
+            path.display(),
+            fusermount.display(),
+            status
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse to mount over giblefs at `path`: a live FUSE mount there with the
+/// default name, or whose control socket answers
+pub fn check_not_mounted(path: &Path) -> Result<()> {
+    let path = fs::canonicalize(path)?;
+    let mountinfo = fs::read_to_string(MOUNTINFO)?;
+    let fuse_sources: Vec<_> = mountinfo
+        .lines()
+        .filter_map(|line| {
+            // see proc_pid_mountinfo(5), optional fields end with `-`
+            let (fields, filesystem) = line.split_once(" - ")?;
+            let mount_point = unescape_mount_field(fields.split(' ').nth(4)?);
+            let mut filesystem = filesystem.split(' ');
+            let fstype = filesystem.next()?;
+            let source = filesystem.next()?;
+            let fuse = fstype == "fuse" || fstype.starts_with("fuse.");
+            (fuse && Path::new(&mount_point) == path).then(|| source.to_string())
+        })
+        .collect();
+    if fuse_sources.is_empty() {
+        return Ok(());
+    }
+
+    let answers = || {
+        control::default_path(&path)
+            .map(|socket| UnixStream::connect(socket).is_ok())
+            .unwrap_or(false)
+    };
+    if fuse_sources.iter().any(|source| source == DEFAULT_FSNAME) || answers() {
+        return Err(anyhow!(
+            "giblefs is already mounted on {}: unmount it first, or pass --force to mount over it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the FUSE mount at `path` still answers requests: statfs always
+/// reaches the filesystem, the kernel caches none of it
+pub fn answers(path: &Path) -> bool {
+    statfs(path).is_ok()
+}
+
+/// Undo the octal escapes of spaces, tabs, newlines and backslashes in
+/// /proc/self/mountinfo
+fn unescape_mount_field(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(idx) = rest.find('\\') {
+        unescaped.push_str(&rest[..idx]);
+        let code = rest
+            .get(idx + 1..idx + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[idx + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[idx + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Serve a /dev/fuse descriptor that was mounted by someone else, e.g. a
+/// privileged parent process or the container runtime.
+pub fn spawn_from_fd<FS: Filesystem + Send + 'static>(
+    fs: FS,
+    fd: RawFd,
+) -> Result<BackgroundSession> {
+    if fcntl(fd, FcntlArg::F_GETFD).is_err() {
+        return Err(anyhow!("--fuse-fd {} is not an open file descriptor", fd));
+    }
+
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // access control is up to the options the mount was established with
+    Ok(Session::from_fd(fs, fd, SessionACL::All).spawn()?)
+}
+
+/// The process serving the mount once it has detached from the one started
+pub struct Detached {
+    ready: File,
+}
+
+impl Detached {
+    /// Let the process started return, the mount is established
+    pub fn ready(mut self) {
+        let _ = self.ready.write_all(b"1");
+    }
+}
+
+/// Fork for `--background`: the process started exits once the child calls
+/// `Detached::ready`, successfully, or as soon as the child exits without
+/// calling it. Threads do not survive the fork, nothing should run any yet.
+pub fn detach() -> Result<Detached> {
+    let (read, write) = unistd::pipe()?;
+    let (mut read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+    match unistd::fork()? {
+        ForkResult::Parent { .. } => {
+            drop(write);
+            let mut ready = [0];
+            // errors were reported by the child on the shared stderr
+            let code = match read.read(&mut ready) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            process::exit(code);
+        }
+        ForkResult::Child => {
+            drop(read);
+            unistd::setsid()?;
+            // stderr is kept for logs
+            let null = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")?;
+            unistd::dup2(null.as_raw_fd(), 0)?;
+            unistd::dup2(null.as_raw_fd(), 1)?;
+            Ok(Detached { ready: write })
+        }
+    }
+}
+
+/// Name the binary is installed under for mount(8) to find it, as in
+/// `mount -t giblefs <repo> <mount> -o <options>` and fstab entries
+pub const HELPER: &str = "mount.giblefs";
+
+/// Options of fstab entries meant for mount(8) or systemd only, and `rw` which
+/// mount(8) passes unless told `ro`
+const FSTAB_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group", "nofail", "_netdev",
+    "rw",
+];
+
+/// Translate a mount(8) helper invocation, `<repo> <mount> [-sfnv] [-o <options>]`,
+/// into the arguments of `giblefs mount`. Options of the mount go to `-o`, any
+/// other `name=value` becomes `--name value`. None when mount(8) only fakes it.
+pub fn helper_args(args: &[OsString]) -> Result<Option<Vec<OsString>>> {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg
+            .to_str()
+            .ok_or_else(|| anyhow!("{:?} is not valid UTF-8", arg))?;
+        match arg {
+            "-o" => options.extend(args.next().and_then(|list| list.to_str())),
+            _ if arg.starts_with("-o") => options.push(&arg[2..]),
+            // the type is ours, and the helper keeps no mtab
+            "-t" => {
+                args.next();
+            }
+            "-f" => return Ok(None),
+            "-s" | "-n" | "-v" => {}
+            _ if arg.starts_with('-') => return Err(anyhow!("unknown argument {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+    let (repo, mount) = match positional[..] {
+        [repo, mount] => (repo, mount),
+        _ => return Err(anyhow!("usage: {} <repo> <mount> [-o <options>]", HELPER)),
+    };
+
+    // mount(8) waits for the helper to exit
+    let mut translated: Vec<OsString> = vec![
+        "mount".into(),
+        repo.into(),
+        mount.into(),
+        "--background".into(),
+    ];
+    let mut mount_options = Vec::new();
+    for option in options.iter().flat_map(|list| list.split(',')) {
+        let name = option.split('=').next().unwrap_or_default();
+        if option.is_empty()
+            || FSTAB_OPTIONS.contains(&name)
+            || name.starts_with("x-")
+            || name == "comment"
+        {
+            continue;
+        }
+        if !matches!(mount_option(option), MountOption::CUSTOM(_))
+            || name == "max_read"
+            || name == "blksize"
+        {
+            mount_options.push(option);
+            continue;
+        }
+        translated.push(format!("--{}", name.replace('_', "-")).into());
+        if let Some((_, value)) = option.split_once('=') {
+            translated.push(value.into());
+        }
+    }
+    if !mount_options.is_empty() {
+        translated.push("-o".into());
+        translated.push(mount_options.join(",").into());
+    }
+    Ok(Some(translated))
+}
+
+/// Add comma separated `-o` style options, replacing earlier values of the
+/// same option as well as their opposites (`rw` replaces `ro`)
+pub fn add_options(options: &mut Vec<String>, list: &str) {
+    for option in list.split(',').filter(|option| !option.is_empty()) {
+        let key = option_key(option);
+        options.retain(|existing| option_key(existing) != key);
+        options.push(option.into());
+    }
+}
+
+fn option_key(option: &str) -> &str {
+    let name = option.split('=').next().unwrap_or_default();
+    match name {
+        "rw" => "ro",
+        "async" => "sync",
+        "nodev" | "nosuid" | "noexec" | "noatime" => &name[2..],
+        _ => name,
+    }
+}
+
+/// Convert a `-o` style option into its `fuser` counterpart
+pub fn mount_option(option: &str) -> MountOption {
+    match option {
+        "auto_unmount" => MountOption::AutoUnmount,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ if option.starts_with("fsname=") => MountOption::FSName(option[7..].into()),
+        _ if option.starts_with("subtype=") => MountOption::Subtype(option[8..].into()),
+        _ => MountOption::CUSTOM(option.into()),
+    }
+}
+
+fn fusermount() -> Option<PathBuf> {
+    find_in_path("fusermount3").or_else(|| find_in_path("fusermount"))
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| Path::is_file(path))
+}
+
+fn user_allow_other() -> bool {
+    fs::read_to_string(FUSE_CONF)
+        .map(|conf| {
+            conf.lines()
+                .map(|line| line.split('#').next().unwrap_or_default().trim())
+                .any(|line| line == "user_allow_other")
+        })
+        .unwrap_or(false)
+}
+
+/// Another mount served by the same process, of the same repository unless
+/// one is given
+#[derive(Clone, Debug)]
+pub struct ExtraMount {
+    pub repo: Option<PathBuf>,
+    pub mount: PathBuf,
+}
+
+impl FromStr for ExtraMount {
+    type Err = anyhow::Error;
+
+    /// Parse `[REPO=]MOUNT`
+    fn from_str(s: &str) -> Result<Self> {
+        let (repo, mount) = match s.split_once('=') {
+            Some((repo, mount)) => (Some(PathBuf::from(repo)), mount),
+            None => (None, s),
+        };
+        if mount.is_empty() {
+            return Err(anyhow!("expected [REPO=]MOUNT, got {:?}", s));
+        }
+        Ok(ExtraMount {
+            repo,
+            mount: mount.into(),
+        })
+    }
+}
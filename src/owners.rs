@@ -0,0 +1,139 @@
+//! Owners of the files of a commit: the local account of its author.
+//!
+//! The map given to `--owners` has one `<email> <user>[:<group>]` line per
+//! author, users and groups by name or number. Without a group, files belong
+//! to the primary group of the user. Author emails go through the mailmap of
+//! the repository first, so one line covers every address an author used:
+//!
+//! ```text
+//! # email               user[:group]
+//! alice@example.com     alice
+//! bob@example.com       1001:100
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{Group, Uid, User};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Translation of the addresses authors committed with to their canonical
+/// one, see gitmailmap(5). Names are only used to pick an entry.
+#[derive(Default)]
+pub struct Mailmap {
+    // (commit name, commit email) => proper email, the last entry wins
+    entries: Vec<(Option<String>, String, String)>,
+}
+
+impl Mailmap {
+    /// Parse mailmap files, concatenated
+    pub fn parse(text: &str) -> Self {
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            // Proper Name <proper@email> Commit Name <commit@email>, all
+            // but one email optional
+            let mut rest = line;
+            let mut fields = Vec::new();
+            while let Some((name, tail)) = rest.split_once('<') {
+                let (email, tail) = match tail.split_once('>') {
+                    Some(email) => email,
+                    None => break,
+                };
+                let name = name.trim();
+                fields.push(((!name.is_empty()).then(|| name.to_string()), email.trim()));
+                rest = tail;
+            }
+            // only the name of those with a single email changes
+            if let [(_, proper), (name, commit)] = &fields[..] {
+                if !proper.is_empty() {
+                    entries.push((name.clone(), commit.to_lowercase(), proper.to_string()));
+                }
+            }
+        }
+        Mailmap { entries }
+    }
+
+    /// Canonical email of an author, entries matching their name too first
+    pub fn resolve<'a>(&'a self, name: &str, email: &'a str) -> &'a str {
+        let email_lower = email.to_lowercase();
+        let (mut by_name, mut by_email) = (None, None);
+        for (entry_name, entry_email, proper) in &self.entries {
+            if *entry_email != email_lower {
+                continue;
+            }
+            match entry_name {
+                None => by_email = Some(proper),
+                Some(entry_name) if entry_name.eq_ignore_ascii_case(name) => by_name = Some(proper),
+                Some(_) => {}
+            }
+        }
+        by_name.or(by_email).map_or(email, String::as_str)
+    }
+}
+
+/// Local accounts of authors, by email
+pub struct Owners {
+    accounts: HashMap<String, (u32, u32)>,
+}
+
+impl Owners {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        let mut accounts = HashMap::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let (email, account) = match (fields.next(), fields.next(), fields.next()) {
+                (None, _, _) => continue,
+                (Some(email), Some(account), None) => (email, account),
+                _ => {
+                    return Err(anyhow!(
+                        "{}:{}: expected `<email> <user>[:<group>]`",
+                        path.display(),
+                        idx + 1
+                    ))
+                }
+            };
+            let owner =
+                account_ids(account).with_context(|| format!("{}:{}", path.display(), idx + 1))?;
+            accounts.insert(email.to_lowercase(), owner);
+        }
+        Ok(Owners { accounts })
+    }
+
+    /// Uid and gid of the author committing as `name <email>`
+    pub fn owner(&self, mailmap: &Mailmap, name: &str, email: &str) -> Option<(u32, u32)> {
+        let email = mailmap.resolve(name, email).to_lowercase();
+        self.accounts.get(&email).copied()
+    }
+}
+
+/// Uid and gid of `user[:group]`
+fn account_ids(account: &str) -> Result<(u32, u32)> {
+    let (user, group) = match account.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (account, None),
+    };
+    let (uid, primary_gid) = match user.parse() {
+        Ok(uid) => (
+            uid,
+            User::from_uid(Uid::from_raw(uid))?.map(|user| user.gid.as_raw()),
+        ),
+        Err(_) => {
+            let user = User::from_name(user)?.ok_or_else(|| anyhow!("unknown user {}", user))?;
+            (user.uid.as_raw(), Some(user.gid.as_raw()))
+        }
+    };
+    let gid = match group {
+        Some(group) => match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => Group::from_name(group)?
+                .ok_or_else(|| anyhow!("unknown group {}", group))?
+                .gid
+                .as_raw(),
+        },
+        None => primary_gid.ok_or_else(|| anyhow!("uid {} has no account, give its group", uid))?,
+    };
+    Ok((uid, gid))
+}
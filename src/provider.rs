@@ -0,0 +1,287 @@
+//! External metadata providers.
+//!
+//! A provider can contribute extra extended attributes to any object and extra
+//! files to the root of any commit. Besides implementing [`MetadataProvider`]
+//! directly, any executable can act as a provider by speaking this protocol:
+//!
+//! * `<program> files <commit>` prints, for each file, a header line
+//!   `<name> <length>` followed by exactly `<length>` bytes of content.
+//! * `<program> xattrs` is started once and kept running. It reads one
+//!   `<commit> <oid>` line per object on its standard input, and answers each
+//!   with one `<name>=<value>` line per attribute followed by an empty line.
+//!
+//! A non-zero exit status is treated as an error, and so is a program that
+//! does not answer within `TIMEOUT`: it is killed, `xattrs` is started again
+//! for the next object. Names of files and attributes may not be empty, `.`,
+//! `..`, nor hold `/` or NUL.
+
+use anyhow::{anyhow, Result};
+use git2::Oid;
+use std::ffi::{OsStr, OsString};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// How long a provider program has to answer before it is killed
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Extended attributes as (name, value) pairs
+pub type Xattrs = Vec<(OsString, Vec<u8>)>;
+
+pub trait MetadataProvider: Send + Sync {
+    /// Extra files placed in the root of `commit`
+    fn files(&self, _commit: Oid) -> Result<Vec<(OsString, Vec<u8>)>> {
+        Ok(Vec::new())
+    }
+
+    /// Extra extended attributes of object `oid` viewed from `commit`
+    fn xattrs(&self, _commit: Oid, _oid: Oid) -> Result<Xattrs> {
+        Ok(Vec::new())
+    }
+}
+
+/// A provider backed by an external executable
+pub struct CommandProvider {
+    program: PathBuf,
+    // `<program> xattrs`, once started
+    session: Mutex<Option<Session>>,
+}
+
+impl CommandProvider {
+    pub fn new<P: Into<PathBuf>>(program: P) -> Self {
+        CommandProvider {
+            program: program.into(),
+            session: Mutex::new(None),
+        }
+    }
+
+    fn run(&self, args: &[String]) -> Result<Vec<u8>> {
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+
+        // read on a thread of its own, for the wait to time out
+        let (sender, output) = mpsc::channel();
+        thread::Builder::new()
+            .name("provider".into())
+            .spawn(move || {
+                let mut buf = Vec::new();
+                let _ = sender.send(stdout.read_to_end(&mut buf).map(|_| buf));
+            })?;
+        let output = match output.recv_timeout(TIMEOUT) {
+            Ok(output) => output?,
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!(
+                    "{} {:?} did not answer within {:?}",
+                    self.program.display(),
+                    args,
+                    TIMEOUT
+                ));
+            }
+        };
+
+        let status = child.wait()?;
+        if status.success() {
+            Ok(output)
+        } else {
+            Err(anyhow!(
+                "{} {:?} exited with {}",
+                self.program.display(),
+                args,
+                status
+            ))
+        }
+    }
+}
+
+/// `<program> xattrs` kept running, and the lines it prints
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<Vec<u8>>,
+}
+
+impl Session {
+    fn start(program: &Path) -> Result<Self> {
+        let mut child = Command::new(program)
+            .arg("xattrs")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        // read on a thread of its own, for waiting for a line to time out
+        let (sender, lines) = mpsc::channel();
+        thread::Builder::new()
+            .name("provider".into())
+            .spawn(move || {
+                for line in BufReader::new(stdout).split(b'\n') {
+                    match line {
+                        Ok(line) if sender.send(line).is_ok() => {}
+                        _ => break,
+                    }
+                }
+            })?;
+
+        Ok(Session {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    /// Lines answered to `request`, up to the empty line ending them
+    fn ask(&mut self, request: &str) -> Result<Vec<Vec<u8>>> {
+        writeln!(self.stdin, "{}", request)?;
+        self.stdin.flush()?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        let mut lines = Vec::new();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(timeout) {
+                Ok(line) if line.is_empty() => return Ok(lines),
+                Ok(line) => lines.push(line),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(anyhow!("no answer within {:?}", TIMEOUT))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(match self.child.try_wait()? {
+                        Some(status) => anyhow!("exited with {}", status),
+                        None => anyhow!("closed its output"),
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl MetadataProvider for CommandProvider {
+    fn files(&self, commit: Oid) -> Result<Vec<(OsString, Vec<u8>)>> {
+        let output = self.run(&["files".into(), commit.to_string()])?;
+        let mut files = Vec::new();
+        let mut rest = &output[..];
+
+        while !rest.is_empty() {
+            let newline = rest
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| anyhow!("truncated file header"))?;
+            let header = std::str::from_utf8(&rest[..newline])?;
+            let mut parts = header.rsplitn(2, ' ');
+            let length: usize = parts.next().unwrap_or_default().parse()?;
+            let name = parts
+                .next()
+                .ok_or_else(|| anyhow!("invalid file header: {:?}", header))?;
+
+            rest = &rest[newline + 1..];
+            if rest.len() < length {
+                return Err(anyhow!("truncated content for {:?}", name));
+            }
+            files.push((name.into(), rest[..length].to_vec()));
+            rest = &rest[length..];
+        }
+
+        Ok(files)
+    }
+
+    fn xattrs(&self, commit: Oid, oid: Oid) -> Result<Xattrs> {
+        let mut session = self.session.lock().unwrap();
+        let mut running = match session.take() {
+            Some(running) => running,
+            None => Session::start(&self.program)?,
+        };
+        // one that failed is dropped and killed, what it would answer late
+        // belongs to no request
+        let lines = running
+            .ask(&format!("{} {}", commit, oid))
+            .map_err(|e| anyhow!("{} xattrs: {}", self.program.display(), e))?;
+        *session = Some(running);
+
+        Ok(lines
+            .into_iter()
+            .filter_map(|line| {
+                let eq = line.iter().position(|b| *b == b'=')?;
+                Some((
+                    OsString::from_vec(line[..eq].to_vec()),
+                    line[eq + 1..].to_vec(),
+                ))
+            })
+            .collect())
+    }
+}
+
+/// All registered providers, queried in order
+#[derive(Default)]
+pub struct Providers(Vec<Box<dyn MetadataProvider>>);
+
+impl Providers {
+    pub fn new(providers: Vec<Box<dyn MetadataProvider>>) -> Self {
+        Providers(providers)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn files(&self, commit: Oid) -> Vec<(OsString, Vec<u8>)> {
+        self.0
+            .iter()
+            .flat_map(|provider| {
+                provider.files(commit).unwrap_or_else(|e| {
+                    error!("metadata provider failed for {}: {}", commit, e);
+                    Vec::new()
+                })
+            })
+            .filter(|(name, _)| valid_name(name, commit))
+            .collect()
+    }
+
+    pub fn xattrs(&self, commit: Oid, oid: Oid) -> Xattrs {
+        self.0
+            .iter()
+            .flat_map(|provider| {
+                provider.xattrs(commit, oid).unwrap_or_else(|e| {
+                    error!("metadata provider failed for {}: {}", oid, e);
+                    Vec::new()
+                })
+            })
+            .filter(|(name, _)| valid_name(name, oid))
+            .collect()
+    }
+}
+
+/// Whether a provider may name a file or an attribute of `oid` `name`: not
+/// empty, `.` nor `..` and without `/`, as injected files, nor NUL, which
+/// ends names in the kernel
+fn valid_name(name: &OsStr, oid: Oid) -> bool {
+    let bytes = name.as_bytes();
+    let valid = !bytes.is_empty()
+        && name != "."
+        && name != ".."
+        && !bytes.contains(&b'/')
+        && !bytes.contains(&0);
+    if !valid {
+        error!("metadata provider gave {} an invalid name: {:?}", oid, name);
+    }
+    valid
+}
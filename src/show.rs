@@ -0,0 +1,170 @@
+//! Options deciding what the mount shows of a repository, shared by
+//! `giblefs mount` and the commands reading a revision as the mount would.
+
+use crate::fs;
+use crate::git;
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct ShowOptions {
+    /// Show this directory of every commit as its root, e.g. `services/api`.
+    /// Commits without it are not found.
+    #[structopt(long, value_name = "PATH")]
+    subdir: Option<PathBuf>,
+
+    /// Do not serve blobs larger than this as they are, see
+    /// `--oversized-files`
+    #[structopt(long, value_name = "BYTES")]
+    max_file_size: Option<usize>,
+
+    /// What is done with files over `--max-file-size`: `hide` leaves them out
+    /// of their directory, the default, `empty` shows them as empty files
+    #[structopt(long, value_name = "HOW", requires = "max-file-size")]
+    oversized_files: Option<git::Oversized>,
+
+    /// Add a `.git-meta` directory to the root of every commit, holding its
+    /// MESSAGE, AUTHOR, COMMITTER, TIMESTAMP, TREE, DIFF and links to its parents
+    #[structopt(long)]
+    git_meta: bool,
+
+    /// Permissions of every file, in octal, 644 by default
+    #[structopt(long, env = "GIBLEFS_FILE_MODE", value_name = "MODE", parse(try_from_str = parse_mode))]
+    file_mode: Option<u16>,
+
+    /// Permissions of every directory, in octal, 755 by default
+    #[structopt(long, env = "GIBLEFS_DIR_MODE", value_name = "MODE", parse(try_from_str = parse_mode))]
+    dir_mode: Option<u16>,
+
+    /// Permission bits to clear from files and directories, in octal, e.g. 027
+    #[structopt(long, env = "GIBLEFS_UMASK", value_name = "MASK", parse(try_from_str = parse_mode))]
+    umask: Option<u16>,
+
+    /// Inject a file into the root of every commit, e.g. `BUILD_INFO={oid} {describe} {timestamp}`
+    #[structopt(long, value_name = "NAME=TEMPLATE", number_of_values = 1)]
+    pub inject: Vec<fs::InjectedFile>,
+
+    /// Library reading trees and blobs, `libgit2` or, when built with the
+    /// `gix` feature, `gix`
+    #[structopt(long, env = "GIBLEFS_BACKEND", value_name = "NAME")]
+    backend: Option<git::Backend>,
+
+    /// Read objects from this object directory too, as if listed in
+    /// `objects/info/alternates`, e.g. the shared store of a forge. Can be
+    /// repeated.
+    #[structopt(long, value_name = "DIR", number_of_values = 1)]
+    alternate: Vec<PathBuf>,
+
+    /// Read objects as they are rather than what `git replace` replaced them
+    /// with, as GIT_NO_REPLACE_OBJECTS does
+    #[structopt(long)]
+    no_replace_objects: bool,
+
+    /// Show submodules as directories holding the tree of their commit, when
+    /// their repository is found under `modules/` of the repository, in the
+    /// work tree, or in `--submodule-cache`
+    #[structopt(long)]
+    recurse_submodules: bool,
+
+    /// Directory of clones of submodules, looked in when recursing
+    #[structopt(long, value_name = "DIR", requires = "recurse-submodules")]
+    submodule_cache: Option<PathBuf>,
+
+    /// Serve Git LFS pointers as the objects they point to, read from the LFS
+    /// store of the repository or downloaded with `git lfs`
+    #[structopt(long)]
+    lfs: bool,
+
+    /// Serve files as a checkout writes them: line endings converted as their
+    /// `text` and `eol` attributes and `core.autocrlf` ask, and run through
+    /// the smudge command of their filter driver
+    #[structopt(long)]
+    filters: bool,
+
+    /// Expand `$Id$` to `$Id: <blob id> $` in files with the `ident`
+    /// attribute, as a checkout does
+    #[structopt(long)]
+    ident: bool,
+
+    /// Serve commits as `git archive` writes them: converted as `--filters`
+    /// and `--ident` do, `export-subst` placeholders expanded and
+    /// `export-ignore` paths left out
+    #[structopt(long)]
+    archive_view: bool,
+
+    /// Only show the paths a sparse checkout with the patterns of this file
+    /// would write, cone patterns as `git sparse-checkout` writes them or
+    /// gitignore-style ones
+    #[structopt(long, value_name = "FILE")]
+    sparse_patterns: Option<PathBuf>,
+
+    /// Only show paths matching this glob, or in a directory matching it,
+    /// matched against the whole path in the commit, e.g. `src/**/*.rs`.
+    /// Can be repeated.
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    include: Vec<String>,
+
+    /// Hide paths matching this glob, and what the directories matching it
+    /// hold, e.g. `**/tests`. Can be repeated.
+    #[structopt(long, value_name = "GLOB", number_of_values = 1)]
+    exclude: Vec<String>,
+}
+
+/// Parse permission bits written in octal, e.g. `0644`
+fn parse_mode(mode: &str) -> Result<u16> {
+    match u16::from_str_radix(mode, 8)? {
+        mode if mode <= 0o7777 => Ok(mode),
+        mode => Err(anyhow!("not a mode: {:o}", mode)),
+    }
+}
+
+impl ShowOptions {
+    /// Set what these options decide in the tunables of the filesystem
+    pub fn apply(&self, fs_options: &mut fs::Options) -> Result<()> {
+        fs_options.subdir = self.subdir.clone();
+        fs_options.git_meta = self.git_meta;
+        fs_options.max_file_size = self
+            .max_file_size
+            .map(|max| (max, self.oversized_files.unwrap_or(git::Oversized::Hide)));
+        if let Some(backend) = self.backend {
+            fs_options.backend = backend;
+        }
+        #[cfg(feature = "gix")]
+        if fs_options.backend == git::Backend::Gix && !self.alternate.is_empty() {
+            return Err(anyhow!("--alternate is not supported by the gix backend"));
+        }
+        #[cfg(feature = "gix")]
+        if fs_options.backend == git::Backend::Gix && self.recurse_submodules {
+            return Err(anyhow!(
+                "--recurse-submodules is not supported by the gix backend"
+            ));
+        }
+        fs_options.open.alternates = self.alternate.clone();
+        fs_options.open.no_replace_objects = self.no_replace_objects;
+        fs_options.open.recurse_submodules = self.recurse_submodules;
+        fs_options.open.submodule_cache = self.submodule_cache.clone();
+        fs_options.open.lfs = self.lfs;
+        fs_options.open.check()?;
+        fs_options.checkout = git::Checkout {
+            filters: self.filters,
+            ident: self.ident,
+            archive_view: self.archive_view,
+        };
+        if let Some(patterns) = &self.sparse_patterns {
+            fs_options.sparse_patterns = Some(Arc::new(git::read_sparse_patterns(patterns)?));
+        }
+        fs_options.path_globs = git::PathGlobs::new(&self.include, &self.exclude).map(Arc::new);
+        if let Some(file_mode) = self.file_mode {
+            fs_options.file_mode = file_mode;
+        }
+        if let Some(dir_mode) = self.dir_mode {
+            fs_options.dir_mode = dir_mode;
+        }
+        if let Some(umask) = self.umask {
+            fs_options.umask = umask;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,590 @@
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{self, Empty};
+use tracing::span::Entered;
+use tracing::{info, trace_span, Span};
+
+macro_rules! ops {
+    ($($op: ident => $name: expr),* $(,)?) => {
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        pub enum Op {
+            $($op),*
+        }
+
+        impl Op {
+            pub const ALL: &'static [Op] = &[$(Op::$op),*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Op::$op => $name),*
+                }
+            }
+        }
+    };
+}
+
+ops! {
+    Lookup => "lookup",
+    Forget => "forget",
+    Getattr => "getattr",
+    Readlink => "readlink",
+    Access => "access",
+    Getxattr => "getxattr",
+    Listxattr => "listxattr",
+    Statfs => "statfs",
+    Open => "open",
+    Release => "release",
+    Read => "read",
+    Lseek => "lseek",
+    Ioctl => "ioctl",
+    CopyFileRange => "copy_file_range",
+    Opendir => "opendir",
+    Releasedir => "releasedir",
+    Readdir => "readdir",
+    Readdirplus => "readdirplus",
+    Setattr => "setattr",
+    Mknod => "mknod",
+    Mkdir => "mkdir",
+    Unlink => "unlink",
+    Rmdir => "rmdir",
+    Symlink => "symlink",
+    Rename => "rename",
+    Link => "link",
+    Write => "write",
+    Create => "create",
+    Setxattr => "setxattr",
+    Removexattr => "removexattr",
+}
+
+impl std::str::FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Op> {
+        Op::ALL
+            .iter()
+            .copied()
+            .find(|op| op.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown operation: {}", s))
+    }
+}
+
+/// Caches whose hits and misses are counted
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cache {
+    Content,
+    Disk,
+    Attr,
+    Negative,
+}
+
+impl Cache {
+    pub const ALL: &'static [Cache] = &[Cache::Content, Cache::Disk, Cache::Attr, Cache::Negative];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cache::Content => "content",
+            Cache::Disk => "disk",
+            Cache::Attr => "attr",
+            Cache::Negative => "negative",
+        }
+    }
+}
+
+/// Number of latency buckets, bucket `i` counts latencies below `2^i` microseconds
+const LATENCY_BUCKETS: usize = 24;
+/// Bucket of the latencies past the last bound, exported only under `+Inf`
+const LATENCY_OVERFLOW: usize = LATENCY_BUCKETS;
+/// Seconds the latency heatmap covers, the latest ones
+const HEATMAP_SECONDS: usize = 300;
+
+/// Bucket of a latency in the histograms and the heatmap
+fn latency_bucket(micros: u64) -> usize {
+    (64 - micros.leading_zeros() as usize).min(LATENCY_OVERFLOW)
+}
+
+/// Name of a latency bucket in `stats` and the heatmap
+fn bucket_name(bucket: usize) -> String {
+    if bucket == LATENCY_OVERFLOW {
+        format!("ge{}us", 1u64 << (LATENCY_BUCKETS - 1))
+    } else {
+        format!("lt{}us", 1u64 << bucket)
+    }
+}
+
+/// Record every n-th call to sample a `rate` fraction of them, 0 for none
+fn sample_every(rate: f64) -> u64 {
+    if rate <= 0.0 {
+        0
+    } else {
+        (1.0 / rate.min(1.0)).round() as u64
+    }
+}
+
+/// Latencies of the calls sampled, all operations together, over the last
+/// `HEATMAP_SECONDS` seconds: a latency histogram per second
+#[derive(Default)]
+struct Heatmap {
+    // seconds since the epoch, oldest first, with their histogram
+    seconds: VecDeque<(u64, [u64; LATENCY_BUCKETS + 1])>,
+}
+
+impl Heatmap {
+    fn record(&mut self, bucket: usize) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        if self.seconds.back().map(|(second, _)| *second) != Some(now) {
+            self.seconds.push_back((now, [0; LATENCY_BUCKETS + 1]));
+        }
+        while self
+            .seconds
+            .front()
+            .is_some_and(|(second, _)| *second + HEATMAP_SECONDS as u64 <= now)
+        {
+            self.seconds.pop_front();
+        }
+        if let Some((_, histogram)) = self.seconds.back_mut() {
+            histogram[bucket] += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct OpStats {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    // bytes of file contents replied with
+    bytes: AtomicU64,
+    // then the overflow bucket
+    latency: [AtomicU64; LATENCY_BUCKETS + 1],
+    // total of the latencies sampled, in microseconds
+    latency_sum: AtomicU64,
+    trace: AtomicBool,
+}
+
+#[derive(Default)]
+struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Session statistics shared between the filesystem and the control interface
+pub struct Stats {
+    ops: Vec<OpStats>,
+    caches: Vec<CacheStats>,
+    // record latency of every n-th call, 0 disables latency sampling
+    latency_sample_every: AtomicU64,
+    // the same for the heatmap, which is off unless asked for
+    heatmap_sample_every: AtomicU64,
+    heatmap: Mutex<Heatmap>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            ops: Op::ALL.iter().map(|_| OpStats::default()).collect(),
+            caches: Cache::ALL.iter().map(|_| CacheStats::default()).collect(),
+            latency_sample_every: AtomicU64::new(1),
+            heatmap_sample_every: AtomicU64::new(0),
+            heatmap: Mutex::new(Heatmap::default()),
+        }
+    }
+
+    /// Start recording a call to `op`, which ends when the returned guard is dropped
+    pub fn op(self: &Arc<Self>, op: Op) -> OpGuard {
+        let stats = &self.ops[op as usize];
+        let calls = stats.calls.fetch_add(1, Ordering::Relaxed);
+        let every = self.latency_sample_every.load(Ordering::Relaxed);
+        let sampled = every != 0 && calls.is_multiple_of(every);
+        let every = self.heatmap_sample_every.load(Ordering::Relaxed);
+        let heatmapped = every != 0 && calls.is_multiple_of(every);
+        let traced = stats.trace.load(Ordering::Relaxed);
+        // disabled unless spans are exported or logged at the trace level
+        let span = trace_span!(
+            "fuse",
+            op = op.name(),
+            ino = Empty,
+            oid = Empty,
+            latency_us = Empty,
+            error = Empty
+        );
+
+        OpGuard {
+            stats: self.clone(),
+            op,
+            start: if sampled || heatmapped || traced || !span.is_disabled() {
+                Some(Instant::now())
+            } else {
+                None
+            },
+            sampled,
+            heatmapped,
+            traced,
+            span,
+        }
+    }
+
+    /// Count a lookup in `cache` that found what it was after, or not
+    pub fn cache(&self, cache: Cache, hit: bool) {
+        let stats = &self.caches[cache as usize];
+        if hit {
+            stats.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            stats.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn reset(&self) {
+        for stats in &self.ops {
+            stats.calls.store(0, Ordering::Relaxed);
+            stats.errors.store(0, Ordering::Relaxed);
+            stats.bytes.store(0, Ordering::Relaxed);
+            for bucket in &stats.latency {
+                bucket.store(0, Ordering::Relaxed);
+            }
+            stats.latency_sum.store(0, Ordering::Relaxed);
+        }
+        for stats in &self.caches {
+            stats.hits.store(0, Ordering::Relaxed);
+            stats.misses.store(0, Ordering::Relaxed);
+        }
+        self.heatmap.lock().unwrap().seconds.clear();
+    }
+
+    /// Sample latency of a `rate` fraction of calls, 0 disables sampling
+    pub fn set_latency_sampling(&self, rate: f64) {
+        self.latency_sample_every
+            .store(sample_every(rate), Ordering::Relaxed);
+    }
+
+    /// Add the latency of a `rate` fraction of calls to the heatmap, 0, the
+    /// default, disables it
+    pub fn set_heatmap_sampling(&self, rate: f64) {
+        self.heatmap_sample_every
+            .store(sample_every(rate), Ordering::Relaxed);
+    }
+
+    /// The heatmap, a line per second with calls sampled: its time in seconds
+    /// since the epoch, then the calls of each latency bucket as `stats` has
+    /// them
+    pub fn heatmap(&self) -> String {
+        let mut out = String::new();
+        for (second, histogram) in &self.heatmap.lock().unwrap().seconds {
+            out.push_str(&second.to_string());
+            for (idx, count) in histogram.iter().enumerate() {
+                if *count != 0 {
+                    out.push_str(&format!(" {}={}", bucket_name(idx), count));
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn set_trace(&self, op: Op, enabled: bool) {
+        self.ops[op as usize]
+            .trace
+            .store(enabled, Ordering::Relaxed);
+    }
+}
+
+type Counter = fn(&OpStats) -> &AtomicU64;
+
+impl Stats {
+    /// Calls of the operations called at least once, and lookups in caches
+    pub fn to_json(&self) -> serde_json::Value {
+        let ops: serde_json::Map<_, _> = Op::ALL
+            .iter()
+            .map(|op| (op.name(), &self.ops[*op as usize]))
+            .filter(|(_, stats)| stats.calls.load(Ordering::Relaxed) != 0)
+            .map(|(name, stats)| {
+                let value = json!({
+                    "calls": stats.calls.load(Ordering::Relaxed),
+                    "errors": stats.errors.load(Ordering::Relaxed),
+                    "bytes": stats.bytes.load(Ordering::Relaxed),
+                    "latency_us": stats.latency_sum.load(Ordering::Relaxed),
+                });
+                (name.to_string(), value)
+            })
+            .collect();
+        let caches: serde_json::Map<_, _> = Cache::ALL
+            .iter()
+            .map(|cache| {
+                let stats = &self.caches[*cache as usize];
+                let value = json!({
+                    "hits": stats.hits.load(Ordering::Relaxed),
+                    "misses": stats.misses.load(Ordering::Relaxed),
+                });
+                (cache.name().to_string(), value)
+            })
+            .collect();
+        json!({ "ops": ops, "caches": caches })
+    }
+}
+
+impl Stats {
+    /// Counters in the Prometheus text format. Latencies are sampled, the
+    /// histogram only counts the calls sampled.
+    pub fn write_prometheus(&self, out: &mut String) -> fmt::Result {
+        use fmt::Write;
+
+        let counters: [(&str, &str, Counter); 3] = [
+            ("calls", "Calls per operation", |stats| &stats.calls),
+            ("errors", "Calls that failed", |stats| &stats.errors),
+            ("bytes", "Bytes of file contents replied with", |stats| {
+                &stats.bytes
+            }),
+        ];
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP giblefs_op_{}_total {}", name, help)?;
+            writeln!(out, "# TYPE giblefs_op_{}_total counter", name)?;
+            for op in Op::ALL {
+                let value = counter(&self.ops[*op as usize]).load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "giblefs_op_{}_total{{op=\"{}\"}} {}",
+                    name,
+                    op.name(),
+                    value
+                )?;
+            }
+        }
+
+        writeln!(
+            out,
+            "# HELP giblefs_op_latency_microseconds Latency of sampled calls"
+        )?;
+        writeln!(out, "# TYPE giblefs_op_latency_microseconds histogram")?;
+        for op in Op::ALL {
+            let stats = &self.ops[*op as usize];
+            let mut count = 0;
+            for (idx, bucket) in stats.latency[..LATENCY_BUCKETS].iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "giblefs_op_latency_microseconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    1u64 << idx,
+                    count
+                )?;
+            }
+            // latencies past the last bound have no bucket of their own
+            count += stats.latency[LATENCY_OVERFLOW].load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "giblefs_op_latency_microseconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                op.name(),
+                count
+            )?;
+            writeln!(
+                out,
+                "giblefs_op_latency_microseconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                stats.latency_sum.load(Ordering::Relaxed)
+            )?;
+            writeln!(
+                out,
+                "giblefs_op_latency_microseconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                count
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP giblefs_cache_hits_total Lookups that found what they were after"
+        )?;
+        writeln!(out, "# TYPE giblefs_cache_hits_total counter")?;
+        for cache in Cache::ALL {
+            let hits = self.caches[*cache as usize].hits.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "giblefs_cache_hits_total{{cache=\"{}\"}} {}",
+                cache.name(),
+                hits
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP giblefs_cache_misses_total Lookups that did not"
+        )?;
+        writeln!(out, "# TYPE giblefs_cache_misses_total counter")?;
+        for cache in Cache::ALL {
+            let misses = self.caches[*cache as usize].misses.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "giblefs_cache_misses_total{{cache=\"{}\"}} {}",
+                cache.name(),
+                misses
+            )?;
+        }
+        writeln!(
+            out,
+            "# HELP giblefs_cache_hit_ratio Hits over lookups since the last reset"
+        )?;
+        writeln!(out, "# TYPE giblefs_cache_hit_ratio gauge")?;
+        for cache in Cache::ALL {
+            let stats = &self.caches[*cache as usize];
+            let hits = stats.hits.load(Ordering::Relaxed);
+            let lookups = hits + stats.misses.load(Ordering::Relaxed);
+            if lookups != 0 {
+                writeln!(
+                    out,
+                    "giblefs_cache_hit_ratio{{cache=\"{}\"}} {}",
+                    cache.name(),
+                    hits as f64 / lookups as f64
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for op in Op::ALL {
+            let stats = &self.ops[*op as usize];
+            write!(
+                f,
+                "{} calls={} errors={}",
+                op.name(),
+                stats.calls.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed)
+            )?;
+            let bytes = stats.bytes.load(Ordering::Relaxed);
+            if bytes != 0 {
+                write!(f, " bytes={}", bytes)?;
+            }
+            for (idx, bucket) in stats.latency.iter().enumerate() {
+                let count = bucket.load(Ordering::Relaxed);
+                if count != 0 {
+                    write!(f, " {}={}", bucket_name(idx), count)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        for cache in Cache::ALL {
+            let stats = &self.caches[*cache as usize];
+            writeln!(
+                f,
+                "cache.{} hits={} misses={}",
+                cache.name(),
+                stats.hits.load(Ordering::Relaxed),
+                stats.misses.load(Ordering::Relaxed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct OpGuard {
+    stats: Arc<Stats>,
+    op: Op,
+    start: Option<Instant>,
+    sampled: bool,
+    heatmapped: bool,
+    traced: bool,
+    span: Span,
+}
+
+impl OpGuard {
+    /// Record the inode the call is about in its span
+    pub fn ino(self, ino: u64) -> Self {
+        self.span.record("ino", ino);
+        self
+    }
+
+    /// Make the span of the call the current one on this thread, for events and
+    /// the object ids found along the way to be recorded in it
+    pub fn enter(&self) -> Entered<'_> {
+        self.span.enter()
+    }
+
+    /// Count the call as failed with `error`, which is passed through
+    pub fn error<E>(&self, error: E) -> E {
+        self.stats.ops[self.op as usize]
+            .errors
+            .fetch_add(1, Ordering::Relaxed);
+        self.span.record("error", true);
+        error
+    }
+
+    /// Count the call as failed if `result` is an error
+    pub fn check<T, E>(&self, result: Result<T, E>) -> Result<T, E> {
+        result.map_err(|e| self.error(e))
+    }
+
+    /// Count `bytes` of file contents replied with
+    pub fn bytes(&self, bytes: usize) {
+        self.stats.ops[self.op as usize]
+            .bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for OpGuard {
+    fn drop(&mut self) {
+        let elapsed = match self.start {
+            Some(start) => start.elapsed(),
+            None => return,
+        };
+        self.span.record("latency_us", elapsed.as_micros() as u64);
+
+        let micros = elapsed.as_micros() as u64;
+        let bucket = latency_bucket(micros);
+        if self.sampled {
+            let stats = &self.stats.ops[self.op as usize];
+            stats.latency[bucket].fetch_add(1, Ordering::Relaxed);
+            stats.latency_sum.fetch_add(micros, Ordering::Relaxed);
+        }
+        if self.heatmapped {
+            self.stats.heatmap.lock().unwrap().record(bucket);
+        }
+
+        if self.traced {
+            info!("{} took {:?}", self.op.name(), elapsed);
+        }
+    }
+}
+
+/// Record the object an operation turned out to be about in its span, if it
+/// is the current one
+pub fn record_oid(oid: git2::Oid) {
+    Span::current().record("oid", field::display(oid));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_past_the_last_bound_only_count_as_inf() {
+        let stats = Stats::new();
+        let top = 1u64 << (LATENCY_BUCKETS - 1);
+        for micros in &[top - 1, top, 60_000_000] {
+            stats.ops[Op::Read as usize].latency[latency_bucket(*micros)]
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut out = String::new();
+        stats.write_prometheus(&mut out).unwrap();
+        let bucket = |le: &str| {
+            let line = format!(
+                "giblefs_op_latency_microseconds_bucket{{op=\"read\",le=\"{}\"}} ",
+                le
+            );
+            out.lines()
+                .find_map(|l| l.strip_prefix(line.as_str()))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(bucket(&top.to_string()), "1");
+        assert_eq!(bucket("+Inf"), "3");
+        assert!(stats.to_string().contains(&format!(" ge{}us=2", top)));
+    }
+}
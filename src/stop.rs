@@ -0,0 +1,33 @@
+//! Asking the process to unmount and exit, from a signal handler or the
+//! control socket, and waiting for it without spinning.
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Stop {
+    stopped: Mutex<bool>,
+    changed: Condvar,
+}
+
+impl Stop {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Ask to stop, waking whoever waits
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.changed.notify_all();
+    }
+
+    /// Wait at most `timeout` for a stop, whether one was asked for
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .changed
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .unwrap();
+        *stopped
+    }
+}
@@ -0,0 +1,60 @@
+//! Notifications to systemd through `$NOTIFY_SOCKET`, see sd_notify(3). They
+//! are dropped when not started by systemd.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::Duration;
+use tracing::debug;
+
+/// Send newline separated `VARIABLE=value` assignments, e.g. `READY=1`
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let path = path.to_string_lossy();
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // names starting with `@` are in the abstract namespace
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&*path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+    if let Err(e) = result {
+        debug!("unable to notify systemd of {:?}: {}", state, e);
+    }
+}
+
+/// Pings for `WatchdogSec=`, if the service has it
+pub struct Watchdog {
+    interval: Duration,
+}
+
+impl Watchdog {
+    pub fn new() -> Option<Self> {
+        // the variables are meant for another process, e.g. before --background
+        if let Some(pid) = env::var_os("WATCHDOG_PID") {
+            if pid.to_str()?.parse::<u32>().ok()? != process::id() {
+                return None;
+            }
+        }
+        let usec: u64 = env::var_os("WATCHDOG_USEC")?.to_str()?.parse().ok()?;
+        Some(Watchdog {
+            // twice as often as required, as sd_watchdog_enabled(3) recommends
+            interval: Duration::from_micros(usec) / 2,
+        })
+    }
+
+    /// How often to ping
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Tell systemd the service is alive
+    pub fn ping(&self) {
+        notify("WATCHDOG=1");
+    }
+}
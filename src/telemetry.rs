@@ -0,0 +1,58 @@
+//! Export of the spans of FUSE operations to an OTLP collector (Jaeger, Tempo,
+//! the OpenTelemetry collector...), with the `otlp` feature.
+
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::warn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Spans are exported in batches until this is dropped, which flushes them
+pub struct Telemetry {
+    provider: TracerProvider,
+    // exports from its own threads, requests are never held up by the collector
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!("unable to flush spans: {}", e);
+        }
+    }
+}
+
+/// Export spans over OTLP/HTTP to `endpoint`, e.g. `http://localhost:4318/v1/traces`
+pub fn init(endpoint: &str) -> Result<Telemetry> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("otlp")
+        .enable_all()
+        .build()?;
+    let _context = runtime.enter();
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            "giblefs",
+        )]))
+        .build();
+
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("giblefs")))
+        .try_init()?;
+
+    Ok(Telemetry {
+        provider,
+        _runtime: runtime,
+    })
+}
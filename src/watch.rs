@@ -0,0 +1,193 @@
+//! Invalidation of branch entries in the kernel cache once their branch moves,
+//! or all of them once refs are refreshed on SIGHUP, or with `--watch` once
+//! the refs or packs of the repository change. Packs changing also has the
+//! object databases refreshed.
+
+use anyhow::Result;
+use fuser::Notifier;
+use git2::{BranchType, Oid, Repository};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of SIGHUPs received
+static REFRESHES: AtomicU64 = AtomicU64::new(0);
+/// Number of times packs were added or removed, SIGHUPs included
+static PACK_CHANGES: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn hangup(_: libc::c_int) {
+    refresh_objects();
+    refresh();
+}
+
+/// Re-read refs, dropping every branch entry and injected file
+pub fn refresh() {
+    REFRESHES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Have the object databases pick up the packs added and drop those removed
+pub fn refresh_objects() {
+    PACK_CHANGES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Refresh refs on SIGHUP, so that a `git fetch` shows without remounting
+pub fn refresh_on_hangup() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(hangup),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(Signal::SIGHUP, &action)? };
+    Ok(())
+}
+
+/// Refresh refs once those of the repository at `path` change or a pack is
+/// added, as commits, fetches and `git gc` do, and objects too in the latter
+/// case, until the watcher returned is dropped. Refs are refreshed on the next
+/// poll, one per second at most, objects on the next lookup of a commit.
+pub fn watch_repository(path: &Path) -> Result<RecommendedWatcher> {
+    let git_dir = Repository::open(path)?.path().to_path_buf();
+    let packs = git_dir.join("objects").join("pack");
+    let mut watcher = notify::recommended_watcher({
+        let (git_dir, packs) = (git_dir.clone(), packs.clone());
+        move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                let changed = |path: &&PathBuf| written_name(&event.kind, path).is_some();
+                for path in event.paths.iter().filter(changed) {
+                    if changes_packs(path, &packs) {
+                        debug!("{} changed, refreshing objects and refs", path.display());
+                        refresh_objects();
+                        refresh();
+                        break;
+                    } else if changes_refs(path, &git_dir) {
+                        debug!("{} changed, refreshing refs", path.display());
+                        refresh();
+                        break;
+                    }
+                }
+            }
+            Err(e) => error!("unable to watch {}: {}", git_dir.display(), e),
+        }
+    })?;
+    watcher.watch(&git_dir.join("refs"), RecursiveMode::Recursive)?;
+    // `packed-refs` and `HEAD` are replaced by renames, their directory is watched
+    watcher.watch(&git_dir, RecursiveMode::NonRecursive)?;
+    if packs.is_dir() {
+        watcher.watch(&packs, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+/// Name of the file at `path` if the event wrote or deleted it, rather than
+/// its lock file
+fn written_name<'a>(kind: &EventKind, path: &'a Path) -> Option<&'a str> {
+    let written = match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(modify) => !matches!(modify, ModifyKind::Metadata(_)),
+        _ => false,
+    };
+    let name = path.file_name().and_then(OsStr::to_str)?;
+    (written && !name.ends_with(".lock")).then(|| name)
+}
+
+/// Whether writing `path` moves refs
+fn changes_refs(path: &Path, git_dir: &Path) -> bool {
+    match (path.file_name(), path.parent()) {
+        (Some(name), Some(dir)) if dir == git_dir => name == "packed-refs" || name == "HEAD",
+        _ => path.starts_with(git_dir.join("refs")),
+    }
+}
+
+/// Whether writing `path` adds or removes a pack, as its index tells
+fn changes_packs(path: &Path, packs: &Path) -> bool {
+    path.parent() == Some(packs) && path.extension().is_some_and(|ext| ext == "idx")
+}
+
+/// Changes every time refs are refreshed
+pub fn refresh_generation() -> u64 {
+    REFRESHES.load(Ordering::SeqCst)
+}
+
+/// Changes every time objects are refreshed
+pub fn objects_generation() -> u64 {
+    PACK_CHANGES.load(Ordering::SeqCst)
+}
+
+/// Branches looked up through the mount, along with the commit they resolved to
+pub type Branches = Arc<Mutex<HashMap<String, Oid>>>;
+
+/// Poll `branches` in the background, invalidating the entry of every branch
+/// that no longer points to the commit it was looked up at, or of every branch
+/// once refs are refreshed. Branches are entries of directory `root`.
+pub fn spawn(path: PathBuf, root: u64, branches: Branches, notifier: Notifier) -> Result<()> {
+    let mut repo = Repository::open(&path)?;
+    let mut generation = refresh_generation();
+
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        // the filesystem is gone, its repository no longer served
+        if Arc::strong_count(&branches) == 1 {
+            return;
+        }
+
+        let refresh = refresh_generation() != generation;
+        if refresh {
+            generation = refresh_generation();
+            info!("refreshing refs");
+            // a new handle reads refs from scratch
+            match Repository::open(&path) {
+                Ok(reopened) => repo = reopened,
+                Err(e) => error!("unable to reopen the repository: {}", e),
+            }
+        }
+
+        // resolved unlocked, lookups record branches meanwhile
+        let watched = branches.lock().unwrap().clone();
+        let moved: Vec<(String, Oid)> = watched
+            .into_iter()
+            .filter(|(name, commit)| {
+                if refresh {
+                    return true;
+                }
+                let current = repo
+                    .find_branch(name, BranchType::Local)
+                    .and_then(|branch| branch.get().peel_to_commit())
+                    .map(|commit| commit.id())
+                    .ok();
+                current != Some(*commit)
+            })
+            .collect();
+
+        for (name, commit) in moved {
+            debug!(
+                "branch {} moved from {}, invalidating its entry",
+                name, commit
+            );
+            // the next lookup records the branch again
+            {
+                let mut branches = branches.lock().unwrap();
+                if branches.get(&name) == Some(&commit) {
+                    branches.remove(&name);
+                }
+            }
+            match notifier.inval_entry(root, OsStr::new(&name)) {
+                // the kernel already dropped the entry
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => error!("unable to invalidate {}: {}", name, e),
+                Ok(()) => {}
+            }
+        }
+    });
+
+    Ok(())
+}
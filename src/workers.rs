@@ -0,0 +1,136 @@
+//! Pool of threads serving the costly part of requests, blob inflation and
+//! reads of streamed blobs, so that reads of different files proceed in
+//! parallel. Every worker opens its own store on the repository, libgit2
+//! handles cannot be shared. Lookups, attributes and listings are not served
+//! here: they stay on the thread serving the session, through the one
+//! repository handle of the filesystem, one at a time.
+//!
+//! Blobs asked for while they are being inflated are not inflated again, the
+//! later callers wait for the same result.
+
+use crate::cache::DiskCache;
+use crate::git::{self, Backend, GitError, ObjectStore, OpenOptions};
+use anyhow::Result;
+use git2::Oid;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{debug, error};
+
+type Job = Box<dyn FnOnce(&dyn ObjectStore) + Send>;
+type Waiter = Box<dyn FnOnce(&Result<Arc<[u8]>, GitError>) + Send>;
+
+#[derive(Clone)]
+pub struct Workers {
+    jobs: Sender<Job>,
+    // blob being inflated => callers waiting for it
+    inflating: Arc<Mutex<HashMap<Oid, Vec<Waiter>>>>,
+    // key of jobs run in order, one being run => those waiting for it
+    ordered: Arc<Mutex<HashMap<u64, VecDeque<Job>>>>,
+    disk: Arc<DiskCache>,
+}
+
+impl Workers {
+    /// Spawn `count` workers, at least one, on the repository at `path`
+    /// opened as `options` ask. Inflated blobs go through `disk`.
+    pub fn new(
+        path: &Path,
+        count: usize,
+        backend: Backend,
+        options: &OpenOptions,
+        disk: Arc<DiskCache>,
+    ) -> Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for n in 0..count.max(1) {
+            let store = git::open_store(path, backend, options)?;
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", n))
+                .spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        // the filesystem is gone
+                        Err(_) => break,
+                    };
+                    job(store.as_ref());
+                })?;
+        }
+
+        Ok(Workers {
+            jobs,
+            inflating: Arc::new(Mutex::new(HashMap::new())),
+            ordered: Arc::new(Mutex::new(HashMap::new())),
+            disk,
+        })
+    }
+
+    /// Run `job` on the next idle worker
+    pub fn run<F: FnOnce(&dyn ObjectStore) + Send + 'static>(&self, job: F) {
+        if self.jobs.send(Box::new(job)).is_err() {
+            error!("all workers are gone, dropping request");
+        }
+    }
+
+    /// Run `job` on the next idle worker once the jobs run before it with the
+    /// same `key` are done, one after the other in the order they were run
+    pub fn run_in_order<F: FnOnce(&dyn ObjectStore) + Send + 'static>(&self, key: u64, job: F) {
+        {
+            let mut ordered = self.ordered.lock().unwrap();
+            if let Some(waiting) = ordered.get_mut(&key) {
+                waiting.push_back(Box::new(job));
+                return;
+            }
+            ordered.insert(key, VecDeque::new());
+        }
+
+        let ordered = self.ordered.clone();
+        self.run(move |store| {
+            job(store);
+            // those that came in meanwhile, on this worker
+            loop {
+                let next = {
+                    let mut ordered = ordered.lock().unwrap();
+                    match ordered.get_mut(&key).and_then(VecDeque::pop_front) {
+                        Some(next) => next,
+                        None => {
+                            ordered.remove(&key);
+                            return;
+                        }
+                    }
+                };
+                next(store);
+            }
+        });
+    }
+
+    /// Inflate the blob `hash` on the next idle worker and hand it to `done`,
+    /// along with every other caller asking for it in the meantime
+    pub fn inflate<F>(&self, hash: Oid, done: F)
+    where
+        F: FnOnce(&Result<Arc<[u8]>, GitError>) + Send + 'static,
+    {
+        {
+            let mut inflating = self.inflating.lock().unwrap();
+            if let Some(waiters) = inflating.get_mut(&hash) {
+                debug!("{} is already being inflated", hash);
+                waiters.push(Box::new(done));
+                return;
+            }
+            inflating.insert(hash, vec![Box::new(done)]);
+        }
+
+        let inflating = self.inflating.clone();
+        let disk = self.disk.clone();
+        self.run(move |store| {
+            let content = disk.get_or_insert_with(hash, || store.blob(hash));
+            let waiters = inflating.lock().unwrap().remove(&hash);
+            for waiter in waiters.into_iter().flatten() {
+                waiter(&content);
+            }
+        });
+    }
+}